@@ -1,5 +1,3 @@
-use std::sync::Arc;
-
 use smolagents_rs::agents::FunctionCallingAgent;
 use smolagents_rs::models::openai::OpenAIServerModel;
 use smolagents_rs::parallel::run_tasks_parallel;
@@ -25,7 +23,9 @@ fn main() {
         "Latest news about AI".to_string(),
     ];
 
-    let results = run_tasks_parallel::<FunctionCallingAgent<OpenAIServerModel>>(Arc::new(build_agent), &tasks);
+    // Configure the agent once, every task runs on a fork of it
+    let agent = build_agent();
+    let results = run_tasks_parallel(agent.fork_builder().unwrap(), &tasks);
 
     for (task, result) in tasks.iter().zip(results.into_iter()) {
         println!("Task: {}\nResult: {:?}\n", task, result.unwrap());
@@ -0,0 +1,45 @@
+use std::sync::Arc;
+
+use smolagents_rs::agents::FunctionCallingAgent;
+use smolagents_rs::models::openai::OpenAIServerModel;
+use smolagents_rs::parallel::run_tasks_streaming_with_concurrency;
+use smolagents_rs::tools::{AnyTool, DuckDuckGoSearchTool, VisitWebsiteTool};
+
+fn build_agent() -> FunctionCallingAgent<OpenAIServerModel> {
+    let tools: Vec<Box<dyn AnyTool>> = vec![
+        Box::new(DuckDuckGoSearchTool::new()),
+        Box::new(VisitWebsiteTool::new()),
+    ];
+    let model = OpenAIServerModel::new(
+        Some("https://api.openai.com/v1/chat/completions"),
+        Some("gpt-4o-mini"),
+        None,
+        None,
+    );
+    FunctionCallingAgent::new(model, tools, None, None, None, None).unwrap()
+}
+
+fn main() {
+    let tasks = vec![
+        "What is Rust?".to_string(),
+        "Latest news about AI".to_string(),
+        "Who won the last football world cup?".to_string(),
+    ];
+
+    // Run at most two agents at a time and print each result as soon as it is ready
+    let results = run_tasks_streaming_with_concurrency::<FunctionCallingAgent<OpenAIServerModel>>(
+        Arc::new(build_agent),
+        &tasks,
+        2,
+    );
+
+    for (completed, (index, result)) in results.iter().enumerate() {
+        println!(
+            "[{}/{}] Task: {}\nResult: {:?}\n",
+            completed + 1,
+            tasks.len(),
+            tasks[index],
+            result
+        );
+    }
+}
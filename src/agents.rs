@@ -11,28 +11,37 @@
 //!
 //!
 use crate::errors::AgentError;
-use crate::models::model_traits::Model;
-use crate::models::openai::ToolCall;
+use crate::models::model_traits::{Model, ModelResponse};
+use crate::models::openai::{parse_arguments, FunctionCall, ToolCall};
 use crate::models::types::Message;
 use crate::models::types::MessageRole;
 use crate::prompts::{
     user_prompt_plan, SYSTEM_PROMPT_FACTS, SYSTEM_PROMPT_PLAN, TOOL_CALLING_SYSTEM_PROMPT,
 };
-use crate::tools::{AnyTool, FinalAnswerTool, ToolGroup, ToolInfo};
+use crate::tools::tool_traits::{traced_tool_call, ToolContext};
+use crate::tools::{default_tools, merge_tools, AnyTool, FinalAnswerTool, ToolGroup, ToolInfo};
 use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{mpsc, Arc, Mutex};
+use std::time::Duration;
 
 use anyhow::Result;
 use colored::Colorize;
-use log::info;
+use log::{info, warn};
 
 use serde::Serialize;
-use serde_json::json;
+use serde_json::{json, Value};
 #[cfg(feature = "code-agent")]
 use {
-    crate::errors::InterpreterError, crate::local_python_interpreter::LocalPythonInterpreter,
-    crate::models::openai::FunctionCall, crate::prompts::CODE_SYSTEM_PROMPT, regex::Regex,
+    crate::errors::InterpreterError,
+    crate::local_python_interpreter::{LocalPythonInterpreter, BASE_AUTHORIZED_IMPORTS},
+    crate::prompts::{CODE_PARSE_CORRECTION_PROMPT, CODE_SYSTEM_PROMPT},
+    regex::Regex,
 };
 
+/// The marker introducing the results of tool calls in the memory of the agents.
+pub const DEFAULT_OBSERVATION_MARKER: &str = "Observation:";
+
 const DEFAULT_TOOL_DESCRIPTION_TEMPLATE: &str = r#"
 {{ tool.name }}: {{ tool.description }}
     Takes inputs: {{tool.inputs}}
@@ -50,6 +59,12 @@ pub fn get_tool_description_with_args(tool: &ToolInfo) -> String {
             .to_string()
             .as_str(),
     );
+    if !tool.function.output_description.is_empty() {
+        description.push_str(&format!(
+            "    Returns: {}\n",
+            tool.function.output_description
+        ));
+    }
 
     description
 }
@@ -71,14 +86,62 @@ pub fn format_prompt_with_tools(tools: Vec<ToolInfo>, prompt_template: &str) ->
     prompt
 }
 
+/// Replace the `{{name}}` placeholders of `prompt` with the values of `vars`. A placeholder without a
+/// value is kept as is, or is an error if `strict` is set.
+pub fn format_prompt_with_vars(
+    prompt: &str,
+    vars: &HashMap<String, String>,
+    strict: bool,
+) -> Result<String, AgentError> {
+    let mut formatted = String::with_capacity(prompt.len());
+    let mut missing = Vec::new();
+    let mut rest = prompt;
+    while let Some(start) = rest.find("{{") {
+        formatted.push_str(&rest[..start]);
+        let placeholder = &rest[start..];
+        let name = placeholder[2..]
+            .find("}}")
+            .map(|end| &placeholder[2..2 + end])
+            .filter(|name| {
+                !name.is_empty() && name.chars().all(|c| c.is_alphanumeric() || c == '_')
+            });
+        let Some(name) = name else {
+            formatted.push_str("{{");
+            rest = &placeholder[2..];
+            continue;
+        };
+        match vars.get(name) {
+            Some(value) => formatted.push_str(value),
+            None => {
+                missing.push(name);
+                formatted.push_str(&placeholder[..name.len() + 4]);
+            }
+        }
+        rest = &placeholder[name.len() + 4..];
+    }
+    formatted.push_str(rest);
+
+    if strict && !missing.is_empty() {
+        return Err(AgentError::Execution(format!(
+            "No value for the prompt placeholders: {}",
+            missing.join(", ")
+        )));
+    }
+    Ok(formatted)
+}
+
 pub fn show_agents_description(managed_agents: &HashMap<String, Box<dyn Agent>>) -> String {
     let mut managed_agent_description = r#"You can also give requests to team members.
 Calling a team member works the same as for calling a tool: simply, the only argument you can give in the call is 'request', a long string explaining your request.
 Given that this team member is a real human, you should be very verbose in your request.
 Here is a list of the team members that you can call:"#.to_string();
 
-    for (name, agent) in managed_agents.iter() {
-        managed_agent_description.push_str(&format!("{}: {:?}\n", name, agent.description()));
+    for (key, agent) in managed_agents {
+        managed_agent_description.push_str(&format!(
+            "\n{}: {:?}",
+            agent.name().unwrap_or(key),
+            agent.description()
+        ));
     }
 
     managed_agent_description
@@ -102,40 +165,235 @@ pub fn format_prompt_with_managed_agent_description(
     }
 }
 
+/// Progress events emitted by an agent while it runs, e.g. to drive a spinner in a UI.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AgentEvent {
+    /// A request to the model was sent.
+    ModelRequestStarted,
+    /// The model request completed, successfully or not.
+    ModelRequestFinished,
+}
+
+/// Callback notified of the [`AgentEvent`]s of an agent.
+pub type EventCallback = Arc<dyn Fn(AgentEvent) + Send + Sync>;
+
+/// Callback asked to approve a tool call before it runs, with the tool name and the arguments. The call is
+/// skipped when it returns `false`.
+pub type ApprovalCallback = Arc<dyn Fn(&str, &serde_json::Value) -> bool + Send + Sync>;
+
+/// Callback transforming the final answer of a run before it is returned, e.g. to strip markdown or to
+/// redact personal data.
+pub type AnswerPostprocessor = Arc<dyn Fn(String) -> String + Send + Sync>;
+
+/// Callback notified of every tool observation as it is recorded, with the name of the tool and the
+/// observation, e.g. to show the output of the tools in a UI before the step ends.
+pub type ObservationCallback = Arc<dyn Fn(&str, &str) + Send + Sync>;
+
 pub trait Agent {
-    fn name(&self) -> &'static str;
+    /// The name introducing the agent to the agents managing it, `None` to introduce it by the key it is
+    /// managed under.
+    fn name(&self) -> Option<&str>;
     fn get_max_steps(&self) -> usize;
     fn get_step_number(&self) -> usize;
     fn reset_step_number(&mut self);
     fn increment_step_number(&mut self);
     fn get_logs_mut(&mut self) -> &mut Vec<Step>;
+    /// The steps of the agent, without borrowing it mutably. Agents keeping their steps override it, the
+    /// default has none.
+    fn steps(&self) -> &[Step] {
+        &[]
+    }
+    /// The final answer of the last task, if one of its steps gave it. An answer provided from the memory
+    /// after the last step is recorded on that step.
+    fn last_answer(&self) -> Option<String> {
+        self.steps()
+            .iter()
+            .rev()
+            .take_while(|step| !matches!(step, Step::TaskStep(_)))
+            .find_map(|step| match step {
+                Step::ActionStep(step_log) => step_log.final_answer.clone(),
+                _ => None,
+            })
+    }
+    /// A Mermaid flowchart of the steps, for debugging and documentation: the tasks, the plans, every action
+    /// step with the tools it called, and the errors and final answers of the steps.
+    fn to_mermaid(&self) -> String {
+        let mut lines = vec!["flowchart TD".to_string()];
+        let mut previous: Option<String> = None;
+        let mut action = 0;
+        for (i, step) in self.steps().iter().enumerate() {
+            let id = format!("step{}", i);
+            let label = match step {
+                Step::TaskStep(task) => {
+                    action = 0;
+                    format!("Task: {}", task)
+                }
+                Step::PlanningStep(plan, _) => format!("Plan: {}", plan),
+                Step::ActionStep(step_log) => {
+                    action += 1;
+                    let tools = step_log
+                        .tool_call
+                        .iter()
+                        .flatten()
+                        .map(|tool_call| tool_call.function.name.as_str())
+                        .collect::<Vec<_>>();
+                    match tools.is_empty() {
+                        true => format!("Step {}", action),
+                        false => format!("Step {}: {}", action, tools.join(", ")),
+                    }
+                }
+                Step::SystemPromptStep(_) | Step::ToolCall(_) => continue,
+            };
+            lines.push(format!("    {}[\"{}\"]", id, mermaid_label(&label)));
+            if let Some(previous) = &previous {
+                lines.push(format!("    {} --> {}", previous, id));
+            }
+            if let Step::ActionStep(step_log) = step {
+                if let Some(error) = &step_log.error {
+                    let label = mermaid_label(&format!("Error: {}", error.message()));
+                    lines.push(format!("    {}_error[\"{}\"]", id, label));
+                    lines.push(format!("    {} -. error .-> {}_error", id, id));
+                }
+                if let Some(answer) = &step_log.final_answer {
+                    let label = mermaid_label(&format!("Final answer: {}", answer));
+                    lines.push(format!("    {}_answer([\"{}\"])", id, label));
+                    lines.push(format!("    {} --> {}_answer", id, id));
+                }
+            }
+            previous = Some(id);
+        }
+        lines.join("\n")
+    }
     fn set_task(&mut self, task: &str);
     fn get_system_prompt(&self) -> &str;
+    /// The marker introducing the results of tool calls in the memory. Generation stops at the marker, so
+    /// the model does not write the results itself.
+    fn observation_marker(&self) -> &str {
+        DEFAULT_OBSERVATION_MARKER
+    }
     fn description(&self) -> String {
         "".to_string()
     }
     fn model(&self) -> &dyn Model;
+    /// The tools available to the agent, as they are described to the model.
+    fn tool_infos(&self) -> Vec<ToolInfo> {
+        Vec::new()
+    }
+    /// The names of the tools available to the agent.
+    fn tool_names(&self) -> Vec<&'static str> {
+        self.tool_infos()
+            .iter()
+            .map(|tool| tool.function.name)
+            .collect()
+    }
+    /// The cancellation flag of the agent, if any. When the flag is set, the run stops before the next step.
+    fn cancel_token(&self) -> Option<&Arc<AtomicBool>> {
+        None
+    }
+    fn is_cancelled(&self) -> bool {
+        self.cancel_token()
+            .map(|token| token.load(Ordering::SeqCst))
+            .unwrap_or(false)
+    }
+    /// The callback notified of the agent's progress events, if any.
+    fn event_callback(&self) -> Option<&EventCallback> {
+        None
+    }
+    fn emit_event(&self, event: AgentEvent) {
+        if let Some(callback) = self.event_callback() {
+            callback(event);
+        }
+    }
+    /// The final answer returned by a run, transformed by the answer postprocessor of the agent if it has
+    /// one.
+    fn postprocess_answer(&self, answer: String) -> String {
+        answer
+    }
+    /// The number of consecutive steps ending in an error after which the agent stops and provides a final
+    /// answer from its memory, if any.
+    fn consecutive_error_threshold(&self) -> Option<usize> {
+        None
+    }
+    /// Whether the last steps of the current task all ended in an error, as many as the
+    /// [`consecutive_error_threshold`](Agent::consecutive_error_threshold).
+    fn error_threshold_reached(&mut self) -> bool {
+        let Some(threshold) = self.consecutive_error_threshold() else {
+            return false;
+        };
+        let errors = self
+            .get_logs_mut()
+            .iter()
+            .rev()
+            .filter(|step| !matches!(step, Step::PlanningStep(..)))
+            .take_while(|step| match step {
+                Step::ActionStep(step) => step.error.is_some() || step.tool_calls_failed,
+                _ => false,
+            })
+            .count();
+        errors >= threshold
+    }
+    /// Shorten the memory after a step failed with [`AgentError::ContextLengthExceeded`], so the step can
+    /// be retried once. The observations of the previous steps are replaced with a note. Returns whether
+    /// anything was removed.
+    fn compact_memory(&mut self) -> bool {
+        let mut compacted = false;
+        for step in self.get_logs_mut() {
+            let Step::ActionStep(AgentStep {
+                observations: Some(observations),
+                ..
+            }) = step
+            else {
+                continue;
+            };
+            // Every tool call keeps an observation
+            for observation in observations.iter_mut() {
+                if observation != COMPACTED_OBSERVATION {
+                    *observation = COMPACTED_OBSERVATION.to_string();
+                    compacted = true;
+                }
+            }
+        }
+        compacted
+    }
     fn step(&mut self, log_entry: &mut Step) -> Result<Option<String>>;
     fn direct_run(&mut self, _task: &str) -> Result<String> {
+        #[cfg(feature = "tracing")]
+        let _span = tracing::info_span!("agent.run", max_steps = self.get_max_steps()).entered();
         let mut final_answer: Option<String> = None;
         while final_answer.is_none() && self.get_step_number() < self.get_max_steps() {
-            println!("Step number: {:?}", self.get_step_number());
-            let mut step_log = Step::ActionStep(AgentStep {
-                agent_memory: None,
-                llm_output: None,
-                tool_call: None,
-                error: None,
-                observations: None,
-                _step: self.get_step_number(),
-            });
+            if self.is_cancelled() {
+                return Err(AgentError::Execution("cancelled".to_string()).into());
+            }
+            info!("Step number: {}", self.get_step_number());
+            let mut step_log = Step::ActionStep(AgentStep::new(self.get_step_number()));
 
-            final_answer = self.step(&mut step_log)?;
+            let mut result = traced_step(self.get_step_number(), &mut step_log, |step_log| {
+                self.step(step_log)
+            });
+            if is_context_length_exceeded(&result) && self.compact_memory() {
+                info!("The memory exceeds the context window of the model, retrying the step with a compacted memory");
+                step_log = Step::ActionStep(AgentStep::new(self.get_step_number()));
+                result = traced_step(self.get_step_number(), &mut step_log, |step_log| {
+                    self.step(step_log)
+                });
+            }
+            final_answer = result?;
+            if let Step::ActionStep(step_log) = &mut step_log {
+                step_log.final_answer = final_answer.clone();
+            }
             self.get_logs_mut().push(step_log);
             self.increment_step_number();
+            if final_answer.is_none() && self.error_threshold_reached() {
+                info!("Too many consecutive errors, providing a final answer");
+                break;
+            }
         }
 
-        if final_answer.is_none() && self.get_step_number() >= self.get_max_steps() {
+        if final_answer.is_none() {
             final_answer = self.provide_final_answer(_task)?;
+            if let Some(answer) = &final_answer {
+                record_provided_answer(self.get_logs_mut(), answer);
+            }
         }
         info!(
             "Final answer: {}",
@@ -143,7 +401,10 @@ pub trait Agent {
                 .clone()
                 .unwrap_or("Could not find answer".to_string())
         );
-        Ok(final_answer.unwrap_or_else(|| "Max steps reached without final answer".to_string()))
+        Ok(match final_answer {
+            Some(answer) => self.postprocess_answer(answer),
+            None => "Max steps reached without final answer".to_string(),
+        })
     }
     fn stream_run(&mut self, _task: &str) -> Result<String> {
         self.direct_run(_task)
@@ -179,14 +440,16 @@ pub trait Agent {
             role: MessageRole::User,
             content: format!("Based on the above, please provide an answer to the following user request: \n```\n{}", task),
         });
-        let response = self
-            .model()
-            .run(input_messages, vec![], None, None)?
-            .get_response()?;
-        Ok(Some(response))
+        self.emit_event(AgentEvent::ModelRequestStarted);
+        let response = traced_model_request(self.model(), input_messages, |model, messages| {
+            model.run(messages, vec![], None, None)
+        });
+        self.emit_event(AgentEvent::ModelRequestFinished);
+        Ok(Some(response?.get_response()?))
     }
 
     fn write_inner_memory_from_logs(&mut self, summary_mode: Option<bool>) -> Result<Vec<Message>> {
+        let marker = self.observation_marker().to_string();
         let mut memory = Vec::new();
         let summary_mode = summary_mode.unwrap_or(false);
         for log in self.get_logs_mut() {
@@ -229,8 +492,11 @@ pub trait Agent {
                             .tool_call
                             .clone()
                             .unwrap()
-                            .iter()
-                            .map(|tool_call| -> Message {
+                            .into_iter()
+                            .map(|mut tool_call| -> Message {
+                                // Arguments given as a string would be escaped twice
+                                tool_call.function.arguments =
+                                    tool_call.function.normalized_arguments();
                                 Message {
                                     role: MessageRole::Assistant,
                                     content: serde_json::to_string_pretty(&tool_call)
@@ -246,8 +512,9 @@ pub trait Agent {
                     {
                         for (i, tool_call) in tool_calls.iter().enumerate() {
                             let message_content = format!(
-                                "Call id: {}\nObservation: {}",
+                                "Call id: {}\n{} {}",
                                 tool_call.id.as_deref().unwrap_or_default(),
+                                marker,
                                 observations[i]
                             );
 
@@ -259,7 +526,7 @@ pub trait Agent {
                     } else if let Some(observations) = &step_log.observations {
                         memory.push(Message {
                             role: MessageRole::User,
-                            content: format!("Observations: {}", observations.join("\n")),
+                            content: format!("{} {}", marker, observations.join("\n")),
                         });
                     }
                     if step_log.error.is_some() {
@@ -306,12 +573,53 @@ impl std::fmt::Display for Step {
 pub struct AgentStep {
     agent_memory: Option<Vec<Message>>,
     llm_output: Option<String>,
+    /// The reasoning of the model accompanying the code or the tool calls.
+    thought: Option<String>,
     tool_call: Option<Vec<ToolCall>>,
     error: Option<AgentError>,
     observations: Option<Vec<String>>,
+    /// The model that served the step, as reported by the backend.
+    model_info: Option<String>,
+    /// The final answer given in the step, if any.
+    final_answer: Option<String>,
+    /// Whether every tool call of the step failed. Counts as an error towards the
+    /// [`consecutive_error_threshold`](Agent::consecutive_error_threshold).
+    #[serde(skip)]
+    tool_calls_failed: bool,
     _step: usize,
 }
 
+impl AgentStep {
+    fn new(step: usize) -> Self {
+        AgentStep {
+            agent_memory: None,
+            llm_output: None,
+            thought: None,
+            tool_call: None,
+            error: None,
+            observations: None,
+            model_info: None,
+            final_answer: None,
+            tool_calls_failed: false,
+            _step: step,
+        }
+    }
+
+    /// The model that served the step, as reported by the backend. See [`ModelResponse::get_model_info`].
+    pub fn model_info(&self) -> Option<&str> {
+        self.model_info.as_deref()
+    }
+
+    pub fn final_answer(&self) -> Option<&str> {
+        self.final_answer.as_deref()
+    }
+
+    /// The reasoning of the model, without the code or the tool calls. See [`parse_thought`].
+    pub fn thought(&self) -> Option<&str> {
+        self.thought.as_deref()
+    }
+}
+
 impl std::fmt::Display for AgentStep {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         write!(f, "AgentStep({:?})", self)
@@ -323,8 +631,13 @@ impl std::fmt::Display for AgentStep {
 pub struct MultiStepAgent<M: Model> {
     pub model: M,
     pub tools: Vec<Box<dyn AnyTool>>,
+    /// The system prompt before the tools, managed agents and current time are filled in.
     pub system_prompt_template: String,
-    pub name: &'static str,
+    /// The system prompt sent to the model, rendered from `system_prompt_template`.
+    pub system_prompt: String,
+    /// The name introducing the agent to the agents managing it. Without one, the agent is introduced by
+    /// the key it is managed under.
+    pub name: Option<String>,
     pub managed_agents: Option<HashMap<String, Box<dyn Agent>>>,
     pub description: String,
     pub max_steps: usize,
@@ -332,11 +645,59 @@ pub struct MultiStepAgent<M: Model> {
     pub task: String,
     pub input_messages: Option<Vec<Message>>,
     pub logs: Vec<Step>,
+    pub cancel_token: Option<Arc<AtomicBool>>,
+    pub parallel_tool_calls: bool,
+    pub tool_timeout: Option<Duration>,
+    pub event_callback: Option<EventCallback>,
+    /// Asked to approve every tool call before it runs. Every call is approved when `None`.
+    pub approval_callback: Option<ApprovalCallback>,
+    /// Transforms the final answer of every run. The answer is returned as is when `None`.
+    pub answer_postprocessor: Option<AnswerPostprocessor>,
+    /// Notified of the observation of every successful tool call as soon as it is recorded. No one is
+    /// notified when `None`.
+    pub observation_callback: Option<ObservationCallback>,
+    /// The system prompt used to survey the facts of the task in the planning step.
+    pub facts_prompt: String,
+    /// The system prompt used to make the plan in the planning step.
+    pub plan_prompt: String,
+    /// The marker introducing the results of tool calls, [`DEFAULT_OBSERVATION_MARKER`] by default.
+    pub observation_marker: String,
+    /// Stop early after this many consecutive error steps. Disabled when `None`.
+    pub consecutive_error_threshold: Option<usize>,
+    /// Whether an empty final answer ends the run. Otherwise the model is asked for the answer again.
+    pub allow_empty_final_answer: bool,
+    /// Which part of an observation over the length limit is kept.
+    pub truncation_strategy: TruncationStrategy,
+    /// How the model calls the tools, natively or by writing the calls as text.
+    pub tool_calling_style: ToolCallingStyle,
+    /// Whether the run only ends with a call of the `final_answer` tool. Otherwise a response without tool
+    /// calls is the final answer.
+    pub require_final_answer_tool: bool,
+    /// Custom values of `{{name}}` placeholders in the system prompt.
+    pub prompt_vars: HashMap<String, String>,
+    /// Whether a placeholder of the system prompt without a value is an error. Otherwise it is kept as is.
+    pub strict_prompt_vars: bool,
+    /// The modules the generated code may import, listed in place of `{{authorized_imports}}` in the
+    /// system prompt. Only set for the code agents.
+    authorized_imports: Option<Vec<String>>,
+    /// Whether a tool call repeating an earlier call of the run returns the earlier result instead of
+    /// calling the tool again.
+    pub dedup_tool_calls: bool,
+    /// The results of the successful tool calls of the run, by tool name and arguments.
+    tool_call_cache: Mutex<HashMap<String, String>>,
+    /// The number of failed model requests retried over a whole run. Once they are all used, the next
+    /// failure ends the run.
+    pub max_run_retries: usize,
+    /// The wait before the first retry of a failed model request, doubled for each further retry of the
+    /// same request.
+    pub run_retry_delay: Duration,
+    /// The retries of failed model requests made in the current run.
+    run_retries: AtomicUsize,
 }
 
 impl<M: Model + Debug> Agent for MultiStepAgent<M> {
-    fn name(&self) -> &'static str {
-        self.name
+    fn name(&self) -> Option<&str> {
+        self.name.as_deref()
     }
     fn get_max_steps(&self) -> usize {
         self.max_steps
@@ -346,9 +707,17 @@ impl<M: Model + Debug> Agent for MultiStepAgent<M> {
     }
     fn set_task(&mut self, task: &str) {
         self.task = task.to_string();
+        self.tool_call_cache.lock().unwrap().clear();
+        self.run_retries.store(0, Ordering::SeqCst);
     }
     fn get_system_prompt(&self) -> &str {
-        &self.system_prompt_template
+        &self.system_prompt
+    }
+    fn observation_marker(&self) -> &str {
+        &self.observation_marker
+    }
+    fn consecutive_error_threshold(&self) -> Option<usize> {
+        self.consecutive_error_threshold
     }
     fn increment_step_number(&mut self) {
         self.step_number += 1;
@@ -359,12 +728,30 @@ impl<M: Model + Debug> Agent for MultiStepAgent<M> {
     fn get_logs_mut(&mut self) -> &mut Vec<Step> {
         &mut self.logs
     }
+    fn steps(&self) -> &[Step] {
+        &self.logs
+    }
     fn description(&self) -> String {
         self.description.clone()
     }
     fn model(&self) -> &dyn Model {
         &self.model
     }
+    fn tool_infos(&self) -> Vec<ToolInfo> {
+        self.tools.tool_info()
+    }
+    fn cancel_token(&self) -> Option<&Arc<AtomicBool>> {
+        self.cancel_token.as_ref()
+    }
+    fn event_callback(&self) -> Option<&EventCallback> {
+        self.event_callback.as_ref()
+    }
+    fn postprocess_answer(&self, answer: String) -> String {
+        match &self.answer_postprocessor {
+            Some(answer_postprocessor) => answer_postprocessor(answer),
+            None => answer,
+        }
+    }
 
     /// Perform one step in the ReAct framework: the agent thinks, acts, and observes the result.
     ///
@@ -376,81 +763,75 @@ impl<M: Model + Debug> Agent for MultiStepAgent<M> {
                 self.input_messages = Some(agent_memory.clone());
                 step_log.agent_memory = Some(agent_memory.clone());
 
-                let tools = self
-                    .tools
-                    .iter()
-                    .map(|tool| tool.tool_info())
-                    .collect::<Vec<_>>();
+                let tools = self.tools_for_model();
 
-                let model_message = self.model.run(
-                    self.input_messages.as_ref().unwrap().clone(),
-                    tools,
-                    None,
-                    Some(HashMap::from([(
-                        "stop".to_string(),
-                        vec!["Observation:".to_string()],
-                    )])),
-                )?;
+                let model_message =
+                    self.model_request(self.input_messages.clone().unwrap(), |model, messages| {
+                        model.run(messages, tools.clone(), None, self.stop_sequences(&[]))
+                    })?;
 
+                step_log.model_info = model_message.get_model_info();
                 let mut observations = Vec::new();
-                let tools = model_message.get_tools_used()?;
+                let tools = self.tool_calls(model_message.as_ref())?;
                 step_log.tool_call = Some(tools.clone());
 
                 if let Ok(response) = model_message.get_response() {
+                    if !tools.is_empty() {
+                        step_log.thought = parse_thought(&response);
+                    }
                     if !response.trim().is_empty() {
                         observations.push(response.clone());
                     }
                     if tools.is_empty() {
+                        if self.require_final_answer_tool {
+                            reject_text_answer(step_log, observations);
+                            return Ok(None);
+                        }
                         return Ok(Some(response));
                     }
                 }
 
-                for tool in tools {
-                    let function_name = tool.clone().function.name;
-                    match function_name.as_str() {
-                        "final_answer" => {
-                            info!("Executing tool call: {}", function_name);
-                            let answer = self.tools.call(&tool.function)?;
-                            self.write_inner_memory_from_logs(None)?;
-                            return Ok(Some(answer));
-                        }
-                        _ => {
-                            info!(
-                                "Executing tool call: {} with arguments: {:?}",
-                                function_name, tool.function.arguments
-                            );
-                            let observation_res = self.tools.call(&tool.function);
-                            match observation_res {
-                                Ok(mut observation) => {
-                                    if let Some(answer) = detect_final_answer(&observation) {
-                                        return Ok(Some(answer));
-                                    }
-                                    if observation.len() > 30000 {
-                                        observation = truncate_observation(&observation, 30000);
-                                    }
-                                    observations.push(format!(
-                                        "Observation from {}: {}",
-                                        function_name, observation
-                                    ));
-                                }
-                                Err(e) => {
-                                    observations.push(e.to_string());
-                                    info!("Error: {}", e);
-                                }
+                let (tool_calls, final_answer_call) = split_final_answer_call(&tools);
+                let mut failed_calls = 0;
+                for (tool, observation_res) in tool_calls.iter().zip(self.call_tools(tool_calls)) {
+                    match observation_res? {
+                        Ok(mut observation) => {
+                            if let Some(answer) = detect_final_answer(&observation) {
+                                return Ok(Some(answer));
                             }
+                            observation = self.truncate(&observation);
+                            self.notify_observation(&tool.function.name, &observation);
+                            observations
+                                .push(self.tool_observation(&tool.function.name, &observation));
+                        }
+                        Err(e) => {
+                            failed_calls += 1;
+                            observations.push(e.to_string());
+                            info!("Error: {}", e);
                         }
                     }
                 }
+                self.record_failed_tool_calls(step_log, failed_calls, tool_calls.len());
+                if let Some(tool) = final_answer_call {
+                    self.check_cancelled()?;
+                    info!("Executing tool call: {}", tool.function.name);
+                    let answer = self.tools.call(&tool.function)?;
+                    if answer.trim().is_empty() && !self.allow_empty_final_answer {
+                        reject_empty_final_answer(step_log, observations);
+                        return Ok(None);
+                    }
+                    self.write_inner_memory_from_logs(None)?;
+                    return Ok(Some(answer));
+                }
 
                 step_log.observations = Some(observations);
-                let summary = truncate_observation(
-                    &step_log
+                let summary = self.truncate(
+                    step_log
                         .observations
                         .clone()
                         .unwrap_or_default()
                         .join("\n")
                         .trim(),
-                    30000,
                 );
                 info!("Observation: {}", summary);
                 Ok(None)
@@ -459,7 +840,7 @@ impl<M: Model + Debug> Agent for MultiStepAgent<M> {
                 let function_name = tool_call.function.name.clone();
                 info!("Executing tool call: {}", function_name);
                 let observation = self.tools.call(&tool_call.function)?;
-                info!("Observation: {}", truncate_observation(&observation, 30000));
+                info!("Observation: {}", self.truncate(&observation));
                 Ok(Some(observation))
             }
             Step::PlanningStep(..) | Step::TaskStep(..) | Step::SystemPromptStep(..) => Ok(None),
@@ -470,16 +851,17 @@ impl<M: Model + Debug> Agent for MultiStepAgent<M> {
 impl<M: Model> MultiStepAgent<M> {
     pub fn new(
         model: M,
-        mut tools: Vec<Box<dyn AnyTool>>,
+        tools: Vec<Box<dyn AnyTool>>,
         system_prompt: Option<&str>,
         managed_agents: Option<HashMap<String, Box<dyn Agent>>>,
+        name: Option<&str>,
         description: Option<&str>,
         max_steps: Option<usize>,
     ) -> Result<Self> {
         // Initialize logger
         crate::logger::init_logger_from_env();
 
-        let name = "MultiStepAgent";
+        let name = name.map(str::to_string);
 
         let system_prompt_template = match system_prompt {
             Some(prompt) => prompt.to_string(),
@@ -490,13 +872,16 @@ impl<M: Model> MultiStepAgent<M> {
             None => "A multi-step agent that can solve tasks using a series of tools".to_string(),
         };
 
-        let final_answer_tool = FinalAnswerTool::new();
-        tools.push(Box::new(final_answer_tool));
+        let tools = merge_tools(tools, vec![Box::new(FinalAnswerTool::new())]);
+        for tool in &tools {
+            validate_tool(tool.as_ref())?;
+        }
 
         let mut agent = MultiStepAgent {
             model,
             tools,
             system_prompt_template,
+            system_prompt: String::new(),
             name,
             managed_agents,
             description,
@@ -505,42 +890,658 @@ impl<M: Model> MultiStepAgent<M> {
             task: "".to_string(),
             logs: Vec::new(),
             input_messages: None,
+            cancel_token: None,
+            parallel_tool_calls: false,
+            tool_timeout: None,
+            event_callback: None,
+            approval_callback: None,
+            answer_postprocessor: None,
+            observation_callback: None,
+            facts_prompt: SYSTEM_PROMPT_FACTS.to_string(),
+            plan_prompt: SYSTEM_PROMPT_PLAN.to_string(),
+            observation_marker: DEFAULT_OBSERVATION_MARKER.to_string(),
+            consecutive_error_threshold: None,
+            allow_empty_final_answer: false,
+            truncation_strategy: TruncationStrategy::default(),
+            tool_calling_style: ToolCallingStyle::default(),
+            require_final_answer_tool: false,
+            prompt_vars: HashMap::new(),
+            strict_prompt_vars: false,
+            authorized_imports: None,
+            dedup_tool_calls: false,
+            tool_call_cache: Mutex::default(),
+            max_run_retries: 0,
+            run_retry_delay: Duration::from_secs(1),
+            run_retries: AtomicUsize::new(0),
         };
 
         agent.initialize_system_prompt()?;
         Ok(agent)
     }
 
+    /// Set the name introducing the agent to the agents managing it.
+    pub fn with_name(mut self, name: &str) -> Self {
+        self.name = Some(name.to_string());
+        self
+    }
+
+    /// Set a cancellation flag for the agent. Setting the flag to `true` makes the current run return
+    /// `AgentError::Execution("cancelled")` before the next step or tool call.
+    ///
+    /// The flag is only checked between model and tool calls: an in-flight HTTP request made with the
+    /// blocking client cannot be interrupted and runs to completion first.
+    pub fn with_cancel_token(mut self, cancel_token: Arc<AtomicBool>) -> Self {
+        self.cancel_token = Some(cancel_token);
+        self
+    }
+
+    /// Execute tool calls concurrently within a step. Only useful for independent tools, as the calls
+    /// of one step no longer see each other's side effects. Observations are still reported in call order.
+    pub fn with_parallel_tool_calls(mut self, parallel_tool_calls: bool) -> Self {
+        self.parallel_tool_calls = parallel_tool_calls;
+        self
+    }
+
+    /// Stop waiting for a tool call after `tool_timeout` and record a timeout observation instead.
+    ///
+    /// Tools are blocking, so a timed out call keeps running in the background (e.g. an HTTP request
+    /// still in flight); the agent just moves on without its result.
+    pub fn with_tool_timeout(mut self, tool_timeout: Duration) -> Self {
+        self.tool_timeout = Some(tool_timeout);
+        self
+    }
+
+    /// Replace the system prompts of the planning step. `None` keeps the current prompt.
+    pub fn with_planning_prompts(
+        mut self,
+        facts_prompt: Option<&str>,
+        plan_prompt: Option<&str>,
+    ) -> Self {
+        if let Some(facts_prompt) = facts_prompt {
+            self.facts_prompt = facts_prompt.to_string();
+        }
+        if let Some(plan_prompt) = plan_prompt {
+            self.plan_prompt = plan_prompt.to_string();
+        }
+        self
+    }
+
+    /// Notify `event_callback` of the agent's progress, e.g. when a model request starts and finishes.
+    pub fn with_event_callback(mut self, event_callback: EventCallback) -> Self {
+        self.event_callback = Some(event_callback);
+        self
+    }
+
+    /// Ask `approval_callback` before every tool call, e.g. to let a human confirm the calls of shell or
+    /// file tools. A denied call is skipped, and its observation says it was denied by the user.
+    pub fn with_approval_callback(mut self, approval_callback: ApprovalCallback) -> Self {
+        self.approval_callback = Some(approval_callback);
+        self
+    }
+
+    /// Transform the final answer with `answer_postprocessor` before a run returns it, e.g. to strip
+    /// markdown, enforce a prefix or redact personal data. The answers provided after the last step are
+    /// transformed too.
+    pub fn with_answer_postprocessor(mut self, answer_postprocessor: AnswerPostprocessor) -> Self {
+        self.answer_postprocessor = Some(answer_postprocessor);
+        self
+    }
+
+    /// Notify `observation_callback` of the observation of every successful tool call as soon as it is
+    /// recorded, instead of when the step ends. The observations are truncated like in the logs; the
+    /// output of the code of the code agent is an observation of `python_interpreter`.
+    pub fn with_observation_callback(mut self, observation_callback: ObservationCallback) -> Self {
+        self.observation_callback = Some(observation_callback);
+        self
+    }
+
+    /// Add the curated [`default_tools`] and `extra_default_tools` to the agent, and list them in the
+    /// system prompt.
+    ///
+    /// Tools are de-duplicated by name. The tools passed to the constructor take precedence, then
+    /// `final_answer`, then `extra_default_tools`, then the curated tools. Fails if one of
+    /// `extra_default_tools` is not usable.
+    pub fn with_default_tools(
+        mut self,
+        extra_default_tools: Vec<Box<dyn AnyTool>>,
+    ) -> Result<Self> {
+        for tool in &extra_default_tools {
+            validate_tool(tool.as_ref())?;
+        }
+        let tools = std::mem::take(&mut self.tools);
+        let tools = merge_tools(tools, extra_default_tools);
+        self.tools = merge_tools(tools, default_tools());
+        self.initialize_system_prompt()?;
+        Ok(self)
+    }
+
+    /// Make a tool available from the next step on, e.g. after the user granted a capability, and list it
+    /// in the system prompt. Fails if the agent already has a tool with the same name or if the tool is
+    /// not usable.
+    pub fn add_tool(&mut self, tool: Box<dyn AnyTool>) -> Result<()> {
+        if self.tools.iter().any(|t| t.name() == tool.name()) {
+            return Err(AgentError::Execution(format!(
+                "The agent already has a tool named '{}'",
+                tool.name()
+            ))
+            .into());
+        }
+        validate_tool(tool.as_ref())?;
+        self.tools.push(tool);
+        self.initialize_system_prompt()?;
+        Ok(())
+    }
+
+    /// Remove the tool named `name` and return it. `final_answer` cannot be removed, the agent needs it
+    /// to finish.
+    pub fn remove_tool(&mut self, name: &str) -> Result<Box<dyn AnyTool>> {
+        if name == "final_answer" {
+            return Err(AgentError::Execution(
+                "The final_answer tool cannot be removed".to_string(),
+            )
+            .into());
+        }
+        let Some(position) = self.tools.iter().position(|tool| tool.name() == name) else {
+            return Err(
+                AgentError::Execution(format!("The agent has no tool named '{}'", name)).into(),
+            );
+        };
+        let tool = self.tools.remove(position);
+        self.initialize_system_prompt()?;
+        Ok(tool)
+    }
+
+    /// Set the values of custom `{{name}}` placeholders in the system prompt, e.g. `{{user_name}}`, and
+    /// render the system prompt again. The built-in placeholders are replaced first.
+    pub fn set_prompt_vars(&mut self, prompt_vars: HashMap<String, String>) -> Result<()> {
+        self.prompt_vars = prompt_vars;
+        self.initialize_system_prompt()?;
+        Ok(())
+    }
+
+    /// Fail, now and whenever the system prompt is rendered, if a placeholder of the system prompt has no
+    /// value. By default such placeholders are kept as is.
+    pub fn with_strict_prompt_vars(mut self, strict_prompt_vars: bool) -> Result<Self> {
+        self.strict_prompt_vars = strict_prompt_vars;
+        self.initialize_system_prompt()?;
+        Ok(self)
+    }
+
+    /// Create a fresh agent from this one, with the same model, tools, prepared system prompt and settings
+    /// but empty logs. Forks share the cancellation flag and the event callback of this agent.
+    ///
+    /// Managed agents cannot be cloned, so an agent with managed agents cannot be forked. Neither can an
+    /// agent whose tools are no longer usable.
+    pub fn fork(&self) -> Result<Self>
+    where
+        M: Clone,
+    {
+        Ok(self.template()?.build())
+    }
+
+    /// A builder forking this agent, to run many tasks with
+    /// [`run_tasks_parallel`](crate::parallel::run_tasks_parallel) without configuring an agent per task.
+    /// See [`MultiStepAgent::fork`].
+    pub fn fork_builder(&self) -> Result<Arc<dyn Fn() -> Self + Send + Sync>>
+    where
+        M: Clone + Send + Sync + 'static,
+    {
+        let template = self.template()?;
+        Ok(Arc::new(move || template.build()))
+    }
+
+    fn template(&self) -> Result<AgentTemplate<M>>
+    where
+        M: Clone,
+    {
+        if self
+            .managed_agents
+            .as_ref()
+            .is_some_and(|agents| !agents.is_empty())
+        {
+            return Err(AgentError::Execution(
+                "An agent with managed agents cannot be forked".to_string(),
+            )
+            .into());
+        }
+        // The tools are public, so they may have changed since they were validated
+        for tool in &self.tools {
+            validate_tool(tool.as_ref())?;
+        }
+        Ok(AgentTemplate {
+            model: self.model.clone(),
+            tools: self.tools.iter().map(|tool| tool.clone_box()).collect(),
+            system_prompt_template: self.system_prompt_template.clone(),
+            system_prompt: self.system_prompt.clone(),
+            name: self.name.clone(),
+            description: self.description.clone(),
+            max_steps: self.max_steps,
+            cancel_token: self.cancel_token.clone(),
+            parallel_tool_calls: self.parallel_tool_calls,
+            tool_timeout: self.tool_timeout,
+            event_callback: self.event_callback.clone(),
+            approval_callback: self.approval_callback.clone(),
+            answer_postprocessor: self.answer_postprocessor.clone(),
+            observation_callback: self.observation_callback.clone(),
+            facts_prompt: self.facts_prompt.clone(),
+            plan_prompt: self.plan_prompt.clone(),
+            observation_marker: self.observation_marker.clone(),
+            consecutive_error_threshold: self.consecutive_error_threshold,
+            allow_empty_final_answer: self.allow_empty_final_answer,
+            truncation_strategy: self.truncation_strategy,
+            tool_calling_style: self.tool_calling_style,
+            require_final_answer_tool: self.require_final_answer_tool,
+            prompt_vars: self.prompt_vars.clone(),
+            strict_prompt_vars: self.strict_prompt_vars,
+            authorized_imports: self.authorized_imports.clone(),
+            dedup_tool_calls: self.dedup_tool_calls,
+            max_run_retries: self.max_run_retries,
+            run_retry_delay: self.run_retry_delay,
+        })
+    }
+
+    /// Replace the marker introducing the results of tool calls, e.g. to match a custom system prompt. The
+    /// marker is also the stop sequence of the model requests.
+    pub fn with_observation_marker(mut self, observation_marker: &str) -> Self {
+        self.observation_marker = observation_marker.to_string();
+        self
+    }
+
+    /// Provide a final answer after `threshold` consecutive steps ending in an error, e.g. a tool that keeps
+    /// failing, instead of retrying until `max_steps`.
+    pub fn with_consecutive_error_threshold(mut self, threshold: usize) -> Self {
+        self.consecutive_error_threshold = Some(threshold);
+        self
+    }
+
+    /// Accept empty final answers. By default, an empty or whitespace-only final answer is an error of the
+    /// step and the model is asked for the answer again, until `max_steps`.
+    pub fn with_allow_empty_final_answer(mut self, allow_empty_final_answer: bool) -> Self {
+        self.allow_empty_final_answer = allow_empty_final_answer;
+        self
+    }
+
+    /// Choose which part of a long observation is kept, the beginning and the end by default.
+    /// Observations are limited to 30000 characters.
+    pub fn with_truncation_strategy(mut self, truncation_strategy: TruncationStrategy) -> Self {
+        self.truncation_strategy = truncation_strategy;
+        self
+    }
+
+    /// Choose how the model calls the tools. With [`ToolCallingStyle::TextReAct`], for models without
+    /// native tool calling, the tools are only described in the system prompt and the model writes its
+    /// calls as text, which the agent parses. Models reporting that they do not support tool calling
+    /// (see [`Model::supports_tool_calling`]) always write their calls as text.
+    pub fn with_tool_calling_style(mut self, tool_calling_style: ToolCallingStyle) -> Self {
+        self.tool_calling_style = tool_calling_style;
+        self
+    }
+
+    /// End the run only with a call of the `final_answer` tool. A response without tool calls is then an
+    /// observation, and the model is asked to call `final_answer`.
+    pub fn with_require_final_answer_tool(mut self, require_final_answer_tool: bool) -> Self {
+        self.require_final_answer_tool = require_final_answer_tool;
+        self
+    }
+
+    /// Answer a tool call repeating an earlier successful call of the run with the earlier result, instead
+    /// of calling the tool again. This breaks the loops of a model calling the same tool every step.
+    pub fn with_dedup_tool_calls(mut self, dedup_tool_calls: bool) -> Self {
+        self.dedup_tool_calls = dedup_tool_calls;
+        self
+    }
+
+    /// Retry failed model requests, at most `max_run_retries` times over a whole run. The budget is shared
+    /// by all the steps, so a flaky provider cannot stretch a run indefinitely: once it is used up, the
+    /// next failed request ends the run. Only temporary failures are retried (see
+    /// [`AgentError::is_retryable`]), and a streamed request only until it sent its first token. Disabled
+    /// by default.
+    pub fn with_max_run_retries(mut self, max_run_retries: usize) -> Self {
+        self.max_run_retries = max_run_retries;
+        self
+    }
+
+    /// Wait `run_retry_delay` before retrying a failed model request, twice as long before each further
+    /// retry of the same request. One second by default.
+    pub fn with_run_retry_delay(mut self, run_retry_delay: Duration) -> Self {
+        self.run_retry_delay = run_retry_delay;
+        self
+    }
+
+    /// The observation of a tool call denied by the approval callback, `None` if the call is approved.
+    fn denied_tool_call(&self, function: &FunctionCall) -> Option<String> {
+        let approval_callback = self.approval_callback.as_ref()?;
+        if approval_callback(&function.name, &function.normalized_arguments()) {
+            return None;
+        }
+        info!("Tool call denied: {}", function.name);
+        Some(format!(
+            "The call of {} was denied by the user",
+            function.name
+        ))
+    }
+
+    /// The observation of an earlier identical tool call of the run, if the calls are deduplicated.
+    fn cached_tool_call(&self, tool: &ToolCall) -> Option<String> {
+        if !self.dedup_tool_calls {
+            return None;
+        }
+        let cache = self.tool_call_cache.lock().unwrap();
+        cache.get(&tool_call_key(tool)).map(|observation| {
+            info!("Skipping repeated tool call: {}", tool.function.name);
+            format!(
+                "You already called {} with these arguments, the result was:\n{}",
+                tool.function.name, observation
+            )
+        })
+    }
+
+    /// Remember the result of a successful tool call, if the calls are deduplicated.
+    fn cache_tool_call(&self, tool: &ToolCall, result: &Result<String, AgentError>) {
+        if let (true, Ok(observation)) = (self.dedup_tool_calls, result) {
+            self.tool_call_cache
+                .lock()
+                .unwrap()
+                .insert(tool_call_key(tool), observation.clone());
+        }
+    }
+
+    /// Truncate an observation with the truncation strategy of the agent.
+    fn truncate(&self, observation: &str) -> String {
+        self.truncation_strategy
+            .truncate(observation, MAX_OBSERVATION_LENGTH)
+    }
+
+    /// Add a saved conversation to the logs, e.g. few-shot examples or the transcript of an earlier run.
+    /// Continue from it with `run(task, stream, false)`, which keeps the logs.
+    ///
+    /// User messages become tasks, assistant messages outputs of the model and tool responses observations.
+    /// System messages are skipped, the agent keeps its own system prompt.
+    pub fn seed_messages(&mut self, messages: Vec<Message>) {
+        if self.logs.is_empty() {
+            self.logs
+                .push(Step::SystemPromptStep(self.system_prompt.clone()));
+        }
+        for message in messages {
+            let step = AgentStep::new(self.step_number);
+            let step = match message.role {
+                MessageRole::System => continue,
+                // Transcripts written from the logs prefix the tasks
+                MessageRole::User => Step::TaskStep(
+                    message
+                        .content
+                        .strip_prefix("New Task: ")
+                        .map(str::to_string)
+                        .unwrap_or(message.content),
+                ),
+                MessageRole::Assistant | MessageRole::ToolCall => Step::ActionStep(AgentStep {
+                    llm_output: Some(message.content),
+                    ..step
+                }),
+                MessageRole::ToolResponse => Step::ActionStep(AgentStep {
+                    observations: Some(vec![message.content]),
+                    ..step
+                }),
+            };
+            self.logs.push(step);
+        }
+    }
+
+    /// How the model calls the tools: as text if the model does not support native tool calling, as
+    /// configured otherwise.
+    fn effective_tool_calling_style(&self) -> ToolCallingStyle {
+        if self.model.supports_tool_calling() {
+            self.tool_calling_style
+        } else {
+            ToolCallingStyle::TextReAct
+        }
+    }
+
+    /// The tools given to the model with a request, none when it writes its tool calls as text.
+    fn tools_for_model(&self) -> Vec<ToolInfo> {
+        match self.effective_tool_calling_style() {
+            ToolCallingStyle::Native => self.tools.iter().map(|tool| tool.tool_info()).collect(),
+            ToolCallingStyle::TextReAct => Vec::new(),
+        }
+    }
+
+    /// The tool calls of a response of the model, native or parsed from its text.
+    fn tool_calls(&self, model_message: &dyn ModelResponse) -> Result<Vec<ToolCall>, AgentError> {
+        match self.effective_tool_calling_style() {
+            ToolCallingStyle::Native => model_message.get_tools_used(),
+            ToolCallingStyle::TextReAct => Ok(model_message
+                .get_response()
+                .ok()
+                .and_then(|response| parse_text_action(&response))
+                .into_iter()
+                .collect()),
+        }
+    }
+
+    /// The request arguments stopping the generation at the observation marker and at `extra` sequences.
+    fn stop_sequences(&self, extra: &[&str]) -> Option<HashMap<String, Vec<String>>> {
+        let stop = std::iter::once(self.observation_marker.as_str())
+            .chain(extra.iter().copied())
+            .map(str::to_string)
+            .collect();
+        Some(HashMap::from([("stop".to_string(), stop)]))
+    }
+
+    /// The observation of a tool call, as it is recorded in the step log.
+    fn tool_observation(&self, tool: &str, observation: &str) -> String {
+        format!(
+            "{} from {}: {}",
+            self.observation_marker.trim_end_matches(':'),
+            tool,
+            observation
+        )
+    }
+
+    /// Notify the observation callback, if any, of the observation of a tool.
+    fn notify_observation(&self, tool: &str, observation: &str) {
+        if let Some(observation_callback) = &self.observation_callback {
+            observation_callback(tool, observation);
+        }
+    }
+
+    /// Mark the step when all its `tool_calls` failed, so it counts towards the
+    /// [`consecutive_error_threshold`](Agent::consecutive_error_threshold).
+    fn record_failed_tool_calls(
+        &self,
+        step_log: &mut AgentStep,
+        failed_calls: usize,
+        tool_calls: usize,
+    ) {
+        step_log.tool_calls_failed = tool_calls > 0 && failed_calls == tool_calls;
+    }
+
+    /// Run a model request on `input_messages`, notifying the event callback before and after it.
+    fn model_request(
+        &self,
+        input_messages: Vec<Message>,
+        request: impl FnMut(&M, Vec<Message>) -> Result<Box<dyn ModelResponse>, AgentError>,
+    ) -> Result<Box<dyn ModelResponse>, AgentError> {
+        self.streamed_model_request(input_messages, &AtomicBool::new(false), request)
+    }
+
+    /// Run a model request like [`MultiStepAgent::model_request`], but without retrying it once `streamed`
+    /// is set, i.e. once the request sent tokens to the stream callback.
+    fn streamed_model_request(
+        &self,
+        input_messages: Vec<Message>,
+        streamed: &AtomicBool,
+        mut request: impl FnMut(&M, Vec<Message>) -> Result<Box<dyn ModelResponse>, AgentError>,
+    ) -> Result<Box<dyn ModelResponse>, AgentError> {
+        let mut delay = self.run_retry_delay;
+        loop {
+            if let Some(callback) = &self.event_callback {
+                callback(AgentEvent::ModelRequestStarted);
+            }
+            let result = traced_model_request(&self.model, input_messages.clone(), &mut request);
+            if let Some(callback) = &self.event_callback {
+                callback(AgentEvent::ModelRequestFinished);
+            }
+            match result {
+                Err(error)
+                    if self.max_run_retries > 0
+                        && error.is_retryable()
+                        && !streamed.load(Ordering::SeqCst) =>
+                {
+                    if !self.take_run_retry() {
+                        return Err(AgentError::Generation(format!(
+                            "{} (all {} retries of the run are used up)",
+                            error, self.max_run_retries
+                        )));
+                    }
+                    warn!("Model request failed: {}. Retrying in {:?}", error, delay);
+                    self.check_cancelled()?;
+                    std::thread::sleep(delay);
+                    delay *= 2;
+                    self.check_cancelled()?;
+                }
+                result => return result,
+            }
+        }
+    }
+
+    /// Use one retry of the run, if the budget is not exhausted. Returns whether a retry is left.
+    fn take_run_retry(&self) -> bool {
+        self.run_retries
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |retries| {
+                (retries < self.max_run_retries).then_some(retries + 1)
+            })
+            .is_ok()
+    }
+
+    /// Execute the given tool calls and return their results in call order.
+    ///
+    /// Sequential calls are made lazily as the iterator is consumed, so the caller can stop early. With
+    /// `parallel_tool_calls` set, all calls run on scoped threads before the first result is returned.
+    /// The outer error is the cancellation of the run, the inner one the error returned by the tool.
+    fn call_tools<'a>(
+        &'a self,
+        tool_calls: &'a [ToolCall],
+    ) -> Box<dyn Iterator<Item = Result<Result<String, AgentError>, AgentError>> + 'a> {
+        let log_call = |tool: &ToolCall| {
+            info!(
+                "Executing tool call: {} with arguments: {:?}",
+                tool.function.name, tool.function.arguments
+            )
+        };
+        if !self.parallel_tool_calls || tool_calls.len() < 2 {
+            return Box::new(tool_calls.iter().map(move |tool| {
+                self.check_cancelled()?;
+                if let Some(observation) = self.cached_tool_call(tool) {
+                    return Ok(Ok(observation));
+                }
+                if let Some(denial) = self.denied_tool_call(&tool.function) {
+                    return Ok(Ok(denial));
+                }
+                log_call(tool);
+                let result = call_tool(&self.tools, &tool.function, self.tool_timeout);
+                self.cache_tool_call(tool, &result);
+                Ok(result)
+            }));
+        }
+        if let Err(e) = self.check_cancelled() {
+            return Box::new(std::iter::once(Err(e)));
+        }
+        let (tools, timeout) = (&self.tools, self.tool_timeout);
+        #[cfg(feature = "tracing")]
+        let step_span = tracing::Span::current();
+        // Repeated and denied calls are answered without running the tool
+        let skipped = tool_calls
+            .iter()
+            .map(|tool| {
+                self.cached_tool_call(tool)
+                    .or_else(|| self.denied_tool_call(&tool.function))
+            })
+            .collect::<Vec<_>>();
+        let results = std::thread::scope(|scope| {
+            let handles = tool_calls
+                .iter()
+                .zip(&skipped)
+                .filter(|(_, skipped)| skipped.is_none())
+                .map(|(tool, _)| {
+                    log_call(tool);
+                    // The spans of the tool calls belong to the step, threads do not inherit it
+                    #[cfg(feature = "tracing")]
+                    let step_span = step_span.clone();
+                    scope.spawn(move || {
+                        #[cfg(feature = "tracing")]
+                        let _span = step_span.entered();
+                        call_tool(tools, &tool.function, timeout)
+                    })
+                })
+                .collect::<Vec<_>>();
+            let mut handles = handles.into_iter();
+            tool_calls
+                .iter()
+                .zip(skipped)
+                .map(|(tool, skipped)| match skipped {
+                    Some(observation) => Ok(observation),
+                    None => {
+                        let result = handles.next().unwrap().join().unwrap_or_else(|_| {
+                            Err(AgentError::Execution("Tool panicked".to_string()))
+                        });
+                        self.cache_tool_call(tool, &result);
+                        result
+                    }
+                })
+                .collect::<Vec<_>>()
+        });
+        Box::new(results.into_iter().map(Ok))
+    }
+
+    fn check_cancelled(&self) -> Result<(), AgentError> {
+        match &self.cancel_token {
+            Some(token) if token.load(Ordering::SeqCst) => {
+                Err(AgentError::Execution("cancelled".to_string()))
+            }
+            _ => Ok(()),
+        }
+    }
+
     fn initialize_system_prompt(&mut self) -> Result<String> {
         let tools = self.tools.tool_info();
-        self.system_prompt_template = format_prompt_with_tools(tools, &self.system_prompt_template);
+        self.system_prompt = format_prompt_with_tools(tools, &self.system_prompt_template);
         match &self.managed_agents {
             Some(managed_agents) => {
-                self.system_prompt_template = format_prompt_with_managed_agent_description(
-                    self.system_prompt_template.clone(),
+                self.system_prompt = format_prompt_with_managed_agent_description(
+                    self.system_prompt.clone(),
                     managed_agents,
                     None,
                 )?;
             }
             None => {
-                self.system_prompt_template = format_prompt_with_managed_agent_description(
-                    self.system_prompt_template.clone(),
+                self.system_prompt = format_prompt_with_managed_agent_description(
+                    self.system_prompt.clone(),
                     &HashMap::new(),
                     None,
                 )?;
             }
         }
-        self.system_prompt_template = self
-            .system_prompt_template
+        self.system_prompt = self
+            .system_prompt
             .replace("{{current_time}}", &chrono::Local::now().to_string());
-        Ok(self.system_prompt_template.clone())
+        if let Some(authorized_imports) = &self.authorized_imports {
+            self.system_prompt = self
+                .system_prompt
+                .replace("{{authorized_imports}}", &authorized_imports.join(", "));
+        }
+        self.system_prompt = format_prompt_with_vars(
+            &self.system_prompt,
+            &self.prompt_vars,
+            self.strict_prompt_vars,
+        )?;
+        Ok(self.system_prompt.clone())
     }
 
-    pub fn planning_step(&mut self, task: &str, is_first_step: bool, _step: usize) {
+    pub fn planning_step(&mut self, task: &str, is_first_step: bool, _step: usize) -> Result<()> {
         if is_first_step {
             let message_prompt_facts = Message {
                 role: MessageRole::System,
-                content: SYSTEM_PROMPT_FACTS.to_string(),
+                content: self.facts_prompt.clone(),
             };
             let message_prompt_task = Message {
                 role: MessageRole::User,
@@ -555,19 +1556,14 @@ impl<M: Model> MultiStepAgent<M> {
             };
 
             let answer_facts = self
-                .model
-                .run(
+                .model_request(
                     vec![message_prompt_facts, message_prompt_task],
-                    vec![],
-                    None,
-                    None,
-                )
-                .unwrap()
-                .get_response()
-                .unwrap_or("".to_string());
+                    |model, messages| model.run(messages, vec![], None, None),
+                )?
+                .get_response()?;
             let message_system_prompt_plan = Message {
                 role: MessageRole::System,
-                content: SYSTEM_PROMPT_PLAN.to_string(),
+                content: self.plan_prompt.clone(),
             };
             let tool_descriptions = serde_json::to_string(
                 &self
@@ -575,8 +1571,7 @@ impl<M: Model> MultiStepAgent<M> {
                     .iter()
                     .map(|tool| tool.tool_info())
                     .collect::<Vec<_>>(),
-            )
-            .unwrap();
+            )?;
             let message_user_prompt_plan = Message {
                 role: MessageRole::User,
                 content: user_prompt_plan(
@@ -589,19 +1584,11 @@ impl<M: Model> MultiStepAgent<M> {
                 ),
             };
             let answer_plan = self
-                .model
-                .run(
+                .model_request(
                     vec![message_system_prompt_plan, message_user_prompt_plan],
-                    vec![],
-                    None,
-                    Some(HashMap::from([(
-                        "stop".to_string(),
-                        vec!["Observation:".to_string()],
-                    )])),
-                )
-                .unwrap()
-                .get_response()
-                .unwrap();
+                    |model, messages| model.run(messages, vec![], None, self.stop_sequences(&[])),
+                )?
+                .get_response()?;
             let final_plan_redaction = format!(
                 "Here is the plan of action that I will follow for the task: \n{}",
                 answer_plan
@@ -614,6 +1601,82 @@ impl<M: Model> MultiStepAgent<M> {
             ));
             info!("Plan: {}", final_plan_redaction.blue().bold());
         }
+        Ok(())
+    }
+}
+
+/// The configuration of a [`MultiStepAgent`] without its managed agents and run state. Unlike the agent,
+/// it can be shared between threads to build forks.
+struct AgentTemplate<M: Model> {
+    model: M,
+    tools: Vec<Box<dyn AnyTool>>,
+    system_prompt_template: String,
+    system_prompt: String,
+    name: Option<String>,
+    description: String,
+    max_steps: usize,
+    cancel_token: Option<Arc<AtomicBool>>,
+    parallel_tool_calls: bool,
+    tool_timeout: Option<Duration>,
+    event_callback: Option<EventCallback>,
+    approval_callback: Option<ApprovalCallback>,
+    answer_postprocessor: Option<AnswerPostprocessor>,
+    observation_callback: Option<ObservationCallback>,
+    facts_prompt: String,
+    plan_prompt: String,
+    observation_marker: String,
+    consecutive_error_threshold: Option<usize>,
+    allow_empty_final_answer: bool,
+    truncation_strategy: TruncationStrategy,
+    tool_calling_style: ToolCallingStyle,
+    require_final_answer_tool: bool,
+    prompt_vars: HashMap<String, String>,
+    strict_prompt_vars: bool,
+    authorized_imports: Option<Vec<String>>,
+    dedup_tool_calls: bool,
+    max_run_retries: usize,
+    run_retry_delay: Duration,
+}
+
+impl<M: Model + Clone> AgentTemplate<M> {
+    fn build(&self) -> MultiStepAgent<M> {
+        MultiStepAgent {
+            model: self.model.clone(),
+            tools: self.tools.iter().map(|tool| tool.clone_box()).collect(),
+            system_prompt_template: self.system_prompt_template.clone(),
+            system_prompt: self.system_prompt.clone(),
+            name: self.name.clone(),
+            managed_agents: None,
+            description: self.description.clone(),
+            max_steps: self.max_steps,
+            step_number: 0,
+            task: String::new(),
+            input_messages: None,
+            logs: Vec::new(),
+            cancel_token: self.cancel_token.clone(),
+            parallel_tool_calls: self.parallel_tool_calls,
+            tool_timeout: self.tool_timeout,
+            event_callback: self.event_callback.clone(),
+            approval_callback: self.approval_callback.clone(),
+            answer_postprocessor: self.answer_postprocessor.clone(),
+            observation_callback: self.observation_callback.clone(),
+            facts_prompt: self.facts_prompt.clone(),
+            plan_prompt: self.plan_prompt.clone(),
+            observation_marker: self.observation_marker.clone(),
+            consecutive_error_threshold: self.consecutive_error_threshold,
+            allow_empty_final_answer: self.allow_empty_final_answer,
+            truncation_strategy: self.truncation_strategy,
+            tool_calling_style: self.tool_calling_style,
+            require_final_answer_tool: self.require_final_answer_tool,
+            prompt_vars: self.prompt_vars.clone(),
+            strict_prompt_vars: self.strict_prompt_vars,
+            authorized_imports: self.authorized_imports.clone(),
+            dedup_tool_calls: self.dedup_tool_calls,
+            max_run_retries: self.max_run_retries,
+            run_retry_delay: self.run_retry_delay,
+            tool_call_cache: Mutex::default(),
+            run_retries: AtomicUsize::new(0),
+        }
     }
 }
 
@@ -636,12 +1699,188 @@ impl<M: Model + Debug> FunctionCallingAgent<M> {
             tools,
             Some(system_prompt),
             managed_agents,
+            None,
             description,
             max_steps,
         )?;
         Ok(Self { base_agent })
     }
 
+    /// Set the name of the agent. See [`MultiStepAgent::with_name`].
+    pub fn with_name(mut self, name: &str) -> Self {
+        self.base_agent = self.base_agent.with_name(name);
+        self
+    }
+
+    /// Set a cancellation flag for the agent. See [`MultiStepAgent::with_cancel_token`].
+    pub fn with_cancel_token(mut self, cancel_token: Arc<AtomicBool>) -> Self {
+        self.base_agent = self.base_agent.with_cancel_token(cancel_token);
+        self
+    }
+
+    /// Execute the tool calls of a step concurrently. See [`MultiStepAgent::with_parallel_tool_calls`].
+    pub fn with_parallel_tool_calls(mut self, parallel_tool_calls: bool) -> Self {
+        self.base_agent = self
+            .base_agent
+            .with_parallel_tool_calls(parallel_tool_calls);
+        self
+    }
+
+    /// Set a timeout for each tool call. See [`MultiStepAgent::with_tool_timeout`].
+    pub fn with_tool_timeout(mut self, tool_timeout: Duration) -> Self {
+        self.base_agent = self.base_agent.with_tool_timeout(tool_timeout);
+        self
+    }
+
+    /// Notify a callback of the agent's progress. See [`MultiStepAgent::with_event_callback`].
+    pub fn with_event_callback(mut self, event_callback: EventCallback) -> Self {
+        self.base_agent = self.base_agent.with_event_callback(event_callback);
+        self
+    }
+
+    /// Ask a callback before every tool call. See [`MultiStepAgent::with_approval_callback`].
+    pub fn with_approval_callback(mut self, approval_callback: ApprovalCallback) -> Self {
+        self.base_agent = self.base_agent.with_approval_callback(approval_callback);
+        self
+    }
+
+    /// Transform the final answer of every run. See [`MultiStepAgent::with_answer_postprocessor`].
+    pub fn with_answer_postprocessor(mut self, answer_postprocessor: AnswerPostprocessor) -> Self {
+        self.base_agent = self
+            .base_agent
+            .with_answer_postprocessor(answer_postprocessor);
+        self
+    }
+
+    /// Notify a callback of every tool observation. See [`MultiStepAgent::with_observation_callback`].
+    pub fn with_observation_callback(mut self, observation_callback: ObservationCallback) -> Self {
+        self.base_agent = self
+            .base_agent
+            .with_observation_callback(observation_callback);
+        self
+    }
+
+    /// Replace the marker introducing the results of tool calls. See
+    /// [`MultiStepAgent::with_observation_marker`].
+    pub fn with_observation_marker(mut self, observation_marker: &str) -> Self {
+        self.base_agent = self.base_agent.with_observation_marker(observation_marker);
+        self
+    }
+
+    /// Stop after repeated errors. See [`MultiStepAgent::with_consecutive_error_threshold`].
+    pub fn with_consecutive_error_threshold(mut self, threshold: usize) -> Self {
+        self.base_agent = self.base_agent.with_consecutive_error_threshold(threshold);
+        self
+    }
+
+    /// Accept empty final answers. See [`MultiStepAgent::with_allow_empty_final_answer`].
+    pub fn with_allow_empty_final_answer(mut self, allow_empty_final_answer: bool) -> Self {
+        self.base_agent = self
+            .base_agent
+            .with_allow_empty_final_answer(allow_empty_final_answer);
+        self
+    }
+
+    /// Choose which part of a long observation is kept. See [`MultiStepAgent::with_truncation_strategy`].
+    pub fn with_truncation_strategy(mut self, truncation_strategy: TruncationStrategy) -> Self {
+        self.base_agent = self
+            .base_agent
+            .with_truncation_strategy(truncation_strategy);
+        self
+    }
+
+    /// Choose how the model calls the tools. See [`MultiStepAgent::with_tool_calling_style`].
+    pub fn with_tool_calling_style(mut self, tool_calling_style: ToolCallingStyle) -> Self {
+        self.base_agent = self.base_agent.with_tool_calling_style(tool_calling_style);
+        self
+    }
+
+    /// End the run only with the `final_answer` tool. See
+    /// [`MultiStepAgent::with_require_final_answer_tool`].
+    pub fn with_require_final_answer_tool(mut self, require_final_answer_tool: bool) -> Self {
+        self.base_agent = self
+            .base_agent
+            .with_require_final_answer_tool(require_final_answer_tool);
+        self
+    }
+
+    /// Reuse the results of repeated tool calls. See [`MultiStepAgent::with_dedup_tool_calls`].
+    pub fn with_dedup_tool_calls(mut self, dedup_tool_calls: bool) -> Self {
+        self.base_agent = self.base_agent.with_dedup_tool_calls(dedup_tool_calls);
+        self
+    }
+
+    /// Retry failed model requests within a budget for the run. See
+    /// [`MultiStepAgent::with_max_run_retries`].
+    pub fn with_max_run_retries(mut self, max_run_retries: usize) -> Self {
+        self.base_agent = self.base_agent.with_max_run_retries(max_run_retries);
+        self
+    }
+
+    /// Wait before retrying failed model requests. See [`MultiStepAgent::with_run_retry_delay`].
+    pub fn with_run_retry_delay(mut self, run_retry_delay: Duration) -> Self {
+        self.base_agent = self.base_agent.with_run_retry_delay(run_retry_delay);
+        self
+    }
+
+    /// Add a saved conversation to the logs. See [`MultiStepAgent::seed_messages`].
+    pub fn seed_messages(&mut self, messages: Vec<Message>) {
+        self.base_agent.seed_messages(messages);
+    }
+
+    /// Add default tools to the agent. See [`MultiStepAgent::with_default_tools`].
+    pub fn with_default_tools(
+        mut self,
+        extra_default_tools: Vec<Box<dyn AnyTool>>,
+    ) -> Result<Self> {
+        self.base_agent = self.base_agent.with_default_tools(extra_default_tools)?;
+        Ok(self)
+    }
+
+    /// Add a tool during a session. See [`MultiStepAgent::add_tool`].
+    pub fn add_tool(&mut self, tool: Box<dyn AnyTool>) -> Result<()> {
+        self.base_agent.add_tool(tool)
+    }
+
+    /// Remove a tool during a session. See [`MultiStepAgent::remove_tool`].
+    pub fn remove_tool(&mut self, name: &str) -> Result<Box<dyn AnyTool>> {
+        self.base_agent.remove_tool(name)
+    }
+
+    /// Set custom placeholder values of the system prompt. See [`MultiStepAgent::set_prompt_vars`].
+    pub fn set_prompt_vars(&mut self, prompt_vars: HashMap<String, String>) -> Result<()> {
+        self.base_agent.set_prompt_vars(prompt_vars)
+    }
+
+    /// Fail on placeholders without a value. See [`MultiStepAgent::with_strict_prompt_vars`].
+    pub fn with_strict_prompt_vars(mut self, strict_prompt_vars: bool) -> Result<Self> {
+        self.base_agent = self
+            .base_agent
+            .with_strict_prompt_vars(strict_prompt_vars)?;
+        Ok(self)
+    }
+
+    /// Create a fresh agent from this one. See [`MultiStepAgent::fork`].
+    pub fn fork(&self) -> Result<Self>
+    where
+        M: Clone,
+    {
+        Ok(Self {
+            base_agent: self.base_agent.fork()?,
+        })
+    }
+
+    /// A builder forking this agent. See [`MultiStepAgent::fork_builder`].
+    pub fn fork_builder(&self) -> Result<Arc<dyn Fn() -> Self + Send + Sync>>
+    where
+        M: Clone + Send + Sync + 'static,
+    {
+        let template = self.base_agent.template()?;
+        Ok(Arc::new(move || Self {
+            base_agent: template.build(),
+        }))
+    }
+
     fn step_stream(
         &mut self,
         log_entry: &mut Step,
@@ -652,82 +1891,94 @@ impl<M: Model + Debug> FunctionCallingAgent<M> {
                 let agent_memory = self.base_agent.write_inner_memory_from_logs(None)?;
                 self.base_agent.input_messages = Some(agent_memory.clone());
                 step_log.agent_memory = Some(agent_memory.clone());
-                let tools = self
-                    .base_agent
-                    .tools
-                    .iter()
-                    .map(|tool| tool.tool_info())
-                    .collect::<Vec<_>>();
-                let model_message = self.base_agent.model.run_stream(
-                    self.base_agent.input_messages.as_ref().unwrap().clone(),
-                    tools,
-                    None,
-                    Some(HashMap::from([(
-                        "stop".to_string(),
-                        vec!["Observation:".to_string()],
-                    )])),
-                    callback,
+                let tools = self.base_agent.tools_for_model();
+                // A request cannot be retried once its tokens were streamed, they would be sent twice
+                let streamed = AtomicBool::new(false);
+                let model_message = self.base_agent.streamed_model_request(
+                    self.base_agent.input_messages.clone().unwrap(),
+                    &streamed,
+                    |model, messages| {
+                        model.run_stream(
+                            messages,
+                            tools.clone(),
+                            None,
+                            self.base_agent.stop_sequences(&[]),
+                            &mut |token| {
+                                streamed.store(true, Ordering::SeqCst);
+                                callback(token)
+                            },
+                        )
+                    },
                 )?;
 
+                step_log.model_info = model_message.get_model_info();
                 let mut observations = Vec::new();
-                let tools = model_message.get_tools_used()?;
+                let tools = self.base_agent.tool_calls(model_message.as_ref())?;
                 step_log.tool_call = Some(tools.clone());
 
                 if let Ok(response) = model_message.get_response() {
+                    if !tools.is_empty() {
+                        step_log.thought = parse_thought(&response);
+                    }
                     if !response.trim().is_empty() {
                         observations.push(response.clone());
                     }
                     if tools.is_empty() {
+                        if self.base_agent.require_final_answer_tool {
+                            reject_text_answer(step_log, observations);
+                            return Ok(None);
+                        }
                         return Ok(Some(response));
                     }
                 }
-                for tool in tools {
-                    let function_name = tool.clone().function.name;
-
-                    match function_name.as_str() {
-                        "final_answer" => {
-                            info!("Executing tool call: {}", function_name);
-                            let answer = self.base_agent.tools.call(&tool.function)?;
-                            self.base_agent.write_inner_memory_from_logs(None)?;
-                            return Ok(Some(answer));
-                        }
-                        _ => {
-                            info!(
-                                "Executing tool call: {} with arguments: {:?}",
-                                function_name, tool.function.arguments
-                            );
-                            let observation_res = self.base_agent.tools.call(&tool.function);
-                            match observation_res {
-                                Ok(mut observation) => {
-                                    if let Some(answer) = detect_final_answer(&observation) {
-                                        return Ok(Some(answer));
-                                    }
-                                    if observation.len() > 30000 {
-                                        observation = truncate_observation(&observation, 30000);
-                                    }
-                                    observations.push(format!(
-                                        "Observation from {}: {}",
-                                        function_name, observation
-                                    ));
-                                }
-                                Err(e) => {
-                                    observations.push(e.to_string());
-                                    info!("Error: {}", e);
-                                }
+                let (tool_calls, final_answer_call) = split_final_answer_call(&tools);
+                let mut failed_calls = 0;
+                for (tool, observation_res) in tool_calls
+                    .iter()
+                    .zip(self.base_agent.call_tools(tool_calls))
+                {
+                    match observation_res? {
+                        Ok(mut observation) => {
+                            if let Some(answer) = detect_final_answer(&observation) {
+                                return Ok(Some(answer));
                             }
+                            observation = self.base_agent.truncate(&observation);
+                            self.base_agent
+                                .notify_observation(&tool.function.name, &observation);
+                            observations.push(
+                                self.base_agent
+                                    .tool_observation(&tool.function.name, &observation),
+                            );
+                        }
+                        Err(e) => {
+                            failed_calls += 1;
+                            observations.push(e.to_string());
+                            info!("Error: {}", e);
                         }
                     }
                 }
+                self.base_agent
+                    .record_failed_tool_calls(step_log, failed_calls, tool_calls.len());
+                if let Some(tool) = final_answer_call {
+                    self.base_agent.check_cancelled()?;
+                    info!("Executing tool call: {}", tool.function.name);
+                    let answer = self.base_agent.tools.call(&tool.function)?;
+                    if answer.trim().is_empty() && !self.base_agent.allow_empty_final_answer {
+                        reject_empty_final_answer(step_log, observations);
+                        return Ok(None);
+                    }
+                    self.base_agent.write_inner_memory_from_logs(None)?;
+                    return Ok(Some(answer));
+                }
                 step_log.observations = Some(observations);
 
-                let summary = truncate_observation(
-                    &step_log
+                let summary = self.base_agent.truncate(
+                    step_log
                         .observations
                         .clone()
                         .unwrap_or_default()
                         .join("\n")
                         .trim(),
-                    30000,
                 );
                 info!(
                     "Observation: {} \n ....This content has been truncated due to the 30000 character limit.....",
@@ -739,7 +1990,7 @@ impl<M: Model + Debug> FunctionCallingAgent<M> {
                 let function_name = tool_call.function.name.clone();
                 info!("Executing tool call: {}", function_name);
                 let observation = self.base_agent.tools.call(&tool_call.function)?;
-                info!("Observation: {}", truncate_observation(&observation, 30000));
+                info!("Observation: {}", self.base_agent.truncate(&observation));
                 Ok(Some(observation))
             }
             Step::PlanningStep(plan, facts) => {
@@ -760,9 +2011,12 @@ impl<M: Model + Debug> FunctionCallingAgent<M> {
 }
 
 impl<M: Model + Debug> Agent for FunctionCallingAgent<M> {
-    fn name(&self) -> &'static str {
+    fn name(&self) -> Option<&str> {
         self.base_agent.name()
     }
+    fn description(&self) -> String {
+        self.base_agent.description()
+    }
     fn set_task(&mut self, task: &str) {
         self.base_agent.set_task(task);
     }
@@ -784,9 +2038,27 @@ impl<M: Model + Debug> Agent for FunctionCallingAgent<M> {
     fn get_logs_mut(&mut self) -> &mut Vec<Step> {
         self.base_agent.get_logs_mut()
     }
+    fn steps(&self) -> &[Step] {
+        self.base_agent.steps()
+    }
     fn model(&self) -> &dyn Model {
         self.base_agent.model()
     }
+    fn tool_infos(&self) -> Vec<ToolInfo> {
+        self.base_agent.tool_infos()
+    }
+    fn cancel_token(&self) -> Option<&Arc<AtomicBool>> {
+        self.base_agent.cancel_token()
+    }
+    fn event_callback(&self) -> Option<&EventCallback> {
+        self.base_agent.event_callback()
+    }
+    fn postprocess_answer(&self, answer: String) -> String {
+        self.base_agent.postprocess_answer(answer)
+    }
+    fn consecutive_error_threshold(&self) -> Option<usize> {
+        self.base_agent.consecutive_error_threshold()
+    }
 
     /// Perform one step in the ReAct framework: the agent thinks, acts, and observes the result.
     ///
@@ -797,66 +2069,75 @@ impl<M: Model + Debug> Agent for FunctionCallingAgent<M> {
                 let agent_memory = self.base_agent.write_inner_memory_from_logs(None)?;
                 self.base_agent.input_messages = Some(agent_memory.clone());
                 step_log.agent_memory = Some(agent_memory.clone());
-                let tools = self
-                    .base_agent
-                    .tools
-                    .iter()
-                    .map(|tool| tool.tool_info())
-                    .collect::<Vec<_>>();
-                let model_message = self.base_agent.model.run(
-                    self.base_agent.input_messages.as_ref().unwrap().clone(),
-                    tools,
-                    None,
-                    Some(HashMap::from([(
-                        "stop".to_string(),
-                        vec!["Observation:".to_string()],
-                    )])),
+                let tools = self.base_agent.tools_for_model();
+                let model_message = self.base_agent.model_request(
+                    self.base_agent.input_messages.clone().unwrap(),
+                    |model, messages| {
+                        model.run(
+                            messages,
+                            tools.clone(),
+                            None,
+                            self.base_agent.stop_sequences(&[]),
+                        )
+                    },
                 )?;
 
+                step_log.model_info = model_message.get_model_info();
                 let mut observations = Vec::new();
-                let tools = model_message.get_tools_used()?;
+                let tools = self.base_agent.tool_calls(model_message.as_ref())?;
                 step_log.tool_call = Some(tools.clone());
 
                 if let Ok(response) = model_message.get_response() {
+                    if !tools.is_empty() {
+                        step_log.thought = parse_thought(&response);
+                    }
                     if !response.trim().is_empty() {
                         observations.push(response.clone());
                     }
                     if tools.is_empty() {
+                        if self.base_agent.require_final_answer_tool {
+                            reject_text_answer(step_log, observations);
+                            return Ok(None);
+                        }
                         return Ok(Some(response));
                     }
                 }
-                for tool in tools {
-                    let function_name = tool.clone().function.name;
-
-                    match function_name.as_str() {
-                        "final_answer" => {
-                            info!("Executing tool call: {}", function_name);
-                            let answer = self.base_agent.tools.call(&tool.function)?;
-                            self.base_agent.write_inner_memory_from_logs(None)?;
-                            return Ok(Some(answer));
-                        }
-                        _ => {
-                            info!(
-                                "Executing tool call: {} with arguments: {:?}",
-                                function_name, tool.function.arguments
+                let (tool_calls, final_answer_call) = split_final_answer_call(&tools);
+                let mut failed_calls = 0;
+                for (tool, observation_res) in tool_calls
+                    .iter()
+                    .zip(self.base_agent.call_tools(tool_calls))
+                {
+                    match observation_res? {
+                        Ok(observation) => {
+                            let observation = self.base_agent.truncate(&observation);
+                            self.base_agent
+                                .notify_observation(&tool.function.name, &observation);
+                            observations.push(
+                                self.base_agent
+                                    .tool_observation(&tool.function.name, &observation),
                             );
-                            let observation = self.base_agent.tools.call(&tool.function);
-                            match observation {
-                                Ok(observation) => {
-                                    observations.push(format!(
-                                        "Observation from {}: {}",
-                                        function_name,
-                                        observation.chars().take(30000).collect::<String>()
-                                    ));
-                                }
-                                Err(e) => {
-                                    observations.push(e.to_string());
-                                    info!("Error: {}", e);
-                                }
-                            }
+                        }
+                        Err(e) => {
+                            failed_calls += 1;
+                            observations.push(e.to_string());
+                            info!("Error: {}", e);
                         }
                     }
                 }
+                self.base_agent
+                    .record_failed_tool_calls(step_log, failed_calls, tool_calls.len());
+                if let Some(tool) = final_answer_call {
+                    self.base_agent.check_cancelled()?;
+                    info!("Executing tool call: {}", tool.function.name);
+                    let answer = self.base_agent.tools.call(&tool.function)?;
+                    if answer.trim().is_empty() && !self.base_agent.allow_empty_final_answer {
+                        reject_empty_final_answer(step_log, observations);
+                        return Ok(None);
+                    }
+                    self.base_agent.write_inner_memory_from_logs(None)?;
+                    return Ok(Some(answer));
+                }
                 step_log.observations = Some(observations);
 
                 let combined = step_log.observations.clone().unwrap_or_default().join("\n");
@@ -876,7 +2157,7 @@ impl<M: Model + Debug> Agent for FunctionCallingAgent<M> {
                 let function_name = tool_call.function.name.clone();
                 info!("Executing tool call: {}", function_name);
                 let observation = self.base_agent.tools.call(&tool_call.function)?;
-                info!("Observation: {}", truncate_observation(&observation, 30000));
+                info!("Observation: {}", self.base_agent.truncate(&observation));
                 return Ok(Some(observation));
             }
             Step::PlanningStep(plan, facts) => {
@@ -896,24 +2177,42 @@ impl<M: Model + Debug> Agent for FunctionCallingAgent<M> {
     }
 
     fn stream_run(&mut self, task: &str) -> Result<String> {
+        #[cfg(feature = "tracing")]
+        let _span = tracing::info_span!("agent.run", max_steps = self.get_max_steps()).entered();
         let mut final_answer: Option<String> = None;
         while final_answer.is_none() && self.get_step_number() < self.get_max_steps() {
-            println!("Step number: {:?}", self.get_step_number());
-            let mut step_log = Step::ActionStep(AgentStep {
-                agent_memory: None,
-                llm_output: None,
-                tool_call: None,
-                error: None,
-                observations: None,
-                _step: self.get_step_number(),
+            if self.is_cancelled() {
+                return Err(AgentError::Execution("cancelled".to_string()).into());
+            }
+            info!("Step number: {}", self.get_step_number());
+            let mut step_log = Step::ActionStep(AgentStep::new(self.get_step_number()));
+            let mut result = traced_step(self.get_step_number(), &mut step_log, |step_log| {
+                self.step_stream(step_log, &mut |t| print_token(t))
             });
-            final_answer = self.step_stream(&mut step_log, &mut |t| print!("{}", t))?;
+            if is_context_length_exceeded(&result) && self.compact_memory() {
+                info!("The memory exceeds the context window of the model, retrying the step with a compacted memory");
+                step_log = Step::ActionStep(AgentStep::new(self.get_step_number()));
+                result = traced_step(self.get_step_number(), &mut step_log, |step_log| {
+                    self.step_stream(step_log, &mut |t| print_token(t))
+                });
+            }
+            final_answer = result?;
+            if let Step::ActionStep(step_log) = &mut step_log {
+                step_log.final_answer = final_answer.clone();
+            }
             self.get_logs_mut().push(step_log);
             self.increment_step_number();
+            if final_answer.is_none() && self.error_threshold_reached() {
+                info!("Too many consecutive errors, providing a final answer");
+                break;
+            }
         }
 
-        if final_answer.is_none() && self.get_step_number() >= self.get_max_steps() {
+        if final_answer.is_none() {
             final_answer = self.provide_final_answer(task)?;
+            if let Some(answer) = &final_answer {
+                record_provided_answer(self.get_logs_mut(), answer);
+            }
         }
         info!(
             "Final answer: {}",
@@ -921,7 +2220,10 @@ impl<M: Model + Debug> Agent for FunctionCallingAgent<M> {
                 .clone()
                 .unwrap_or("Could not find answer".to_string())
         );
-        Ok(final_answer.unwrap_or_else(|| "Max steps reached without final answer".to_string()))
+        Ok(match final_answer {
+            Some(answer) => self.postprocess_answer(answer),
+            None => "Max steps reached without final answer".to_string(),
+        })
     }
 }
 
@@ -929,6 +2231,8 @@ impl<M: Model + Debug> Agent for FunctionCallingAgent<M> {
 pub struct CodeAgent<M: Model> {
     base_agent: MultiStepAgent<M>,
     local_python_interpreter: LocalPythonInterpreter,
+    max_parse_retries: usize,
+    reset_interpreter_on_run: bool,
 }
 
 #[cfg(feature = "code-agent")]
@@ -941,36 +2245,273 @@ impl<M: Model> CodeAgent<M> {
         description: Option<&str>,
         max_steps: Option<usize>,
     ) -> Result<Self> {
-        let system_prompt = system_prompt.unwrap_or(CODE_SYSTEM_PROMPT);
-
-        let base_agent = MultiStepAgent::new(
+        let mut base_agent = MultiStepAgent::new(
             model,
             tools,
-            Some(system_prompt),
+            Some(system_prompt.unwrap_or(CODE_SYSTEM_PROMPT)),
             managed_agents,
+            None,
             description,
             max_steps,
         )?;
+        base_agent.authorized_imports = Some(
+            BASE_AUTHORIZED_IMPORTS
+                .iter()
+                .map(|module| module.to_string())
+                .collect(),
+        );
+        base_agent.initialize_system_prompt()?;
         let local_python_interpreter = LocalPythonInterpreter::new(
             base_agent
                 .tools
                 .iter()
                 .map(|tool| tool.clone_box())
                 .collect(),
+            None,
         );
 
         Ok(Self {
             base_agent,
             local_python_interpreter,
+            max_parse_retries: 1,
+            reset_interpreter_on_run: false,
         })
     }
+
+    /// Set the name of the agent. See [`MultiStepAgent::with_name`].
+    pub fn with_name(mut self, name: &str) -> Self {
+        self.base_agent = self.base_agent.with_name(name);
+        self
+    }
+
+    /// Set a cancellation flag for the agent. See [`MultiStepAgent::with_cancel_token`].
+    pub fn with_cancel_token(mut self, cancel_token: Arc<AtomicBool>) -> Self {
+        self.base_agent = self.base_agent.with_cancel_token(cancel_token);
+        self
+    }
+
+    /// Notify a callback of the agent's progress. See [`MultiStepAgent::with_event_callback`].
+    pub fn with_event_callback(mut self, event_callback: EventCallback) -> Self {
+        self.base_agent = self.base_agent.with_event_callback(event_callback);
+        self
+    }
+
+    /// Ask a callback before every tool call. See [`MultiStepAgent::with_approval_callback`].
+    pub fn with_approval_callback(mut self, approval_callback: ApprovalCallback) -> Self {
+        self.base_agent = self.base_agent.with_approval_callback(approval_callback);
+        self
+    }
+
+    /// Transform the final answer of every run. See [`MultiStepAgent::with_answer_postprocessor`].
+    pub fn with_answer_postprocessor(mut self, answer_postprocessor: AnswerPostprocessor) -> Self {
+        self.base_agent = self
+            .base_agent
+            .with_answer_postprocessor(answer_postprocessor);
+        self
+    }
+
+    /// Notify a callback of every tool observation. See [`MultiStepAgent::with_observation_callback`].
+    pub fn with_observation_callback(mut self, observation_callback: ObservationCallback) -> Self {
+        self.base_agent = self
+            .base_agent
+            .with_observation_callback(observation_callback);
+        self
+    }
+
+    /// Replace the marker introducing the results of tool calls. See
+    /// [`MultiStepAgent::with_observation_marker`].
+    pub fn with_observation_marker(mut self, observation_marker: &str) -> Self {
+        self.base_agent = self.base_agent.with_observation_marker(observation_marker);
+        self
+    }
+
+    /// Stop after repeated errors. See [`MultiStepAgent::with_consecutive_error_threshold`].
+    pub fn with_consecutive_error_threshold(mut self, threshold: usize) -> Self {
+        self.base_agent = self.base_agent.with_consecutive_error_threshold(threshold);
+        self
+    }
+
+    /// Retry failed model requests within a budget for the run. See
+    /// [`MultiStepAgent::with_max_run_retries`].
+    pub fn with_max_run_retries(mut self, max_run_retries: usize) -> Self {
+        self.base_agent = self.base_agent.with_max_run_retries(max_run_retries);
+        self
+    }
+
+    /// Wait before retrying failed model requests. See [`MultiStepAgent::with_run_retry_delay`].
+    pub fn with_run_retry_delay(mut self, run_retry_delay: Duration) -> Self {
+        self.base_agent = self.base_agent.with_run_retry_delay(run_retry_delay);
+        self
+    }
+
+    /// Accept empty final answers. See [`MultiStepAgent::with_allow_empty_final_answer`].
+    pub fn with_allow_empty_final_answer(mut self, allow_empty_final_answer: bool) -> Self {
+        self.base_agent = self
+            .base_agent
+            .with_allow_empty_final_answer(allow_empty_final_answer);
+        self
+    }
+
+    /// Choose which part of a long observation is kept. See [`MultiStepAgent::with_truncation_strategy`].
+    pub fn with_truncation_strategy(mut self, truncation_strategy: TruncationStrategy) -> Self {
+        self.base_agent = self
+            .base_agent
+            .with_truncation_strategy(truncation_strategy);
+        self
+    }
+
+    /// Add a saved conversation to the logs. See [`MultiStepAgent::seed_messages`].
+    pub fn seed_messages(&mut self, messages: Vec<Message>) {
+        self.base_agent.seed_messages(messages);
+    }
+
+    /// Set how many times the model is asked to correct an output without a valid code block
+    /// within the same step, before the parsing error is returned. Defaults to 1.
+    pub fn with_max_parse_retries(mut self, max_parse_retries: usize) -> Self {
+        self.max_parse_retries = max_parse_retries;
+        self
+    }
+
+    /// Clear the variables of the interpreter at the start of every run, so each task starts fresh.
+    /// By default, the variables defined by the code of a run are still defined in the next runs.
+    pub fn with_reset_interpreter_on_run(mut self, reset_interpreter_on_run: bool) -> Self {
+        self.reset_interpreter_on_run = reset_interpreter_on_run;
+        self
+    }
+
+    /// Forget the variables defined by the code run so far. See [`LocalPythonInterpreter::clear_state`].
+    pub fn clear_interpreter_state(&mut self) {
+        self.local_python_interpreter.clear_state();
+    }
+
+    /// Set the modules the generated code may import, and list them in the system prompt.
+    /// See [`LocalPythonInterpreter::with_authorized_imports`].
+    pub fn with_authorized_imports(mut self, imports: &[&str]) -> Result<Self> {
+        self.local_python_interpreter = self
+            .local_python_interpreter
+            .with_authorized_imports(imports);
+        self.base_agent.authorized_imports = Some(imports.iter().map(|s| s.to_string()).collect());
+        self.base_agent.initialize_system_prompt()?;
+        Ok(self)
+    }
+
+    /// Create a fresh agent from this one, with a new interpreter. See [`MultiStepAgent::fork`].
+    pub fn fork(&self) -> Result<Self>
+    where
+        M: Clone,
+    {
+        Ok(Self::from_base_agent(
+            self.base_agent.fork()?,
+            self.max_parse_retries,
+            self.reset_interpreter_on_run,
+        ))
+    }
+
+    /// A builder forking this agent. See [`MultiStepAgent::fork_builder`].
+    pub fn fork_builder(&self) -> Result<Arc<dyn Fn() -> Self + Send + Sync>>
+    where
+        M: Clone + Send + Sync + 'static,
+    {
+        let template = self.base_agent.template()?;
+        let max_parse_retries = self.max_parse_retries;
+        let reset_interpreter_on_run = self.reset_interpreter_on_run;
+        Ok(Arc::new(move || {
+            Self::from_base_agent(
+                template.build(),
+                max_parse_retries,
+                reset_interpreter_on_run,
+            )
+        }))
+    }
+
+    /// Build a code agent around a configured base agent, with an interpreter for its tools.
+    fn from_base_agent(
+        base_agent: MultiStepAgent<M>,
+        max_parse_retries: usize,
+        reset_interpreter_on_run: bool,
+    ) -> Self {
+        let imports = base_agent
+            .authorized_imports
+            .iter()
+            .flatten()
+            .map(String::as_str)
+            .collect::<Vec<_>>();
+        let local_python_interpreter = LocalPythonInterpreter::new(
+            base_agent
+                .tools
+                .iter()
+                .map(|tool| tool.clone_box())
+                .collect(),
+            None,
+        )
+        .with_authorized_imports(&imports);
+        Self {
+            base_agent,
+            local_python_interpreter,
+            max_parse_retries,
+            reset_interpreter_on_run,
+        }
+    }
+
+    /// Add default tools to the agent and make them callable from the generated code. See
+    /// [`MultiStepAgent::with_default_tools`].
+    pub fn with_default_tools(
+        mut self,
+        extra_default_tools: Vec<Box<dyn AnyTool>>,
+    ) -> Result<Self> {
+        self.base_agent = self.base_agent.with_default_tools(extra_default_tools)?;
+        self.update_interpreter_tools();
+        Ok(self)
+    }
+
+    /// Add a tool during a session and make it callable from the generated code. See
+    /// [`MultiStepAgent::add_tool`].
+    pub fn add_tool(&mut self, tool: Box<dyn AnyTool>) -> Result<()> {
+        self.base_agent.add_tool(tool)?;
+        self.update_interpreter_tools();
+        Ok(())
+    }
+
+    /// Remove a tool during a session. See [`MultiStepAgent::remove_tool`].
+    pub fn remove_tool(&mut self, name: &str) -> Result<Box<dyn AnyTool>> {
+        let tool = self.base_agent.remove_tool(name)?;
+        self.update_interpreter_tools();
+        Ok(tool)
+    }
+
+    /// Set custom placeholder values of the system prompt. See [`MultiStepAgent::set_prompt_vars`].
+    pub fn set_prompt_vars(&mut self, prompt_vars: HashMap<String, String>) -> Result<()> {
+        self.base_agent.set_prompt_vars(prompt_vars)
+    }
+
+    /// Fail on placeholders without a value. See [`MultiStepAgent::with_strict_prompt_vars`].
+    pub fn with_strict_prompt_vars(mut self, strict_prompt_vars: bool) -> Result<Self> {
+        self.base_agent = self
+            .base_agent
+            .with_strict_prompt_vars(strict_prompt_vars)?;
+        Ok(self)
+    }
+
+    /// Give the interpreter the current tools of the agent.
+    fn update_interpreter_tools(&mut self) {
+        self.local_python_interpreter.set_custom_tools(
+            self.base_agent
+                .tools
+                .iter()
+                .map(|tool| tool.clone_box())
+                .collect(),
+        );
+    }
 }
 
 #[cfg(feature = "code-agent")]
 impl<M: Model + Debug> Agent for CodeAgent<M> {
-    fn name(&self) -> &'static str {
+    fn name(&self) -> Option<&str> {
         self.base_agent.name()
     }
+    fn description(&self) -> String {
+        self.base_agent.description()
+    }
     fn get_max_steps(&self) -> usize {
         self.base_agent.get_max_steps()
     }
@@ -983,10 +2524,17 @@ impl<M: Model + Debug> Agent for CodeAgent<M> {
     fn get_logs_mut(&mut self) -> &mut Vec<Step> {
         self.base_agent.get_logs_mut()
     }
+    fn steps(&self) -> &[Step] {
+        self.base_agent.steps()
+    }
     fn reset_step_number(&mut self) {
         self.base_agent.reset_step_number()
     }
     fn set_task(&mut self, task: &str) {
+        // The task is set once at the start of every run
+        if self.reset_interpreter_on_run {
+            self.clear_interpreter_state();
+        }
         self.base_agent.set_task(task);
     }
     fn get_system_prompt(&self) -> &str {
@@ -995,6 +2543,21 @@ impl<M: Model + Debug> Agent for CodeAgent<M> {
     fn model(&self) -> &dyn Model {
         self.base_agent.model()
     }
+    fn tool_infos(&self) -> Vec<ToolInfo> {
+        self.base_agent.tool_infos()
+    }
+    fn cancel_token(&self) -> Option<&Arc<AtomicBool>> {
+        self.base_agent.cancel_token()
+    }
+    fn event_callback(&self) -> Option<&EventCallback> {
+        self.base_agent.event_callback()
+    }
+    fn postprocess_answer(&self, answer: String) -> String {
+        self.base_agent.postprocess_answer(answer)
+    }
+    fn consecutive_error_threshold(&self) -> Option<usize> {
+        self.base_agent.consecutive_error_threshold()
+    }
     fn step(&mut self, log_entry: &mut Step) -> Result<Option<String>> {
         let result = match log_entry {
             Step::ActionStep(step_log) => {
@@ -1002,34 +2565,68 @@ impl<M: Model + Debug> Agent for CodeAgent<M> {
                 self.base_agent.input_messages = Some(agent_memory.clone());
                 step_log.agent_memory = Some(agent_memory);
 
-                let llm_output = self.base_agent.model.run(
-                    self.base_agent.input_messages.as_ref().unwrap().clone(),
-                    vec![],
-                    None,
-                    Some(HashMap::from([(
-                        "stop".to_string(),
-                        vec!["Observation:".to_string(), "<end_code>".to_string()],
-                    )])),
-                )?;
+                let mut messages = self.base_agent.input_messages.clone().unwrap();
+                let mut parse_retries = 0;
+                let (response, code) = loop {
+                    let llm_output =
+                        self.base_agent
+                            .model_request(messages.clone(), |model, messages| {
+                                model.run(
+                                    messages,
+                                    vec![],
+                                    None,
+                                    self.base_agent.stop_sequences(&["<end_code>"]),
+                                )
+                            })?;
+                    let response = llm_output.get_response()?;
+                    step_log.llm_output = Some(response.clone());
+                    step_log.model_info = llm_output.get_model_info();
+                    self.base_agent.check_cancelled()?;
 
-                let response = llm_output.get_response()?;
-                step_log.llm_output = Some(response.clone());
-
-                let code = parse_code_blobs(&response).map_err(|e| {
-                    step_log.error = Some(e.clone());
-                    info!("Error: {}\n{}", response, e);
-                    anyhow::anyhow!(e)
-                })?;
+                    match parse_code_blobs(&response) {
+                        Ok(code) => break (response, code),
+                        // Ask for a corrected output without consuming a step
+                        Err(e) if parse_retries < self.max_parse_retries => {
+                            parse_retries += 1;
+                            info!(
+                                "Could not parse code, asking the model to correct it: {}",
+                                e
+                            );
+                            messages.push(Message {
+                                role: MessageRole::Assistant,
+                                content: response,
+                            });
+                            messages.push(Message {
+                                role: MessageRole::User,
+                                content: CODE_PARSE_CORRECTION_PROMPT.to_string(),
+                            });
+                        }
+                        Err(e) => {
+                            step_log.error = Some(e.clone());
+                            info!("Error: {}\n{}", response, e);
+                            return Err(anyhow::anyhow!(e));
+                        }
+                    }
+                };
+                step_log.thought = parse_thought(&response);
+                step_log.llm_output = Some(response);
 
                 info!("Code: {}", code);
-                step_log.tool_call = Some(vec![ToolCall {
+                let tool_call = ToolCall {
                     id: None,
                     call_type: Some("function".to_string()),
                     function: FunctionCall {
                         name: "python_interpreter".to_string(),
                         arguments: serde_json::json!({ "code": code }),
                     },
-                }]);
+                };
+                // The whole code is approved at once, as a call of the python_interpreter tool
+                let denial = self.base_agent.denied_tool_call(&tool_call.function);
+                step_log.tool_call = Some(vec![tool_call]);
+                if let Some(denial) = denial {
+                    step_log.observations = Some(vec![denial]);
+                    return Ok(None);
+                }
                 let result = self.local_python_interpreter.forward(&code);
                 match result {
                     Ok(result) => {
@@ -1037,20 +2634,27 @@ impl<M: Model + Debug> Agent for CodeAgent<M> {
                         let mut observation = if !execution_logs.is_empty() {
                             format!("Execution logs: {}", execution_logs)
                         } else {
-                            format!("Observation: {}", result)
+                            format!("{} {}", self.base_agent.observation_marker, result)
                         };
                         if let Some(answer) = detect_final_answer(&observation) {
                             return Ok(Some(answer));
                         }
-                        if observation.len() > 30000 {
-                            observation = truncate_observation(&observation, 30000);
-                        }
+                        observation = self.base_agent.truncate(&observation);
                         info!("Observation: {}", observation);
+                        self.base_agent
+                            .notify_observation("python_interpreter", &observation);
 
                         step_log.observations = Some(vec![observation]);
                     }
                     Err(e) => match e {
                         InterpreterError::FinalAnswer(answer) => {
+                            if answer.trim().is_empty() && !self.base_agent.allow_empty_final_answer
+                            {
+                                info!("Empty final answer, asking the model again");
+                                step_log.error =
+                                    Some(AgentError::Execution(EMPTY_FINAL_ANSWER.to_string()));
+                                return Ok(None);
+                            }
                             return Ok(Some(answer));
                         }
                         _ => {
@@ -1065,7 +2669,7 @@ impl<M: Model + Debug> Agent for CodeAgent<M> {
                 let function_name = tool_call.function.name.clone();
                 info!("Executing tool call: {}", function_name);
                 let observation = self.base_agent.tools.call(&tool_call.function)?;
-                info!("Observation: {}", truncate_observation(&observation, 30000));
+                info!("Observation: {}", self.base_agent.truncate(&observation));
                 return Ok(Some(observation));
             }
             Step::PlanningStep(plan, facts) => {
@@ -1122,51 +2726,371 @@ pub fn parse_code_blobs(code_blob: &str) -> Result<String, AgentError> {
     Ok(matches.join("\n\n"))
 }
 
-/// Try to detect a final answer in the given text.
-///
-/// It looks for patterns like `Final Answer: foo` or `final_answer("foo")` and
-/// returns the captured answer if found.
-pub fn detect_final_answer(text: &str) -> Option<String> {
-    let lower = text.to_lowercase();
-    if let Some(start) = lower.find("final answer:") {
-        let answer = text[start + 13..].trim();
-        if !answer.is_empty() {
-            return Some(answer.to_string());
-        }
-    }
-    if let Some(start) = lower.find("final_answer(") {
-        let remainder = &text[start + 13..];
-        if let Some(end) = remainder.find(')') {
-            let answer = remainder[..end].trim().trim_matches('"');
-            if !answer.is_empty() {
-                return Some(answer.to_string());
+/// Make a model request. With the `tracing` feature, the request runs in a `model.run` span recording
+/// the model id, the number and size of the input messages, the size of the response, the number of tool
+/// calls, the number of input and output tokens if the backend reports them and whether the request
+/// failed. Generation errors saying the request is too long for the
+/// model are returned as [`AgentError::ContextLengthExceeded`].
+fn traced_model_request<M: Model + ?Sized>(
+    model: &M,
+    input_messages: Vec<Message>,
+    request: impl FnOnce(&M, Vec<Message>) -> Result<Box<dyn ModelResponse>, AgentError>,
+) -> Result<Box<dyn ModelResponse>, AgentError> {
+    #[cfg(feature = "tracing")]
+    let span = tracing::info_span!(
+        "model.run",
+        model_id = model.model_id().unwrap_or_default(),
+        input_messages = input_messages.len(),
+        input_bytes = input_messages
+            .iter()
+            .map(|message| message.content.len())
+            .sum::<usize>(),
+        output_bytes = tracing::field::Empty,
+        tool_calls = tracing::field::Empty,
+        input_tokens = tracing::field::Empty,
+        output_tokens = tracing::field::Empty,
+        error = tracing::field::Empty,
+    )
+    .entered();
+
+    let result = request(model, input_messages).map_err(|e| match e {
+        AgentError::Generation(message) => AgentError::from_generation(message),
+        e => e,
+    });
+    #[cfg(feature = "tracing")]
+    {
+        if let Ok(response) = &result {
+            if let Ok(text) = response.get_response() {
+                span.record("output_bytes", text.len());
+            }
+            if let Ok(tool_calls) = response.get_tools_used() {
+                span.record("tool_calls", tool_calls.len());
+            }
+            if let Some(usage) = response.get_token_usage() {
+                span.record("input_tokens", usage.input_tokens);
+                span.record("output_tokens", usage.output_tokens);
             }
         }
+        span.record("error", result.is_err());
     }
-    None
+    result
 }
 
-/// Truncate an observation string while trying to keep both the beginning and
-/// end. Returns the truncated string.
-pub fn truncate_observation(text: &str, limit: usize) -> String {
-    if text.chars().count() <= limit {
-        return text.to_string();
+/// Print a token streamed by the model, unless the output is quiet.
+fn print_token(token: &str) {
+    if !crate::logger::is_quiet() {
+        print!("{}", token);
     }
-    let half = limit / 2;
-    let start: String = text.chars().take(half).collect();
-    let end: String = text
-        .chars()
-        .rev()
-        .take(half)
-        .collect::<String>()
-        .chars()
-        .rev()
-        .collect();
-    format!("{} ...[truncated]... {}", start, end)
 }
 
-/// An agent that first generates a high level plan and then executes each plan
+/// Whether a step failed because the memory does not fit in the context window of the model.
+fn is_context_length_exceeded(result: &Result<Option<String>>) -> bool {
+    result.as_ref().is_err_and(|e| {
+        matches!(
+            e.downcast_ref::<AgentError>(),
+            Some(AgentError::ContextLengthExceeded(_))
+        )
+    })
+}
+
+/// Run one step of an agent. With the `tracing` feature, the step runs in an `agent.step` span recording
+/// the step number and whether the step failed or recorded an error.
+fn traced_step(
+    step: usize,
+    step_log: &mut Step,
+    run: impl FnOnce(&mut Step) -> Result<Option<String>>,
+) -> Result<Option<String>> {
+    #[cfg(feature = "tracing")]
+    let span = tracing::info_span!("agent.step", step, error = tracing::field::Empty).entered();
+    #[cfg(not(feature = "tracing"))]
+    let _ = step;
+
+    let result = run(step_log);
+    #[cfg(feature = "tracing")]
+    {
+        let step_error = matches!(step_log, Step::ActionStep(AgentStep { error: Some(_), .. }));
+        span.record("error", result.is_err() || step_error);
+    }
+    result
+}
+
+/// Extract the reasoning of the model from its output: the text before the first code block, without
+/// the `Thought:` label and the trailing `Code:` label. Returns `None` if there is no reasoning.
+pub fn parse_thought(llm_output: &str) -> Option<String> {
+    let text = llm_output.split("```").next().unwrap_or_default().trim();
+    let text = text.strip_suffix("Code:").unwrap_or(text).trim_end();
+    let text = ["Thoughts:", "Thought:"]
+        .iter()
+        .find_map(|label| text.strip_prefix(label))
+        .unwrap_or(text)
+        .trim();
+    (!text.is_empty()).then(|| text.to_string())
+}
+
+/// Record an answer provided from the memory after the last step of a task on that step, where the
+/// answers of the steps are.
+fn record_provided_answer(steps: &mut [Step], answer: &str) {
+    let last_step = steps
+        .iter_mut()
+        .rev()
+        .take_while(|step| !matches!(step, Step::TaskStep(_)))
+        .find_map(|step| match step {
+            Step::ActionStep(step_log) => Some(step_log),
+            _ => None,
+        });
+    if let Some(step_log) = last_step {
+        step_log.final_answer = Some(answer.to_string());
+    }
+}
+
+/// Check that a tool given to an agent is usable. See [`crate::tools::Tool::validate`].
+fn validate_tool(tool: &dyn AnyTool) -> Result<()> {
+    tool.validate().map_err(|e| {
+        AgentError::Execution(format!("The tool '{}' is not usable: {}", tool.name(), e)).into()
+    })
+}
+
+/// The key of a tool call in the cache of the deduplicated calls.
+fn tool_call_key(tool: &ToolCall) -> String {
+    format!(
+        "{}({})",
+        tool.function.name,
+        tool.function.normalized_arguments()
+    )
+}
+
+/// Call the tool named in `function`, giving up after `timeout` if one is set. The timed out call is
+/// left running on its own thread.
+fn call_tool(
+    tools: &[Box<dyn AnyTool>],
+    function: &FunctionCall,
+    timeout: Option<Duration>,
+) -> Result<String, AgentError> {
+    traced_tool_call(function, || {
+        let tool = tools
+            .iter()
+            .find(|tool| tool.name() == function.name)
+            .ok_or_else(|| AgentError::Execution("Tool not found".to_string()))?;
+        let context = ToolContext::new();
+        let Some(timeout) = timeout else {
+            return tool
+                .forward_json_with_context(function.arguments.clone(), &context)
+                .map(|observation| with_tool_notes(observation, context.notes()));
+        };
+
+        let tool = tool.clone_box();
+        let arguments = function.arguments.clone();
+        let (sender, receiver) = mpsc::channel();
+        let thread_context = context.clone();
+        std::thread::spawn(move || {
+            let _ = sender.send(tool.forward_json_with_context(arguments, &thread_context));
+        });
+        match receiver.recv_timeout(timeout) {
+            Ok(result) => result.map(|observation| with_tool_notes(observation, context.notes())),
+            Err(mpsc::RecvTimeoutError::Timeout) => Err(AgentError::Execution(format!(
+                "Tool call {} timed out after {:.1}s",
+                function.name,
+                timeout.as_secs_f64()
+            ))),
+            Err(mpsc::RecvTimeoutError::Disconnected) => {
+                Err(AgentError::Execution("Tool panicked".to_string()))
+            }
+        }
+    })
+}
+
+/// A node label for [`Agent::to_mermaid`]: one line of at most 60 characters, with the characters Mermaid
+/// would interpret escaped.
+fn mermaid_label(text: &str) -> String {
+    let text = text.split_whitespace().collect::<Vec<_>>().join(" ");
+    let mut label = text.chars().take(60).collect::<String>();
+    if label.len() < text.len() {
+        label.push_str("...");
+    }
+    label
+        .replace('"', "#quot;")
+        .replace('<', "#lt;")
+        .replace('>', "#gt;")
+}
+
+/// Append the notes a tool added to its [`ToolContext`] to the observation of the call.
+fn with_tool_notes(observation: String, notes: Vec<String>) -> String {
+    if notes.is_empty() {
+        return observation;
+    }
+    format!(
+        "{}\nNotes from the tool:\n{}",
+        observation,
+        notes.join("\n")
+    )
+}
+
+/// Replaces the observations removed from the memory by [`Agent::compact_memory`].
+const COMPACTED_OBSERVATION: &str =
+    "[This observation was removed to fit the memory in the context window]";
+
+/// The error of a step whose final answer is empty.
+const EMPTY_FINAL_ANSWER: &str =
+    "The final answer is empty. Provide the complete answer to the task with final_answer.";
+
+/// Record an empty final answer as an error of the step, so the model is asked for the answer again in the
+/// next step.
+fn reject_empty_final_answer(step_log: &mut AgentStep, mut observations: Vec<String>) {
+    info!("Empty final answer, asking the model again");
+    // Every tool call of the step has an observation in the memory, the final answer call included
+    observations.push("Empty final answer".to_string());
+    step_log.observations = Some(observations);
+    step_log.error = Some(AgentError::Execution(EMPTY_FINAL_ANSWER.to_string()));
+}
+
+/// Asks the model to give its final answer with the tool, after a response without tool calls.
+const FINAL_ANSWER_TOOL_REQUIRED: &str =
+    "You answered without calling a tool. To finish the task, call the final_answer tool with your final answer.";
+
+/// Record a response without tool calls as an observation asking the model to call `final_answer`, for the
+/// agents requiring the final answer tool.
+fn reject_text_answer(step_log: &mut AgentStep, mut observations: Vec<String>) {
+    info!("Response without tool calls, asking the model to call final_answer");
+    observations.push(FINAL_ANSWER_TOOL_REQUIRED.to_string());
+    step_log.observations = Some(observations);
+}
+
+/// Split the tool calls of a step at the first `final_answer` call. Calls after it are never executed.
+fn split_final_answer_call(tool_calls: &[ToolCall]) -> (&[ToolCall], Option<&ToolCall>) {
+    match tool_calls
+        .iter()
+        .position(|tool| tool.function.name == "final_answer")
+    {
+        Some(index) => (&tool_calls[..index], Some(&tool_calls[index])),
+        None => (tool_calls, None),
+    }
+}
+
+/// Try to detect a final answer in the given text.
+///
+/// It looks for patterns like `Final Answer: foo` or `final_answer("foo")` and
+/// returns the captured answer if found.
+pub fn detect_final_answer(text: &str) -> Option<String> {
+    let lower = text.to_lowercase();
+    if let Some(start) = lower.find("final answer:") {
+        let answer = text[start + 13..].trim();
+        if !answer.is_empty() {
+            return Some(answer.to_string());
+        }
+    }
+    if let Some(start) = lower.find("final_answer(") {
+        let remainder = &text[start + 13..];
+        if let Some(end) = remainder.find(')') {
+            let answer = remainder[..end].trim().trim_matches('"');
+            if !answer.is_empty() {
+                return Some(answer.to_string());
+            }
+        }
+    }
+    None
+}
+
+/// The maximum number of characters of an observation kept in the logs.
+const MAX_OBSERVATION_LENGTH: usize = 30000;
+
+/// Which part of an observation over the length limit is kept.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize)]
+pub enum TruncationStrategy {
+    /// Keep the beginning.
+    Head,
+    /// Keep the end, e.g. of logs where the last lines matter.
+    Tail,
+    /// Keep the beginning and the end, dropping the middle.
+    #[default]
+    Middle,
+}
+
+impl TruncationStrategy {
+    /// Truncate `text` to about `limit` characters, marking where the text was cut.
+    pub fn truncate(self, text: &str, limit: usize) -> String {
+        let length = text.chars().count();
+        if length <= limit {
+            return text.to_string();
+        }
+        let head = |n: usize| text.chars().take(n).collect::<String>();
+        let tail = |n: usize| text.chars().skip(length - n).collect::<String>();
+        match self {
+            TruncationStrategy::Head => format!("{} ...[truncated]", head(limit)),
+            TruncationStrategy::Tail => format!("[truncated]... {}", tail(limit)),
+            TruncationStrategy::Middle => {
+                let half = limit / 2;
+                format!("{} ...[truncated]... {}", head(half), tail(half))
+            }
+        }
+    }
+}
+
+/// How the model calls the tools.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize)]
+pub enum ToolCallingStyle {
+    /// With the tool calling of the model, the tools being given with every request.
+    #[default]
+    Native,
+    /// By writing the calls as text, for models without native tool calling. The tools are described
+    /// in the system prompt and the calls are parsed with [`parse_text_action`].
+    TextReAct,
+}
+
+/// Parse the tool call a model wrote as text: an `Action: tool_name` line followed by `Action Input:` and
+/// the JSON arguments, or `Action:` followed by a JSON blob with `tool_name` and `tool_arguments`, as in
+/// [`TOOL_CALLING_SYSTEM_PROMPT`]. Arguments that are not JSON are given as a string. Returns `None` if
+/// there is no action.
+pub fn parse_text_action(text: &str) -> Option<ToolCall> {
+    // The first JSON value at the start of `text`, ignoring what follows it
+    let json_value = |text: &str| {
+        serde_json::Deserializer::from_str(text)
+            .into_iter::<Value>()
+            .next()
+            .and_then(|value| value.ok())
+    };
+    let (_, action) = text.split_once("Action:")?;
+    let action = action.trim_start();
+    let (name, arguments) = if action.starts_with('{') {
+        let blob = json_value(action)?;
+        let name = blob.get("tool_name")?.as_str()?.to_string();
+        let arguments = blob.get("tool_arguments").cloned().unwrap_or(json!({}));
+        (name, arguments)
+    } else {
+        let name = action.lines().next()?.trim().trim_matches('`').to_string();
+        let arguments = match action.split_once("Action Input:") {
+            Some((_, input)) => {
+                let input = input.trim();
+                json_value(input).unwrap_or_else(|| {
+                    let input = input.split("Observation:").next().unwrap_or_default();
+                    Value::String(input.trim().to_string())
+                })
+            }
+            None => json!({}),
+        };
+        (name, arguments)
+    };
+    (!name.is_empty()).then(|| ToolCall {
+        id: None,
+        call_type: Some("function".to_string()),
+        function: FunctionCall {
+            name,
+            arguments: parse_arguments(arguments),
+        },
+    })
+}
+
+/// Truncate an observation string while trying to keep both the beginning and
+/// end. Returns the truncated string.
+pub fn truncate_observation(text: &str, limit: usize) -> String {
+    TruncationStrategy::Middle.truncate(text, limit)
+}
+
+/// An agent that first generates a high level plan and then executes each plan
 /// step using a `FunctionCallingAgent`.
+///
+/// The facts survey and the plan can be made by a different model than the steps, e.g. a stronger
+/// model to plan and a cheaper one to call the tools. Planning happens once per run, before the first
+/// step, so the planning model is called twice per run whatever the number of steps.
 pub struct PlanningAgent<M: Model + Clone> {
     planner: MultiStepAgent<M>,
     executor: FunctionCallingAgent<M>,
@@ -1174,6 +3098,7 @@ pub struct PlanningAgent<M: Model + Clone> {
 }
 
 impl<M: Model + Debug + Clone> PlanningAgent<M> {
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         model: M,
         tools: Vec<Box<dyn AnyTool>>,
@@ -1181,16 +3106,21 @@ impl<M: Model + Debug + Clone> PlanningAgent<M> {
         managed_agents: Option<HashMap<String, Box<dyn Agent>>>,
         description: Option<&str>,
         max_steps: Option<usize>,
+        facts_prompt: Option<&str>,
+        plan_prompt: Option<&str>,
+        planning_model: Option<M>,
     ) -> Result<Self> {
         let planner_tools = tools.iter().map(|t| t.clone_box()).collect();
         let planner = MultiStepAgent::new(
-            model.clone(),
+            planning_model.unwrap_or_else(|| model.clone()),
             planner_tools,
             None,
             None,
+            None,
             description,
             max_steps,
-        )?;
+        )?
+        .with_planning_prompts(facts_prompt, plan_prompt);
         let executor = FunctionCallingAgent::new(
             model,
             tools,
@@ -1206,6 +3136,87 @@ impl<M: Model + Debug + Clone> PlanningAgent<M> {
         })
     }
 
+    /// Set a cancellation flag for the agent. See [`MultiStepAgent::with_cancel_token`].
+    pub fn with_cancel_token(mut self, cancel_token: Arc<AtomicBool>) -> Self {
+        self.planner = self.planner.with_cancel_token(cancel_token.clone());
+        self.executor = self.executor.with_cancel_token(cancel_token);
+        self
+    }
+
+    /// Execute the tool calls of a step concurrently. See [`MultiStepAgent::with_parallel_tool_calls`].
+    pub fn with_parallel_tool_calls(mut self, parallel_tool_calls: bool) -> Self {
+        self.executor = self.executor.with_parallel_tool_calls(parallel_tool_calls);
+        self
+    }
+
+    /// Set a timeout for each tool call. See [`MultiStepAgent::with_tool_timeout`].
+    pub fn with_tool_timeout(mut self, tool_timeout: Duration) -> Self {
+        self.executor = self.executor.with_tool_timeout(tool_timeout);
+        self
+    }
+
+    /// Notify a callback of the progress of both the planner and the executor.
+    /// See [`MultiStepAgent::with_event_callback`].
+    pub fn with_event_callback(mut self, event_callback: EventCallback) -> Self {
+        self.planner = self.planner.with_event_callback(event_callback.clone());
+        self.executor = self.executor.with_event_callback(event_callback);
+        self
+    }
+
+    /// Ask a callback before every tool call of the executor. See
+    /// [`MultiStepAgent::with_approval_callback`].
+    pub fn with_approval_callback(mut self, approval_callback: ApprovalCallback) -> Self {
+        self.executor = self.executor.with_approval_callback(approval_callback);
+        self
+    }
+
+    /// Transform the final answer of every run of the executor. See
+    /// [`MultiStepAgent::with_answer_postprocessor`].
+    pub fn with_answer_postprocessor(mut self, answer_postprocessor: AnswerPostprocessor) -> Self {
+        self.executor = self
+            .executor
+            .with_answer_postprocessor(answer_postprocessor);
+        self
+    }
+
+    /// Notify a callback of every tool observation of the executor. See
+    /// [`MultiStepAgent::with_observation_callback`].
+    pub fn with_observation_callback(mut self, observation_callback: ObservationCallback) -> Self {
+        self.executor = self
+            .executor
+            .with_observation_callback(observation_callback);
+        self
+    }
+
+    /// Retry failed model requests within a budget for the run, which the planner and the executor each
+    /// have. See [`MultiStepAgent::with_max_run_retries`].
+    pub fn with_max_run_retries(mut self, max_run_retries: usize) -> Self {
+        self.planner = self.planner.with_max_run_retries(max_run_retries);
+        self.executor = self.executor.with_max_run_retries(max_run_retries);
+        self
+    }
+
+    /// Wait before retrying failed model requests. See [`MultiStepAgent::with_run_retry_delay`].
+    pub fn with_run_retry_delay(mut self, run_retry_delay: Duration) -> Self {
+        self.planner = self.planner.with_run_retry_delay(run_retry_delay);
+        self.executor = self.executor.with_run_retry_delay(run_retry_delay);
+        self
+    }
+
+    /// Survey the facts of `task` and make a plan for it. The planning step is recorded in the logs
+    /// and the steps of the plan are returned.
+    pub fn planning_step(&mut self, task: &str) -> Result<Vec<String>> {
+        self.planner.planning_step(task, true, 0)?;
+        match self.planner.logs.last().cloned() {
+            Some(Step::PlanningStep(plan, facts)) => {
+                let steps = Self::parse_plan(&plan);
+                self.logs.push(Step::PlanningStep(plan, facts));
+                Ok(steps)
+            }
+            _ => Err(anyhow::anyhow!("Failed to generate plan")),
+        }
+    }
+
     fn parse_plan(plan: &str) -> Vec<String> {
         plan.lines()
             .filter_map(|l| {
@@ -1234,77 +3245,1682 @@ impl<M: Model + Debug + Clone> PlanningAgent<M> {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::models::model_traits::ModelResponse;
+    use crate::models::openai::FunctionCall;
+    use crate::test_tools::{CounterTool, NoParams};
+    use crate::tools::{DuckDuckGoSearchTool, GoogleSearchTool, Tool, VisitWebsiteTool};
+    use schemars::JsonSchema;
+    use serde::Deserialize;
 
-    #[test]
-    fn test_detect_final_answer_colon() {
-        let text = "Some text. Final Answer: 42";
-        assert_eq!(detect_final_answer(text), Some("42".to_string()));
+    #[derive(Debug, Clone)]
+    struct MockModel {
+        tool_calls: Vec<ToolCall>,
     }
 
-    #[test]
-    fn test_detect_final_answer_fn() {
-        let text = "ignored final_answer(\"hello\") trailing";
-        assert_eq!(detect_final_answer(text), Some("hello".to_string()));
+    struct MockResponse {
+        tool_calls: Vec<ToolCall>,
     }
 
-    #[test]
-    fn test_truncate_observation() {
-        let text = "a".repeat(35000);
-        let truncated = truncate_observation(&text, 30000);
-        assert!(truncated.len() < text.len());
-        assert!(truncated.contains("truncated"));
+    impl ModelResponse for MockResponse {
+        fn get_response(&self) -> Result<String, AgentError> {
+            Ok(String::new())
+        }
+        fn get_tools_used(&self) -> Result<Vec<ToolCall>, AgentError> {
+            Ok(self.tool_calls.clone())
+        }
     }
-}
 
-impl<M: Model + Debug + Clone> Agent for PlanningAgent<M> {
-    fn name(&self) -> &'static str {
-        "PlanningAgent"
+    impl Model for MockModel {
+        fn run(
+            &self,
+            _messages: Vec<Message>,
+            _tools: Vec<ToolInfo>,
+            _max_tokens: Option<usize>,
+            _args: Option<HashMap<String, Vec<String>>>,
+        ) -> Result<Box<dyn ModelResponse>, AgentError> {
+            Ok(Box::new(MockResponse {
+                tool_calls: self.tool_calls.clone(),
+            }))
+        }
     }
-    fn get_max_steps(&self) -> usize {
-        self.executor.get_max_steps()
+
+    fn tool_call(name: &str, arguments: serde_json::Value) -> ToolCall {
+        ToolCall {
+            id: Some(format!("call_{}", name)),
+            call_type: Some("function".to_string()),
+            function: FunctionCall {
+                name: name.to_string(),
+                arguments,
+            },
+        }
     }
-    fn get_step_number(&self) -> usize {
-        self.executor.get_step_number()
+
+    #[derive(Debug, Deserialize, JsonSchema)]
+    struct CancelToolParams {
+        #[allow(dead_code)]
+        reason: String,
     }
-    fn reset_step_number(&mut self) {
-        self.executor.reset_step_number();
+
+    /// A tool that sets the cancellation flag when called.
+    #[derive(Debug, Clone)]
+    struct CancelTool {
+        token: Arc<AtomicBool>,
     }
-    fn increment_step_number(&mut self) {
-        self.executor.increment_step_number();
+
+    impl Tool for CancelTool {
+        type Params = CancelToolParams;
+        fn name(&self) -> &'static str {
+            "cancel"
+        }
+        fn description(&self) -> &'static str {
+            "Cancels the run"
+        }
+        fn forward(&self, _arguments: CancelToolParams) -> Result<String> {
+            self.token.store(true, Ordering::SeqCst);
+            Ok("cancelled".to_string())
+        }
     }
-    fn get_logs_mut(&mut self) -> &mut Vec<Step> {
-        &mut self.logs
+
+    #[derive(Debug, Deserialize, JsonSchema)]
+    struct SleepToolParams {
+        label: String,
+        millis: u64,
     }
-    fn set_task(&mut self, task: &str) {
-        self.planner.set_task(task);
-        self.executor.set_task(task);
+
+    #[derive(Debug, Clone)]
+    struct SleepTool;
+
+    impl Tool for SleepTool {
+        type Params = SleepToolParams;
+        fn name(&self) -> &'static str {
+            "sleep"
+        }
+        fn description(&self) -> &'static str {
+            "Sleeps and returns the label"
+        }
+        fn forward(&self, arguments: SleepToolParams) -> Result<String> {
+            std::thread::sleep(std::time::Duration::from_millis(arguments.millis));
+            Ok(arguments.label)
+        }
     }
-    fn get_system_prompt(&self) -> &str {
-        self.executor.get_system_prompt()
+
+    /// A tool waiting at most `millis` for another call to run at the same time. `peak` is the largest
+    /// number of calls seen running at once.
+    #[derive(Debug, Clone, Default)]
+    struct OverlapTool {
+        running: Arc<AtomicUsize>,
+        peak: Arc<AtomicUsize>,
     }
-    fn model(&self) -> &dyn Model {
-        self.executor.model()
+
+    impl Tool for OverlapTool {
+        type Params = SleepToolParams;
+        fn name(&self) -> &'static str {
+            "overlap"
+        }
+        fn description(&self) -> &'static str {
+            "Waits for another call and returns the label"
+        }
+        fn forward(&self, arguments: SleepToolParams) -> Result<String> {
+            let running = self.running.fetch_add(1, Ordering::SeqCst) + 1;
+            self.peak.fetch_max(running, Ordering::SeqCst);
+            let deadline = std::time::Instant::now() + Duration::from_millis(arguments.millis);
+            while self.peak.load(Ordering::SeqCst) < 2 && std::time::Instant::now() < deadline {
+                std::thread::sleep(Duration::from_millis(1));
+            }
+            self.running.fetch_sub(1, Ordering::SeqCst);
+            Ok(arguments.label)
+        }
     }
-    fn step(&mut self, log_entry: &mut Step) -> Result<Option<String>> {
-        self.executor.step(log_entry)
+
+    #[test]
+    fn test_parallel_tool_calls() {
+        let model = MockModel {
+            tool_calls: vec![
+                tool_call("overlap", json!({"label": "a", "millis": 5000})),
+                tool_call("overlap", json!({"label": "b", "millis": 5000})),
+            ],
+        };
+        let tool = OverlapTool::default();
+        let peak = tool.peak.clone();
+        let tools: Vec<Box<dyn AnyTool>> = vec![Box::new(tool)];
+        let mut agent = FunctionCallingAgent::new(model, tools, None, None, None, Some(1))
+            .unwrap()
+            .with_parallel_tool_calls(true);
+        agent.run("task", false, true).unwrap();
+        assert_eq!(peak.load(Ordering::SeqCst), 2);
+
+        match agent.get_logs_mut().last() {
+            Some(Step::ActionStep(step)) => assert_eq!(
+                step.observations,
+                Some(vec![
+                    "Observation from overlap: a".to_string(),
+                    "Observation from overlap: b".to_string()
+                ])
+            ),
+            _ => panic!("expected an action step"),
+        }
     }
-    fn run(&mut self, task: &str, stream: bool, reset: bool) -> Result<String> {
-        if reset {
-            self.logs.clear();
+
+    #[test]
+    fn test_tool_timeout() {
+        let model = MockModel {
+            tool_calls: vec![tool_call("sleep", json!({"label": "slow", "millis": 2000}))],
+        };
+        let tools: Vec<Box<dyn AnyTool>> = vec![Box::new(SleepTool)];
+        let mut agent = FunctionCallingAgent::new(model, tools, None, None, None, Some(1))
+            .unwrap()
+            .with_tool_timeout(Duration::from_millis(100));
+
+        let start = std::time::Instant::now();
+        agent.run("task", false, true).unwrap();
+        assert!(start.elapsed() < Duration::from_millis(1000));
+
+        match agent.get_logs_mut().last() {
+            Some(Step::ActionStep(step)) => assert_eq!(
+                step.observations,
+                Some(vec!["Tool call sleep timed out after 0.1s".to_string()])
+            ),
+            _ => panic!("expected an action step"),
         }
-        self.set_task(task);
-        self.planner.planning_step(task, true, 0);
-        if let Some(Step::PlanningStep(plan, facts)) = self.planner.logs.last().cloned() {
-            self.logs.push(Step::PlanningStep(plan.clone(), facts));
-            let steps = Self::parse_plan(&plan);
-            let mut final_answer = String::new();
-            for step_task in steps {
-                final_answer = self.executor.run(&step_task, stream, true)?;
-                self.logs.extend(self.executor.get_logs_mut().drain(..));
+    }
+
+    #[test]
+    fn test_dedup_tool_calls() {
+        let model = MockModel {
+            tool_calls: vec![tool_call("counter", json!({}))],
+        };
+        let counter = CounterTool::default();
+        let calls = counter.calls.clone();
+        let tools: Vec<Box<dyn AnyTool>> = vec![Box::new(counter)];
+        let mut agent = FunctionCallingAgent::new(model.clone(), tools, None, None, None, Some(3))
+            .unwrap()
+            .with_dedup_tool_calls(true);
+        agent.run("task", false, true).unwrap();
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+        match agent.get_logs_mut().last() {
+            Some(Step::ActionStep(step)) => assert_eq!(
+                step.observations,
+                Some(vec![
+                    "Observation from counter: You already called counter with these arguments, the result was:\ncall 1"
+                        .to_string()
+                ])
+            ),
+            _ => panic!("expected an action step"),
+        }
+
+        // The cache is cleared for the next run
+        agent.run("task", false, true).unwrap();
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+
+        // Every call runs the tool by default
+        let counter = CounterTool::default();
+        let calls = counter.calls.clone();
+        let tools: Vec<Box<dyn AnyTool>> = vec![Box::new(counter)];
+        let mut agent = FunctionCallingAgent::new(model, tools, None, None, None, Some(3)).unwrap();
+        agent.run("task", false, true).unwrap();
+        assert_eq!(calls.load(Ordering::SeqCst), 3);
+    }
+
+    #[test]
+    fn test_require_final_answer_tool() {
+        let script = || {
+            ScriptedToolModel::with_texts(vec![
+                ("I think it is Paris.", vec![]),
+                (
+                    "",
+                    vec![tool_call("final_answer", json!({"answer": "Paris"}))],
+                ),
+            ])
+        };
+        let mut agent = FunctionCallingAgent::new(script(), vec![], None, None, None, Some(5))
+            .unwrap()
+            .with_require_final_answer_tool(true);
+        assert_eq!(agent.run("task", false, true).unwrap(), "Paris");
+        assert_eq!(agent.get_step_number(), 2);
+        match &agent.get_logs_mut()[2] {
+            Step::ActionStep(step) => assert_eq!(
+                step.observations,
+                Some(vec![
+                    "I think it is Paris.".to_string(),
+                    FINAL_ANSWER_TOOL_REQUIRED.to_string()
+                ])
+            ),
+            _ => panic!("expected an action step"),
+        }
+
+        // By default the plain text ends the run
+        let mut agent =
+            FunctionCallingAgent::new(script(), vec![], None, None, None, Some(5)).unwrap();
+        assert_eq!(
+            agent.run("task", false, true).unwrap(),
+            "I think it is Paris."
+        );
+    }
+
+    #[test]
+    fn test_conversation_keeps_one_system_prompt() {
+        let model = MockModel {
+            tool_calls: vec![tool_call("final_answer", json!({"answer": "paris"}))],
+        };
+        let mut agent =
+            FunctionCallingAgent::new(model, vec![], None, None, None, Some(2)).unwrap();
+        agent.run("first task", false, true).unwrap();
+        agent.reset_step_number();
+        agent.run("second task", false, false).unwrap();
+
+        let logs = agent.get_logs_mut();
+        let system_prompts = logs
+            .iter()
+            .filter(|step| matches!(step, Step::SystemPromptStep(_)))
+            .count();
+        let tasks = logs
+            .iter()
+            .filter(|step| matches!(step, Step::TaskStep(_)))
+            .count();
+        assert_eq!((system_prompts, tasks), (1, 2));
+    }
+
+    #[test]
+    fn test_tool_output_description() {
+        let description = get_tool_description_with_args(&DuckDuckGoSearchTool::new().tool_info());
+        assert!(description.ends_with(
+            "\n    Returns: The results as markdown, a `[title](url)` link and the snippet for each\n"
+        ));
+
+        // Tools without an output description have no returns line
+        let description = get_tool_description_with_args(&FinalAnswerTool::new().tool_info());
+        assert!(!description.contains("Returns:"));
+    }
+
+    #[cfg(feature = "tracing")]
+    #[test]
+    fn test_model_span_records_token_usage() {
+        use crate::models::openai::OpenAIResponse;
+        use tracing::field::{Field, Visit};
+        use tracing::span::{Attributes, Id, Record};
+
+        /// A subscriber keeping the fields recorded in all spans.
+        struct FieldRecorder {
+            fields: Arc<std::sync::Mutex<Vec<(String, String)>>>,
+            next_id: AtomicUsize,
+        }
+
+        impl Visit for FieldRecorder {
+            fn record_debug(&mut self, field: &Field, value: &dyn std::fmt::Debug) {
+                self.fields
+                    .lock()
+                    .unwrap()
+                    .push((field.name().to_string(), format!("{:?}", value)));
             }
-            Ok(final_answer)
-        } else {
-            Err(anyhow::anyhow!("Failed to generate plan"))
         }
+
+        impl tracing::Subscriber for FieldRecorder {
+            fn enabled(&self, _metadata: &tracing::Metadata<'_>) -> bool {
+                true
+            }
+            fn new_span(&self, span: &Attributes<'_>) -> Id {
+                span.record(&mut FieldRecorder {
+                    fields: self.fields.clone(),
+                    next_id: AtomicUsize::new(0),
+                });
+                Id::from_u64(self.next_id.fetch_add(1, Ordering::SeqCst) as u64 + 1)
+            }
+            fn record(&self, _span: &Id, values: &Record<'_>) {
+                values.record(&mut FieldRecorder {
+                    fields: self.fields.clone(),
+                    next_id: AtomicUsize::new(0),
+                });
+            }
+            fn record_follows_from(&self, _span: &Id, _follows: &Id) {}
+            fn event(&self, _event: &tracing::Event<'_>) {}
+            fn enter(&self, _span: &Id) {}
+            fn exit(&self, _span: &Id) {}
+        }
+
+        #[derive(Debug)]
+        struct UsageModel;
+
+        impl Model for UsageModel {
+            fn run(
+                &self,
+                _messages: Vec<Message>,
+                _tools: Vec<ToolInfo>,
+                _max_tokens: Option<usize>,
+                _args: Option<HashMap<String, Vec<String>>>,
+            ) -> Result<Box<dyn ModelResponse>, AgentError> {
+                let response: OpenAIResponse = serde_json::from_value(json!({
+                    "choices": [{"message": {
+                        "role": "assistant",
+                        "content": null,
+                        "tool_calls": [{
+                            "id": "call_1",
+                            "type": "function",
+                            "function": {"name": "final_answer", "arguments": "{\"answer\": \"done\"}"}
+                        }]
+                    }}],
+                    "usage": {"prompt_tokens": 9, "completion_tokens": 3, "total_tokens": 12}
+                }))
+                .unwrap();
+                Ok(Box::new(response))
+            }
+        }
+
+        let fields = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let recorder = FieldRecorder {
+            fields: fields.clone(),
+            next_id: AtomicUsize::new(0),
+        };
+        let mut agent =
+            FunctionCallingAgent::new(UsageModel, vec![], None, None, None, Some(2)).unwrap();
+        let answer =
+            tracing::subscriber::with_default(recorder, || agent.run("task", false, true).unwrap());
+        assert_eq!(answer, "done");
+
+        let fields = fields.lock().unwrap();
+        let field = |name: &str| {
+            fields
+                .iter()
+                .find(|(field, _)| field == name)
+                .map(|(_, value)| value.clone())
+        };
+        assert_eq!(field("input_tokens").as_deref(), Some("9"));
+        assert_eq!(field("output_tokens").as_deref(), Some("3"));
+        assert_eq!(field("tool_calls").as_deref(), Some("1"));
+    }
+
+    #[test]
+    fn test_answer_postprocessor() {
+        let model = MockModel {
+            tool_calls: vec![tool_call("final_answer", json!({"answer": "paris"}))],
+        };
+        let mut agent = FunctionCallingAgent::new(model, vec![], None, None, None, Some(2))
+            .unwrap()
+            .with_answer_postprocessor(Arc::new(|answer| answer.to_uppercase()));
+        assert_eq!(agent.run("task", false, true).unwrap(), "PARIS");
+        assert_eq!(agent.run("task", true, true).unwrap(), "PARIS");
+    }
+
+    #[test]
+    fn test_approval_callback_denies_calls() {
+        let model = MockModel {
+            tool_calls: vec![
+                tool_call("sleep", json!({"label": "allowed", "millis": 0})),
+                tool_call("sleep", json!({"label": "rm -rf", "millis": 0})),
+            ],
+        };
+        let tools: Vec<Box<dyn AnyTool>> = vec![Box::new(SleepTool)];
+        let requests = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let approval_requests = requests.clone();
+        let mut agent = FunctionCallingAgent::new(model, tools, None, None, None, Some(1))
+            .unwrap()
+            .with_approval_callback(Arc::new(move |name, arguments| {
+                approval_requests
+                    .lock()
+                    .unwrap()
+                    .push(format!("{} {}", name, arguments["label"]));
+                arguments["label"] != "rm -rf"
+            }));
+        agent.run("task", false, true).unwrap();
+
+        assert_eq!(
+            *requests.lock().unwrap(),
+            vec!["sleep \"allowed\"", "sleep \"rm -rf\""]
+        );
+        match agent.get_logs_mut().last() {
+            Some(Step::ActionStep(step)) => assert_eq!(
+                step.observations,
+                Some(vec![
+                    "Observation from sleep: allowed".to_string(),
+                    "Observation from sleep: The call of sleep was denied by the user".to_string()
+                ])
+            ),
+            _ => panic!("expected an action step"),
+        }
+    }
+
+    /// A tool adding a note to its observation.
+    #[derive(Debug, Clone)]
+    struct NotingTool;
+
+    impl Tool for NotingTool {
+        type Params = NoParams;
+        fn name(&self) -> &'static str {
+            "noting"
+        }
+        fn description(&self) -> &'static str {
+            "Adds a note"
+        }
+        fn forward(&self, _arguments: NoParams) -> Result<String> {
+            Ok("done".to_string())
+        }
+        fn forward_with_context(
+            &self,
+            arguments: NoParams,
+            context: &ToolContext,
+        ) -> Result<String> {
+            context.note("fetched 3 pages");
+            self.forward(arguments)
+        }
+    }
+
+    #[test]
+    fn test_tool_context_notes_in_observations() {
+        let model = MockModel {
+            tool_calls: vec![tool_call("noting", json!({}))],
+        };
+        let tools: Vec<Box<dyn AnyTool>> = vec![Box::new(NotingTool)];
+        let mut agent = FunctionCallingAgent::new(model, tools, None, None, None, Some(1)).unwrap();
+        agent.run("task", false, true).unwrap();
+        match agent.get_logs_mut().last() {
+            Some(Step::ActionStep(step)) => assert_eq!(
+                step.observations,
+                Some(vec![
+                    "Observation from noting: done\nNotes from the tool:\nfetched 3 pages"
+                        .to_string()
+                ])
+            ),
+            _ => panic!("expected an action step"),
+        }
+    }
+
+    /// A tool that always fails.
+    #[derive(Debug, Clone)]
+    struct FailingTool;
+
+    impl Tool for FailingTool {
+        type Params = NoParams;
+        fn name(&self) -> &'static str {
+            "failing"
+        }
+        fn description(&self) -> &'static str {
+            "Always fails"
+        }
+        fn forward(&self, _arguments: NoParams) -> Result<String> {
+            Err(anyhow::anyhow!("service unavailable"))
+        }
+    }
+
+    #[test]
+    fn test_to_mermaid() {
+        let model = ScriptedToolModel::new(vec![
+            vec![tool_call("failing", json!({}))],
+            vec![tool_call(
+                "final_answer",
+                json!({"answer": "The \"capital\" is Paris"}),
+            )],
+        ]);
+        let tools: Vec<Box<dyn AnyTool>> = vec![Box::new(FailingTool)];
+        let mut agent = FunctionCallingAgent::new(model, tools, None, None, None, Some(5)).unwrap();
+        agent.run("Find the capital", false, true).unwrap();
+
+        let chart = agent.to_mermaid();
+        assert!(chart.starts_with("flowchart TD\n"), "{}", chart);
+        for line in [
+            "step1[\"Task: Find the capital\"]",
+            "step2[\"Step 1: failing\"]",
+            "step1 --> step2",
+            "step3[\"Step 2: final_answer\"]",
+            "step2 --> step3",
+            "step3_answer([\"Final answer: The #quot;capital#quot; is Paris\"])",
+            "step3 --> step3_answer",
+        ] {
+            assert!(chart.contains(line), "{} not in:\n{}", line, chart);
+        }
+        // A failed tool call is an observation, not an error of the step
+        assert!(!chart.contains("step2_error"), "{}", chart);
+    }
+
+    #[test]
+    fn test_consecutive_errors_finalize_early() {
+        let model = MockModel {
+            tool_calls: vec![tool_call("failing", json!({}))],
+        };
+        let tools: Vec<Box<dyn AnyTool>> = vec![Box::new(FailingTool)];
+        let mut agent = FunctionCallingAgent::new(model.clone(), tools, None, None, None, Some(10))
+            .unwrap()
+            .with_consecutive_error_threshold(3);
+        agent.run("task", false, true).unwrap();
+        assert_eq!(agent.get_step_number(), 3);
+
+        // Disabled by default, the agent retries until the last step
+        let tools: Vec<Box<dyn AnyTool>> = vec![Box::new(FailingTool)];
+        let mut agent =
+            FunctionCallingAgent::new(model, tools, None, None, None, Some(10)).unwrap();
+        agent.run("task", false, true).unwrap();
+        assert_eq!(agent.get_step_number(), 10);
+    }
+
+    /// A model making the scripted tool calls, one set per request.
+    #[derive(Debug, Clone)]
+    struct ScriptedToolModel {
+        responses: Arc<std::sync::Mutex<Vec<TextAndToolsResponse>>>,
+    }
+
+    impl ScriptedToolModel {
+        fn new(tool_calls: Vec<Vec<ToolCall>>) -> Self {
+            Self::with_texts(tool_calls.into_iter().map(|calls| ("", calls)).collect())
+        }
+
+        /// Answer each request with the text next to the tool calls.
+        fn with_texts(responses: Vec<(&str, Vec<ToolCall>)>) -> Self {
+            let mut responses = responses
+                .into_iter()
+                .map(|(text, calls)| TextAndToolsResponse(text.to_string(), calls))
+                .collect::<Vec<_>>();
+            responses.reverse();
+            Self {
+                responses: Arc::new(std::sync::Mutex::new(responses)),
+            }
+        }
+    }
+
+    #[derive(Debug, Clone, Default)]
+    struct TextAndToolsResponse(String, Vec<ToolCall>);
+
+    impl ModelResponse for TextAndToolsResponse {
+        fn get_response(&self) -> Result<String, AgentError> {
+            Ok(self.0.clone())
+        }
+        fn get_tools_used(&self) -> Result<Vec<ToolCall>, AgentError> {
+            Ok(self.1.clone())
+        }
+    }
+
+    impl Model for ScriptedToolModel {
+        fn run(
+            &self,
+            _messages: Vec<Message>,
+            _tools: Vec<ToolInfo>,
+            _max_tokens: Option<usize>,
+            _args: Option<HashMap<String, Vec<String>>>,
+        ) -> Result<Box<dyn ModelResponse>, AgentError> {
+            let response = self.responses.lock().unwrap().pop().unwrap_or_default();
+            Ok(Box::new(response))
+        }
+    }
+
+    #[test]
+    fn test_observation_callback() {
+        let model = ScriptedToolModel::new(vec![
+            vec![
+                tool_call("sleep", json!({"label": "first", "millis": 0})),
+                tool_call("sleep", json!({"label": "second", "millis": 0})),
+            ],
+            vec![tool_call("final_answer", json!({"answer": "done"}))],
+        ]);
+        let observed = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let callback_observed = observed.clone();
+        let tools: Vec<Box<dyn AnyTool>> = vec![Box::new(SleepTool)];
+        let mut agent = FunctionCallingAgent::new(model, tools, None, None, None, Some(3))
+            .unwrap()
+            .with_observation_callback(Arc::new(move |tool, observation| {
+                callback_observed
+                    .lock()
+                    .unwrap()
+                    .push(format!("{}: {}", tool, observation));
+            }));
+        assert_eq!(agent.run("task", false, true).unwrap(), "done");
+        assert_eq!(
+            *observed.lock().unwrap(),
+            vec!["sleep: first", "sleep: second"]
+        );
+    }
+
+    #[test]
+    fn test_empty_final_answer_is_retried() {
+        let script = || {
+            vec![
+                vec![tool_call("final_answer", json!({"answer": "  "}))],
+                vec![tool_call("final_answer", json!({"answer": "Paris"}))],
+            ]
+        };
+        let model = ScriptedToolModel::new(script());
+        let mut agent =
+            FunctionCallingAgent::new(model, vec![], None, None, None, Some(5)).unwrap();
+        assert_eq!(agent.run("task", false, true).unwrap(), "Paris");
+        assert_eq!(agent.get_step_number(), 2);
+
+        let model = ScriptedToolModel::new(script());
+        let mut agent = FunctionCallingAgent::new(model, vec![], None, None, None, Some(5))
+            .unwrap()
+            .with_allow_empty_final_answer(true);
+        assert_eq!(agent.run("task", false, true).unwrap(), "  ");
+        assert_eq!(agent.get_step_number(), 1);
+    }
+
+    #[test]
+    fn test_last_answer_and_steps() {
+        let model = ScriptedToolModel::new(vec![
+            vec![tool_call("final_answer", json!({"answer": ""}))],
+            vec![tool_call("final_answer", json!({"answer": "Paris"}))],
+        ]);
+        let mut agent =
+            FunctionCallingAgent::new(model, vec![], None, None, None, Some(5)).unwrap();
+        assert_eq!(agent.last_answer(), None);
+        agent.run("task", false, true).unwrap();
+
+        assert_eq!(agent.last_answer().as_deref(), Some("Paris"));
+        let answers = agent
+            .steps()
+            .iter()
+            .filter_map(|step| match step {
+                Step::ActionStep(step_log) => Some(step_log.final_answer()),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(answers, vec![None, Some("Paris")]);
+
+        // A new task without an answer yet
+        agent
+            .get_logs_mut()
+            .push(Step::TaskStep("next task".to_string()));
+        assert_eq!(agent.last_answer(), None);
+
+        // An answer provided after the last step
+        let model = ScriptedModel::new(&["Lyon", "Lyon"]);
+        let mut agent = FunctionCallingAgent::new(model, vec![], None, None, None, Some(1))
+            .unwrap()
+            .with_require_final_answer_tool(true);
+        assert_eq!(agent.run("task", false, true).unwrap(), "Lyon");
+        assert_eq!(agent.last_answer().as_deref(), Some("Lyon"));
+    }
+
+    /// A model failing with a context length error while the memory holds a long observation, or after
+    /// the first request if the memory cannot be made to fit.
+    #[derive(Debug, Clone)]
+    struct OverflowModel {
+        requests: Arc<AtomicUsize>,
+        compactable: bool,
+    }
+
+    impl Model for OverflowModel {
+        fn run(
+            &self,
+            messages: Vec<Message>,
+            _tools: Vec<ToolInfo>,
+            _max_tokens: Option<usize>,
+            _args: Option<HashMap<String, Vec<String>>>,
+        ) -> Result<Box<dyn ModelResponse>, AgentError> {
+            let previous_requests = self.requests.fetch_add(1, Ordering::SeqCst);
+            let memory = messages
+                .iter()
+                .map(|message| message.content.as_str())
+                .collect::<String>();
+            if memory.contains("from sleep: long observation")
+                || (!self.compactable && previous_requests > 0)
+            {
+                return Err(AgentError::Generation(
+                    "This model's maximum context length is 8192 tokens".to_string(),
+                ));
+            }
+            let tool_calls = if previous_requests == 0 {
+                vec![tool_call(
+                    "sleep",
+                    json!({"label": "long observation", "millis": 0}),
+                )]
+            } else {
+                vec![tool_call("final_answer", json!({"answer": "compacted"}))]
+            };
+            Ok(Box::new(MockResponse { tool_calls }))
+        }
+    }
+
+    #[test]
+    fn test_context_length_exceeded_compacts_memory() {
+        let run = |compactable| {
+            let requests = Arc::new(AtomicUsize::new(0));
+            let model = OverflowModel {
+                requests: requests.clone(),
+                compactable,
+            };
+            let tools: Vec<Box<dyn AnyTool>> = vec![Box::new(SleepTool)];
+            let mut agent =
+                FunctionCallingAgent::new(model, tools, None, None, None, Some(5)).unwrap();
+            let result = agent.run("task", false, true);
+            (result, requests.load(Ordering::SeqCst))
+        };
+
+        // The second step overflows once and succeeds after the compaction
+        let (answer, requests) = run(true);
+        assert_eq!(answer.unwrap(), "compacted");
+        assert_eq!(requests, 3);
+
+        // The step is retried only once
+        let (error, requests) = run(false);
+        assert!(matches!(
+            error.unwrap_err().downcast_ref::<AgentError>(),
+            Some(AgentError::ContextLengthExceeded(_))
+        ));
+        assert_eq!(requests, 3);
+    }
+
+    /// A model failing `failures` times with `error` before each request that succeeds. It calls `sleep`
+    /// and then `final_answer`. Streamed requests send a token before they fail.
+    #[derive(Debug, Clone)]
+    struct FlakyModel {
+        requests: Arc<AtomicUsize>,
+        failures: usize,
+        error: AgentError,
+    }
+
+    impl FlakyModel {
+        fn new(requests: Arc<AtomicUsize>) -> Self {
+            Self {
+                requests,
+                failures: 1,
+                error: AgentError::Unavailable(
+                    "HTTP 503 Service Unavailable: overloaded".to_string(),
+                ),
+            }
+        }
+    }
+
+    impl Model for FlakyModel {
+        fn run(
+            &self,
+            _messages: Vec<Message>,
+            _tools: Vec<ToolInfo>,
+            _max_tokens: Option<usize>,
+            _args: Option<HashMap<String, Vec<String>>>,
+        ) -> Result<Box<dyn ModelResponse>, AgentError> {
+            let request = self.requests.fetch_add(1, Ordering::SeqCst);
+            if request % (self.failures + 1) < self.failures {
+                return Err(self.error.clone());
+            }
+            let tool_calls = match request / (self.failures + 1) {
+                0 => vec![tool_call("sleep", json!({"label": "a", "millis": 0}))],
+                _ => vec![tool_call("final_answer", json!({"answer": "done"}))],
+            };
+            Ok(Box::new(MockResponse { tool_calls }))
+        }
+
+        fn run_stream(
+            &self,
+            messages: Vec<Message>,
+            tools: Vec<ToolInfo>,
+            max_tokens: Option<usize>,
+            args: Option<HashMap<String, Vec<String>>>,
+            callback: &mut dyn FnMut(&str),
+        ) -> Result<Box<dyn ModelResponse>, AgentError> {
+            callback("Let me think");
+            self.run(messages, tools, max_tokens, args)
+        }
+    }
+
+    #[test]
+    fn test_max_run_retries() {
+        let run = |max_run_retries| {
+            let requests = Arc::new(AtomicUsize::new(0));
+            let model = FlakyModel::new(requests.clone());
+            let tools: Vec<Box<dyn AnyTool>> = vec![Box::new(SleepTool)];
+            let mut agent = FunctionCallingAgent::new(model, tools, None, None, None, Some(5))
+                .unwrap()
+                .with_max_run_retries(max_run_retries)
+                .with_run_retry_delay(Duration::ZERO);
+            let result = agent.run("task", false, true);
+            (result, requests.load(Ordering::SeqCst))
+        };
+
+        // Both steps fail once, within the budget
+        let (answer, requests) = run(2);
+        assert_eq!(answer.unwrap(), "done");
+        assert_eq!(requests, 4);
+
+        // The second failure exhausts the budget and ends the run
+        let (error, requests) = run(1);
+        assert_eq!(
+            error.unwrap_err().to_string(),
+            "HTTP 503 Service Unavailable: overloaded (all 1 retries of the run are used up)"
+        );
+        assert_eq!(requests, 3);
+
+        // Without a budget, the first failure ends the run
+        let (error, requests) = run(0);
+        assert_eq!(
+            error.unwrap_err().to_string(),
+            "HTTP 503 Service Unavailable: overloaded"
+        );
+        assert_eq!(requests, 1);
+    }
+
+    #[test]
+    fn test_run_retries_back_off_on_temporary_failures() {
+        let run = |model: FlakyModel, stream| {
+            let tools: Vec<Box<dyn AnyTool>> = vec![Box::new(SleepTool)];
+            let mut agent = FunctionCallingAgent::new(model, tools, None, None, None, Some(5))
+                .unwrap()
+                .with_max_run_retries(4)
+                .with_run_retry_delay(Duration::from_millis(20));
+            let start = std::time::Instant::now();
+            let result = agent.run("task", stream, true);
+            (result, start.elapsed())
+        };
+
+        // The wait doubles for each retry of the same request: 20 + 40 ms for each of the two steps
+        let requests = Arc::new(AtomicUsize::new(0));
+        let model = FlakyModel {
+            failures: 2,
+            ..FlakyModel::new(requests.clone())
+        };
+        let (answer, elapsed) = run(model, false);
+        assert_eq!(answer.unwrap(), "done");
+        assert_eq!(requests.load(Ordering::SeqCst), 6);
+        assert!(elapsed >= Duration::from_millis(120), "{:?}", elapsed);
+
+        // A request the backend refused is not sent again
+        let requests = Arc::new(AtomicUsize::new(0));
+        let model = FlakyModel {
+            error: AgentError::Generation(
+                "HTTP 400 Bad Request: the upstream answered HTTP 503".to_string(),
+            ),
+            ..FlakyModel::new(requests.clone())
+        };
+        let (error, _) = run(model, false);
+        assert_eq!(
+            error.unwrap_err().to_string(),
+            "HTTP 400 Bad Request: the upstream answered HTTP 503"
+        );
+        assert_eq!(requests.load(Ordering::SeqCst), 1);
+
+        // Neither is a streamed request that already sent tokens
+        let requests = Arc::new(AtomicUsize::new(0));
+        let (error, _) = run(FlakyModel::new(requests.clone()), true);
+        assert_eq!(
+            error.unwrap_err().to_string(),
+            "HTTP 503 Service Unavailable: overloaded"
+        );
+        assert_eq!(requests.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn test_build_multiple_agents() {
+        let model = MockModel { tool_calls: vec![] };
+        let first = FunctionCallingAgent::new(model.clone(), vec![], None, None, None, None);
+        let second = FunctionCallingAgent::new(model, vec![], None, None, None, None);
+        assert!(first.is_ok());
+        assert!(second.is_ok());
+    }
+
+    #[test]
+    fn test_cancel_token_stops_run() {
+        let token = Arc::new(AtomicBool::new(false));
+        let model = MockModel {
+            tool_calls: vec![tool_call("cancel", json!({"reason": "done"}))],
+        };
+        let tools: Vec<Box<dyn AnyTool>> = vec![Box::new(CancelTool {
+            token: token.clone(),
+        })];
+        let mut agent = FunctionCallingAgent::new(model, tools, None, None, None, Some(5))
+            .unwrap()
+            .with_cancel_token(token.clone());
+
+        let err = agent.run("task", false, true).unwrap_err();
+        assert_eq!(err.to_string(), "cancelled");
+        // The first step sets the flag, the run stops before the second one.
+        assert_eq!(agent.get_step_number(), 1);
+
+        let err = agent.run("task", false, true).unwrap_err();
+        assert_eq!(err.to_string(), "cancelled");
+        assert_eq!(agent.get_step_number(), 0);
+    }
+
+    #[test]
+    fn test_event_callback_wraps_model_requests() {
+        let events = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let recorded = events.clone();
+        let model = MockModel {
+            tool_calls: vec![tool_call("final_answer", json!({"answer": "42"}))],
+        };
+        let mut agent = FunctionCallingAgent::new(model, vec![], None, None, None, Some(5))
+            .unwrap()
+            .with_event_callback(Arc::new(move |event| recorded.lock().unwrap().push(event)));
+
+        agent.run("task", false, true).unwrap();
+        assert_eq!(
+            *events.lock().unwrap(),
+            vec![
+                AgentEvent::ModelRequestStarted,
+                AgentEvent::ModelRequestFinished
+            ]
+        );
+    }
+
+    #[test]
+    fn test_add_and_remove_tool() {
+        let model = MockModel { tool_calls: vec![] };
+        let mut agent = FunctionCallingAgent::new(model, vec![], None, None, None, None).unwrap();
+        assert!(!agent.get_system_prompt().contains("sleep"));
+
+        agent.add_tool(Box::new(SleepTool)).unwrap();
+        assert!(agent.tool_names().contains(&"sleep"));
+        assert!(agent
+            .get_system_prompt()
+            .contains("Sleeps and returns the label"));
+        assert!(agent.add_tool(Box::new(SleepTool)).is_err());
+
+        assert_eq!(agent.remove_tool("sleep").unwrap().name(), "sleep");
+        assert!(!agent.tool_names().contains(&"sleep"));
+        assert!(!agent.get_system_prompt().contains("sleep"));
+        assert!(agent.remove_tool("sleep").is_err());
+        assert!(agent.remove_tool("final_answer").is_err());
+    }
+
+    #[test]
+    fn test_managed_agent_name_in_system_prompt() {
+        let model = MockModel { tool_calls: vec![] };
+        let researcher = MultiStepAgent::new(
+            model.clone(),
+            vec![],
+            None,
+            None,
+            Some("researcher"),
+            Some("Searches the web for sources"),
+            None,
+        )
+        .unwrap();
+        let writer = FunctionCallingAgent::new(
+            model.clone(),
+            vec![],
+            None,
+            None,
+            Some("Writes the report"),
+            None,
+        )
+        .unwrap()
+        .with_name("writer");
+        let managed_agents = HashMap::from([
+            ("a".to_string(), Box::new(researcher) as Box<dyn Agent>),
+            ("b".to_string(), Box::new(writer) as Box<dyn Agent>),
+        ]);
+
+        let manager =
+            FunctionCallingAgent::new(model, vec![], None, Some(managed_agents), None, None)
+                .unwrap();
+        let system_prompt = manager.get_system_prompt();
+        assert!(system_prompt.contains("\nresearcher: \"Searches the web for sources\""));
+        assert!(system_prompt.contains("\nwriter: \"Writes the report\""));
+        assert_eq!(manager.name(), None);
+    }
+
+    #[test]
+    fn test_unnamed_managed_agents_are_introduced_by_key() {
+        let model = MockModel { tool_calls: vec![] };
+        let agent = |description| {
+            FunctionCallingAgent::new(model.clone(), vec![], None, None, Some(description), None)
+                .unwrap()
+        };
+        let managed_agents = HashMap::from([
+            (
+                "researcher".to_string(),
+                Box::new(agent("Searches the web for sources")) as Box<dyn Agent>,
+            ),
+            (
+                "writer".to_string(),
+                Box::new(agent("Writes the report")) as Box<dyn Agent>,
+            ),
+        ]);
+
+        let description = show_agents_description(&managed_agents);
+        assert!(description.contains("\nresearcher: \"Searches the web for sources\""));
+        assert!(description.contains("\nwriter: \"Writes the report\""));
+    }
+
+    #[test]
+    fn test_tools_are_validated() {
+        let model = MockModel { tool_calls: vec![] };
+        let keyless = || -> Vec<Box<dyn AnyTool>> {
+            vec![Box::new(GoogleSearchTool::new(Some(String::new())))]
+        };
+        let Err(error) =
+            FunctionCallingAgent::new(model.clone(), keyless(), None, None, None, None)
+        else {
+            panic!("An agent with a keyless search tool was built");
+        };
+        let error = error.to_string();
+        assert!(
+            error.contains("The tool 'google_search' is not usable: SERPAPI_API_KEY missing"),
+            "{}",
+            error
+        );
+
+        let mut agent =
+            FunctionCallingAgent::new(model.clone(), vec![], None, None, None, None).unwrap();
+        assert!(agent
+            .add_tool(Box::new(GoogleSearchTool::new(Some(String::new()))))
+            .is_err());
+        agent
+            .add_tool(Box::new(GoogleSearchTool::new(Some("key".to_string()))))
+            .unwrap();
+
+        // The extra default tools are validated, and so are the tools of a forked agent
+        let plain = FunctionCallingAgent::new(model, vec![], None, None, None, None).unwrap();
+        assert!(plain.with_default_tools(keyless()).is_err());
+        agent.base_agent.tools.extend(keyless());
+        assert!(agent.fork_builder().is_err());
+    }
+
+    #[test]
+    fn test_prompt_vars() {
+        let model = MockModel { tool_calls: vec![] };
+        let system_prompt = "Help {{user_name}} ({{locale}}). Tools: {{tool_names}}";
+        let mut agent =
+            FunctionCallingAgent::new(model.clone(), vec![], Some(system_prompt), None, None, None)
+                .unwrap();
+        agent
+            .set_prompt_vars(HashMap::from([(
+                "user_name".to_string(),
+                "Ada".to_string(),
+            )]))
+            .unwrap();
+        assert_eq!(
+            agent.get_system_prompt(),
+            "Help Ada ({{locale}}). Tools: final_answer"
+        );
+
+        // The placeholder without a value is an error in strict mode
+        assert!(agent.with_strict_prompt_vars(true).is_err());
+        let mut agent =
+            FunctionCallingAgent::new(model, vec![], Some(system_prompt), None, None, None)
+                .unwrap();
+        let vars = HashMap::from([
+            ("user_name".to_string(), "Ada".to_string()),
+            ("locale".to_string(), "en-GB".to_string()),
+        ]);
+        agent.set_prompt_vars(vars).unwrap();
+        let agent = agent.with_strict_prompt_vars(true).unwrap();
+        assert_eq!(
+            agent.get_system_prompt(),
+            "Help Ada (en-GB). Tools: final_answer"
+        );
+    }
+
+    #[test]
+    fn test_default_tools_are_deduplicated() {
+        let model = MockModel { tool_calls: vec![] };
+        let tools: Vec<Box<dyn AnyTool>> = vec![
+            Box::new(VisitWebsiteTool::new()),
+            Box::new(FinalAnswerTool::new()),
+        ];
+        let agent = FunctionCallingAgent::new(model, tools, None, None, None, None)
+            .unwrap()
+            .with_default_tools(vec![
+                Box::new(SleepTool),
+                Box::new(DuckDuckGoSearchTool::new()),
+            ])
+            .unwrap();
+        assert_eq!(
+            agent.tool_names(),
+            vec![
+                "visit_website",
+                "final_answer",
+                "sleep",
+                "duckduckgo_search"
+            ]
+        );
+        assert!(agent.get_system_prompt().contains("duckduckgo_search"));
+    }
+
+    #[test]
+    fn test_forked_agents_have_independent_logs() {
+        let model = MockModel {
+            tool_calls: vec![tool_call("final_answer", json!({"answer": "42"}))],
+        };
+        let template = FunctionCallingAgent::new(model, vec![], None, None, None, Some(5)).unwrap();
+        let mut first = template.fork().unwrap();
+        let second = template.fork_builder().unwrap()();
+
+        assert_eq!(first.run("task", false, true).unwrap(), "42");
+        assert_eq!(first.get_logs_mut().len(), 3);
+        assert!(template.base_agent.logs.is_empty());
+        assert!(second.base_agent.logs.is_empty());
+        assert_eq!(second.get_system_prompt(), template.get_system_prompt());
+
+        let results = crate::parallel::run_tasks_parallel(
+            template.fork_builder().unwrap(),
+            &["a".to_string(), "b".to_string()],
+        );
+        assert!(results
+            .iter()
+            .all(|result| result.as_deref().ok() == Some("42")));
+    }
+
+    /// The stop sequences and the last message of a request.
+    type Request = (Vec<String>, String);
+
+    /// Records the requests it receives.
+    #[derive(Debug, Clone, Default)]
+    struct RecordingModel {
+        requests: Arc<std::sync::Mutex<Vec<Request>>>,
+    }
+
+    impl Model for RecordingModel {
+        fn run(
+            &self,
+            messages: Vec<Message>,
+            _tools: Vec<ToolInfo>,
+            _max_tokens: Option<usize>,
+            args: Option<HashMap<String, Vec<String>>>,
+        ) -> Result<Box<dyn ModelResponse>, AgentError> {
+            let stop = args
+                .and_then(|mut args| args.remove("stop"))
+                .unwrap_or_default();
+            let last = messages
+                .last()
+                .map(|m| m.content.clone())
+                .unwrap_or_default();
+            self.requests.lock().unwrap().push((stop, last));
+            Ok(Box::new(MockResponse {
+                tool_calls: vec![tool_call("sleep", json!({"label": "done", "millis": 0}))],
+            }))
+        }
+    }
+
+    #[test]
+    fn test_custom_observation_marker() {
+        let model = RecordingModel::default();
+        let requests = model.requests.clone();
+        let tools: Vec<Box<dyn AnyTool>> = vec![Box::new(SleepTool)];
+        let mut agent = FunctionCallingAgent::new(model, tools, None, None, None, Some(2))
+            .unwrap()
+            .with_observation_marker("Result:");
+        agent.run("task", false, true).unwrap();
+
+        let requests = requests.lock().unwrap();
+        assert_eq!(requests[0].0, vec!["Result:"]);
+        assert_eq!(
+            requests[1].1,
+            "Call id: call_sleep\nResult: Result from sleep: done"
+        );
+    }
+
+    #[test]
+    fn test_string_tool_arguments_in_memory() {
+        let mut agent = FunctionCallingAgent::new(
+            MockModel { tool_calls: vec![] },
+            vec![],
+            None,
+            None,
+            None,
+            None,
+        )
+        .unwrap();
+        let arguments = [
+            json!("{\"query\": \"rust\"}"),
+            json!(json!("{\"query\": \"rust\"}").to_string()),
+            json!({"query": "rust"}),
+        ];
+        for (i, arguments) in arguments.into_iter().enumerate() {
+            let mut step_log = AgentStep::new(i);
+            step_log.tool_call = Some(vec![ToolCall {
+                id: Some(format!("call_{}", i)),
+                call_type: Some("function".to_string()),
+                function: FunctionCall {
+                    name: "search".to_string(),
+                    arguments,
+                },
+            }]);
+            agent.get_logs_mut().push(Step::ActionStep(step_log));
+        }
+
+        let memory = agent.write_inner_memory_from_logs(None).unwrap();
+        assert_eq!(memory.len(), 3);
+        for message in &memory {
+            assert!(!message.content.contains("\\\""), "{}", message.content);
+            let tool_call = serde_json::from_str::<serde_json::Value>(&message.content).unwrap();
+            assert_eq!(tool_call["function"]["arguments"], json!({"query": "rust"}));
+        }
+    }
+
+    #[test]
+    fn test_seed_messages() {
+        let transcript = r#"[
+            {"role": "system", "content": "You are a helpful assistant"},
+            {"role": "user", "content": "New Task: What is 2 + 2?"},
+            {"role": "assistant", "content": "Let me compute it"},
+            {"role": "tool_response", "content": "4"}
+        ]"#;
+        let messages = serde_json::from_str::<Vec<Message>>(transcript).unwrap();
+        assert_eq!(messages[3].role, MessageRole::ToolResponse);
+        let round_trip = serde_json::to_string(&messages).unwrap();
+        assert_eq!(
+            serde_json::from_str::<serde_json::Value>(&round_trip).unwrap(),
+            serde_json::from_str::<serde_json::Value>(transcript).unwrap()
+        );
+
+        let mut agent = FunctionCallingAgent::new(
+            MockModel { tool_calls: vec![] },
+            vec![],
+            None,
+            None,
+            None,
+            None,
+        )
+        .unwrap();
+        agent.seed_messages(messages);
+        let memory = agent.write_inner_memory_from_logs(None).unwrap();
+        let memory = memory
+            .iter()
+            .map(|m| (m.role, m.content.as_str()))
+            .collect::<Vec<_>>();
+        assert_eq!(memory[0].0, MessageRole::System);
+        assert_ne!(memory[0].1, "You are a helpful assistant");
+        assert_eq!(
+            memory[1..],
+            [
+                (MessageRole::User, "New Task: What is 2 + 2?"),
+                (MessageRole::Assistant, "Let me compute it"),
+                (MessageRole::User, "Observation: 4"),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_tool_names() {
+        let model = MockModel { tool_calls: vec![] };
+        let tools: Vec<Box<dyn AnyTool>> = vec![Box::new(SleepTool)];
+        let agent = FunctionCallingAgent::new(model, tools, None, None, None, None).unwrap();
+        assert_eq!(agent.tool_names(), vec!["sleep", "final_answer"]);
+        assert_eq!(
+            agent.tool_infos()[0].function.description,
+            "Sleeps and returns the label"
+        );
+    }
+
+    /// A model that answers the planner with a plan and calls `final_answer` with the step it is given.
+    #[derive(Debug, Clone)]
+    struct PlanningModel {
+        plan: &'static str,
+    }
+
+    impl Model for PlanningModel {
+        fn run(
+            &self,
+            messages: Vec<Message>,
+            tools: Vec<ToolInfo>,
+            _max_tokens: Option<usize>,
+            _args: Option<HashMap<String, Vec<String>>>,
+        ) -> Result<Box<dyn ModelResponse>, AgentError> {
+            // The planner asks for the facts and the plan without tools
+            if tools.is_empty() {
+                if self.plan.is_empty() {
+                    return Err(AgentError::Generation("model unavailable".to_string()));
+                }
+                return Ok(Box::new(TextResponse(self.plan.to_string())));
+            }
+            let step = messages
+                .iter()
+                .rev()
+                .find(|message| message.role == MessageRole::User)
+                .map(|message| message.content.clone())
+                .unwrap_or_default();
+            Ok(Box::new(MockResponse {
+                tool_calls: vec![tool_call("final_answer", json!({ "answer": step }))],
+            }))
+        }
+    }
+
+    #[test]
+    fn test_planning_agent_runs_plan() {
+        let model = PlanningModel {
+            plan: "1. Find the population\n2. Report it\n<end_plan>",
+        };
+        let mut agent = PlanningAgent::new(
+            model,
+            vec![],
+            None,
+            None,
+            None,
+            Some(3),
+            Some("List the facts."),
+            None,
+            None,
+        )
+        .unwrap();
+        let answer = agent
+            .run("What is the population of Paris?", false, true)
+            .unwrap();
+        assert!(answer.contains("Report it"), "{}", answer);
+
+        let logs = agent.get_logs_mut();
+        assert!(
+            matches!(&logs[0], Step::PlanningStep(plan, _) if plan.contains("Find the population"))
+        );
+        let actions = logs
+            .iter()
+            .filter(|step| matches!(step, Step::ActionStep(_)))
+            .count();
+        assert_eq!(actions, 2);
+
+        let mut failing = PlanningAgent::new(
+            PlanningModel { plan: "" },
+            vec![],
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+        )
+        .unwrap();
+        assert!(failing.run("task", false, true).is_err());
+    }
+
+    #[test]
+    fn test_planning_agent_uses_planning_model() {
+        // The main model fails the planning calls, so only the planning model can make the plan
+        let mut agent = PlanningAgent::new(
+            PlanningModel { plan: "" },
+            vec![],
+            None,
+            None,
+            None,
+            Some(3),
+            None,
+            None,
+            Some(PlanningModel {
+                plan: "1. Find the population\n<end_plan>",
+            }),
+        )
+        .unwrap();
+        let answer = agent
+            .run("What is the population of Paris?", false, true)
+            .unwrap();
+        assert!(answer.contains("Find the population"), "{}", answer);
+        assert!(matches!(
+            &agent.get_logs_mut()[0],
+            Step::PlanningStep(plan, _) if plan.contains("Find the population")
+        ));
+    }
+
+    /// A model that returns the given text responses in order.
+    #[derive(Debug, Clone)]
+    struct ScriptedModel {
+        responses: Arc<std::sync::Mutex<Vec<String>>>,
+    }
+
+    impl ScriptedModel {
+        fn new(responses: &[&str]) -> Self {
+            let mut responses = responses.iter().map(|r| r.to_string()).collect::<Vec<_>>();
+            responses.reverse();
+            Self {
+                responses: Arc::new(std::sync::Mutex::new(responses)),
+            }
+        }
+    }
+
+    struct TextResponse(String);
+
+    impl ModelResponse for TextResponse {
+        fn get_response(&self) -> Result<String, AgentError> {
+            Ok(self.0.clone())
+        }
+        fn get_tools_used(&self) -> Result<Vec<ToolCall>, AgentError> {
+            Ok(vec![])
+        }
+    }
+
+    impl Model for ScriptedModel {
+        fn run(
+            &self,
+            _messages: Vec<Message>,
+            _tools: Vec<ToolInfo>,
+            _max_tokens: Option<usize>,
+            _args: Option<HashMap<String, Vec<String>>>,
+        ) -> Result<Box<dyn ModelResponse>, AgentError> {
+            let response = self.responses.lock().unwrap().pop().unwrap_or_default();
+            Ok(Box::new(TextResponse(response)))
+        }
+    }
+
+    #[test]
+    fn test_parse_text_action() {
+        let call = parse_text_action(
+            "Thought: I need the weather.\nAction: get_weather\nAction Input: {\"city\": \"Paris\"}\nObservation: sunny",
+        )
+        .unwrap();
+        assert_eq!(call.function.name, "get_weather");
+        assert_eq!(call.function.arguments, json!({"city": "Paris"}));
+
+        // The JSON blob of the tool calling system prompt
+        let call = parse_text_action(
+            "Action:\n{\n  \"tool_name\": \"final_answer\",\n  \"tool_arguments\": {\"answer\": \"42\"}\n}",
+        )
+        .unwrap();
+        assert_eq!(call.function.name, "final_answer");
+        assert_eq!(call.function.arguments, json!({"answer": "42"}));
+
+        let call = parse_text_action("Action: search\nAction Input: Population Shanghai").unwrap();
+        assert_eq!(call.function.arguments, json!("Population Shanghai"));
+        assert!(parse_text_action("The answer is 42").is_none());
+    }
+
+    #[cfg(feature = "code-agent")]
+    #[test]
+    fn test_text_react_tool_calls() {
+        let model = ScriptedModel::new(&[
+            "Thought: count first\nAction: counter\nAction Input: {}",
+            "Action: final_answer\nAction Input: {\"answer\": \"counted\"}",
+        ]);
+        let counter = CounterTool::default();
+        let calls = counter.calls.clone();
+        let tools: Vec<Box<dyn AnyTool>> = vec![Box::new(counter)];
+        let mut agent = FunctionCallingAgent::new(model, tools, None, None, None, Some(3))
+            .unwrap()
+            .with_tool_calling_style(ToolCallingStyle::TextReAct);
+        assert_eq!(agent.run("task", false, true).unwrap(), "counted");
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+    }
+
+    #[cfg(feature = "code-agent")]
+    #[test]
+    fn test_code_agent_retries_unparsable_output() {
+        let responses = [
+            "I think the answer is 42.",
+            "Thought: return it.\nCode:\n```py\nfinal_answer(\"42\")\n```",
+        ];
+        let model = ScriptedModel::new(&responses);
+        let mut agent = CodeAgent::new(model, vec![], None, None, None, Some(3)).unwrap();
+        assert_eq!(agent.run("task", false, true).unwrap(), "42");
+        // The correction happened within the first step
+        assert_eq!(agent.get_step_number(), 1);
+
+        let model = ScriptedModel::new(&responses);
+        let mut agent = CodeAgent::new(model, vec![], None, None, None, Some(3))
+            .unwrap()
+            .with_max_parse_retries(0);
+        assert!(agent.run("task", false, true).is_err());
+    }
+
+    #[cfg(feature = "code-agent")]
+    #[test]
+    fn test_authorized_imports_in_system_prompt() {
+        let model = ScriptedModel::new(&[]);
+        let agent = CodeAgent::new(model, vec![], None, None, None, Some(3))
+            .unwrap()
+            .with_authorized_imports(&[])
+            .unwrap();
+        assert!(agent
+            .get_system_prompt()
+            .contains("only from the following list of modules: \n"));
+
+        let agent = agent.with_authorized_imports(&["statistics"]).unwrap();
+        let prompt = agent.get_system_prompt();
+        assert!(prompt.contains("only from the following list of modules: statistics\n"));
+        assert_eq!(prompt.matches("statistics").count(), 1);
+        let prompt = agent.fork().unwrap().get_system_prompt().to_string();
+        assert!(prompt.contains("modules: statistics\n"));
+    }
+
+    #[cfg(feature = "code-agent")]
+    #[test]
+    fn test_reset_interpreter_on_run() {
+        let first_run = "Code:\n```py\nx = 5\nfinal_answer(x)\n```";
+        let second_run = "Code:\n```py\nfinal_answer(x)\n```";
+
+        // The variables of the first run are kept by default
+        let model = ScriptedModel::new(&[first_run, second_run]);
+        let mut agent = CodeAgent::new(model, vec![], None, None, None, Some(3)).unwrap();
+        assert_eq!(agent.run("set x", false, true).unwrap(), "5");
+        assert_eq!(agent.run("return x", false, true).unwrap(), "5");
+
+        let fresh = "Code:\n```py\nfinal_answer('fresh')\n```";
+        let model = ScriptedModel::new(&[first_run, second_run, fresh]);
+        let mut agent = CodeAgent::new(model, vec![], None, None, None, Some(3))
+            .unwrap()
+            .with_reset_interpreter_on_run(true);
+        assert_eq!(agent.run("set x", false, true).unwrap(), "5");
+        // `x` is undefined in the second run, the step fails and the model tries again
+        assert_eq!(agent.run("return x", false, true).unwrap(), "fresh");
+        assert_eq!(agent.get_step_number(), 2);
+    }
+
+    #[test]
+    fn test_parse_thought() {
+        let response = "Thought: I need the population of Paris.\nLet me search for it.\nCode:\n```py\nsearch(\"population of Paris\")\n```";
+        assert_eq!(
+            parse_thought(response).as_deref(),
+            Some("I need the population of Paris.\nLet me search for it.")
+        );
+        assert_eq!(parse_thought("Code:\n```py\nx = 1\n```"), None);
+        // The text accompanying a tool call has no code block
+        assert_eq!(
+            parse_thought("Searching the web first.").as_deref(),
+            Some("Searching the web first.")
+        );
+    }
+
+    #[cfg(feature = "code-agent")]
+    #[test]
+    fn test_code_agent_logs_thought() {
+        let model = ScriptedModel::new(&[
+            "Thought: The answer is known.\nCode:\n```py\nfinal_answer(\"42\")\n```",
+        ]);
+        let mut agent = CodeAgent::new(model, vec![], None, None, None, Some(3)).unwrap();
+        assert_eq!(agent.run("task", false, true).unwrap(), "42");
+        let thoughts = agent
+            .steps()
+            .iter()
+            .filter_map(|step| match step {
+                Step::ActionStep(step_log) => step_log.thought(),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(thoughts, vec!["The answer is known."]);
+    }
+
+    #[test]
+    fn test_detect_final_answer_colon() {
+        let text = "Some text. Final Answer: 42";
+        assert_eq!(detect_final_answer(text), Some("42".to_string()));
+    }
+
+    #[test]
+    fn test_detect_final_answer_fn() {
+        let text = "ignored final_answer(\"hello\") trailing";
+        assert_eq!(detect_final_answer(text), Some("hello".to_string()));
+    }
+
+    #[test]
+    fn test_truncate_observation() {
+        let text = "a".repeat(35000);
+        let truncated = truncate_observation(&text, 30000);
+        assert!(truncated.len() < text.len());
+        assert!(truncated.contains("truncated"));
+    }
+
+    #[test]
+    fn test_truncation_strategies() {
+        let text = format!("start {} end", "x".repeat(100));
+        assert_eq!(
+            TruncationStrategy::Head.truncate(&text, 8),
+            "start xx ...[truncated]"
+        );
+        assert_eq!(
+            TruncationStrategy::Tail.truncate(&text, 8),
+            "[truncated]... xxxx end"
+        );
+        assert_eq!(
+            TruncationStrategy::Middle.truncate(&text, 8),
+            "star ...[truncated]...  end"
+        );
+        assert_eq!(TruncationStrategy::Tail.truncate("short", 8), "short");
+    }
+
+    #[test]
+    fn test_default_truncation_keeps_both_ends() {
+        assert_eq!(TruncationStrategy::default(), TruncationStrategy::Middle);
+        let model = MockModel { tool_calls: vec![] };
+        let agent = FunctionCallingAgent::new(model, vec![], None, None, None, None).unwrap();
+        let observation = format!("start {} end", "x".repeat(40000));
+        let truncated = agent.base_agent.truncate(&observation);
+        assert!(truncated.starts_with("start "));
+        assert!(truncated.ends_with(" end"));
+        assert!(truncated.contains("...[truncated]..."));
+    }
+}
+
+impl<M: Model + Debug + Clone> Agent for PlanningAgent<M> {
+    fn name(&self) -> Option<&str> {
+        None
+    }
+    fn get_max_steps(&self) -> usize {
+        self.executor.get_max_steps()
+    }
+    fn get_step_number(&self) -> usize {
+        self.executor.get_step_number()
+    }
+    fn reset_step_number(&mut self) {
+        self.executor.reset_step_number();
+    }
+    fn increment_step_number(&mut self) {
+        self.executor.increment_step_number();
+    }
+    fn get_logs_mut(&mut self) -> &mut Vec<Step> {
+        &mut self.logs
+    }
+    fn steps(&self) -> &[Step] {
+        &self.logs
+    }
+    fn set_task(&mut self, task: &str) {
+        self.planner.set_task(task);
+        self.executor.set_task(task);
+    }
+    fn get_system_prompt(&self) -> &str {
+        self.executor.get_system_prompt()
+    }
+    fn observation_marker(&self) -> &str {
+        self.executor.observation_marker()
+    }
+    fn consecutive_error_threshold(&self) -> Option<usize> {
+        self.executor.consecutive_error_threshold()
+    }
+    fn model(&self) -> &dyn Model {
+        self.executor.model()
+    }
+    fn tool_infos(&self) -> Vec<ToolInfo> {
+        self.executor.tool_infos()
+    }
+    fn cancel_token(&self) -> Option<&Arc<AtomicBool>> {
+        self.executor.cancel_token()
+    }
+    fn event_callback(&self) -> Option<&EventCallback> {
+        self.executor.event_callback()
+    }
+    fn step(&mut self, log_entry: &mut Step) -> Result<Option<String>> {
+        self.executor.step(log_entry)
+    }
+    fn run(&mut self, task: &str, stream: bool, reset: bool) -> Result<String> {
+        if reset {
+            self.logs.clear();
+        }
+        self.set_task(task);
+        let steps = self.planning_step(task)?;
+        let mut final_answer = String::new();
+        for step_task in steps {
+            final_answer = self.executor.run(&step_task, stream, true)?;
+            self.logs.extend(self.executor.get_logs_mut().drain(..));
+        }
+        Ok(final_answer)
     }
 }
@@ -1,25 +1,32 @@
-use anyhow::Result;
+use anyhow::{anyhow, Context, Result};
 use clap::{Parser, ValueEnum};
 use colored::*;
+use indicatif::ProgressBar;
 use smolagents_rs::agents::Step;
-use smolagents_rs::agents::{Agent, CodeAgent, FunctionCallingAgent, PlanningAgent};
+use smolagents_rs::agents::{
+    Agent, AgentEvent, CodeAgent, EventCallback, FunctionCallingAgent, PlanningAgent,
+    ToolCallingStyle,
+};
 use smolagents_rs::errors::AgentError;
+use smolagents_rs::logger::{set_verbosity, Verbosity};
 use smolagents_rs::models::azure::AzureOpenAIModel;
-use smolagents_rs::models::candle::CandleModel;
+#[cfg(feature = "bedrock")]
+use smolagents_rs::models::bedrock::BedrockModel;
+use smolagents_rs::models::candle::{CandleDevice, CandleModel};
 use smolagents_rs::models::huggingface::HuggingFaceModel;
 use smolagents_rs::models::lightllm::LightLLMModel;
 use smolagents_rs::models::model_traits::{Model, ModelResponse};
 use smolagents_rs::models::ollama::{OllamaModel, OllamaModelBuilder};
 use smolagents_rs::models::openai::OpenAIServerModel;
+use smolagents_rs::models::openai_responses::OpenAIResponsesModel;
 use smolagents_rs::models::types::Message;
 use smolagents_rs::sandbox::Sandbox;
-use smolagents_rs::tools::{
-    AnyTool, DuckDuckGoSearchTool, GoogleSearchTool, RagTool, ToolInfo, TreeSitterTool,
-    VisitWebsiteTool, WikipediaSearchTool,
-};
+use smolagents_rs::tools::{AnyTool, ToolInfo, ToolRegistry};
 use std::collections::HashMap;
 use std::fs::File;
-use std::io::{self, Write};
+use std::io::{self, IsTerminal, Write};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
 
 #[derive(Debug, Clone, ValueEnum)]
 enum AgentType {
@@ -28,6 +35,23 @@ enum AgentType {
     Planning,
 }
 
+#[derive(Debug, Clone, ValueEnum)]
+enum ToolCalling {
+    /// With the tool calling of the model
+    Native,
+    /// By writing the calls as text
+    Text,
+}
+
+impl ToolCalling {
+    fn style(&self) -> ToolCallingStyle {
+        match self {
+            ToolCalling::Native => ToolCallingStyle::Native,
+            ToolCalling::Text => ToolCallingStyle::TextReAct,
+        }
+    }
+}
+
 #[derive(Debug, Clone, ValueEnum)]
 enum ToolType {
     DuckDuckGo,
@@ -36,26 +60,85 @@ enum ToolType {
     WikipediaSearch,
     Rag,
     TreeSitter,
+    Memory,
+    Wikidata,
+    Diff,
+    HtmlSelect,
+    ListDir,
+    WebSearch,
+    #[cfg(feature = "image-generation")]
+    ImageGeneration,
 }
 
-#[derive(Debug, Clone, ValueEnum)]
+#[derive(Debug, Clone, PartialEq, ValueEnum)]
 enum ModelType {
     OpenAI,
+    /// OpenAI's Responses API instead of chat completions
+    OpenAIResponses,
+    Anthropic,
     AzureOpenAI,
     Ollama,
     HuggingFace,
     Candle,
     LightLLM,
+    #[cfg(feature = "bedrock")]
+    Bedrock,
+}
+
+/// Anthropic's OpenAI-compatible chat completions endpoint.
+const ANTHROPIC_BASE_URL: &str = "https://api.anthropic.com/v1/chat/completions";
+
+impl ModelType {
+    /// The model used when `--model-id` is not given.
+    fn default_model_id(&self) -> &'static str {
+        match self {
+            ModelType::Anthropic => "claude-sonnet-4-5",
+            ModelType::Ollama => "llama3.2",
+            ModelType::HuggingFace => "HuggingFaceH4/zephyr-7b-beta",
+            _ => "gpt-4o-mini",
+        }
+    }
+}
+
+/// Pick the model type from the environment when `--model-type` is not given: OpenAI if `OPENAI_API_KEY`
+/// is set, then Anthropic if `ANTHROPIC_API_KEY` is set, and a local Ollama server otherwise.
+fn resolve_model_type(env: impl Fn(&str) -> Option<String>) -> ModelType {
+    if env("OPENAI_API_KEY").is_some() {
+        ModelType::OpenAI
+    } else if env("ANTHROPIC_API_KEY").is_some() {
+        ModelType::Anthropic
+    } else {
+        ModelType::Ollama
+    }
+}
+
+/// The value of the environment variable `name`, if it is set and not empty.
+fn env_var(name: &str) -> Option<String> {
+    std::env::var(name).ok().filter(|value| !value.is_empty())
+}
+
+/// The value given with the `flag` option, or else the environment variable `name`.
+fn arg_or_env(value: Option<String>, name: &str, flag: &str) -> Result<String> {
+    value.or_else(|| env_var(name)).ok_or_else(|| {
+        anyhow!(
+            "{} is not set: set the environment variable or pass {}",
+            name,
+            flag
+        )
+    })
 }
 
 #[derive(Debug, Clone)]
 enum ModelWrapper {
     OpenAI(OpenAIServerModel),
+    OpenAIResponses(OpenAIResponsesModel),
     AzureOpenAI(AzureOpenAIModel),
     Ollama(OllamaModel),
     HuggingFace(HuggingFaceModel),
     Candle(CandleModel),
     LightLLM(LightLLMModel),
+    #[cfg(feature = "bedrock")]
+    Bedrock(BedrockModel),
 }
 
 enum AgentWrapper {
@@ -79,8 +162,29 @@ impl AgentWrapper {
             AgentWrapper::Planning(agent) => agent.get_logs_mut(),
         }
     }
+    fn reset_step_number(&mut self) {
+        match self {
+            AgentWrapper::FunctionCalling(agent) => agent.reset_step_number(),
+            AgentWrapper::Code(agent) => agent.reset_step_number(),
+            AgentWrapper::Planning(agent) => agent.reset_step_number(),
+        }
+    }
 }
 impl Model for ModelWrapper {
+    fn supports_tool_calling(&self) -> bool {
+        match self {
+            ModelWrapper::OpenAI(m) => m.supports_tool_calling(),
+            ModelWrapper::OpenAIResponses(m) => m.supports_tool_calling(),
+            ModelWrapper::AzureOpenAI(m) => m.supports_tool_calling(),
+            ModelWrapper::Ollama(m) => m.supports_tool_calling(),
+            ModelWrapper::HuggingFace(m) => m.supports_tool_calling(),
+            ModelWrapper::Candle(m) => m.supports_tool_calling(),
+            ModelWrapper::LightLLM(m) => m.supports_tool_calling(),
+            #[cfg(feature = "bedrock")]
+            ModelWrapper::Bedrock(m) => m.supports_tool_calling(),
+        }
+    }
+
     fn run(
         &self,
         messages: Vec<Message>,
@@ -90,11 +194,14 @@ impl Model for ModelWrapper {
     ) -> Result<Box<dyn ModelResponse>, AgentError> {
         match self {
             ModelWrapper::OpenAI(m) => Ok(m.run(messages, tools, max_tokens, args)?),
+            ModelWrapper::OpenAIResponses(m) => Ok(m.run(messages, tools, max_tokens, args)?),
             ModelWrapper::AzureOpenAI(m) => Ok(m.run(messages, tools, max_tokens, args)?),
             ModelWrapper::Ollama(m) => Ok(m.run(messages, tools, max_tokens, args)?),
             ModelWrapper::HuggingFace(m) => Ok(m.run(messages, tools, max_tokens, args)?),
             ModelWrapper::Candle(m) => Ok(m.run(messages, tools, max_tokens, args)?),
             ModelWrapper::LightLLM(m) => Ok(m.run(messages, tools, max_tokens, args)?),
+            #[cfg(feature = "bedrock")]
+            ModelWrapper::Bedrock(m) => Ok(m.run(messages, tools, max_tokens, args)?),
         }
     }
 }
@@ -106,21 +213,26 @@ struct Args {
     #[arg(short = 'a', long, value_enum, default_value = "function-calling")]
     agent_type: AgentType,
 
+    /// How the function-calling agent calls the tools. Models without native tool calling always write
+    /// their calls as text
+    #[arg(long, value_enum, default_value = "native")]
+    tool_calling: ToolCalling,
+
     /// List of tools to use
     #[arg(short = 'l', long = "tools", value_enum, num_args = 1.., value_delimiter = ',', default_values_t = [ToolType::DuckDuckGo, ToolType::VisitWebsite])]
     tools: Vec<ToolType>,
 
-    /// The type of model to use
-    #[arg(short = 'm', long, value_enum, default_value = "open-ai")]
-    model_type: ModelType,
+    /// The type of model to use. Picked from the API keys in the environment if not given
+    #[arg(short = 'm', long, value_enum)]
+    model_type: Option<ModelType>,
 
-    /// API key for the selected model (OpenAI or Hugging Face)
+    /// API key for the selected model (OpenAI, Anthropic, Azure OpenAI, Hugging Face or LightLLM)
     #[arg(short = 'k', long)]
     api_key: Option<String>,
 
-    /// Model ID (e.g., "gpt-4" for OpenAI or "qwen2.5" for Ollama)
-    #[arg(long, default_value = "gpt-4o-mini")]
-    model_id: String,
+    /// Model ID (e.g., "gpt-4" for OpenAI or "qwen2.5" for Ollama). Defaults to a model of the provider
+    #[arg(long)]
+    model_id: Option<String>,
 
     /// Whether to stream the output
     #[arg(short, long, default_value = "false")]
@@ -130,140 +242,351 @@ struct Args {
     #[arg(short, long)]
     base_url: Option<String>,
 
-    /// Path to the local model directory for Candle
+    /// Path to the local model directory for Candle, or to a quantized `.gguf` file
     #[arg(long)]
     model_path: Option<String>,
 
+    /// AWS region for Bedrock (defaults to AWS_REGION)
+    #[cfg(feature = "bedrock")]
+    #[arg(long)]
+    region: Option<String>,
+
     /// Run the agent in a sandboxed temporary directory
     #[arg(long, default_value_t = false)]
     sandbox: bool,
+
+    /// Keep the sandbox directory on exit instead of deleting it
+    #[arg(long, default_value_t = false, requires = "sandbox")]
+    keep_sandbox: bool,
+
+    /// Log the debug output as well
+    #[arg(short, long, default_value_t = false, conflicts_with = "quiet")]
+    verbose: bool,
+
+    /// Only print the final answers
+    #[arg(short, long, default_value_t = false)]
+    quiet: bool,
+
+    /// Remember the conversation between the tasks, until `/reset` clears it. The planning agent still
+    /// executes every step of its plans afresh
+    #[arg(long, default_value_t = false)]
+    interactive_memory: bool,
 }
 
-fn create_tool(tool_type: &ToolType) -> Box<dyn AnyTool> {
-    match tool_type {
-        ToolType::DuckDuckGo => Box::new(DuckDuckGoSearchTool::new()),
-        ToolType::VisitWebsite => Box::new(VisitWebsiteTool::new()),
-        ToolType::GoogleSearchTool => Box::new(GoogleSearchTool::new(None)),
-        ToolType::WikipediaSearch => Box::new(WikipediaSearchTool::new()),
-        ToolType::Rag => Box::new(RagTool::new(vec![], 3)),
-        ToolType::TreeSitter => Box::new(TreeSitterTool::new()),
+impl ToolType {
+    /// The name of the tool in the [`ToolRegistry`].
+    fn tool_name(&self) -> &'static str {
+        match self {
+            ToolType::DuckDuckGo => "duckduckgo_search",
+            ToolType::VisitWebsite => "visit_website",
+            ToolType::GoogleSearchTool => "google_search",
+            ToolType::WikipediaSearch => "wikipedia_search",
+            ToolType::Rag => "rag",
+            ToolType::TreeSitter => "tree_sitter_parse",
+            ToolType::Memory => "memory",
+            ToolType::Wikidata => "wikidata",
+            ToolType::Diff => "diff",
+            ToolType::HtmlSelect => "html_select",
+            ToolType::ListDir => "list_dir",
+            ToolType::WebSearch => "web_search",
+            #[cfg(feature = "image-generation")]
+            ToolType::ImageGeneration => "image_generation",
+        }
     }
 }
 
-fn main() -> Result<()> {
-    let args = Args::parse();
+fn create_tool(registry: &ToolRegistry, tool_type: &ToolType) -> Result<Box<dyn AnyTool>> {
+    Ok(registry.build(tool_type.tool_name())?)
+}
 
-    let _sandbox = if args.sandbox {
-        let sb = Sandbox::new()?;
-        sb.set_as_cwd()?;
-        println!("Using sandbox at {}", sb.path().display());
-        Some(sb)
-    } else {
-        None
+fn create_model(args: &Args) -> Result<ModelWrapper> {
+    let model_type = match &args.model_type {
+        Some(model_type) => model_type.clone(),
+        None => {
+            let model_type = resolve_model_type(env_var);
+            if !args.quiet {
+                println!(
+                    "Using the {:?} model, pass --model-type to choose another one",
+                    model_type
+                );
+            }
+            model_type
+        }
     };
+    let model_id = args
+        .model_id
+        .clone()
+        .unwrap_or_else(|| model_type.default_model_id().to_string());
 
-    let tools: Vec<Box<dyn AnyTool>> = args.tools.iter().map(create_tool).collect();
-
-    // Create model based on type
-    let model = match args.model_type {
+    let model = match model_type {
         ModelType::OpenAI => ModelWrapper::OpenAI(OpenAIServerModel::new(
             args.base_url.as_deref(),
-            Some(&args.model_id),
+            Some(&model_id),
             None,
-            args.api_key,
+            Some(arg_or_env(
+                args.api_key.clone(),
+                "OPENAI_API_KEY",
+                "--api-key",
+            )?),
         )),
-        ModelType::AzureOpenAI => ModelWrapper::AzureOpenAI(AzureOpenAIModel::new(
+        ModelType::OpenAIResponses => ModelWrapper::OpenAIResponses(OpenAIResponsesModel::new(
             args.base_url.as_deref(),
-            Some(&args.model_id),
+            Some(&model_id),
             None,
+            Some(arg_or_env(
+                args.api_key.clone(),
+                "OPENAI_API_KEY",
+                "--api-key",
+            )?),
+        )),
+        ModelType::Anthropic => ModelWrapper::OpenAI(OpenAIServerModel::new(
+            Some(args.base_url.as_deref().unwrap_or(ANTHROPIC_BASE_URL)),
+            Some(&model_id),
             None,
-            args.api_key,
+            Some(arg_or_env(
+                args.api_key.clone(),
+                "ANTHROPIC_API_KEY",
+                "--api-key",
+            )?),
         )),
+        ModelType::AzureOpenAI => {
+            let endpoint =
+                arg_or_env(args.base_url.clone(), "AZURE_OPENAI_ENDPOINT", "--base-url")?;
+            let deployment_id = arg_or_env(
+                args.model_id.clone(),
+                "AZURE_OPENAI_DEPLOYMENT_ID",
+                "--model-id",
+            )?;
+            ModelWrapper::AzureOpenAI(AzureOpenAIModel::new(
+                Some(&endpoint),
+                Some(&deployment_id),
+                None,
+                None,
+                Some(arg_or_env(
+                    args.api_key.clone(),
+                    "AZURE_OPENAI_API_KEY",
+                    "--api-key",
+                )?),
+            ))
+        }
         ModelType::Ollama => ModelWrapper::Ollama(
             OllamaModelBuilder::new()
-                .model_id(&args.model_id)
+                .model_id(&model_id)
                 .ctx_length(8000)
                 .build(),
         ),
         ModelType::HuggingFace => ModelWrapper::HuggingFace(HuggingFaceModel::new(
             args.base_url.as_deref(),
-            Some(&args.model_id),
+            Some(&model_id),
             None,
-            args.api_key,
+            Some(arg_or_env(args.api_key.clone(), "HF_API_KEY", "--api-key")?),
         )),
         ModelType::Candle => {
-            let path = args.model_path.clone().unwrap_or_else(|| {
-                std::env::var("CANDLE_MODEL_PATH").expect("CANDLE_MODEL_PATH must be set")
-            });
+            let path = arg_or_env(args.model_path.clone(), "CANDLE_MODEL_PATH", "--model-path")?;
             ModelWrapper::Candle(
-                CandleModel::new(&path, None).expect("Failed to load candle model"),
+                CandleModel::new(&path, None, None, CandleDevice::Cpu)
+                    .with_context(|| format!("Failed to load the candle model from {}", path))?,
             )
         }
         ModelType::LightLLM => ModelWrapper::LightLLM(LightLLMModel::new(
             args.base_url.as_deref(),
-            Some(&args.model_id),
+            Some(&model_id),
             None,
-            args.api_key,
+            args.api_key.clone(),
         )),
+        #[cfg(feature = "bedrock")]
+        ModelType::Bedrock => ModelWrapper::Bedrock(BedrockModel::new(
+            &model_id,
+            args.region.as_deref(),
+            None,
+            None,
+        )?),
+    };
+    Ok(model)
+}
+
+/// Show a spinner while a model request is in flight. Returns `None` when stdout is not a terminal.
+fn spinner_callback() -> Option<EventCallback> {
+    if !io::stdout().is_terminal() {
+        return None;
+    }
+    let spinner: Mutex<Option<ProgressBar>> = Mutex::new(None);
+    Some(Arc::new(move |event| {
+        let mut spinner = spinner.lock().unwrap();
+        match event {
+            AgentEvent::ModelRequestStarted => {
+                let progress = ProgressBar::new_spinner();
+                progress.set_message("Waiting for the model...");
+                progress.enable_steady_tick(Duration::from_millis(100));
+                *spinner = Some(progress);
+            }
+            AgentEvent::ModelRequestFinished => {
+                if let Some(progress) = spinner.take() {
+                    progress.finish_and_clear();
+                }
+            }
+        }
+    }))
+}
+
+fn main() -> Result<()> {
+    let args = Args::parse();
+
+    if args.quiet {
+        set_verbosity(Verbosity::Quiet);
+    } else if args.verbose {
+        set_verbosity(Verbosity::Verbose);
+    }
+
+    let sandbox = if args.sandbox {
+        let sb = Sandbox::new()?;
+        sb.set_as_cwd()?;
+        if !args.quiet {
+            println!("Using sandbox at {}", sb.path().display());
+        }
+        Some(sb)
+    } else {
+        None
+    };
+
+    let registry = ToolRegistry::new();
+    let tools = args
+        .tools
+        .iter()
+        .map(|tool_type| create_tool(&registry, tool_type))
+        .collect::<Result<Vec<_>>>()?;
+
+    let model = create_model(&args)?;
+
+    // The spinner would garble streamed tokens, so it is only shown for non-streaming runs
+    let event_callback = if args.stream || args.quiet {
+        None
+    } else {
+        spinner_callback()
     };
 
     // Create agent based on type
     let mut agent = match args.agent_type {
-        AgentType::FunctionCalling => AgentWrapper::FunctionCalling(FunctionCallingAgent::new(
-            model,
-            tools,
-            None,
-            None,
-            Some("CLI Agent"),
-            None,
-        )?),
-        AgentType::Code => AgentWrapper::Code(CodeAgent::new(
-            model,
-            tools,
-            None,
-            None,
-            Some("CLI Agent"),
-            None,
-        )?),
-        AgentType::Planning => AgentWrapper::Planning(PlanningAgent::new(
-            model,
-            tools,
-            None,
-            None,
-            Some("CLI Agent"),
-            None,
-        )?),
+        AgentType::FunctionCalling => {
+            let mut agent =
+                FunctionCallingAgent::new(model, tools, None, None, Some("CLI Agent"), None)?
+                    .with_tool_calling_style(args.tool_calling.style());
+            if let Some(callback) = event_callback {
+                agent = agent.with_event_callback(callback);
+            }
+            AgentWrapper::FunctionCalling(agent)
+        }
+        AgentType::Code => {
+            let mut agent = CodeAgent::new(model, tools, None, None, Some("CLI Agent"), None)?;
+            if let Some(callback) = event_callback {
+                agent = agent.with_event_callback(callback);
+            }
+            AgentWrapper::Code(agent)
+        }
+        AgentType::Planning => {
+            let mut agent = PlanningAgent::new(
+                model,
+                tools,
+                None,
+                None,
+                Some("CLI Agent"),
+                None,
+                None,
+                None,
+                None,
+            )?;
+            if let Some(callback) = event_callback {
+                agent = agent.with_event_callback(callback);
+            }
+            AgentWrapper::Planning(agent)
+        }
     };
 
     let mut file: File = File::create("logs.txt")?;
 
+    // Whether the next task continues the conversation, and how many of its logs are already written
+    let mut in_conversation = false;
+    let mut written_logs = 0;
     loop {
-        print!("{}", "User: ".yellow().bold());
-        io::stdout().flush()?;
+        if !args.quiet {
+            print!("{}", "User: ".yellow().bold());
+            io::stdout().flush()?;
+        }
 
         let mut task = String::new();
-        io::stdin().read_line(&mut task)?;
+        if io::stdin().read_line(&mut task)? == 0 {
+            // End of the input
+            break;
+        }
         let task = task.trim();
 
-        // Exit if user enters empty line or Ctrl+D
+        // Ask again after an empty line
         if task.is_empty() {
-            println!("Enter a task to execute");
+            if !args.quiet {
+                println!("Enter a task to execute");
+            }
             continue;
         }
         if task == "exit" {
             break;
         }
+        if task == "/reset" {
+            in_conversation = false;
+            if !args.quiet {
+                println!("The conversation is cleared");
+            }
+            continue;
+        }
 
-        // Run the agent with the task from stdin
-        let _result = agent.run(task, args.stream, true)?;
-        // Get the last log entry and serialize it in a controlled way
+        // Run the agent with the task from stdin. A task continuing the conversation keeps the memory,
+        // with its system prompt, but gets all the steps of the agent
+        let reset = !in_conversation;
+        if !reset {
+            agent.reset_step_number();
+        }
+        let result = agent.run(task, args.stream, reset)?;
+        in_conversation = args.interactive_memory;
+        if args.quiet {
+            println!("{}", result);
+        }
 
+        // Write the logs of this task, the earlier ones of the conversation are in the file already
+        if reset {
+            written_logs = 0;
+        }
         let logs = agent.get_logs_mut();
-        for log in logs {
+        for log in logs.iter().skip(written_logs) {
             // Serialize to JSON with pretty printing
             serde_json::to_writer_pretty(&mut file, &log)?;
         }
+        written_logs = logs.len();
+    }
+    if let Some(sandbox) = sandbox {
+        if args.keep_sandbox {
+            let path = sandbox.persist();
+            if !args.quiet {
+                println!("Sandbox kept at {}", path.display());
+            }
+        }
     }
     // Successful execution of the CLI
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_resolve_model_type() {
+        let resolve = |vars: &[&str]| {
+            resolve_model_type(|name| vars.contains(&name).then(|| "key".to_string()))
+        };
+        assert_eq!(
+            resolve(&["ANTHROPIC_API_KEY", "OPENAI_API_KEY"]),
+            ModelType::OpenAI
+        );
+        assert_eq!(resolve(&["ANTHROPIC_API_KEY"]), ModelType::Anthropic);
+        assert_eq!(resolve(&[]), ModelType::Ollama);
+    }
+}
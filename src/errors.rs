@@ -8,6 +8,12 @@ pub enum AgentError {
     Execution(String),
     MaxSteps(String),
     Generation(String),
+    /// The request did not fit in the context window of the model.
+    ContextLengthExceeded(String),
+    /// The model request failed but may succeed when it is sent again: it could not be sent, e.g. the
+    /// connection failed or timed out, or the backend answered with a rate limit (HTTP 429) or a server
+    /// error (HTTP 5xx).
+    Unavailable(String),
 }
 
 impl std::error::Error for AgentError {}
@@ -19,8 +25,35 @@ impl AgentError {
             Self::Execution(msg) => msg,
             Self::MaxSteps(msg) => msg,
             Self::Generation(msg) => msg,
+            Self::ContextLengthExceeded(msg) => msg,
+            Self::Unavailable(msg) => msg,
         }
     }
+
+    /// A generation error, or [`AgentError::ContextLengthExceeded`] when the message of the backend says
+    /// the request was too long for the model.
+    pub fn from_generation(message: String) -> Self {
+        const PATTERNS: [&str; 7] = [
+            "context_length_exceeded",
+            "context length",
+            "context window",
+            "maximum context",
+            "prompt is too long",
+            "input is too long",
+            "too many tokens",
+        ];
+        let lowercase = message.to_lowercase();
+        if PATTERNS.iter().any(|pattern| lowercase.contains(pattern)) {
+            Self::ContextLengthExceeded(message)
+        } else {
+            Self::Generation(message)
+        }
+    }
+
+    /// Whether a failed model request may succeed when it is sent again. See [`AgentError::Unavailable`].
+    pub fn is_retryable(&self) -> bool {
+        matches!(self, Self::Unavailable(_))
+    }
 }
 impl std::fmt::Display for AgentError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
@@ -29,6 +62,8 @@ impl std::fmt::Display for AgentError {
             Self::Execution(msg) => write!(f, "{}", msg),
             Self::MaxSteps(msg) => write!(f, "{}", msg),
             Self::Generation(msg) => write!(f, "{}", msg),
+            Self::ContextLengthExceeded(msg) => write!(f, "{}", msg),
+            Self::Unavailable(msg) => write!(f, "{}", msg),
         }
     }
 }
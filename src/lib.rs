@@ -46,12 +46,16 @@ pub mod errors;
 
 #[cfg(feature = "code-agent")]
 pub mod local_python_interpreter;
-pub(crate) mod logger;
+pub mod logger;
 pub mod models;
 pub mod prompts;
 pub mod tools;
 pub mod parallel;
 pub mod sandbox;
+#[cfg(test)]
+mod test_server;
+#[cfg(test)]
+mod test_tools;
 
 pub use agents::*;
 pub use sandbox::Sandbox;
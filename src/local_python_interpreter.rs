@@ -2,17 +2,17 @@ use crate::errors::InterpreterError;
 use crate::tools::AnyTool;
 use anyhow::Result;
 use pyo3::prelude::*;
-use pyo3::types::{PyDict, PyModule, PyTuple};
+use pyo3::types::{PyDict, PyModule, PySequence, PyTuple};
 use rustpython_parser::{
     ast::{
         self,
         bigint::{BigInt, Sign},
-        Constant, Expr, Operator, Stmt, UnaryOp,
+        CmpOp, Constant, Expr, Operator, Stmt, UnaryOp,
     },
     Parse,
 };
 use serde_json::{self, json};
-use std::{any::Any, collections::HashMap};
+use std::{any::Any, collections::HashMap, sync::Arc};
 
 pub fn get_base_python_tools() -> HashMap<&'static str, &'static str> {
     [
@@ -74,6 +74,145 @@ pub fn get_base_python_tools() -> HashMap<&'static str, &'static str> {
     .collect()
 }
 
+/// The modules that can be imported by default. This is the list promised to the model in the
+/// description of the python interpreter tool.
+pub const BASE_AUTHORIZED_IMPORTS: &[&str] = &[
+    "collections",
+    "datetime",
+    "itertools",
+    "math",
+    "queue",
+    "random",
+    "re",
+    "stat",
+    "statistics",
+    "time",
+    "unicodedata",
+];
+
+/// The state key holding the `Vec<String>` of modules that can be imported, when it differs from
+/// [`BASE_AUTHORIZED_IMPORTS`].
+const AUTHORIZED_IMPORTS_KEY: &str = "__authorized_imports__";
+
+/// The default maximum number of statements and expressions evaluated by one execution.
+pub const DEFAULT_MAX_OPERATIONS: usize = 10_000_000;
+
+/// The state key holding the [`OperationCounter`] of the current execution.
+const OPERATIONS_KEY: &str = "__operations__";
+
+/// Counts the statements and expressions evaluated by one execution, so that runaway loops end with
+/// [`InterpreterError::OperationLimitExceeded`] instead of hanging the agent.
+struct OperationCounter {
+    count: usize,
+    limit: usize,
+}
+
+/// Start counting the operations of a new execution.
+fn reset_operation_counter(state: &mut HashMap<String, Box<dyn Any>>, limit: usize) {
+    state.insert(
+        OPERATIONS_KEY.to_string(),
+        Box::new(OperationCounter { count: 0, limit }),
+    );
+}
+
+/// Count one evaluated node, failing once the limit of the execution is exceeded.
+fn count_operation(state: &mut HashMap<String, Box<dyn Any>>) -> Result<(), InterpreterError> {
+    count_operations(state, 1)
+}
+
+/// Count `operations` operations at once, e.g. the values of a range built natively.
+fn count_operations(
+    state: &mut HashMap<String, Box<dyn Any>>,
+    operations: usize,
+) -> Result<(), InterpreterError> {
+    match state
+        .get_mut(OPERATIONS_KEY)
+        .and_then(|counter| counter.downcast_mut::<OperationCounter>())
+    {
+        Some(counter) => {
+            counter.count = counter.count.saturating_add(operations);
+            if counter.count > counter.limit {
+                return Err(InterpreterError::OperationLimitExceeded);
+            }
+        }
+        None => reset_operation_counter(state, DEFAULT_MAX_OPERATIONS),
+    }
+    Ok(())
+}
+
+/// Python truthiness of a value.
+fn is_truthy(value: &CustomConstant) -> Result<bool, InterpreterError> {
+    Ok(match value {
+        CustomConstant::Bool(b) => *b,
+        CustomConstant::None => false,
+        CustomConstant::Int(i) => *i != BigInt::from(0),
+        CustomConstant::Float(f) => *f != 0.0,
+        CustomConstant::Str(s) => !s.is_empty(),
+        CustomConstant::Tuple(items) => !items.is_empty(),
+        CustomConstant::Dict(keys, _) => !keys.is_empty(),
+        CustomConstant::PyObj(obj) => Python::with_gil(|py| obj.as_ref(py).is_true())?,
+        CustomConstant::Function(_) => true,
+    })
+}
+
+/// Check that `module` or one of its parent packages is in the import allowlist.
+fn check_import(
+    module: &str,
+    state: &HashMap<String, Box<dyn Any>>,
+) -> Result<(), InterpreterError> {
+    let is_allowed = |allowed: &str| {
+        module == allowed
+            || module
+                .strip_prefix(allowed)
+                .is_some_and(|rest| rest.starts_with('.'))
+    };
+    let allowed = match state
+        .get(AUTHORIZED_IMPORTS_KEY)
+        .and_then(|imports| imports.downcast_ref::<Vec<String>>())
+    {
+        Some(imports) => imports.iter().any(|allowed| is_allowed(allowed)),
+        None => BASE_AUTHORIZED_IMPORTS
+            .iter()
+            .any(|allowed| is_allowed(allowed)),
+    };
+    if allowed {
+        Ok(())
+    } else {
+        Err(InterpreterError::UnauthorizedImport(module.to_string()))
+    }
+}
+
+/// Get the attribute `name` of a Python object. Private attributes and modules outside the import
+/// allowlist are refused, so that the allowlist cannot be bypassed through the modules an allowed module
+/// imports itself, like `random._os`.
+fn get_attribute(
+    obj: &PyObject,
+    name: &str,
+    py: Python<'_>,
+    state: &HashMap<String, Box<dyn Any>>,
+) -> Result<PyObject, InterpreterError> {
+    if name.starts_with('_') {
+        return Err(InterpreterError::UnsupportedOperation(format!(
+            "access to the private attribute '{}'",
+            name
+        )));
+    }
+    let attribute = obj.getattr(py, name)?;
+    check_module_object(attribute.as_ref(py), state)?;
+    Ok(attribute)
+}
+
+/// Check that `obj`, if it is a module, is in the import allowlist.
+fn check_module_object(
+    obj: &PyAny,
+    state: &HashMap<String, Box<dyn Any>>,
+) -> Result<(), InterpreterError> {
+    match obj.downcast::<PyModule>() {
+        Ok(module) => check_import(module.name()?, state),
+        Err(_) => Ok(()),
+    }
+}
+
 impl From<PyErr> for InterpreterError {
     fn from(err: PyErr) -> Self {
         InterpreterError::RuntimeError(err.to_string())
@@ -86,9 +225,20 @@ pub enum CustomConstant {
     Float(f64),
     Str(String),
     Bool(bool),
+    /// Python's `None`.
+    None,
     Tuple(Vec<CustomConstant>),
     PyObj(PyObject),
     Dict(Vec<String>, Vec<CustomConstant>),
+    Function(Arc<Function>),
+}
+
+/// A function defined in the interpreted code. Only `lambda` expressions create functions for now.
+#[derive(Debug)]
+pub struct Function {
+    /// The parameter names, with the default value of the optional ones.
+    pub params: Vec<(String, Option<CustomConstant>)>,
+    pub body: Expr,
 }
 
 impl CustomConstant {
@@ -137,6 +287,8 @@ impl CustomConstant {
             }
             CustomConstant::PyObj(obj) => obj.to_string(),
             CustomConstant::Bool(b) => b.to_string(),
+            CustomConstant::None => "None".to_string(),
+            CustomConstant::Function(_) => "<function <lambda>>".to_string(),
         }
     }
     pub fn tuple(&self) -> Option<Vec<CustomConstant>> {
@@ -154,7 +306,9 @@ impl From<CustomConstant> for Constant {
             CustomConstant::Float(f) => Constant::Float(f),
             CustomConstant::Str(s) => Constant::Str(s),
             CustomConstant::Bool(b) => Constant::Bool(b),
+            CustomConstant::None => Constant::None,
             CustomConstant::PyObj(obj) => Constant::Str(obj.to_string()),
+            function @ CustomConstant::Function(_) => Constant::Str(function.str()),
             CustomConstant::Tuple(t) => {
                 let tuple_items = t
                     .iter()
@@ -176,6 +330,31 @@ impl From<CustomConstant> for Constant {
     }
 }
 
+/// JSON values as the interpreter represents them: arrays are lists and `null` is `None`.
+impl From<serde_json::Value> for CustomConstant {
+    fn from(value: serde_json::Value) -> Self {
+        match value {
+            serde_json::Value::Null => CustomConstant::None,
+            serde_json::Value::Bool(b) => CustomConstant::Bool(b),
+            serde_json::Value::Number(n) => match n.as_i64() {
+                Some(i) => CustomConstant::Int(BigInt::from(i)),
+                None => CustomConstant::Float(n.as_f64().unwrap_or(f64::NAN)),
+            },
+            serde_json::Value::String(s) => CustomConstant::Str(s),
+            serde_json::Value::Array(items) => {
+                CustomConstant::Tuple(items.into_iter().map(CustomConstant::from).collect())
+            }
+            serde_json::Value::Object(map) => {
+                let (keys, values) = map
+                    .into_iter()
+                    .map(|(key, value)| (key, CustomConstant::from(value)))
+                    .unzip();
+                CustomConstant::Dict(keys, values)
+            }
+        }
+    }
+}
+
 impl From<Constant> for CustomConstant {
     fn from(constant: Constant) -> Self {
         match constant {
@@ -183,7 +362,7 @@ impl From<Constant> for CustomConstant {
             Constant::Float(f) => CustomConstant::Float(f),
             Constant::Str(s) => CustomConstant::Str(s),
             Constant::Bool(b) => CustomConstant::Bool(b),
-            Constant::None => CustomConstant::Str("None".to_string()),
+            Constant::None => CustomConstant::None,
             Constant::Tuple(t) => {
                 CustomConstant::Tuple(t.iter().map(|c| c.clone().into()).collect())
             }
@@ -199,6 +378,7 @@ impl IntoPy<PyObject> for CustomConstant {
             CustomConstant::Float(f) => f.into_py(py),
             CustomConstant::Str(s) => s.into_py(py),
             CustomConstant::Bool(b) => b.into_py(py),
+            CustomConstant::None => py.None(),
             CustomConstant::Tuple(t) => {
                 let py_list = t
                     .iter()
@@ -207,6 +387,8 @@ impl IntoPy<PyObject> for CustomConstant {
                 py_list.into_py(py)
             }
             CustomConstant::PyObj(obj) => obj,
+            // Functions only exist in the interpreter, Python gets their name
+            function @ CustomConstant::Function(_) => function.str().into_py(py),
             CustomConstant::Dict(keys, values) => {
                 let dict = PyDict::new(py);
                 for (key, value) in keys.iter().zip(values.iter()) {
@@ -219,7 +401,9 @@ impl IntoPy<PyObject> for CustomConstant {
     }
 }
 
-type ToolFunction = Box<dyn Fn(Vec<Constant>) -> Result<CustomConstant, InterpreterError>>;
+type ToolFunction = Box<
+    dyn Fn(Vec<Constant>, HashMap<String, Constant>) -> Result<CustomConstant, InterpreterError>,
+>;
 type CustomToolFunction =
     Box<dyn Fn(Vec<Constant>, HashMap<String, String>) -> Result<CustomConstant, InterpreterError>>;
 
@@ -242,8 +426,8 @@ fn setup_custom_tools(tools: Vec<Box<dyn AnyTool>>) -> HashMap<String, CustomToo
                     for (key, value) in kwargs {
                         new_args.insert(key, value);
                     }
-                    match tool.forward_json(json!(new_args)) {
-                        Ok(results) => Ok(CustomConstant::Str(results)),
+                    match tool.forward_value(json!(new_args)) {
+                        Ok(results) => Ok(results.into()),
                         Err(e) => Ok(CustomConstant::Str(format!("Error: {}", e))),
                     }
                 },
@@ -258,7 +442,7 @@ pub fn setup_static_tools(
 ) -> HashMap<String, ToolFunction> {
     let mut tools = HashMap::new();
     let static_tools_clone = static_tools.clone();
-    let eval_py = move |func: &str, args: Vec<Constant>| {
+    let eval_py = move |func: &str, args: Vec<Constant>, kwargs: HashMap<String, Constant>| {
         Python::with_gil(|py| {
             let locals = PyDict::new(py);
 
@@ -266,35 +450,28 @@ pub fn setup_static_tools(
             let math = PyModule::import(py, "math")?;
             locals.set_item("math", math)?;
 
+            let mut arg_names = Vec::new();
             for (i, arg) in args.iter().enumerate() {
-                match arg {
-                    Constant::Float(f) => locals.set_item(format!("arg{}", i), f)?,
-                    Constant::Int(int) => {
-                        locals.set_item(format!("arg{}", i), convert_bigint_to_i64(int))?;
-                    }
-                    Constant::Str(s) => locals.set_item(format!("arg{}", i), s)?,
-                    Constant::Tuple(t) => {
-                        let py_list: Vec<f64> = t
-                            .iter()
-                            .map(|x| match x {
-                                Constant::Float(f) => *f,
-                                Constant::Int(i) => convert_bigint_to_f64(i),
-                                _ => 0.0,
-                            })
-                            .collect();
-                        locals.set_item(format!("arg{}", i), py_list)?
-                    }
-                    _ => locals.set_item(format!("arg{}", i), 0.0)?,
-                }
+                locals.set_item(format!("arg{}", i), constant_into_py(arg, py))?;
+                arg_names.push(format!("arg{}", i));
+            }
+            // Keyword names come from the parsed code, so they are valid identifiers
+            for (key, value) in kwargs.iter() {
+                locals.set_item(format!("kwarg_{}", key), constant_into_py(value, py))?;
+                arg_names.push(format!("{}=kwarg_{}", key, key));
             }
 
-            let arg_names: Vec<String> = (0..args.len()).map(|i| format!("arg{}", i)).collect();
             let func_path = static_tools.get(func).unwrap_or(&"builtins.float");
             let expr = format!("{}({})", func_path, arg_names.join(","));
 
             let result = py.eval(&expr, None, Some(locals))?;
-            // Handle different return types
-            if let Ok(float_val) = result.extract::<f64>() {
+            // Handle different return types. Ints convert to floats as well, and bools to ints, so the
+            // narrower types are tried first
+            if let Ok(bool_val) = result.extract::<bool>() {
+                Ok(CustomConstant::Bool(bool_val))
+            } else if let Ok(int_val) = result.extract::<i64>() {
+                Ok(CustomConstant::Int(BigInt::from(int_val)))
+            } else if let Ok(float_val) = result.extract::<f64>() {
                 Ok(CustomConstant::Float(float_val))
             } else if let Ok(list_val) = result.extract::<Vec<String>>() {
                 Ok(CustomConstant::Tuple(
@@ -302,10 +479,6 @@ pub fn setup_static_tools(
                 ))
             } else if let Ok(string_val) = result.extract::<String>() {
                 Ok(CustomConstant::Str(string_val))
-            } else if let Ok(bool_val) = result.extract::<bool>() {
-                Ok(CustomConstant::Bool(bool_val))
-            } else if let Ok(int_val) = result.extract::<i64>() {
-                Ok(CustomConstant::Int(BigInt::from(int_val)))
             } else {
                 Ok(CustomConstant::PyObj(result.into_py(py)))
             }
@@ -318,19 +491,36 @@ pub fn setup_static_tools(
         let eval_py = eval_py.clone(); // Clone the closure
         tools.insert(
             func.clone(),
-            Box::new(move |args| eval_py(&func, args)) as ToolFunction,
+            Box::new(move |args, kwargs| eval_py(&func, args, kwargs)) as ToolFunction,
         );
     }
 
     tools
 }
 
+fn constant_into_py(constant: &Constant, py: Python<'_>) -> PyObject {
+    match constant {
+        Constant::None => py.None(),
+        Constant::Float(f) => f.into_py(py),
+        Constant::Int(i) => convert_bigint_to_i64(i).into_py(py),
+        Constant::Str(s) => s.into_py(py),
+        Constant::Bool(b) => b.into_py(py),
+        Constant::Tuple(t) => t
+            .iter()
+            .map(|c| constant_into_py(c, py))
+            .collect::<Vec<PyObject>>()
+            .into_py(py),
+        _ => py.None(),
+    }
+}
+
 fn evaluate_stmt(
     node: &ast::Stmt,
     state: &mut HashMap<String, Box<dyn Any>>,
     static_tools: &HashMap<String, StaticTool>,
     custom_tools: &HashMap<String, CustomToolFunction>,
 ) -> Result<CustomConstant, InterpreterError> {
+    count_operation(state)?;
     match node {
         Stmt::FunctionDef(func) => Ok(CustomConstant::Str(format!("Function: {:?}", func.name))),
         Stmt::Expr(expr) => {
@@ -370,20 +560,11 @@ fn evaluate_stmt(
                     ))
                 }
             };
-            // Get the target variable name
-            let target_name = match &*for_stmt.target {
-                ast::Expr::Name(name) => name.id.to_string(),
-                _ => {
-                    return Err(InterpreterError::RuntimeError(
-                        "Expected name as loop target".to_string(),
-                    ))
-                }
-            };
             let mut for_loop_result = CustomConstant::Str(String::new());
             // Iterate over the values and execute the body for each iteration
             for value in values {
-                // Update the loop variable in the state
-                state.insert(target_name.clone(), Box::new(value));
+                // Update the loop variable(s) in the state
+                bind_loop_target(&for_stmt.target, value, state)?;
 
                 // Execute each statement in the loop body
                 for stmt in &for_stmt.body {
@@ -392,24 +573,68 @@ fn evaluate_stmt(
             }
             Ok(for_loop_result)
         }
+        Stmt::While(while_stmt) => {
+            let mut while_loop_result = CustomConstant::Str(String::new());
+            while is_truthy(&evaluate_expr(
+                &while_stmt.test,
+                state,
+                static_tools,
+                custom_tools,
+            )?)? {
+                for stmt in &while_stmt.body {
+                    while_loop_result = evaluate_stmt(stmt, state, static_tools, custom_tools)?;
+                }
+            }
+            for stmt in &while_stmt.orelse {
+                while_loop_result = evaluate_stmt(stmt, state, static_tools, custom_tools)?;
+            }
+            Ok(while_loop_result)
+        }
+        Stmt::If(if_stmt) => {
+            let branch = if is_truthy(&evaluate_expr(
+                &if_stmt.test,
+                state,
+                static_tools,
+                custom_tools,
+            )?)? {
+                &if_stmt.body
+            } else {
+                &if_stmt.orelse
+            };
+            let mut if_result = CustomConstant::Str(String::new());
+            for stmt in branch {
+                if_result = evaluate_stmt(stmt, state, static_tools, custom_tools)?;
+            }
+            Ok(if_result)
+        }
+        Stmt::Assert(assert) => {
+            let test = evaluate_expr(&assert.test, state, static_tools, custom_tools)?;
+            if is_truthy(&test)? {
+                return Ok(CustomConstant::Str(String::new()));
+            }
+            // The message is only evaluated when the assertion fails, like in Python
+            let message = match &assert.msg {
+                Some(msg) => format!(
+                    "AssertionError: {}",
+                    evaluate_expr(msg, state, static_tools, custom_tools)?.str()
+                ),
+                None => "AssertionError".to_string(),
+            };
+            Err(InterpreterError::RuntimeError(message))
+        }
+        Stmt::Pass(_) => Ok(CustomConstant::Str(String::new())),
 
         Stmt::Assign(assign) => {
+            // `a = b = f()` calls `f` once and binds the result to every target
+            let value = evaluate_expr(&assign.value, state, static_tools, custom_tools)?;
             for target in assign.targets.iter() {
-                // let target = evaluate_expr(&Box::new(target.clone()), state, static_tools)?;
                 match target {
                     ast::Expr::Name(name) => {
-                        let value =
-                            evaluate_expr(&assign.value, state, static_tools, custom_tools)?;
-                        state.insert(name.id.to_string(), Box::new(value));
+                        state.insert(name.id.to_string(), Box::new(value.clone()));
                     }
                     ast::Expr::Tuple(target_names) => {
-                        let value =
-                            evaluate_expr(&assign.value, state, static_tools, custom_tools)?;
-                        let values = value.tuple().ok_or_else(|| {
-                            InterpreterError::RuntimeError(
-                                "Tuple unpacking failed. Expected values of type tuple".to_string(),
-                            )
-                        })?;
+                        let from_tool = is_tool_call(&assign.value, custom_tools);
+                        let values = unpacked_values(&value, from_tool)?;
                         if target_names.elts.len() != values.len() {
                             return Err(InterpreterError::RuntimeError(format!(
                                 "Tuple unpacking failed. Expected {} values, got {}",
@@ -432,6 +657,67 @@ fn evaluate_stmt(
             Ok(CustomConstant::Str(String::new()))
         }
 
+        Stmt::Import(import) => {
+            for alias in &import.names {
+                let module = alias.name.as_str();
+                check_import(module, state)?;
+                // `import a.b` binds `a`, while `import a.b as c` binds the submodule to `c`
+                let (name, value) = Python::with_gil(|py| -> PyResult<(String, PyObject)> {
+                    let imported = PyModule::import(py, module)?;
+                    Ok(match &alias.asname {
+                        Some(asname) => (asname.to_string(), imported.into_py(py)),
+                        None => {
+                            let top_level = module.split('.').next().unwrap_or(module);
+                            (
+                                top_level.to_string(),
+                                PyModule::import(py, top_level)?.into_py(py),
+                            )
+                        }
+                    })
+                })?;
+                state.insert(name, Box::new(CustomConstant::PyObj(value)));
+            }
+            Ok(CustomConstant::Str(String::new()))
+        }
+        Stmt::ImportFrom(import_from) => {
+            let is_relative = import_from
+                .level
+                .as_ref()
+                .is_some_and(|level| level.to_u32() > 0);
+            let module = match &import_from.module {
+                Some(module) if !is_relative => module.as_str(),
+                _ => {
+                    return Err(InterpreterError::UnauthorizedImport(
+                        "relative imports".to_string(),
+                    ))
+                }
+            };
+            check_import(module, state)?;
+            for alias in &import_from.names {
+                if alias.name.as_str() == "*" {
+                    return Err(InterpreterError::UnsupportedOperation(format!(
+                        "from {} import *",
+                        module
+                    )));
+                }
+                let value = Python::with_gil(|py| {
+                    let imported = PyModule::import(py, module)?.into_py(py);
+                    let value = get_attribute(&imported, alias.name.as_str(), py, state)?;
+                    extract_constant_from_pyobject(value.as_ref(py), py)
+                })?;
+                let name = alias.asname.as_ref().unwrap_or(&alias.name).to_string();
+                state.insert(name, Box::new(value));
+            }
+            Ok(CustomConstant::Str(String::new()))
+        }
+
+        Stmt::Delete(delete) => {
+            for target in &delete.targets {
+                delete_target(target, state, static_tools, custom_tools)?;
+            }
+            Ok(CustomConstant::Str(String::new()))
+        }
+
         _ => Err(InterpreterError::RuntimeError(format!(
             "Unsupported statement {:?}",
             node
@@ -439,6 +725,685 @@ fn evaluate_stmt(
     }
 }
 
+/// Delete the target of a `del` statement: a name, or an item or a slice of the list or dict bound to a
+/// name. Tuples of targets are deleted from left to right.
+fn delete_target(
+    target: &ast::Expr,
+    state: &mut HashMap<String, Box<dyn Any>>,
+    static_tools: &HashMap<String, StaticTool>,
+    custom_tools: &HashMap<String, CustomToolFunction>,
+) -> Result<(), InterpreterError> {
+    let subscript = match target {
+        ast::Expr::Name(name) => {
+            return match state.remove(name.id.as_str()) {
+                Some(_) => Ok(()),
+                None => Err(InterpreterError::RuntimeError(format!(
+                    "NameError: name '{}' is not defined",
+                    name.id
+                ))),
+            };
+        }
+        ast::Expr::Tuple(ast::ExprTuple { elts, .. })
+        | ast::Expr::List(ast::ExprList { elts, .. }) => {
+            for target in elts {
+                delete_target(target, state, static_tools, custom_tools)?;
+            }
+            return Ok(());
+        }
+        ast::Expr::Subscript(subscript) => subscript,
+        _ => {
+            return Err(InterpreterError::UnsupportedOperation(
+                "del of a target other than a name or a subscript".to_string(),
+            ))
+        }
+    };
+    let ast::Expr::Name(name) = &*subscript.value else {
+        return Err(InterpreterError::UnsupportedOperation(
+            "del of an item of a value not bound to a name".to_string(),
+        ));
+    };
+
+    let mut value = evaluate_expr(&subscript.value, state, static_tools, custom_tools)?;
+    match (&mut value, &*subscript.slice) {
+        (CustomConstant::Tuple(items), ast::Expr::Slice(slice)) => {
+            let start = slice_bound(&slice.lower, "start", state, static_tools, custom_tools)?;
+            let stop = slice_bound(&slice.upper, "stop", state, static_tools, custom_tools)?;
+            let step = slice_bound(&slice.step, "step", state, static_tools, custom_tools)?;
+            let deleted = slice_indices(items.len(), start, stop, step)?;
+            let mut index = 0;
+            items.retain(|_| {
+                index += 1;
+                !deleted.contains(&(index - 1))
+            });
+        }
+        (value, ast::Expr::Slice(_)) => {
+            return Err(InterpreterError::RuntimeError(format!(
+                "TypeError: '{}' does not support item deletion",
+                value.str()
+            )))
+        }
+        (value, key) => {
+            let key = evaluate_expr(key, state, static_tools, custom_tools)?;
+            match (value, key) {
+                (CustomConstant::Tuple(items), CustomConstant::Int(index)) => {
+                    let index = normalize_index(convert_bigint_to_i64(&index), items.len())
+                        .ok_or_else(|| {
+                            InterpreterError::RuntimeError(
+                                "IndexError: list assignment index out of range".to_string(),
+                            )
+                        })?;
+                    items.remove(index);
+                }
+                (CustomConstant::Dict(keys, values), CustomConstant::Str(key)) => {
+                    let position = keys.iter().position(|k| *k == key).ok_or_else(|| {
+                        InterpreterError::RuntimeError(format!("KeyError: '{}'", key))
+                    })?;
+                    keys.remove(position);
+                    values.remove(position);
+                }
+                (value, _) => {
+                    return Err(InterpreterError::RuntimeError(format!(
+                        "TypeError: '{}' does not support item deletion",
+                        value.str()
+                    )))
+                }
+            }
+        }
+    }
+    state.insert(name.id.to_string(), Box::new(value));
+    Ok(())
+}
+
+/// Whether `expr` calls one of the tools, like `f()` when `f` is a tool.
+fn is_tool_call(expr: &ast::Expr, custom_tools: &HashMap<String, CustomToolFunction>) -> bool {
+    match expr {
+        ast::Expr::Call(call) => match &*call.func {
+            ast::Expr::Name(name) => custom_tools.contains_key(name.id.as_str()),
+            _ => false,
+        },
+        _ => false,
+    }
+}
+
+/// The values unpacked into several targets, like in `a, b = f()`. Tools without structured output
+/// return strings, so the string returned by a tool call (`from_tool`) holding a JSON array is unpacked
+/// as a list. Other strings cannot be unpacked.
+fn unpacked_values(
+    value: &CustomConstant,
+    from_tool: bool,
+) -> Result<Vec<CustomConstant>, InterpreterError> {
+    let values = match value {
+        CustomConstant::Str(text) if from_tool => match serde_json::from_str(text.trim()) {
+            Ok(serde_json::Value::Array(items)) => {
+                Some(items.into_iter().map(CustomConstant::from).collect())
+            }
+            _ => None,
+        },
+        value => value.tuple(),
+    };
+    values.ok_or_else(|| {
+        InterpreterError::RuntimeError(
+            "Tuple unpacking failed. Expected values of type tuple".to_string(),
+        )
+    })
+}
+
+/// Bind a loop value to the loop target, unpacking tuples for targets like `for i, x in ...`.
+fn bind_loop_target(
+    target: &ast::Expr,
+    value: CustomConstant,
+    state: &mut HashMap<String, Box<dyn Any>>,
+) -> Result<(), InterpreterError> {
+    let targets = match target {
+        ast::Expr::Name(name) => {
+            state.insert(name.id.to_string(), Box::new(value));
+            return Ok(());
+        }
+        ast::Expr::Tuple(tuple) => &tuple.elts,
+        ast::Expr::List(list) => &list.elts,
+        _ => {
+            return Err(InterpreterError::RuntimeError(
+                "Expected name as loop target".to_string(),
+            ))
+        }
+    };
+    let values = unpacked_values(&value, false)?;
+    if targets.len() != values.len() {
+        return Err(InterpreterError::RuntimeError(format!(
+            "Tuple unpacking failed. Expected {} values, got {}",
+            targets.len(),
+            values.len()
+        )));
+    }
+    for (target, value) in targets.iter().zip(values) {
+        bind_loop_target(target, value, state)?;
+    }
+    Ok(())
+}
+
+/// Evaluate `range` natively when its arguments are ints, see [`evaluate_native_builtin`]. The values are
+/// counted as operations before they are built, so a huge range fails with
+/// [`InterpreterError::OperationLimitExceeded`] instead of taking all the memory.
+fn evaluate_native_range(
+    args: &[CustomConstant],
+    state: &mut HashMap<String, Box<dyn Any>>,
+) -> Result<Option<CustomConstant>, InterpreterError> {
+    // Larger ints are left to the Python builtin
+    let int = |value: &CustomConstant| match value {
+        CustomConstant::Int(i) => i64::try_from(i).ok(),
+        _ => None,
+    };
+    let bounds = match args {
+        [stop] => int(stop).map(|stop| (0, stop, 1)),
+        [start, stop] => int(start)
+            .zip(int(stop))
+            .map(|(start, stop)| (start, stop, 1)),
+        [start, stop, step] => int(start)
+            .zip(int(stop))
+            .zip(int(step))
+            .map(|((start, stop), step)| (start, stop, step)),
+        _ => None,
+    };
+    let Some((start, stop, step)) = bounds.filter(|(_, _, step)| *step != 0) else {
+        return Ok(None);
+    };
+    let (start_wide, stop_wide, step_wide) = (start as i128, stop as i128, step as i128);
+    let len = if step > 0 {
+        (stop_wide - start_wide + step_wide - 1) / step_wide
+    } else {
+        (start_wide - stop_wide - step_wide - 1) / -step_wide
+    };
+    count_operations(state, usize::try_from(len.max(0)).unwrap_or(usize::MAX))?;
+
+    let mut values = Vec::new();
+    let mut next = Some(start);
+    while let Some(i) = next.filter(|&i| (step > 0 && i < stop) || (step < 0 && i > stop)) {
+        values.push(CustomConstant::Int(BigInt::from(i)));
+        next = i.checked_add(step);
+    }
+    Ok(Some(CustomConstant::Tuple(values)))
+}
+
+/// Evaluate `enumerate`, `zip` and `list` natively when their arguments are plain ints, lists and
+/// strings, to avoid a round trip through Python. Returns `None` for anything else so the call falls
+/// back to the Python builtin.
+fn evaluate_native_builtin(
+    func: &str,
+    args: &[CustomConstant],
+    keywords: &HashMap<String, CustomConstant>,
+) -> Option<CustomConstant> {
+    fn int(value: &CustomConstant) -> Option<i64> {
+        match value {
+            CustomConstant::Int(i) => Some(convert_bigint_to_i64(i)),
+            _ => None,
+        }
+    }
+    fn items(value: &CustomConstant) -> Option<Vec<CustomConstant>> {
+        match value {
+            CustomConstant::Tuple(items) => Some(items.clone()),
+            CustomConstant::Str(s) => Some(
+                s.chars()
+                    .map(|c| CustomConstant::Str(c.to_string()))
+                    .collect(),
+            ),
+            _ => None,
+        }
+    }
+
+    match func {
+        "enumerate" => {
+            let (iterable, start) = match (args, keywords.get("start")) {
+                ([iterable], None) if keywords.is_empty() => (iterable, 0),
+                ([iterable], Some(start)) if keywords.len() == 1 => (iterable, int(start)?),
+                ([iterable, start], None) if keywords.is_empty() => (iterable, int(start)?),
+                _ => return None,
+            };
+            Some(CustomConstant::Tuple(
+                items(iterable)?
+                    .into_iter()
+                    .enumerate()
+                    .map(|(i, item)| {
+                        CustomConstant::Tuple(vec![
+                            CustomConstant::Int(BigInt::from(start + i as i64)),
+                            item,
+                        ])
+                    })
+                    .collect(),
+            ))
+        }
+        "list" if keywords.is_empty() => match args {
+            [] => Some(CustomConstant::Tuple(Vec::new())),
+            [iterable] => Some(CustomConstant::Tuple(items(iterable)?)),
+            _ => None,
+        },
+        "zip" if keywords.is_empty() => {
+            let iterables = args.iter().map(items).collect::<Option<Vec<_>>>()?;
+            let len = iterables.iter().map(|items| items.len()).min().unwrap_or(0);
+            Some(CustomConstant::Tuple(
+                (0..len)
+                    .map(|i| {
+                        CustomConstant::Tuple(
+                            iterables.iter().map(|items| items[i].clone()).collect(),
+                        )
+                    })
+                    .collect(),
+            ))
+        }
+        _ => None,
+    }
+}
+
+/// Python equality between two values. Ints and floats compare numerically, lists and dicts element-wise.
+fn constants_equal(left: &CustomConstant, right: &CustomConstant) -> bool {
+    match (left, right) {
+        (CustomConstant::Int(a), CustomConstant::Int(b)) => a == b,
+        (CustomConstant::Int(a), CustomConstant::Float(b)) => convert_bigint_to_f64(a) == *b,
+        (CustomConstant::Float(a), CustomConstant::Int(b)) => *a == convert_bigint_to_f64(b),
+        (CustomConstant::Float(a), CustomConstant::Float(b)) => a == b,
+        (CustomConstant::Str(a), CustomConstant::Str(b)) => a == b,
+        (CustomConstant::Bool(a), CustomConstant::Bool(b)) => a == b,
+        (CustomConstant::None, _) | (_, CustomConstant::None) => is_none(left) && is_none(right),
+        (CustomConstant::Tuple(a), CustomConstant::Tuple(b)) => {
+            a.len() == b.len() && a.iter().zip(b).all(|(a, b)| constants_equal(a, b))
+        }
+        (CustomConstant::Dict(keys_a, values_a), CustomConstant::Dict(keys_b, values_b)) => {
+            keys_a.len() == keys_b.len()
+                && keys_a.iter().zip(values_a).all(|(key, value)| {
+                    keys_b
+                        .iter()
+                        .position(|k| k == key)
+                        .is_some_and(|i| constants_equal(value, &values_b[i]))
+                })
+        }
+        (CustomConstant::PyObj(a), CustomConstant::PyObj(b)) => a.to_string() == b.to_string(),
+        (CustomConstant::Function(a), CustomConstant::Function(b)) => Arc::ptr_eq(a, b),
+        _ => false,
+    }
+}
+
+/// Order two values like Python's `<`: numbers numerically, strings lexicographically and lists
+/// element by element.
+fn compare_values(
+    left: &CustomConstant,
+    right: &CustomConstant,
+) -> Result<std::cmp::Ordering, InterpreterError> {
+    let number = |value: &CustomConstant| match value {
+        CustomConstant::Int(i) => Some(convert_bigint_to_f64(i)),
+        CustomConstant::Float(f) => Some(*f),
+        CustomConstant::Bool(b) => Some(*b as u8 as f64),
+        _ => None,
+    };
+    match (left, right) {
+        (CustomConstant::Str(a), CustomConstant::Str(b)) => Ok(a.cmp(b)),
+        (CustomConstant::Tuple(a), CustomConstant::Tuple(b)) => {
+            for (a, b) in a.iter().zip(b) {
+                let ordering = compare_values(a, b)?;
+                if ordering.is_ne() {
+                    return Ok(ordering);
+                }
+            }
+            Ok(a.len().cmp(&b.len()))
+        }
+        _ => match (number(left), number(right)) {
+            (Some(a), Some(b)) => a.partial_cmp(&b).ok_or_else(|| {
+                InterpreterError::RuntimeError("Cannot compare NaN values".to_string())
+            }),
+            _ => Err(InterpreterError::RuntimeError(format!(
+                "TypeError: Cannot compare {} and {}",
+                left.str(),
+                right.str()
+            ))),
+        },
+    }
+}
+
+/// Check that any two of `values` can be compared, so they can be sorted: they are all numbers other than
+/// NaN, all strings, or all tuples whose elements at each position can be compared in turn.
+fn check_comparable(values: &[&CustomConstant]) -> Result<(), InterpreterError> {
+    let Some(first) = values.first() else {
+        return Ok(());
+    };
+    for value in values {
+        compare_values(first, value)?;
+    }
+    if let CustomConstant::Tuple(_) = first {
+        let longest = values
+            .iter()
+            .map(|value| match value {
+                CustomConstant::Tuple(items) => items.len(),
+                _ => 0,
+            })
+            .max()
+            .unwrap_or_default();
+        for position in 0..longest {
+            let elements = values
+                .iter()
+                .filter_map(|value| match value {
+                    CustomConstant::Tuple(items) => items.get(position),
+                    _ => None,
+                })
+                .collect::<Vec<_>>();
+            check_comparable(&elements)?;
+        }
+    }
+    Ok(())
+}
+
+/// Evaluate a single comparison such as `a < b` or `x in items`.
+fn evaluate_comparison(
+    op: &CmpOp,
+    left: &CustomConstant,
+    right: &CustomConstant,
+) -> Result<bool, InterpreterError> {
+    let ordering = || compare_values(left, right);
+    match op {
+        CmpOp::Eq => Ok(constants_equal(left, right)),
+        CmpOp::NotEq => Ok(!constants_equal(left, right)),
+        CmpOp::Lt => Ok(ordering()?.is_lt()),
+        CmpOp::LtE => Ok(ordering()?.is_le()),
+        CmpOp::Gt => Ok(ordering()?.is_gt()),
+        CmpOp::GtE => Ok(ordering()?.is_ge()),
+        CmpOp::In => evaluate_membership(left, right),
+        CmpOp::NotIn => evaluate_membership(left, right).map(|found| !found),
+        CmpOp::Is => is_same(left, right),
+        CmpOp::IsNot => is_same(left, right).map(|same| !same),
+    }
+}
+
+/// Whether a value is `None`, including a `None` returned by Python.
+fn is_none(value: &CustomConstant) -> bool {
+    match value {
+        CustomConstant::None => true,
+        CustomConstant::PyObj(obj) => Python::with_gil(|py| obj.is_none(py)),
+        _ => false,
+    }
+}
+
+/// `left is right`. The identity of values is only tracked for `None`, booleans, functions and Python
+/// objects, comparing the identity of other values is an error.
+fn is_same(left: &CustomConstant, right: &CustomConstant) -> Result<bool, InterpreterError> {
+    match (left, right) {
+        _ if is_none(left) || is_none(right) => Ok(is_none(left) && is_none(right)),
+        (CustomConstant::Bool(a), CustomConstant::Bool(b)) => Ok(a == b),
+        (CustomConstant::Function(a), CustomConstant::Function(b)) => Ok(Arc::ptr_eq(a, b)),
+        (CustomConstant::PyObj(a), CustomConstant::PyObj(b)) => Ok(a.is(b)),
+        _ => Err(InterpreterError::UnsupportedOperation(format!(
+            "'is' comparison of {} and {}, use '==' instead",
+            left.str(),
+            right.str()
+        ))),
+    }
+}
+
+/// `needle in haystack`: substring for strings, element equality for lists and key presence for dicts.
+fn evaluate_membership(
+    needle: &CustomConstant,
+    haystack: &CustomConstant,
+) -> Result<bool, InterpreterError> {
+    match (needle, haystack) {
+        (CustomConstant::Str(needle), CustomConstant::Str(haystack)) => {
+            Ok(haystack.contains(needle.as_str()))
+        }
+        (_, CustomConstant::Str(_)) => Err(InterpreterError::RuntimeError(
+            "'in <string>' requires string as left operand".to_string(),
+        )),
+        (needle, CustomConstant::Tuple(items)) => {
+            Ok(items.iter().any(|item| constants_equal(needle, item)))
+        }
+        (CustomConstant::Str(key), CustomConstant::Dict(keys, _)) => Ok(keys.contains(key)),
+        (_, CustomConstant::Dict(_, _)) => Ok(false),
+        (needle, CustomConstant::PyObj(obj)) => Python::with_gil(|py| {
+            let needle = needle.clone().into_py(py);
+            Ok(obj.as_ref(py).contains(needle)?)
+        }),
+        (_, haystack) => Err(InterpreterError::RuntimeError(format!(
+            "Argument of type {} is not iterable",
+            haystack.str()
+        ))),
+    }
+}
+
+/// Evaluate the common string methods natively, so their results are plain strings and lists instead of
+/// Python objects. Returns `None` for other methods or unusual arguments so they fall back to Python.
+fn evaluate_str_method(
+    string: &str,
+    method: &str,
+    args: &[CustomConstant],
+) -> Option<Result<CustomConstant, InterpreterError>> {
+    fn strings(values: Vec<&str>) -> CustomConstant {
+        CustomConstant::Tuple(
+            values
+                .into_iter()
+                .map(|s| CustomConstant::Str(s.to_string()))
+                .collect(),
+        )
+    }
+    fn chars(args: &[CustomConstant]) -> Option<Option<Vec<char>>> {
+        match args {
+            [] => Some(None),
+            [CustomConstant::Str(chars)] => Some(Some(chars.chars().collect())),
+            _ => None,
+        }
+    }
+    fn affixes(args: &[CustomConstant]) -> Option<Vec<String>> {
+        match args {
+            [CustomConstant::Str(affix)] => Some(vec![affix.clone()]),
+            [CustomConstant::Tuple(affixes)] => affixes
+                .iter()
+                .map(|affix| match affix {
+                    CustomConstant::Str(affix) => Some(affix.clone()),
+                    _ => None,
+                })
+                .collect(),
+            _ => None,
+        }
+    }
+
+    let result = match (method, args) {
+        ("split", []) => strings(string.split_whitespace().collect()),
+        ("split", [CustomConstant::Str(sep)]) | ("split", [CustomConstant::Str(sep), _]) => {
+            if sep.is_empty() {
+                return Some(Err(InterpreterError::RuntimeError(
+                    "ValueError: empty separator".to_string(),
+                )));
+            }
+            match args.get(1) {
+                None => strings(string.split(sep.as_str()).collect()),
+                Some(CustomConstant::Int(max_split)) => {
+                    let max_split = convert_bigint_to_i64(max_split);
+                    if max_split < 0 {
+                        strings(string.split(sep.as_str()).collect())
+                    } else {
+                        strings(
+                            string
+                                .splitn(max_split as usize + 1, sep.as_str())
+                                .collect(),
+                        )
+                    }
+                }
+                Some(_) => return None,
+            }
+        }
+        ("join", [CustomConstant::Tuple(items)]) => {
+            let items = items
+                .iter()
+                .map(|item| match item {
+                    CustomConstant::Str(item) => Ok(item.as_str()),
+                    _ => Err(InterpreterError::RuntimeError(format!(
+                        "TypeError: sequence item: expected str instance, found {}",
+                        item.str()
+                    ))),
+                })
+                .collect::<Result<Vec<_>, _>>();
+            match items {
+                Ok(items) => CustomConstant::Str(items.join(string)),
+                Err(e) => return Some(Err(e)),
+            }
+        }
+        ("replace", [CustomConstant::Str(from), CustomConstant::Str(to)]) => {
+            CustomConstant::Str(string.replace(from.as_str(), to))
+        }
+        (
+            "replace",
+            [CustomConstant::Str(from), CustomConstant::Str(to), CustomConstant::Int(count)],
+        ) => {
+            let count = convert_bigint_to_i64(count);
+            if count < 0 {
+                CustomConstant::Str(string.replace(from.as_str(), to))
+            } else {
+                CustomConstant::Str(string.replacen(from.as_str(), to, count as usize))
+            }
+        }
+        ("strip", _) | ("lstrip", _) | ("rstrip", _) => {
+            let chars = chars(args)?;
+            let matches = |c: char| match &chars {
+                Some(chars) => chars.contains(&c),
+                None => c.is_whitespace(),
+            };
+            CustomConstant::Str(
+                match method {
+                    "strip" => string.trim_matches(matches),
+                    "lstrip" => string.trim_start_matches(matches),
+                    _ => string.trim_end_matches(matches),
+                }
+                .to_string(),
+            )
+        }
+        ("lower", []) => CustomConstant::Str(string.to_lowercase()),
+        ("upper", []) => CustomConstant::Str(string.to_uppercase()),
+        ("startswith", _) => CustomConstant::Bool(
+            affixes(args)?
+                .iter()
+                .any(|prefix| string.starts_with(prefix.as_str())),
+        ),
+        ("endswith", _) => CustomConstant::Bool(
+            affixes(args)?
+                .iter()
+                .any(|suffix| string.ends_with(suffix.as_str())),
+        ),
+        ("format", _) => CustomConstant::Str(format_str(string, args)?),
+        _ => return None,
+    };
+    Some(Ok(result))
+}
+
+/// Substitute `{}` and `{0}` placeholders like `str.format`. Returns `None` for named fields, format
+/// specs and values whose Python representation differs from ours, which are left to Python.
+fn format_str(template: &str, args: &[CustomConstant]) -> Option<String> {
+    let mut result = String::new();
+    let mut chars = template.chars().peekable();
+    let mut next_index = 0;
+    while let Some(c) = chars.next() {
+        match c {
+            '{' if chars.peek() == Some(&'{') => {
+                chars.next();
+                result.push('{');
+            }
+            '}' if chars.peek() == Some(&'}') => {
+                chars.next();
+                result.push('}');
+            }
+            '{' => {
+                let mut field = String::new();
+                loop {
+                    match chars.next()? {
+                        '}' => break,
+                        c => field.push(c),
+                    }
+                }
+                let index = if field.is_empty() {
+                    next_index += 1;
+                    next_index - 1
+                } else {
+                    field.parse::<usize>().ok()?
+                };
+                match args.get(index)? {
+                    CustomConstant::Str(s) => result.push_str(s),
+                    CustomConstant::Int(i) => result.push_str(&i.to_string()),
+                    CustomConstant::Bool(true) => result.push_str("True"),
+                    CustomConstant::Bool(false) => result.push_str("False"),
+                    _ => return None,
+                }
+            }
+            '}' => return None,
+            c => result.push(c),
+        }
+    }
+    Some(result)
+}
+
+/// Format an f-string replacement field, applying the `!s`, `!r` and `!a` conversions and the format spec
+/// like Python's `format`.
+fn format_value(
+    value: CustomConstant,
+    conversion: ast::ConversionFlag,
+    format_spec: &str,
+) -> Result<String, InterpreterError> {
+    if matches!(conversion, ast::ConversionFlag::None) && format_spec.is_empty() {
+        return Ok(value.str());
+    }
+    Python::with_gil(|py| {
+        let builtins = PyModule::import(py, "builtins")?;
+        let value = match conversion {
+            ast::ConversionFlag::None => value.into_py(py),
+            ast::ConversionFlag::Str => value.str().into_py(py),
+            ast::ConversionFlag::Repr => builtins
+                .getattr("repr")?
+                .call1((value.into_py(py),))?
+                .into_py(py),
+            ast::ConversionFlag::Ascii => builtins
+                .getattr("ascii")?
+                .call1((value.into_py(py),))?
+                .into_py(py),
+        };
+        Ok(builtins
+            .getattr("format")?
+            .call1((value, format_spec))?
+            .extract::<String>()?)
+    })
+}
+
+/// Evaluate the common read-only dict methods natively. Going through Python would return views like
+/// `dict_items` that cannot be converted back, so `for k, v in d.items():` could not unpack them.
+/// Returns `None` for other methods so they fall back to Python.
+fn evaluate_dict_method(
+    keys: &[String],
+    values: &[CustomConstant],
+    method: &str,
+    args: &[CustomConstant],
+) -> Option<Result<CustomConstant, InterpreterError>> {
+    match (method, args) {
+        ("keys", []) => Some(Ok(CustomConstant::Tuple(
+            keys.iter().cloned().map(CustomConstant::Str).collect(),
+        ))),
+        ("values", []) => Some(Ok(CustomConstant::Tuple(values.to_vec()))),
+        ("items", []) => Some(Ok(CustomConstant::Tuple(
+            keys.iter()
+                .zip(values)
+                .map(|(key, value)| {
+                    CustomConstant::Tuple(vec![CustomConstant::Str(key.clone()), value.clone()])
+                })
+                .collect(),
+        ))),
+        ("get", [key]) | ("get", [key, _]) => {
+            let CustomConstant::Str(key) = key else {
+                return Some(Err(InterpreterError::RuntimeError(
+                    "Dictionary keys must be strings".to_string(),
+                )));
+            };
+            let value = match keys.iter().position(|k| k == key) {
+                Some(i) => values[i].clone(),
+                None => args
+                    .get(1)
+                    .cloned()
+                    .unwrap_or_else(|| Constant::None.into()),
+            };
+            Some(Ok(value))
+        }
+        _ => None,
+    }
+}
+
 fn evaluate_ast(
     ast: &ast::Suite,
     state: &mut HashMap<String, Box<dyn Any>>,
@@ -469,7 +1434,9 @@ fn convert_bigint_to_i64(i: &BigInt) -> i64 {
     }
 }
 
-type StaticTool = Box<dyn Fn(Vec<Constant>) -> Result<CustomConstant, InterpreterError>>;
+type StaticTool = Box<
+    dyn Fn(Vec<Constant>, HashMap<String, Constant>) -> Result<CustomConstant, InterpreterError>,
+>;
 type CustomTool =
     Box<dyn Fn(Vec<Constant>, HashMap<String, String>) -> Result<CustomConstant, InterpreterError>>;
 
@@ -479,6 +1446,7 @@ fn evaluate_expr(
     static_tools: &HashMap<String, StaticTool>,
     custom_tools: &HashMap<String, CustomTool>,
 ) -> Result<CustomConstant, InterpreterError> {
+    count_operation(state)?;
     match &expr {
         ast::Expr::Dict(dict) => {
             let keys = dict
@@ -534,11 +1502,7 @@ fn evaluate_expr(
             Ok(CustomConstant::Tuple(result))
         }
         ast::Expr::Call(call) => {
-            let args = call
-                .args
-                .iter()
-                .map(|e| evaluate_expr(&Box::new(e.clone()), state, static_tools, custom_tools))
-                .collect::<Result<Vec<CustomConstant>, InterpreterError>>()?;
+            let args = evaluate_call_args(&call.args, state, static_tools, custom_tools)?;
             let func = match &*call.func {
                 ast::Expr::Name(name) => name.id.to_string(),
                 ast::Expr::Attribute(attr) => {
@@ -550,10 +1514,22 @@ fn evaluate_expr(
                     )?;
 
                     let func_name = attr.attr.to_string();
+                    if let CustomConstant::Str(string) = &obj {
+                        if let Some(result) = evaluate_str_method(string, &func_name, &args) {
+                            return result;
+                        }
+                    }
+                    if let CustomConstant::Dict(keys, values) = &obj {
+                        if let Some(result) = evaluate_dict_method(keys, values, &func_name, &args)
+                        {
+                            return result;
+                        }
+                    }
                     let output =
                         Python::with_gil(|py| -> Result<CustomConstant, InterpreterError> {
                             let obj = obj.into_py(py);
-                            let func = obj.getattr(py, func_name.as_str())?;
+                            check_module_object(obj.as_ref(py), state)?;
+                            let func = get_attribute(&obj, func_name.as_str(), py, state)?;
                             let py_args = args
                                 .iter()
                                 .map(|a| match a {
@@ -565,6 +1541,7 @@ fn evaluate_expr(
                                 .collect::<Vec<PyObject>>();
                             let py_tuple = PyTuple::new(py, py_args);
                             let result = func.call1(py, py_tuple)?;
+                            check_module_object(result.as_ref(py), state)?;
 
                             // For methods that modify in place (like append), return the original object
                             if func_name == "append"
@@ -587,22 +1564,22 @@ fn evaluate_expr(
                 _ => panic!("Expected function name"),
             };
 
-            let keywords = call
-                .keywords
-                .iter()
-                .map(|k| {
-                    let value = evaluate_expr(
-                        &Box::new(k.value.clone()),
-                        state,
-                        static_tools,
-                        custom_tools,
-                    )?;
-                    Ok((k.arg.as_ref().unwrap().to_string(), value.str()))
-                })
-                .collect::<Result<HashMap<String, String>, InterpreterError>>()?;
+            if let Some(result) = evaluate_keyed_builtin(
+                &func,
+                &args,
+                &call.keywords,
+                state,
+                static_tools,
+                custom_tools,
+            )? {
+                return Ok(result);
+            }
+
+            let keywords =
+                evaluate_call_keywords(&func, &call.keywords, state, static_tools, custom_tools)?;
             if func == "final_answer" {
                 if let Some(answer) = keywords.get("answer") {
-                    return Err(InterpreterError::FinalAnswer(answer.to_string()));
+                    return Err(InterpreterError::FinalAnswer(answer.str()));
                 } else {
                     return Err(InterpreterError::FinalAnswer(
                         args.iter()
@@ -613,15 +1590,15 @@ fn evaluate_expr(
                 }
             }
             if func == "print" {
+                let line = args
+                    .iter()
+                    .map(|c| c.str())
+                    .collect::<Vec<String>>()
+                    .join(" ");
                 match state.get_mut("print_logs") {
                     Some(logs) => {
                         if let Some(logs) = logs.downcast_mut::<Vec<String>>() {
-                            logs.push(
-                                args.iter()
-                                    .map(|c| c.str())
-                                    .collect::<Vec<String>>()
-                                    .join(" "),
-                            );
+                            logs.push(line.clone());
                         } else {
                             return Err(InterpreterError::RuntimeError(
                                 "print_logs is not a list".to_string(),
@@ -629,35 +1606,12 @@ fn evaluate_expr(
                         }
                     }
                     None => {
-                        state.insert(
-                            "print_logs".to_string(),
-                            Box::new(args.iter().map(|c| c.str()).collect::<Vec<String>>()),
-                        );
+                        state.insert("print_logs".to_string(), Box::new(vec![line.clone()]));
                     }
                 }
-                return Ok(CustomConstant::Str(
-                    args.iter()
-                        .map(|c| c.str())
-                        .collect::<Vec<String>>()
-                        .join(" "),
-                ));
-            }
-            if static_tools.contains_key(&func) {
-                let result =
-                    static_tools[&func](args.iter().map(|c| Constant::from(c.clone())).collect());
-                result
-            } else if custom_tools.contains_key(&func) {
-                let result = custom_tools[&func](
-                    args.iter().map(|c| Constant::from(c.clone())).collect(),
-                    keywords,
-                );
-                result
-            } else {
-                Err(InterpreterError::RuntimeError(format!(
-                    "Function '{}' not found",
-                    func
-                )))
+                return Ok(CustomConstant::Str(line));
             }
+            call_function(&func, args, keywords, state, static_tools, custom_tools)
         }
         ast::Expr::BinOp(binop) => {
             let left_val_exp =
@@ -759,6 +1713,26 @@ fn evaluate_expr(
                 }
             }
         }
+        ast::Expr::Compare(compare) => {
+            // `a < b < c` is `a < b and b < c`, with `b` evaluated once and `c` only if `a < b`
+            let mut left = evaluate_expr(&compare.left, state, static_tools, custom_tools)?;
+            for (op, comparator) in compare.ops.iter().zip(&compare.comparators) {
+                let right = evaluate_expr(comparator, state, static_tools, custom_tools)?;
+                if !evaluate_comparison(op, &left, &right)? {
+                    return Ok(CustomConstant::Bool(false));
+                }
+                left = right;
+            }
+            Ok(CustomConstant::Bool(true))
+        }
+        ast::Expr::Attribute(attribute) => {
+            let value = evaluate_expr(&attribute.value, state, static_tools, custom_tools)?;
+            Python::with_gil(|py| {
+                let value = value.into_py(py);
+                let attr = get_attribute(&value, attribute.attr.as_str(), py, state)?;
+                extract_constant_from_pyobject(attr.as_ref(py), py)
+            })
+        }
         ast::Expr::Constant(constant) => match &constant.value {
             Constant::Int(i) => Ok(CustomConstant::Int(i.clone())),
             _ => Ok(constant.value.clone().into()),
@@ -786,6 +1760,32 @@ fn evaluate_expr(
                 )))
             }
         }
+        ast::Expr::Lambda(lambda) => {
+            let args = &lambda.args;
+            if args.vararg.is_some() || args.kwarg.is_some() || !args.kwonlyargs.is_empty() {
+                return Err(InterpreterError::UnsupportedOperation(
+                    "lambda with *args, **kwargs or keyword-only parameters".to_string(),
+                ));
+            }
+            // Defaults are evaluated once, when the lambda is defined
+            let params = args
+                .posonlyargs
+                .iter()
+                .chain(&args.args)
+                .map(|param| {
+                    let default = param
+                        .default
+                        .as_ref()
+                        .map(|default| evaluate_expr(default, state, static_tools, custom_tools))
+                        .transpose()?;
+                    Ok((param.def.arg.to_string(), default))
+                })
+                .collect::<Result<Vec<_>, InterpreterError>>()?;
+            Ok(CustomConstant::Function(Arc::new(Function {
+                params,
+                body: (*lambda.body).clone(),
+            })))
+        }
         ast::Expr::Tuple(tuple) => Ok(CustomConstant::Tuple(
             tuple
                 .elts
@@ -806,145 +1806,589 @@ fn evaluate_expr(
         )),
         ast::Expr::FormattedValue(formattedvalue) => {
             let result = evaluate_expr(&formattedvalue.value, state, static_tools, custom_tools)?;
+            // The spec is itself an f-string, e.g. `{x:.{digits}f}`
+            let format_spec = match &formattedvalue.format_spec {
+                Some(format_spec) => {
+                    evaluate_expr(format_spec, state, static_tools, custom_tools)?.str()
+                }
+                None => String::new(),
+            };
 
-            Ok(CustomConstant::Str(result.str()))
+            Ok(CustomConstant::Str(format_value(
+                result,
+                formattedvalue.conversion,
+                &format_spec,
+            )?))
         }
         ast::Expr::Subscript(subscript) => {
-            let result = Python::with_gil(|py| {
-                // Get the value being subscripted (e.g., the list/string)
-                let value = evaluate_expr(&subscript.value, state, static_tools, custom_tools)?;
-                let value_obj = value.into_py(py);
+            // Get the value being subscripted (e.g., the list/string)
+            let value = evaluate_expr(&subscript.value, state, static_tools, custom_tools)?;
+
+            // For slice operations like num[1:3:2]
+            if let ast::Expr::Slice(slice) = &*subscript.slice {
+                let start = slice_bound(&slice.lower, "start", state, static_tools, custom_tools)?;
+                let stop = slice_bound(&slice.upper, "stop", state, static_tools, custom_tools)?;
+                let step = slice_bound(&slice.step, "step", state, static_tools, custom_tools)?;
+                return slice_value(value, start, stop, step);
+            }
+
+            let slice = Constant::from(evaluate_expr(
+                &subscript.slice,
+                state,
+                static_tools,
+                custom_tools,
+            )?);
+            match slice {
+                // Handle integer indices for lists/sequences
+                Constant::Int(i) => index_value(value, convert_bigint_to_i64(&i)),
+                // Handle string keys for dictionaries
+                Constant::Str(s) => Python::with_gil(|py| {
+                    let value_obj = value.into_py(py);
+                    let Ok(dict) = value_obj.as_ref(py).downcast::<PyDict>() else {
+                        return Err(InterpreterError::RuntimeError("Invalid slice".to_string()));
+                    };
+                    match dict.get_item(s.clone()) {
+                        Some(value) => extract_constant_from_pyobject(value, py),
+                        None => Err(InterpreterError::RuntimeError(format!("KeyError: '{}'", s))),
+                    }
+                }),
+                _ => Err(InterpreterError::RuntimeError("Invalid slice".to_string())),
+            }
+        }
+        ast::Expr::Slice(slice) => {
+            let start = match &slice.lower {
+                Some(lower) => evaluate_expr(lower, state, static_tools, custom_tools)?,
+                None => CustomConstant::Int(BigInt::from(0)),
+            };
+            let end = match &slice.upper {
+                Some(upper) => evaluate_expr(upper, state, static_tools, custom_tools)?,
+                None => CustomConstant::Int(BigInt::from(0)),
+            };
+            let step = match &slice.step {
+                Some(step) => evaluate_expr(step, state, static_tools, custom_tools)?,
+                None => CustomConstant::Int(BigInt::from(1)),
+            };
+            Ok(CustomConstant::Tuple(vec![start, end, step]))
+        }
+        _ => {
+            panic!("Unsupported expression: {:?}", expr);
+        }
+    }
+}
 
-                let slice = Constant::from(evaluate_expr(
-                    &subscript.slice,
+/// Evaluate the positional arguments of a call, unpacking `*iterable` arguments.
+fn evaluate_call_args(
+    args: &[Expr],
+    state: &mut HashMap<String, Box<dyn Any>>,
+    static_tools: &HashMap<String, StaticTool>,
+    custom_tools: &HashMap<String, CustomTool>,
+) -> Result<Vec<CustomConstant>, InterpreterError> {
+    let mut values = Vec::with_capacity(args.len());
+    for arg in args {
+        match arg {
+            ast::Expr::Starred(starred) => {
+                let iterable = evaluate_expr(&starred.value, state, static_tools, custom_tools)?;
+                values.extend(iterate_value(&iterable)?);
+            }
+            arg => values.push(evaluate_expr(arg, state, static_tools, custom_tools)?),
+        }
+    }
+    Ok(values)
+}
+
+/// Evaluate the keyword arguments of a call to `func`, unpacking `**mapping` arguments.
+fn evaluate_call_keywords(
+    func: &str,
+    keywords: &[ast::Keyword],
+    state: &mut HashMap<String, Box<dyn Any>>,
+    static_tools: &HashMap<String, StaticTool>,
+    custom_tools: &HashMap<String, CustomTool>,
+) -> Result<HashMap<String, CustomConstant>, InterpreterError> {
+    let mut values = HashMap::new();
+    for keyword in keywords {
+        let value = evaluate_expr(&keyword.value, state, static_tools, custom_tools)?;
+        let entries = match (&keyword.arg, value) {
+            (Some(arg), value) => vec![(arg.to_string(), value)],
+            (None, CustomConstant::Dict(keys, dict_values)) => {
+                keys.into_iter().zip(dict_values).collect()
+            }
+            (None, other) => {
+                return Err(InterpreterError::RuntimeError(format!(
+                    "TypeError: {}() argument after ** must be a mapping, not {}",
+                    func,
+                    other.str()
+                )))
+            }
+        };
+        for (key, value) in entries {
+            if values.insert(key.clone(), value).is_some() {
+                return Err(InterpreterError::RuntimeError(format!(
+                    "TypeError: {}() got multiple values for keyword argument '{}'",
+                    func, key
+                )));
+            }
+        }
+    }
+    Ok(values)
+}
+
+/// Call the function `func` by name: a builtin, a tool or a function imported into the state.
+fn call_function(
+    func: &str,
+    args: Vec<CustomConstant>,
+    keywords: HashMap<String, CustomConstant>,
+    state: &mut HashMap<String, Box<dyn Any>>,
+    static_tools: &HashMap<String, StaticTool>,
+    custom_tools: &HashMap<String, CustomTool>,
+) -> Result<CustomConstant, InterpreterError> {
+    // Functions defined in the code cannot be passed to Python, so `map` and `filter` call them here
+    match (func, args.as_slice()) {
+        ("map", [function @ CustomConstant::Function(_), iterables @ ..])
+            if !iterables.is_empty() =>
+        {
+            let iterables = iterables
+                .iter()
+                .map(iterate_value)
+                .collect::<Result<Vec<_>, _>>()?;
+            let len = iterables.iter().map(Vec::len).min().unwrap_or(0);
+            let results = (0..len)
+                .map(|i| {
+                    let args = iterables.iter().map(|items| items[i].clone()).collect();
+                    call_value(
+                        function,
+                        args,
+                        HashMap::new(),
+                        state,
+                        static_tools,
+                        custom_tools,
+                    )
+                })
+                .collect::<Result<Vec<_>, _>>()?;
+            return Ok(CustomConstant::Tuple(results));
+        }
+        ("filter", [function @ CustomConstant::Function(_), iterable]) => {
+            let mut results = Vec::new();
+            for item in iterate_value(iterable)? {
+                let keep = call_value(
+                    function,
+                    vec![item.clone()],
+                    HashMap::new(),
                     state,
                     static_tools,
                     custom_tools,
-                )?);
-
-                // Handle integer indices for lists/sequences
-                if let Constant::Int(i) = slice {
-                    let index = convert_bigint_to_i64(&i);
-                    let result = value_obj.as_ref(py).get_item(index);
-                    match result {
-                        Ok(result) => return extract_constant_from_pyobject(result, py),
-                        Err(e) => return Err(InterpreterError::RuntimeError(e.to_string())),
-                    }
+                )?;
+                if is_truthy(&keep)? {
+                    results.push(item);
                 }
+            }
+            return Ok(CustomConstant::Tuple(results));
+        }
+        _ => {}
+    }
+    if func == "range" && keywords.is_empty() {
+        if let Some(result) = evaluate_native_range(&args, state)? {
+            return Ok(result);
+        }
+    }
+    if let Some(result) = evaluate_native_builtin(func, &args, &keywords) {
+        return Ok(result);
+    }
+    if let Some(tool) = static_tools.get(func) {
+        tool(
+            args.into_iter().map(Constant::from).collect(),
+            keywords
+                .into_iter()
+                .map(|(k, v)| (k, Constant::from(v)))
+                .collect(),
+        )
+    } else if let Some(tool) = custom_tools.get(func) {
+        tool(
+            args.into_iter().map(Constant::from).collect(),
+            keywords.into_iter().map(|(k, v)| (k, v.str())).collect(),
+        )
+    } else if let Some(function) = state
+        .get(func)
+        .and_then(|value| value.downcast_ref::<CustomConstant>())
+    {
+        // A function assigned in the code or imported with `from module import function`
+        call_value(
+            &function.clone(),
+            args,
+            keywords,
+            state,
+            static_tools,
+            custom_tools,
+        )
+    } else {
+        Err(InterpreterError::RuntimeError(format!(
+            "Function '{}' not found",
+            func
+        )))
+    }
+}
 
-                // Handle string keys for dictionaries
-                if let Constant::Str(s) = slice {
-                    // Try to extract as dictionary first
-                    if let Ok(dict) = value_obj.as_ref(py).downcast::<PyDict>() {
-                        let result = dict.get_item(s.clone());
-                        match result {
-                            Some(value) => return extract_constant_from_pyobject(value, py),
-                            None => {
-                                return Err(InterpreterError::RuntimeError(format!(
-                                    "KeyError: '{}'",
-                                    s
-                                )))
-                            }
-                        }
-                    }
-                }
+/// Call a callable value: a function defined in the code or a Python function, e.g. one imported
+/// from a module.
+fn call_value(
+    function: &CustomConstant,
+    args: Vec<CustomConstant>,
+    keywords: HashMap<String, CustomConstant>,
+    state: &mut HashMap<String, Box<dyn Any>>,
+    static_tools: &HashMap<String, StaticTool>,
+    custom_tools: &HashMap<String, CustomTool>,
+) -> Result<CustomConstant, InterpreterError> {
+    match function {
+        CustomConstant::Function(function) => {
+            call_defined_function(function, args, keywords, state, static_tools, custom_tools)
+        }
+        CustomConstant::PyObj(function) => Python::with_gil(|py| {
+            let py_args = PyTuple::new(py, args.into_iter().map(|arg| arg.into_py(py)));
+            let py_kwargs = PyDict::new(py);
+            for (key, value) in keywords {
+                py_kwargs.set_item(key, value.into_py(py))?;
+            }
+            let result = function.call(py, py_args, Some(py_kwargs))?;
+            check_module_object(result.as_ref(py), state)?;
+            extract_constant_from_pyobject(result.as_ref(py), py)
+        }),
+        other => Err(InterpreterError::RuntimeError(format!(
+            "TypeError: '{}' is not callable",
+            other.str()
+        ))),
+    }
+}
 
-                // Handle both simple indexing and slicing
-                let result = match &*subscript.slice {
-                    // For slice operations like num[1:3:2]
-                    ast::Expr::Slice(slice) => {
-                        let start = match &slice.lower {
-                            Some(lower) => {
-                                evaluate_expr(lower, state, static_tools, custom_tools)?.into()
-                            }
-                            None => None,
-                        };
-                        let start = start
-                            .map(|start| {
-                                let constant = Constant::from(start);
-                                constant
-                                    .int()
-                                    .map(|i| convert_bigint_to_i64(&i))
-                                    .ok_or_else(|| {
-                                        InterpreterError::RuntimeError(
-                                            "Invalid start value in slice".to_string(),
-                                        )
-                                    })
-                            })
-                            .transpose()?;
-
-                        let stop = match &slice.upper {
-                            Some(upper) => {
-                                evaluate_expr(upper, state, static_tools, custom_tools)?.into()
-                            }
-                            None => None,
-                        };
-                        let stop = stop
-                            .map(|stop| {
-                                let constant = Constant::from(stop);
-                                constant
-                                    .int()
-                                    .map(|i| convert_bigint_to_i64(&i))
-                                    .ok_or_else(|| {
-                                        InterpreterError::RuntimeError(
-                                            "Invalid stop value in slice".to_string(),
-                                        )
-                                    })
-                            })
-                            .transpose()?;
-
-                        let step = match &slice.step {
-                            Some(step) => {
-                                evaluate_expr(step, state, static_tools, custom_tools)?.into()
-                            }
-                            None => None,
-                        };
-                        let step = step
-                            .map(|step| {
-                                let constant = Constant::from(step);
-                                constant
-                                    .int()
-                                    .map(|i| convert_bigint_to_i64(&i))
-                                    .ok_or_else(|| {
-                                        InterpreterError::RuntimeError(
-                                            "Invalid step value in slice".to_string(),
-                                        )
-                                    })
-                            })
-                            .transpose()?;
-
-                        let slice_obj = py
-                            .eval("slice", None, None)?
-                            .call1((start, stop, step))?
-                            .into_py(py);
-                        value_obj.as_ref(py).get_item(slice_obj)?
-                    }
-                    _ => return Err(InterpreterError::RuntimeError("Invalid slice".to_string())),
-                };
+/// Call a function defined in the code. The parameters are bound in the state for the duration of
+/// the call, so the body also sees the variables of the code, with their values at call time.
+fn call_defined_function(
+    function: &Function,
+    args: Vec<CustomConstant>,
+    mut keywords: HashMap<String, CustomConstant>,
+    state: &mut HashMap<String, Box<dyn Any>>,
+    static_tools: &HashMap<String, StaticTool>,
+    custom_tools: &HashMap<String, CustomTool>,
+) -> Result<CustomConstant, InterpreterError> {
+    if args.len() > function.params.len() {
+        return Err(InterpreterError::RuntimeError(format!(
+            "TypeError: <lambda>() takes {} positional arguments but {} were given",
+            function.params.len(),
+            args.len()
+        )));
+    }
+    let mut args = args.into_iter();
+    let mut bindings = Vec::with_capacity(function.params.len());
+    for (name, default) in &function.params {
+        let value = args
+            .next()
+            .or_else(|| keywords.remove(name))
+            .or_else(|| default.clone())
+            .ok_or_else(|| {
+                InterpreterError::RuntimeError(format!(
+                    "TypeError: <lambda>() missing required argument: '{}'",
+                    name
+                ))
+            })?;
+        bindings.push((name.clone(), value));
+    }
+    if let Some(name) = keywords.keys().next() {
+        return Err(InterpreterError::RuntimeError(format!(
+            "TypeError: <lambda>() got an unexpected keyword argument '{}'",
+            name
+        )));
+    }
+
+    let shadowed = bindings
+        .into_iter()
+        .map(|(name, value)| {
+            let previous = state.insert(name.clone(), Box::new(value));
+            (name, previous)
+        })
+        .collect::<Vec<_>>();
+    let result = evaluate_expr(&function.body, state, static_tools, custom_tools);
+    for (name, previous) in shadowed {
+        match previous {
+            Some(previous) => state.insert(name, previous),
+            None => state.remove(&name),
+        };
+    }
+    result
+}
+
+/// The elements of an iterable value.
+fn iterate_value(value: &CustomConstant) -> Result<Vec<CustomConstant>, InterpreterError> {
+    match value {
+        CustomConstant::Tuple(items) => Ok(items.clone()),
+        CustomConstant::Str(s) => Ok(s
+            .chars()
+            .map(|c| CustomConstant::Str(c.to_string()))
+            .collect()),
+        CustomConstant::Dict(keys, _) => {
+            Ok(keys.iter().cloned().map(CustomConstant::Str).collect())
+        }
+        CustomConstant::PyObj(obj) => Python::with_gil(|py| {
+            obj.as_ref(py)
+                .iter()?
+                .map(|item| extract_constant_from_pyobject(item?, py))
+                .collect()
+        }),
+        other => Err(InterpreterError::RuntimeError(format!(
+            "TypeError: '{}' is not iterable",
+            other.str()
+        ))),
+    }
+}
+
+/// Evaluate `min`, `max` and `sorted` called with a `key` function. The key is called on each element
+/// by the interpreter, so it can be any function the code can call. Returns `None` for other calls,
+/// which go to the Python builtins.
+fn evaluate_keyed_builtin(
+    func: &str,
+    args: &[CustomConstant],
+    keywords: &[ast::Keyword],
+    state: &mut HashMap<String, Box<dyn Any>>,
+    static_tools: &HashMap<String, StaticTool>,
+    custom_tools: &HashMap<String, CustomTool>,
+) -> Result<Option<CustomConstant>, InterpreterError> {
+    if !matches!(func, "min" | "max" | "sorted") {
+        return Ok(None);
+    }
+    let Some(key) = keywords.iter().find(|keyword| {
+        keyword
+            .arg
+            .as_ref()
+            .is_some_and(|arg| arg.as_str() == "key")
+    }) else {
+        return Ok(None);
+    };
+    let mut reverse = false;
+    let mut default = None;
+    for keyword in keywords {
+        let mut value = || evaluate_expr(&keyword.value, state, static_tools, custom_tools);
+        match keyword.arg.as_ref().map(|arg| arg.as_str()) {
+            Some("key") => {}
+            Some("reverse") if func == "sorted" => reverse = is_truthy(&value()?)?,
+            Some("default") if func != "sorted" => default = Some(value()?),
+            arg => {
+                return Err(InterpreterError::RuntimeError(format!(
+                    "TypeError: {}() got an unexpected keyword argument '{}'",
+                    func,
+                    arg.unwrap_or("**")
+                )))
+            }
+        }
+    }
+
+    let items = match args {
+        [iterable] => iterate_value(iterable)?,
+        [_, _, ..] if func != "sorted" => args.to_vec(),
+        _ => {
+            return Err(InterpreterError::RuntimeError(format!(
+                "TypeError: {}() takes one iterable argument",
+                func
+            )))
+        }
+    };
+    // A function name is called by name, anything else is evaluated once to a callable value
+    let key_function = match &key.value {
+        ast::Expr::Name(name) if !state.contains_key(name.id.as_str()) => None,
+        expr => Some(evaluate_expr(expr, state, static_tools, custom_tools)?),
+    };
+    let mut keyed = Vec::with_capacity(items.len());
+    for item in items {
+        let key_value = match (&key_function, &key.value) {
+            (Some(function), _) => call_value(
+                function,
+                vec![item.clone()],
+                HashMap::new(),
+                state,
+                static_tools,
+                custom_tools,
+            )?,
+            (None, ast::Expr::Name(name)) => call_function(
+                name.id.as_str(),
+                vec![item.clone()],
+                HashMap::new(),
+                state,
+                static_tools,
+                custom_tools,
+            )?,
+            (None, _) => unreachable!("only names are called by name"),
+        };
+        keyed.push((key_value, item));
+    }
+
+    if func == "sorted" {
+        // The keys are checked first: sorting keys that do not form a total order can panic
+        check_comparable(&keyed.iter().map(|(key, _)| key).collect::<Vec<_>>())?;
+        // Python's sort is stable, also in reverse, so equal keys keep their order
+        keyed.sort_by(|(a, _), (b, _)| {
+            let (a, b) = if reverse { (b, a) } else { (a, b) };
+            compare_values(a, b).expect("the keys are comparable")
+        });
+        let sorted = keyed.into_iter().map(|(_, item)| item).collect();
+        return Ok(Some(CustomConstant::Tuple(sorted)));
+    }
+
+    let mut keyed = keyed.into_iter();
+    let Some(mut best) = keyed.next() else {
+        return default.map(Some).ok_or_else(|| {
+            InterpreterError::RuntimeError(format!(
+                "ValueError: {}() arg is an empty sequence",
+                func
+            ))
+        });
+    };
+    // Like Python, the first of several equal elements wins
+    for candidate in keyed {
+        let ordering = compare_values(&candidate.0, &best.0)?;
+        if (func == "max" && ordering.is_gt()) || (func == "min" && ordering.is_lt()) {
+            best = candidate;
+        }
+    }
+    Ok(Some(best.1))
+}
+
+/// Resolve a possibly negative `index` into a sequence of length `len`, like Python does.
+fn normalize_index(index: i64, len: usize) -> Option<usize> {
+    let index = if index < 0 { index + len as i64 } else { index };
+    (0..len as i64).contains(&index).then_some(index as usize)
+}
+
+/// Index a value with an integer. Strings and lists are indexed natively, other sequences through
+/// Python, and out-of-range indices raise the same `IndexError` as Python either way.
+fn index_value(value: CustomConstant, index: i64) -> Result<CustomConstant, InterpreterError> {
+    let out_of_range = |kind: &str| {
+        InterpreterError::RuntimeError(format!("IndexError: {} index out of range", kind))
+    };
+    match value {
+        CustomConstant::Str(s) => {
+            let len = s.chars().count();
+            normalize_index(index, len)
+                .and_then(|index| s.chars().nth(index))
+                .map(|c| CustomConstant::Str(c.to_string()))
+                .ok_or_else(|| out_of_range("string"))
+        }
+        CustomConstant::Tuple(items) => {
+            let len = items.len();
+            normalize_index(index, len)
+                .and_then(|index| items.into_iter().nth(index))
+                .ok_or_else(|| out_of_range("list"))
+        }
+        value => Python::with_gil(|py| {
+            let value_obj = value.into_py(py);
+            let value_obj = value_obj.as_ref(py);
+            // Mappings like dicts with integer keys are looked up as they are
+            let Ok(sequence) = value_obj.downcast::<PySequence>() else {
+                let result = value_obj
+                    .get_item(index)
+                    .map_err(|e| InterpreterError::RuntimeError(e.to_string()))?;
+                return extract_constant_from_pyobject(result, py);
+            };
+            let len = sequence.len()?;
+            let index =
+                normalize_index(index, len).ok_or_else(|| match value_obj.get_type().name() {
+                    Ok("str") => out_of_range("string"),
+                    Ok(kind) => out_of_range(kind),
+                    Err(_) => out_of_range("sequence"),
+                })?;
+            extract_constant_from_pyobject(sequence.get_item(index)?, py)
+        }),
+    }
+}
+
+/// Evaluate a bound of a slice. An omitted bound and `None` are both `None`.
+fn slice_bound(
+    bound: &Option<Box<Expr>>,
+    name: &str,
+    state: &mut HashMap<String, Box<dyn Any>>,
+    static_tools: &HashMap<String, StaticTool>,
+    custom_tools: &HashMap<String, CustomTool>,
+) -> Result<Option<i64>, InterpreterError> {
+    let Some(bound) = bound else {
+        return Ok(None);
+    };
+    match evaluate_expr(bound, state, static_tools, custom_tools)? {
+        CustomConstant::None => Ok(None),
+        CustomConstant::Int(i) => Ok(Some(convert_bigint_to_i64(&i))),
+        _ => Err(InterpreterError::RuntimeError(format!(
+            "Invalid {} value in slice",
+            name
+        ))),
+    }
+}
+
+/// The indices selected by the slice `start:stop:step` of a sequence of length `len`, in order. Negative
+/// bounds count from the end and out-of-range bounds are clamped, like Python does.
+fn slice_indices(
+    len: usize,
+    start: Option<i64>,
+    stop: Option<i64>,
+    step: Option<i64>,
+) -> Result<Vec<usize>, InterpreterError> {
+    let len = len as i64;
+    let step = step.unwrap_or(1);
+    if step == 0 {
+        return Err(InterpreterError::RuntimeError(
+            "ValueError: slice step cannot be zero".to_string(),
+        ));
+    }
+    // When stepping backwards, -1 is the bound before the first element
+    let (lower, upper) = if step > 0 { (0, len) } else { (-1, len - 1) };
+    let clamp = |bound: Option<i64>, default: i64| match bound {
+        None => default,
+        Some(bound) if bound < 0 => (bound + len).max(lower),
+        Some(bound) => bound.min(upper),
+    };
+    let (start, stop) = if step > 0 {
+        (clamp(start, lower), clamp(stop, upper))
+    } else {
+        (clamp(start, upper), clamp(stop, lower))
+    };
 
-                // Convert the result back to our CustomConstant type
-                extract_constant_from_pyobject(result, py)
-            });
-            result
+    let mut indices = Vec::new();
+    let mut index = start;
+    while (step > 0 && index < stop) || (step < 0 && index > stop) {
+        indices.push(index as usize);
+        match index.checked_add(step) {
+            Some(next) => index = next,
+            None => break,
         }
-        ast::Expr::Slice(slice) => {
-            let start = match &slice.lower {
-                Some(lower) => evaluate_expr(lower, state, static_tools, custom_tools)?,
-                None => CustomConstant::Int(BigInt::from(0)),
-            };
-            let end = match &slice.upper {
-                Some(upper) => evaluate_expr(upper, state, static_tools, custom_tools)?,
-                None => CustomConstant::Int(BigInt::from(0)),
-            };
-            let step = match &slice.step {
-                Some(step) => evaluate_expr(step, state, static_tools, custom_tools)?,
-                None => CustomConstant::Int(BigInt::from(1)),
-            };
-            Ok(CustomConstant::Tuple(vec![start, end, step]))
+    }
+    Ok(indices)
+}
+
+/// Slice a value. Strings and lists are sliced natively, other sequences through Python.
+fn slice_value(
+    value: CustomConstant,
+    start: Option<i64>,
+    stop: Option<i64>,
+    step: Option<i64>,
+) -> Result<CustomConstant, InterpreterError> {
+    match value {
+        CustomConstant::Str(s) => {
+            let chars = s.chars().collect::<Vec<_>>();
+            let indices = slice_indices(chars.len(), start, stop, step)?;
+            Ok(CustomConstant::Str(
+                indices.into_iter().map(|index| chars[index]).collect(),
+            ))
         }
-        _ => {
-            panic!("Unsupported expression: {:?}", expr);
+        CustomConstant::Tuple(items) => {
+            let indices = slice_indices(items.len(), start, stop, step)?;
+            Ok(CustomConstant::Tuple(
+                indices
+                    .into_iter()
+                    .map(|index| items[index].clone())
+                    .collect(),
+            ))
         }
+        value => Python::with_gil(|py| {
+            let value_obj = value.into_py(py);
+            let slice_obj = py
+                .eval("slice", None, None)?
+                .call1((start, stop, step))?
+                .into_py(py);
+            extract_constant_from_pyobject(value_obj.as_ref(py).get_item(slice_obj)?, py)
+        }),
     }
 }
 
@@ -952,14 +2396,15 @@ fn extract_constant_from_pyobject(
     obj: &PyAny,
     py: Python<'_>,
 ) -> Result<CustomConstant, InterpreterError> {
-    if let Ok(float_val) = obj.extract::<f64>() {
-        Ok(CustomConstant::Float(float_val))
-    } else if let Ok(string_val) = obj.extract::<String>() {
-        Ok(CustomConstant::Str(string_val))
-    } else if let Ok(bool_val) = obj.extract::<bool>() {
+    // Ints convert to floats as well, and bools to ints, so the narrower types are tried first
+    if let Ok(bool_val) = obj.extract::<bool>() {
         Ok(CustomConstant::Bool(bool_val))
     } else if let Ok(int_val) = obj.extract::<i64>() {
         Ok(CustomConstant::Int(BigInt::from(int_val)))
+    } else if let Ok(float_val) = obj.extract::<f64>() {
+        Ok(CustomConstant::Float(float_val))
+    } else if let Ok(string_val) = obj.extract::<String>() {
+        Ok(CustomConstant::Str(string_val))
     } else if let Ok(list_val) = obj.extract::<Vec<String>>() {
         Ok(CustomConstant::Tuple(
             list_val.into_iter().map(CustomConstant::Str).collect(),
@@ -1002,6 +2447,7 @@ pub fn evaluate_python_code(
     let ast = ast::Suite::parse(code, "<embedded>")
         .map_err(|e| InterpreterError::SyntaxError(e.to_string()))?;
 
+    reset_operation_counter(state, DEFAULT_MAX_OPERATIONS);
     let result = evaluate_ast(&ast, state, &static_tools, &custom_tools)?;
     Ok(result.str())
 }
@@ -1010,10 +2456,14 @@ pub struct LocalPythonInterpreter {
     static_tools: HashMap<String, ToolFunction>,
     custom_tools: HashMap<String, CustomToolFunction>,
     state: HashMap<String, Box<dyn Any>>,
+    max_operations: usize,
 }
 
 impl LocalPythonInterpreter {
-    pub fn new(custom_tools: Vec<Box<dyn AnyTool>>) -> Self {
+    /// Create an interpreter for the given tools. Each execution stops with
+    /// [`InterpreterError::OperationLimitExceeded`] after `max_operations` evaluated statements and
+    /// expressions, [`DEFAULT_MAX_OPERATIONS`] by default.
+    pub fn new(custom_tools: Vec<Box<dyn AnyTool>>, max_operations: Option<usize>) -> Self {
         let custom_tools = setup_custom_tools(custom_tools);
         let base_tools = get_base_python_tools();
         let static_tools = setup_static_tools(base_tools);
@@ -1021,12 +2471,35 @@ impl LocalPythonInterpreter {
             static_tools,
             custom_tools,
             state: HashMap::new(),
+            max_operations: max_operations.unwrap_or(DEFAULT_MAX_OPERATIONS),
         }
     }
+    /// Replace the modules the code is allowed to import, [`BASE_AUTHORIZED_IMPORTS`] by default.
+    /// A module also allows its submodules, e.g. `"collections"` allows `collections.abc`.
+    pub fn with_authorized_imports(mut self, imports: &[&str]) -> Self {
+        self.state.insert(
+            AUTHORIZED_IMPORTS_KEY.to_string(),
+            Box::new(imports.iter().map(|s| s.to_string()).collect::<Vec<_>>()),
+        );
+        self
+    }
+
+    /// Forget the variables, imports and printed output of the code run so far. The authorized imports
+    /// are kept.
+    pub fn clear_state(&mut self) {
+        self.state.retain(|key, _| key == AUTHORIZED_IMPORTS_KEY);
+    }
+
+    /// Replace the tools the code can call, keeping the variables defined so far.
+    pub fn set_custom_tools(&mut self, custom_tools: Vec<Box<dyn AnyTool>>) {
+        self.custom_tools = setup_custom_tools(custom_tools);
+    }
+
     pub fn forward(&mut self, code: &str) -> Result<(String, String), InterpreterError> {
         let ast = ast::Suite::parse(code, "<embedded>")
             .map_err(|e| InterpreterError::SyntaxError(e.to_string()))?;
         let state = &mut self.state;
+        reset_operation_counter(state, self.max_operations);
         let result = evaluate_ast(&ast, state, &self.static_tools, &self.custom_tools)?;
 
         let mut empty_string = Vec::new();
@@ -1041,6 +2514,7 @@ impl LocalPythonInterpreter {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::test_tools::{CounterTool, JsonTool};
     use crate::tools::{DuckDuckGoSearchTool, FinalAnswerTool, VisitWebsiteTool};
     use std::collections::HashMap;
 
@@ -1153,6 +2627,29 @@ print(f"The letter 'r' appears {r_count} times in the word '{word}'.")"#;
         );
     }
 
+    #[test]
+    fn test_index_bounds() {
+        let run = |code: &str| {
+            let mut state = HashMap::new();
+            evaluate_python_code(code, vec![], &mut state)
+        };
+        let list_error = Err(InterpreterError::RuntimeError(
+            "IndexError: list index out of range".to_string(),
+        ));
+        let string_error = Err(InterpreterError::RuntimeError(
+            "IndexError: string index out of range".to_string(),
+        ));
+
+        assert_eq!(run("lst = [1, 2, 3]\nprint(lst[-3])"), Ok("1".to_string()));
+        assert_eq!(run("lst = [1, 2, 3]\nprint(lst[-1])"), Ok("3".to_string()));
+        assert_eq!(run("lst = [1, 2, 3]\nprint(lst[3])"), list_error);
+        assert_eq!(run("lst = [1, 2, 3]\nprint(lst[-4])"), list_error);
+        assert_eq!(run("word = 'abc'\nprint(word[-3])"), Ok("a".to_string()));
+        assert_eq!(run("word = 'abc'\nprint(word[3])"), string_error);
+        assert_eq!(run("word = 'abc'\nprint(word[-4])"), string_error);
+        assert_eq!(run("print([][0])"), list_error);
+    }
+
     #[test]
     fn test_evaluate_python_code_with_slice() {
         let code = textwrap::dedent(
@@ -1201,6 +2698,83 @@ print(f"The letter 'r' appears {r_count} times in the word '{word}'.")"#;
         assert_eq!(result, "[5, 4, 3, 2, 1]");
     }
 
+    #[test]
+    fn test_del() {
+        let run = |code: &str| {
+            let mut state = HashMap::new();
+            evaluate_python_code(code, vec![], &mut state)
+        };
+
+        assert_eq!(
+            run("x = 1\ndel x\nprint(x)"),
+            Err(InterpreterError::RuntimeError(
+                "Variable 'x' used before assignment".to_string()
+            ))
+        );
+        assert_eq!(
+            run("del y"),
+            Err(InterpreterError::RuntimeError(
+                "NameError: name 'y' is not defined".to_string()
+            ))
+        );
+        let key_error = Err(InterpreterError::RuntimeError("KeyError: 'k'".to_string()));
+        assert_eq!(
+            run("d = {'a': 1, 'k': 2}\ndel d['k']\nprint(len(d))"),
+            Ok("1".to_string())
+        );
+        assert_eq!(
+            run("d = {'a': 1, 'k': 2}\ndel d['k']\nprint(d['k'])"),
+            key_error
+        );
+        assert_eq!(run("d = {'a': 1}\ndel d['k']"), key_error);
+        assert_eq!(
+            run("lst = [1, 2, 3, 4, 5]\ndel lst[-1], lst[::2]\nprint(lst)"),
+            Ok("[2, 4]".to_string())
+        );
+        assert_eq!(
+            run("lst = [1]\ndel lst[1]"),
+            Err(InterpreterError::RuntimeError(
+                "IndexError: list assignment index out of range".to_string()
+            ))
+        );
+    }
+
+    #[test]
+    fn test_negative_slicing() {
+        let run = |code: &str| {
+            let mut state = HashMap::new();
+            evaluate_python_code(code, vec![], &mut state)
+        };
+
+        assert_eq!(run("print([1, 2, 3, 4, 5][-2:])"), Ok("[4, 5]".to_string()));
+        assert_eq!(
+            run("print([1, 2, 3, 4, 5][:-2])"),
+            Ok("[1, 2, 3]".to_string())
+        );
+        assert_eq!(
+            run("print([1, 2, 3, 4, 5][-1:-4:-1])"),
+            Ok("[5, 4, 3]".to_string())
+        );
+        assert_eq!(
+            run("print([1, 2, 3, 4, 5][-10:10:2])"),
+            Ok("[1, 3, 5]".to_string())
+        );
+        assert_eq!(
+            run("print([1, 2, 3, 4, 5][10::-2])"),
+            Ok("[5, 3, 1]".to_string())
+        );
+        assert_eq!(run("print([1, 2, 3][None:2])"), Ok("[1, 2]".to_string()));
+        assert_eq!(run("print([1, 2, 3][2:1])"), Ok("[]".to_string()));
+        assert_eq!(run("print('strawberry'[-5::2])"), Ok("bry".to_string()));
+        assert_eq!(run("print('héllo'[1:-1])"), Ok("éll".to_string()));
+        assert_eq!(
+            run("print([1, 2, 3][::0])"),
+            Err(InterpreterError::RuntimeError(
+                "ValueError: slice step cannot be zero".to_string()
+            ))
+        );
+    }
+
     #[test]
     fn test_for_loop() {
         let code = textwrap::dedent(
@@ -1276,7 +2850,7 @@ for place in dinner_places:
     print(f"{place['title']}: {place['url']}")
         "#,
         );
-        let mut local_python_interpreter = LocalPythonInterpreter::new(vec![]);
+        let mut local_python_interpreter = LocalPythonInterpreter::new(vec![], None);
         let (_, execution_logs) = local_python_interpreter.forward(&code).unwrap();
         assert_eq!(execution_logs, "25 Best Restaurants in Berlin, By Local Foodies: https://www.timeout.com/berlin/restaurants/best-restaurants-in-berlin\nThe 38 Best Berlin Restaurants - Eater: https://www.eater.com/maps/best-restaurants-berlin\nTHE 10 BEST Restaurants in Berlin - Tripadvisor: https://www.tripadvisor.com/Restaurants-g187323-Berlin.html\n12 Unique Restaurants in Berlin: https://www.myglobalviewpoint.com/unique-restaurants-in-berlin/\nBerlin's best restaurants: 101 places to eat right now: https://www.the-berliner.com/food/best-restaurants-berlin-101-places-to-eat/");
 
@@ -1306,7 +2880,7 @@ for movie in movies:
 
         "#,
         );
-        let mut local_python_interpreter = LocalPythonInterpreter::new(vec![]);
+        let mut local_python_interpreter = LocalPythonInterpreter::new(vec![], None);
         let (_, _) = local_python_interpreter.forward(&code).unwrap();
 
         let code = textwrap::dedent(
@@ -1395,6 +2969,476 @@ print(movies)
         );
     }
 
+    #[test]
+    fn test_static_tool_keyword_arguments() {
+        let code = textwrap::dedent(
+            r#"
+        numbers = sorted([3, 1, 2], reverse=True)
+        print(numbers)
+        print(round(3.14159, ndigits=2))
+    "#,
+        );
+        let mut state = HashMap::new();
+        let _ = evaluate_python_code(&code, vec![], &mut state).unwrap();
+        assert_eq!(
+            state
+                .get("print_logs")
+                .unwrap()
+                .downcast_ref::<Vec<String>>()
+                .unwrap(),
+            &vec!["[3, 2, 1]", "3.14"]
+        );
+    }
+
+    #[test]
+    fn test_native_range_matches_python() {
+        let static_tools = setup_static_tools(get_base_python_tools());
+        for args in [vec![5], vec![2, 8], vec![10, 1, -3], vec![3, 1]] {
+            let native = evaluate_native_range(
+                &args
+                    .iter()
+                    .map(|i| CustomConstant::Int(BigInt::from(*i)))
+                    .collect::<Vec<_>>(),
+                &mut HashMap::new(),
+            )
+            .unwrap()
+            .unwrap();
+            let python = static_tools["range"](
+                args.iter()
+                    .map(|i| Constant::Int(BigInt::from(*i)))
+                    .collect(),
+                HashMap::new(),
+            )
+            .unwrap();
+            let python = match python {
+                CustomConstant::PyObj(obj) => Python::with_gil(|py| {
+                    obj.as_ref(py)
+                        .iter()
+                        .unwrap()
+                        .map(|item| extract_constant_from_pyobject(item.unwrap(), py).unwrap())
+                        .collect::<Vec<_>>()
+                }),
+                // Empty ranges are extracted as empty lists
+                CustomConstant::Tuple(items) => items,
+                _ => panic!("Expected a range object"),
+            };
+            assert_eq!(native.str(), CustomConstant::Tuple(python).str());
+        }
+    }
+
+    #[test]
+    fn test_native_enumerate_and_zip() {
+        let code = textwrap::dedent(
+            r#"
+        for i in range(2, 8, 2):
+            print(i)
+        for i, letter in enumerate(['a', 'b'], start=1):
+            print(f"{i}:{letter}")
+        for number, letter in zip([1, 2, 3], 'xy'):
+            print(f"{number}{letter}")
+    "#,
+        );
+        let mut state = HashMap::new();
+        let _ = evaluate_python_code(&code, vec![], &mut state).unwrap();
+        assert_eq!(
+            state
+                .get("print_logs")
+                .unwrap()
+                .downcast_ref::<Vec<String>>()
+                .unwrap(),
+            &vec!["2", "4", "6", "1:a", "2:b", "1x", "2y"]
+        );
+    }
+
+    #[test]
+    fn test_membership_operators() {
+        let mut state = HashMap::new();
+        let cases = [
+            ("'a' in 'cat'", "true"),
+            ("'z' not in 'cat'", "true"),
+            ("3 in [1, 2, 3]", "true"),
+            ("4 in [1, 2, 3]", "false"),
+            ("3.0 in [1, 2, 3]", "true"),
+            ("'k' in {'k': 1}", "true"),
+            ("'v' not in {'k': 1}", "true"),
+            ("x = [[1, 2], [3]]\n[3] in x", "true"),
+        ];
+        for (code, expected) in cases {
+            let result = evaluate_python_code(code, vec![], &mut state).unwrap();
+            assert_eq!(result, expected, "{}", code);
+        }
+        assert!(evaluate_python_code("1 in 'cat'", vec![], &mut state).is_err());
+    }
+
+    #[test]
+    fn test_identity_operators() {
+        let mut state = HashMap::new();
+        let cases = [
+            ("None is None", "true"),
+            ("'x' is not None", "true"),
+            ("0 is None", "false"),
+            ("x = None\nx is not None", "false"),
+            ("{'a': 1}.get('b') is None", "true"),
+            ("True is True", "true"),
+            (
+                "result = None\nif result is None:\n    result = 'empty'\nresult",
+                "empty",
+            ),
+        ];
+        for (code, expected) in cases {
+            let result = evaluate_python_code(code, vec![], &mut state).unwrap();
+            assert_eq!(result, expected, "{}", code);
+        }
+        assert!(evaluate_python_code("'a' is 'a'", vec![], &mut state).is_err());
+    }
+
+    #[test]
+    fn test_dict_methods() {
+        let code = textwrap::dedent(
+            r#"
+        d = {'a': 1, 'b': 2}
+        for k, v in d.items():
+            print(k, v)
+        print(d.keys(), d.values())
+        print(d.get('a'), d.get('c'), d.get('c', 3))
+        "#,
+        );
+        let mut state = HashMap::new();
+        let _ = evaluate_python_code(&code, vec![], &mut state).unwrap();
+        assert_eq!(
+            state
+                .get("print_logs")
+                .unwrap()
+                .downcast_ref::<Vec<String>>()
+                .unwrap(),
+            &vec!["a 1", "b 2", "[a, b] [1, 2]", "1 None 3"]
+        );
+    }
+
+    #[test]
+    fn test_string_methods() {
+        let mut state = HashMap::new();
+        let cases = [
+            (r#""a,b,c".split(",")"#, "[a, b, c]"),
+            (r#""a,b,c".split(",", 1)"#, "[a, b,c]"),
+            (r#""  a  b ".split()"#, "[a, b]"),
+            (r#"", ".join(["a", "b"])"#, "a, b"),
+            (r#""aaa".replace("a", "b", 2)"#, "bba"),
+            (r#""  padded  ".strip()"#, "padded"),
+            (r#""xxhixx".strip("x")"#, "hi"),
+            (r#""MiXeD".lower() + "MiXeD".upper()"#, "mixedMIXED"),
+            (r#""report.pdf".endswith((".txt", ".pdf"))"#, "true"),
+            (r#""report.pdf".startswith("rep")"#, "true"),
+            (
+                r#""{} has {} items, {{literal}}".format("list", 3)"#,
+                "list has 3 items, {literal}",
+            ),
+            (r#""{1} before {0}".format("a", "b")"#, "b before a"),
+        ];
+        for (code, expected) in cases {
+            let result = evaluate_python_code(code, vec![], &mut state).unwrap();
+            assert_eq!(result, expected, "{}", code);
+        }
+        assert!(evaluate_python_code(r#"", ".join(["a", 1])"#, vec![], &mut state).is_err());
+    }
+
+    #[test]
+    fn test_imports() {
+        let mut state = HashMap::new();
+        let code = "import math\nfrom math import sqrt, pi as PI\nmath.floor(PI) + sqrt(16)";
+        assert_eq!(evaluate_python_code(code, vec![], &mut state).unwrap(), "7");
+        assert_eq!(
+            evaluate_python_code("import os", vec![], &mut state),
+            Err(InterpreterError::UnauthorizedImport("os".to_string()))
+        );
+        assert_eq!(
+            evaluate_python_code("from os.path import join", vec![], &mut state),
+            Err(InterpreterError::UnauthorizedImport("os.path".to_string()))
+        );
+
+        let mut interpreter =
+            LocalPythonInterpreter::new(vec![], None).with_authorized_imports(&["json"]);
+        assert!(interpreter.forward("import json").is_ok());
+        assert_eq!(
+            interpreter.forward("import math"),
+            Err(InterpreterError::UnauthorizedImport("math".to_string()))
+        );
+    }
+
+    #[test]
+    fn test_private_attributes_and_modules_are_refused() {
+        let mut interpreter = LocalPythonInterpreter::new(vec![], None);
+        let private = |name: &str| {
+            Err(InterpreterError::UnsupportedOperation(format!(
+                "access to the private attribute '{}'",
+                name
+            )))
+        };
+        assert_eq!(
+            interpreter.forward("import random\nrandom._os.system('echo unsafe')"),
+            private("_os")
+        );
+        assert_eq!(
+            interpreter.forward("import random\nopen = random.__builtins__['open']"),
+            private("__builtins__")
+        );
+        assert_eq!(
+            interpreter.forward("'text'.__class__"),
+            private("__class__")
+        );
+        assert_eq!(
+            interpreter.forward("from random import _os"),
+            private("_os")
+        );
+        // `statistics` imports `sys`, which is not allowed
+        assert_eq!(
+            interpreter.forward("import statistics\nstatistics.sys.modules"),
+            Err(InterpreterError::UnauthorizedImport("sys".to_string()))
+        );
+        assert_eq!(
+            interpreter.forward("import statistics\ns = statistics.sys"),
+            Err(InterpreterError::UnauthorizedImport("sys".to_string()))
+        );
+        assert_eq!(
+            interpreter
+                .forward("import math\nmath.floor(2.5)")
+                .unwrap()
+                .0,
+            "2"
+        );
+    }
+
+    #[test]
+    fn test_operation_limit() {
+        let mut interpreter = LocalPythonInterpreter::new(vec![], Some(1000));
+        assert_eq!(
+            interpreter.forward("while True:\n    pass"),
+            Err(InterpreterError::OperationLimitExceeded)
+        );
+
+        // The count starts over for each execution
+        let code = "i = 0\nwhile i < 10:\n    i = i + 1\ni";
+        assert_eq!(interpreter.forward(code).unwrap().0, "10");
+        assert_eq!(interpreter.forward(code).unwrap().0, "10");
+
+        // A range is counted before it is built
+        assert_eq!(
+            interpreter.forward("x = range(1000000000000)"),
+            Err(InterpreterError::OperationLimitExceeded)
+        );
+        let code = format!("x = range(0, {}, {})\nx", i64::MAX, i64::MAX - 1);
+        assert_eq!(
+            interpreter.forward(&code).unwrap().0,
+            "[0, 9223372036854775806]"
+        );
+    }
+
+    #[test]
+    fn test_key_functions() {
+        let mut interpreter =
+            LocalPythonInterpreter::new(vec![], None).with_authorized_imports(&["operator"]);
+        let code = "words = ['kiwi', 'banana', 'fig', 'cherry']\nmax(words, key=len)";
+        assert_eq!(interpreter.forward(code).unwrap().0, "banana");
+        let code = "min(['kiwi', 'banana', 'fig', 'pea'], key=len)";
+        assert_eq!(interpreter.forward(code).unwrap().0, "fig");
+        let code = "sorted(['kiwi', 'banana', 'fig'], key=len, reverse=True)";
+        assert_eq!(interpreter.forward(code).unwrap().0, "[banana, kiwi, fig]");
+
+        let code = textwrap::dedent(
+            r#"
+        from operator import itemgetter
+        pairs = [('a', 3), ('b', 1), ('c', 2)]
+        [pair[0] for pair in sorted(pairs, key=itemgetter(1))]"#,
+        );
+        assert_eq!(interpreter.forward(&code).unwrap().0, "[b, c, a]");
+        assert_eq!(
+            interpreter
+                .forward("max([], key=len, default='none')")
+                .unwrap()
+                .0,
+            "none"
+        );
+
+        // Keys that cannot all be compared are refused before sorting
+        let code = "sorted([3, 'a', 2, 'b', 1], key=lambda x: x)";
+        assert_eq!(
+            interpreter.forward(code),
+            Err(InterpreterError::RuntimeError(
+                "TypeError: Cannot compare 3 and a".to_string()
+            ))
+        );
+        let code = "sorted([(1, 'a'), (2, 3), (1, 5)], key=lambda x: x)";
+        assert_eq!(
+            interpreter.forward(code),
+            Err(InterpreterError::RuntimeError(
+                "TypeError: Cannot compare a and 5".to_string()
+            ))
+        );
+        let code = "sorted([(2, 'b'), (1,), (2, 'a')], key=lambda x: x)";
+        assert_eq!(
+            interpreter.forward(code).unwrap().0,
+            "[[1], [2, a], [2, b]]"
+        );
+    }
+
+    #[test]
+    fn test_lambda() {
+        let mut interpreter = LocalPythonInterpreter::new(vec![], None);
+        let code = "pairs = [('a', 3), ('b', 1), ('c', 2)]\nsorted(pairs, key=lambda p: p[1])";
+        assert_eq!(
+            interpreter.forward(code).unwrap().0,
+            "[[b, 1], [c, 2], [a, 3]]"
+        );
+        let code = "list(map(lambda x: x * 2, [1, 2, 3]))";
+        assert_eq!(interpreter.forward(code).unwrap().0, "[2, 4, 6]");
+        let code = "list(filter(lambda x: x > 1, [1, 2, 3]))";
+        assert_eq!(interpreter.forward(code).unwrap().0, "[2, 3]");
+
+        // Parameters shadow variables only during the call, defaults are bound at definition
+        let code = "x = 10\nn = 1\nadd = lambda x, y=n: x + y\nn = 5\n[add(1), add(1, y=2), x]";
+        assert_eq!(interpreter.forward(code).unwrap().0, "[2, 3, 10]");
+        assert!(interpreter.forward("add(1, 2, 3)").is_err());
+    }
+
+    #[test]
+    fn test_format_spec() {
+        let mut interpreter = LocalPythonInterpreter::new(vec![], None);
+        let mut format = |code: &str| interpreter.forward(code).unwrap().0;
+        assert_eq!(format("f'{3.14159:.2f}'"), "3.14");
+        assert_eq!(format("f'{1000000:,}'"), "1,000,000");
+        assert_eq!(format("f'{len([1, 2, 3]):03d}'"), "003");
+        assert_eq!(format("digits = 1\nf'{2.25:>8.{digits}f}|'"), "     2.2|");
+        assert_eq!(
+            format("name = 'crab'\nf'{name!r} {name:<6}|'"),
+            "'crab' crab  |"
+        );
+        assert_eq!(format("f'{7 / 2}'"), "3.5");
+        assert_eq!(format("f'{2.0:>5}|{2:>5}'"), "  2.0|    2");
+    }
+
+    #[test]
+    fn test_unpack_json_array_from_tool() {
+        let tools: Vec<Box<dyn AnyTool>> = vec![Box::new(JsonTool {
+            output: json!([1, 2]),
+            as_string: true,
+        })];
+        let mut interpreter = LocalPythonInterpreter::new(tools, None);
+        let code = "low, high = lookup()\nhigh - low";
+        assert_eq!(interpreter.forward(code).unwrap().0, "1");
+
+        let code = "a, b, c = lookup()";
+        assert!(interpreter
+            .forward(code)
+            .unwrap_err()
+            .to_string()
+            .contains("Expected 3 values, got 2"));
+        let code = "a, b = 'not json'";
+        assert!(interpreter.forward(code).is_err());
+        // Only the strings returned by tools are parsed
+        let code = "a, b = '[1, 2]'";
+        assert!(interpreter.forward(code).is_err());
+    }
+
+    #[test]
+    fn test_tool_forward_value() {
+        let inventory = JsonTool {
+            output: json!({"count": 2, "items": ["bolt", "nut"], "price": 0.5}),
+            as_string: false,
+        };
+        assert_eq!(
+            inventory.forward_json(json!({})).unwrap(),
+            r#"{"count":2,"items":["bolt","nut"],"price":0.5}"#
+        );
+        assert_eq!(
+            AnyTool::forward_value(&inventory, json!({})).unwrap()["count"],
+            2
+        );
+
+        let tools: Vec<Box<dyn AnyTool>> = vec![Box::new(inventory)];
+        let mut interpreter = LocalPythonInterpreter::new(tools, None);
+        let code = "stock = lookup()\nstock['items'][1]";
+        assert_eq!(interpreter.forward(code).unwrap().0, "nut");
+        let code = "stock['count'] + 1";
+        assert_eq!(interpreter.forward(code).unwrap().0, "3");
+
+        // Tools without structured output still return their string
+        let tools: Vec<Box<dyn AnyTool>> = vec![Box::new(FinalAnswerTool::new())];
+        assert_eq!(
+            tools[0].forward_value(json!({"answer": "done"})).unwrap(),
+            json!("done")
+        );
+    }
+
+    #[test]
+    fn test_multi_target_assignment_evaluates_once() {
+        let tool = CounterTool::default();
+        let calls = tool.calls.clone();
+        let tools: Vec<Box<dyn AnyTool>> = vec![Box::new(tool)];
+        let mut interpreter = LocalPythonInterpreter::new(tools, None);
+        let code = "x = y = counter()
+f'{x}, {y}'";
+        assert_eq!(interpreter.forward(code).unwrap().0, "call 1, call 1");
+        assert_eq!(calls.load(std::sync::atomic::Ordering::SeqCst), 1);
+
+        let code = "a = (b, c) = (counter(), 2)
+f'{b} {c}'";
+        assert_eq!(interpreter.forward(code).unwrap().0, "call 2 2");
+        assert_eq!(calls.load(std::sync::atomic::Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn test_assert() {
+        let mut interpreter = LocalPythonInterpreter::new(vec![], None);
+        let code = "total = sum([1, 2, 3])\nassert total == 6, f'bad total {total}'\ntotal";
+        assert_eq!(interpreter.forward(code).unwrap().0, "6");
+        // The message of a passing assertion is not evaluated
+        assert!(interpreter.forward("assert True, undefined_name").is_ok());
+
+        assert_eq!(
+            interpreter.forward("x = 2\nassert x > 3, f'x is too small: {x}'"),
+            Err(InterpreterError::RuntimeError(
+                "AssertionError: x is too small: 2".to_string()
+            ))
+        );
+        assert_eq!(
+            interpreter.forward("assert []"),
+            Err(InterpreterError::RuntimeError("AssertionError".to_string()))
+        );
+    }
+
+    #[test]
+    fn test_call_unpacking() {
+        let mut interpreter = LocalPythonInterpreter::new(vec![], None);
+        assert!(matches!(
+            interpreter.forward("final_answer(**{'answer': 'hi'})"),
+            Err(InterpreterError::FinalAnswer(answer)) if answer == "hi"
+        ));
+        let code = "params = {'answer': 'built'}\nfinal_answer(**params)";
+        assert!(matches!(
+            interpreter.forward(code),
+            Err(InterpreterError::FinalAnswer(answer)) if answer == "built"
+        ));
+
+        let code = "args = [3, 1, 2]\nmax(*args)";
+        assert_eq!(interpreter.forward(code).unwrap().0, "3");
+        let code = "f = lambda a, b, c=0: a - b + c\n[f(*(5, 2)), f(1, **{'b': 1, 'c': 4})]";
+        assert_eq!(interpreter.forward(code).unwrap().0, "[3, 4]");
+        assert!(interpreter.forward("f(1, b=1, **{'b': 2})").is_err());
+        assert!(interpreter.forward("f(1, **[2])").is_err());
+    }
+
+    #[test]
+    fn test_chained_comparison() {
+        let mut interpreter = LocalPythonInterpreter::new(vec![], None);
+        let code = "x = 5\nif 0 < x < 10:\n    result = 'in range'\nelse:\n    result = 'out of range'\nresult";
+        assert_eq!(interpreter.forward(code).unwrap().0, "in range");
+        let code = "x = 15\nif 0 < x < 10:\n    result = 'in range'\nelse:\n    result = 'out of range'\nresult";
+        assert_eq!(interpreter.forward(code).unwrap().0, "out of range");
+        assert_eq!(interpreter.forward("1 <= 1 < 2 != 3").unwrap().0, "true");
+        // The comparisons stop at the first false one, so `missing` is never evaluated
+        assert_eq!(interpreter.forward("3 < 2 < missing").unwrap().0, "false");
+    }
+
     #[test]
     fn test_evaluate_python_code_with_error() {
         let code = textwrap::dedent(
@@ -1416,7 +3460,7 @@ guidelines = (
             "#,
         );
         let tools: Vec<Box<dyn AnyTool>> = vec![Box::new(VisitWebsiteTool::new())];
-        let mut local_python_interpreter = LocalPythonInterpreter::new(tools);
+        let mut local_python_interpreter = LocalPythonInterpreter::new(tools, None);
         let (_, logs) = local_python_interpreter.forward(&code).unwrap();
         println!("logs: {:?}", logs);
         let (_, logs_2) = local_python_interpreter.forward(&code_2).unwrap();
@@ -1,13 +1,19 @@
+//! Loggers used by the agents. `ColoredLogger` prints boxed, colored output for interactive use and
+//! `JsonLogger` prints one JSON object per record for log collectors.
+
 use colored::Colorize;
-use log::{Level, LevelFilter, Metadata, Record};
+use log::{LevelFilter, Log, Metadata, Record};
+use serde_json::json;
 use std::io::Write;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU8, Ordering};
 use terminal_size::{self, Width};
 
 pub struct ColoredLogger;
 
 impl log::Log for ColoredLogger {
     fn enabled(&self, metadata: &Metadata) -> bool {
-        metadata.level() <= Level::Info
+        verbosity() != Some(Verbosity::Quiet) && metadata.level() <= log::max_level()
     }
 
     fn log(&self, record: &Record) {
@@ -115,17 +121,182 @@ impl log::Log for ColoredLogger {
 
 pub static LOGGER: ColoredLogger = ColoredLogger;
 
+/// Writes every log record as a single line JSON object with `timestamp`, `level`, `target` and `message`.
+pub struct JsonLogger;
+
+impl JsonLogger {
+    fn format(record: &Record) -> String {
+        json!({
+            "timestamp": chrono::Utc::now().to_rfc3339(),
+            "level": record.level().to_string(),
+            "target": record.target(),
+            "message": record.args().to_string(),
+        })
+        .to_string()
+    }
+}
+
+impl log::Log for JsonLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= log::max_level()
+    }
+
+    fn log(&self, record: &Record) {
+        if self.enabled(record.metadata()) {
+            let _ = writeln!(std::io::stdout().lock(), "{}", Self::format(record));
+        }
+    }
+
+    fn flush(&self) {
+        let _ = std::io::stdout().flush();
+    }
+}
+
+pub static JSON_LOGGER: JsonLogger = JsonLogger;
+
+/// The output format of the global logger.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum LogFormat {
+    #[default]
+    Colored,
+    Json,
+}
+
+impl FromStr for LogFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "colored" | "pretty" => Ok(LogFormat::Colored),
+            "json" => Ok(LogFormat::Json),
+            _ => Err(format!("Unknown log format: {}", s)),
+        }
+    }
+}
+
+/// How much the agents log.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Verbosity {
+    /// Nothing is logged, not even the errors.
+    Quiet,
+    /// The steps, tool calls and final answers.
+    #[default]
+    Normal,
+    /// The debug records as well.
+    Verbose,
+}
+
+impl Verbosity {
+    fn level_filter(self) -> LevelFilter {
+        match self {
+            Verbosity::Quiet => LevelFilter::Off,
+            Verbosity::Normal => LevelFilter::Info,
+            Verbosity::Verbose => LevelFilter::Debug,
+        }
+    }
+}
+
+/// The verbosity set with [`set_verbosity`], 0 until it is set.
+static VERBOSITY: AtomicU8 = AtomicU8::new(0);
+
+fn verbosity() -> Option<Verbosity> {
+    match VERBOSITY.load(Ordering::Relaxed) {
+        1 => Some(Verbosity::Quiet),
+        2 => Some(Verbosity::Normal),
+        3 => Some(Verbosity::Verbose),
+        _ => None,
+    }
+}
+
+/// Set how much the agents log. Takes precedence over `SMOLAGENTS_LOG_LEVEL` and can be called before
+/// or after the logger is initialized.
+///
+/// ```
+/// use smolagents_rs::logger::{set_verbosity, Verbosity};
+///
+/// set_verbosity(Verbosity::Quiet);
+/// assert_eq!(log::max_level(), log::LevelFilter::Off);
+/// ```
+pub fn set_verbosity(verbosity: Verbosity) {
+    VERBOSITY.store(verbosity as u8 + 1, Ordering::Relaxed);
+    log::set_max_level(verbosity.level_filter());
+}
+
+/// Whether the verbosity was set to [`Verbosity::Quiet`], in which case nothing but the final answers
+/// should be printed.
+pub fn is_quiet() -> bool {
+    verbosity() == Some(Verbosity::Quiet)
+}
+
+/// Initialize the global logger with the given format.
+///
+/// Only the first initialization takes effect: agents call [`init_logger_from_env`] when they are
+/// created, so call this before building any agent to select the format. The log level is read from
+/// `SMOLAGENTS_LOG_LEVEL` (e.g. "info", "debug", "error") and defaults to `info`, unless it was set with
+/// [`set_verbosity`].
+pub fn init(format: LogFormat) {
+    let logger: &'static dyn Log = match format {
+        LogFormat::Colored => &LOGGER,
+        LogFormat::Json => &JSON_LOGGER,
+    };
+    if log::set_logger(logger).is_ok() {
+        let level = match verbosity() {
+            Some(verbosity) => verbosity.level_filter(),
+            None => std::env::var("SMOLAGENTS_LOG_LEVEL")
+                .ok()
+                .and_then(|lvl| lvl.parse::<LevelFilter>().ok())
+                .unwrap_or(LevelFilter::Info),
+        };
+        log::set_max_level(level);
+    }
+}
+
 /// Initialize the global logger.
 ///
-/// The log level can be configured using the `SMOLAGENTS_LOG_LEVEL` environment
-/// variable (e.g. "info", "debug", "error"). If the variable is not set,
-/// `info` level logging is used by default.
+/// The format is selected with the `SMOLAGENTS_LOG_FORMAT` environment variable ("colored" or "json")
+/// and defaults to the colored console logger. Does nothing if a logger was already initialized.
 pub fn init_logger_from_env() {
-    if log::set_logger(&LOGGER).is_ok() {
-        let level = std::env::var("SMOLAGENTS_LOG_LEVEL")
-            .ok()
-            .and_then(|lvl| lvl.parse::<LevelFilter>().ok())
-            .unwrap_or(LevelFilter::Info);
-        log::set_max_level(level);
+    let format = std::env::var("SMOLAGENTS_LOG_FORMAT")
+        .ok()
+        .and_then(|format| format.parse::<LogFormat>().ok())
+        .unwrap_or_default();
+    init(format);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use log::Level;
+
+    #[test]
+    fn test_json_logger_format() {
+        let line = JsonLogger::format(
+            &Record::builder()
+                .args(format_args!("Observation: 42"))
+                .level(Level::Warn)
+                .target("smolagents_rs::agents")
+                .build(),
+        );
+        let value: serde_json::Value = serde_json::from_str(&line).unwrap();
+        assert_eq!(value["level"], "WARN");
+        assert_eq!(value["target"], "smolagents_rs::agents");
+        assert_eq!(value["message"], "Observation: 42");
+        assert!(value["timestamp"].is_string());
+    }
+
+    #[test]
+    fn test_quiet_verbosity() {
+        init_logger_from_env();
+        let metadata = |level| Metadata::builder().level(level).build();
+
+        set_verbosity(Verbosity::Quiet);
+        for level in [Level::Error, Level::Warn, Level::Info] {
+            assert!(!LOGGER.enabled(&metadata(level)));
+            assert!(!JSON_LOGGER.enabled(&metadata(level)));
+        }
+
+        set_verbosity(Verbosity::Normal);
+        assert!(LOGGER.enabled(&metadata(Level::Info)));
+        assert!(!LOGGER.enabled(&metadata(Level::Debug)));
     }
 }
@@ -1,13 +1,15 @@
 use std::collections::HashMap;
 
 use crate::errors::AgentError;
+use crate::models::error_body::{response_error, send_error};
 use crate::models::model_traits::{Model, ModelResponse};
-use crate::models::openai::{AssistantMessage, Choice, OpenAIResponse};
-use crate::models::types::{Message, MessageRole};
+use crate::models::openai::OpenAIResponse;
+use crate::models::sse::read_chat_completion_stream;
+use crate::models::types::Message;
 use crate::tools::ToolInfo;
 use anyhow::Result;
 use reqwest::blocking::Client;
-use serde_json::{json, Value};
+use serde_json::json;
 
 #[derive(Debug, Clone)]
 pub struct AzureOpenAIModel {
@@ -58,6 +60,10 @@ impl AzureOpenAIModel {
 }
 
 impl Model for AzureOpenAIModel {
+    fn model_id(&self) -> Option<&str> {
+        Some(&self.deployment_id)
+    }
+
     fn run(
         &self,
         messages: Vec<Message>,
@@ -100,19 +106,19 @@ impl Model for AzureOpenAIModel {
             .header("api-key", &self.api_key)
             .json(&body)
             .send()
-            .map_err(|e| {
-                AgentError::Generation(format!("Failed to get response from Azure OpenAI: {}", e))
-            })?;
+            .map_err(|e| send_error("Failed to get response from Azure OpenAI", e))?;
 
         match response.status() {
             reqwest::StatusCode::OK => {
-                let response = response.json::<OpenAIResponse>().unwrap();
+                let response = response.json::<OpenAIResponse>().map_err(|e| {
+                    AgentError::Generation(format!("Failed to parse response: {}", e))
+                })?;
                 Ok(Box::new(response))
             }
-            _ => Err(AgentError::Generation(format!(
-                "Failed to get response from Azure OpenAI: {}",
-                response.text().unwrap()
-            ))),
+            _ => Err(response_error(
+                "Failed to get response from Azure OpenAI",
+                response,
+            )),
         }
     }
 
@@ -160,45 +166,9 @@ impl Model for AzureOpenAIModel {
             .header("api-key", &self.api_key)
             .json(&body)
             .send()
-            .map_err(|e| {
-                AgentError::Generation(format!("Failed to get response from Azure OpenAI: {}", e))
-            })?;
-
-        use std::io::{BufRead, BufReader};
-
-        let mut reader = BufReader::new(response);
-        let mut content = String::new();
-        let mut line = String::new();
-        while reader
-            .read_line(&mut line)
-            .map_err(|e| AgentError::Generation(e.to_string()))?
-            > 0
-        {
-            if line.starts_with("data: ") {
-                let data = line.trim_start_matches("data: ").trim();
-                if data == "[DONE]" {
-                    break;
-                }
-                if let Ok(val) = serde_json::from_str::<Value>(data) {
-                    if let Some(token) = val["choices"][0]["delta"]["content"].as_str() {
-                        callback(token);
-                        content.push_str(token);
-                    }
-                }
-            }
-            line.clear();
-        }
+            .map_err(|e| send_error("Failed to get response from Azure OpenAI", e))?;
 
-        let response = OpenAIResponse {
-            choices: vec![Choice {
-                message: AssistantMessage {
-                    role: MessageRole::Assistant,
-                    content: Some(content),
-                    tool_calls: None,
-                    refusal: None,
-                },
-            }],
-        };
+        let response = read_chat_completion_stream(std::io::BufReader::new(response), callback)?;
         Ok(Box::new(response))
     }
 }
@@ -0,0 +1,582 @@
+//! AWS Bedrock backend. Requests are sent to the `InvokeModel` endpoint of the Bedrock runtime
+//! and signed with AWS Signature Version 4.
+//!
+//! Anthropic (`anthropic.*`) and Meta Llama (`meta.llama*`) model families are supported.
+
+use std::collections::HashMap;
+
+use anyhow::Result;
+use hmac::{Hmac, Mac};
+use reqwest::blocking::Client;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+
+use crate::errors::AgentError;
+use crate::models::error_body::{max_error_body_length, send_error, truncate_error_body};
+use crate::models::model_traits::{Model, ModelResponse};
+use crate::models::openai::{FunctionCall, ToolCall};
+use crate::models::types::{Message, MessageRole};
+use crate::tools::ToolInfo;
+
+const SIGNING_SERVICE: &str = "bedrock";
+
+/// Credentials used to sign Bedrock requests.
+#[derive(Debug, Clone)]
+pub struct AwsCredentials {
+    pub access_key_id: String,
+    pub secret_access_key: String,
+    pub session_token: Option<String>,
+}
+
+impl AwsCredentials {
+    /// Resolve credentials using the standard AWS chain: the `AWS_ACCESS_KEY_ID`,
+    /// `AWS_SECRET_ACCESS_KEY` and `AWS_SESSION_TOKEN` environment variables first, then the
+    /// shared credentials file (`AWS_SHARED_CREDENTIALS_FILE` or `~/.aws/credentials`) using the
+    /// profile named by `AWS_PROFILE` (default `default`).
+    pub fn from_default_chain() -> Result<Self, AgentError> {
+        if let (Ok(access_key_id), Ok(secret_access_key)) = (
+            std::env::var("AWS_ACCESS_KEY_ID"),
+            std::env::var("AWS_SECRET_ACCESS_KEY"),
+        ) {
+            return Ok(Self {
+                access_key_id,
+                secret_access_key,
+                session_token: std::env::var("AWS_SESSION_TOKEN").ok(),
+            });
+        }
+
+        let profile = std::env::var("AWS_PROFILE").unwrap_or_else(|_| "default".to_string());
+        let path = std::env::var("AWS_SHARED_CREDENTIALS_FILE").ok().or_else(|| {
+            std::env::var("HOME")
+                .ok()
+                .map(|home| format!("{}/.aws/credentials", home))
+        });
+        let contents = path
+            .as_ref()
+            .and_then(|path| std::fs::read_to_string(path).ok())
+            .ok_or_else(|| {
+                AgentError::Generation(
+                    "No AWS credentials found. Set AWS_ACCESS_KEY_ID and AWS_SECRET_ACCESS_KEY, or configure ~/.aws/credentials".to_string(),
+                )
+            })?;
+        Self::from_credentials_file(&contents, &profile)
+    }
+
+    /// Parse the given profile out of the contents of an AWS shared credentials file.
+    pub fn from_credentials_file(contents: &str, profile: &str) -> Result<Self, AgentError> {
+        let mut in_profile = false;
+        let mut values = HashMap::new();
+        for line in contents.lines() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') || line.starts_with(';') {
+                continue;
+            }
+            if line.starts_with('[') && line.ends_with(']') {
+                in_profile = line[1..line.len() - 1].trim() == profile;
+                continue;
+            }
+            if in_profile {
+                if let Some((key, value)) = line.split_once('=') {
+                    values.insert(key.trim().to_string(), value.trim().to_string());
+                }
+            }
+        }
+        match (
+            values.remove("aws_access_key_id"),
+            values.remove("aws_secret_access_key"),
+        ) {
+            (Some(access_key_id), Some(secret_access_key)) => Ok(Self {
+                access_key_id,
+                secret_access_key,
+                session_token: values.remove("aws_session_token"),
+            }),
+            _ => Err(AgentError::Generation(format!(
+                "AWS profile '{}' is missing aws_access_key_id or aws_secret_access_key",
+                profile
+            ))),
+        }
+    }
+}
+
+/// The request/response format used by the model hosted on Bedrock.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BedrockModelFamily {
+    Anthropic,
+    Llama,
+}
+
+impl BedrockModelFamily {
+    pub fn from_model_id(model_id: &str) -> Option<Self> {
+        // Cross-region inference profiles prefix the id with a region group, e.g. `us.anthropic...`
+        if model_id.contains("anthropic.") {
+            Some(Self::Anthropic)
+        } else if model_id.contains("meta.llama") {
+            Some(Self::Llama)
+        } else {
+            None
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct AnthropicContentBlock {
+    #[serde(rename = "type")]
+    pub block_type: String,
+    pub text: Option<String>,
+    pub id: Option<String>,
+    pub name: Option<String>,
+    pub input: Option<Value>,
+}
+
+#[derive(Debug)]
+pub struct BedrockResponse {
+    pub text: String,
+    pub tool_calls: Vec<ToolCall>,
+}
+
+impl BedrockResponse {
+    fn from_body(family: BedrockModelFamily, body: &Value) -> Result<Self, AgentError> {
+        match family {
+            BedrockModelFamily::Anthropic => {
+                let blocks: Vec<AnthropicContentBlock> =
+                    serde_json::from_value(body["content"].clone()).map_err(|e| {
+                        AgentError::Generation(format!("Failed to parse Bedrock response: {}", e))
+                    })?;
+                let mut text = String::new();
+                let mut tool_calls = Vec::new();
+                for block in blocks {
+                    match block.block_type.as_str() {
+                        "text" => text.push_str(&block.text.unwrap_or_default()),
+                        "tool_use" => tool_calls.push(ToolCall {
+                            id: block.id,
+                            call_type: Some("function".to_string()),
+                            function: FunctionCall {
+                                name: block.name.unwrap_or_default(),
+                                arguments: block.input.unwrap_or_else(|| json!({})),
+                            },
+                        }),
+                        _ => {}
+                    }
+                }
+                Ok(Self { text, tool_calls })
+            }
+            BedrockModelFamily::Llama => Ok(Self {
+                text: body["generation"]
+                    .as_str()
+                    .ok_or_else(|| {
+                        AgentError::Generation(
+                            "Bedrock response did not contain a generation".to_string(),
+                        )
+                    })?
+                    .to_string(),
+                tool_calls: vec![],
+            }),
+        }
+    }
+}
+
+impl ModelResponse for BedrockResponse {
+    fn get_response(&self) -> Result<String, AgentError> {
+        Ok(self.text.clone())
+    }
+
+    fn get_tools_used(&self) -> Result<Vec<ToolCall>, AgentError> {
+        Ok(self.tool_calls.clone())
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct BedrockModel {
+    pub model_id: String,
+    pub region: String,
+    pub family: BedrockModelFamily,
+    pub client: Client,
+    pub temperature: f32,
+    pub credentials: AwsCredentials,
+}
+
+impl BedrockModel {
+    /// Create a new Bedrock model.
+    ///
+    /// The region falls back to `AWS_REGION` and then `AWS_DEFAULT_REGION`. Credentials are
+    /// resolved with [`AwsCredentials::from_default_chain`] unless given explicitly.
+    pub fn new(
+        model_id: &str,
+        region: Option<&str>,
+        temperature: Option<f32>,
+        credentials: Option<AwsCredentials>,
+    ) -> Result<Self, AgentError> {
+        let region = region
+            .map(|r| r.to_string())
+            .or_else(|| std::env::var("AWS_REGION").ok())
+            .or_else(|| std::env::var("AWS_DEFAULT_REGION").ok())
+            .ok_or_else(|| {
+                AgentError::Generation(
+                    "No AWS region configured. Pass a region or set AWS_REGION".to_string(),
+                )
+            })?;
+        let family = BedrockModelFamily::from_model_id(model_id).ok_or_else(|| {
+            AgentError::Generation(format!(
+                "Unsupported Bedrock model '{}'. Only anthropic.* and meta.llama* models are supported",
+                model_id
+            ))
+        })?;
+        let credentials = match credentials {
+            Some(credentials) => credentials,
+            None => AwsCredentials::from_default_chain()?,
+        };
+        Ok(Self {
+            model_id: model_id.to_string(),
+            region,
+            family,
+            client: Client::new(),
+            temperature: temperature.unwrap_or(0.5),
+            credentials,
+        })
+    }
+
+    fn host(&self) -> String {
+        format!("bedrock-runtime.{}.amazonaws.com", self.region)
+    }
+
+    fn build_body(
+        &self,
+        messages: &[Message],
+        tools: &[ToolInfo],
+        max_tokens: usize,
+        stop: Option<&Vec<String>>,
+    ) -> Value {
+        match self.family {
+            BedrockModelFamily::Anthropic => {
+                let (system, messages) = anthropic_messages(messages);
+                let mut body = json!({
+                    "anthropic_version": "bedrock-2023-05-31",
+                    "max_tokens": max_tokens,
+                    "temperature": self.temperature,
+                    "messages": messages,
+                });
+                if !system.is_empty() {
+                    body["system"] = json!(system);
+                }
+                if let Some(stop) = stop {
+                    body["stop_sequences"] = json!(stop);
+                }
+                if !tools.is_empty() {
+                    body["tools"] = json!(tools
+                        .iter()
+                        .map(|tool| {
+                            let mut schema = json!(tool.function.parameters);
+                            if let Some(schema) = schema.as_object_mut() {
+                                schema.remove("$schema");
+                            }
+                            json!({
+                                "name": tool.function.name,
+                                "description": tool.function.description,
+                                "input_schema": schema,
+                            })
+                        })
+                        .collect::<Vec<_>>());
+                    body["tool_choice"] = json!({"type": "any"});
+                }
+                body
+            }
+            BedrockModelFamily::Llama => json!({
+                "prompt": llama_prompt(messages),
+                "max_gen_len": max_tokens,
+                "temperature": self.temperature,
+            }),
+        }
+    }
+}
+
+/// Split out the system prompt and merge consecutive messages of the same role, since the
+/// Anthropic messages API requires alternating user/assistant turns.
+fn anthropic_messages(messages: &[Message]) -> (String, Vec<Value>) {
+    let mut system = Vec::new();
+    let mut turns: Vec<(&str, String)> = Vec::new();
+    for message in messages {
+        let role = match message.role {
+            MessageRole::System => {
+                system.push(message.content.clone());
+                continue;
+            }
+            MessageRole::Assistant => "assistant",
+            MessageRole::User | MessageRole::ToolCall | MessageRole::ToolResponse => "user",
+        };
+        match turns.last_mut() {
+            Some((last_role, content)) if *last_role == role => {
+                content.push_str("\n\n");
+                content.push_str(&message.content);
+            }
+            _ => turns.push((role, message.content.clone())),
+        }
+    }
+    let messages = turns
+        .into_iter()
+        .map(|(role, content)| json!({"role": role, "content": content}))
+        .collect();
+    (system.join("\n\n"), messages)
+}
+
+fn llama_prompt(messages: &[Message]) -> String {
+    let mut prompt = "<|begin_of_text|>".to_string();
+    for message in messages {
+        let role = match message.role {
+            MessageRole::System => "system",
+            MessageRole::Assistant => "assistant",
+            MessageRole::User | MessageRole::ToolCall | MessageRole::ToolResponse => "user",
+        };
+        prompt.push_str(&format!(
+            "<|start_header_id|>{}<|end_header_id|>\n\n{}<|eot_id|>",
+            role, message.content
+        ));
+    }
+    prompt.push_str("<|start_header_id|>assistant<|end_header_id|>\n\n");
+    prompt
+}
+
+type HmacSha256 = Hmac<Sha256>;
+
+fn hmac_sha256(key: &[u8], data: &str) -> Vec<u8> {
+    // HMAC accepts keys of any length, so this cannot fail
+    let mut mac = HmacSha256::new_from_slice(key).expect("HMAC can take a key of any size");
+    mac.update(data.as_bytes());
+    mac.finalize().into_bytes().to_vec()
+}
+
+fn sha256_hex(data: &[u8]) -> String {
+    hex::encode(Sha256::digest(data))
+}
+
+/// URI-encode a string as required by SigV4 (everything except unreserved characters).
+fn uri_encode(input: &str) -> String {
+    let mut encoded = String::new();
+    for byte in input.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => {
+                encoded.push(byte as char)
+            }
+            _ => encoded.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+    encoded
+}
+
+/// Compute the SigV4 `Authorization` header for a request.
+///
+/// `headers` must contain every header that should be signed, including `host` and `x-amz-date`.
+/// `path` is the already-encoded request path; it is encoded once more for the canonical request
+/// as required for every service except S3.
+#[allow(clippy::too_many_arguments)]
+pub fn sigv4_authorization(
+    credentials: &AwsCredentials,
+    method: &str,
+    path: &str,
+    headers: &[(String, String)],
+    payload: &[u8],
+    region: &str,
+    service: &str,
+    amz_date: &str,
+) -> String {
+    let date_stamp = &amz_date[..8];
+    let mut headers = headers
+        .iter()
+        .map(|(k, v)| (k.to_lowercase(), v.trim().to_string()))
+        .collect::<Vec<_>>();
+    headers.sort();
+
+    let canonical_uri = path
+        .split('/')
+        .map(uri_encode)
+        .collect::<Vec<_>>()
+        .join("/");
+    let canonical_headers = headers
+        .iter()
+        .map(|(k, v)| format!("{}:{}\n", k, v))
+        .collect::<String>();
+    let signed_headers = headers
+        .iter()
+        .map(|(k, _)| k.as_str())
+        .collect::<Vec<_>>()
+        .join(";");
+    let canonical_request = format!(
+        "{}\n{}\n\n{}\n{}\n{}",
+        method,
+        canonical_uri,
+        canonical_headers,
+        signed_headers,
+        sha256_hex(payload)
+    );
+
+    let scope = format!("{}/{}/{}/aws4_request", date_stamp, region, service);
+    let string_to_sign = format!(
+        "AWS4-HMAC-SHA256\n{}\n{}\n{}",
+        amz_date,
+        scope,
+        sha256_hex(canonical_request.as_bytes())
+    );
+
+    let k_date = hmac_sha256(
+        format!("AWS4{}", credentials.secret_access_key).as_bytes(),
+        date_stamp,
+    );
+    let k_region = hmac_sha256(&k_date, region);
+    let k_service = hmac_sha256(&k_region, service);
+    let k_signing = hmac_sha256(&k_service, "aws4_request");
+    let signature = hex::encode(hmac_sha256(&k_signing, &string_to_sign));
+
+    format!(
+        "AWS4-HMAC-SHA256 Credential={}/{}, SignedHeaders={}, Signature={}",
+        credentials.access_key_id, scope, signed_headers, signature
+    )
+}
+
+impl Model for BedrockModel {
+    fn model_id(&self) -> Option<&str> {
+        Some(&self.model_id)
+    }
+
+    fn run(
+        &self,
+        messages: Vec<Message>,
+        tools_to_call_from: Vec<ToolInfo>,
+        max_tokens: Option<usize>,
+        args: Option<HashMap<String, Vec<String>>>,
+    ) -> Result<Box<dyn ModelResponse>, AgentError> {
+        let max_tokens = max_tokens.unwrap_or(1500);
+        let stop = args.as_ref().and_then(|args| args.get("stop"));
+        let body = self.build_body(&messages, &tools_to_call_from, max_tokens, stop);
+        let payload = serde_json::to_vec(&body).map_err(|e| AgentError::Generation(e.to_string()))?;
+
+        let host = self.host();
+        let path = format!("/model/{}/invoke", uri_encode(&self.model_id));
+        let amz_date = chrono::Utc::now().format("%Y%m%dT%H%M%SZ").to_string();
+        let mut headers = vec![
+            ("content-type".to_string(), "application/json".to_string()),
+            ("host".to_string(), host.clone()),
+            ("x-amz-date".to_string(), amz_date.clone()),
+        ];
+        if let Some(token) = &self.credentials.session_token {
+            headers.push(("x-amz-security-token".to_string(), token.clone()));
+        }
+        let authorization = sigv4_authorization(
+            &self.credentials,
+            "POST",
+            &path,
+            &headers,
+            &payload,
+            &self.region,
+            SIGNING_SERVICE,
+            &amz_date,
+        );
+
+        let mut request = self
+            .client
+            .post(format!("https://{}{}", host, path))
+            .header("Authorization", authorization)
+            .header("Accept", "application/json");
+        for (key, value) in headers.into_iter().filter(|(k, _)| k != "host") {
+            request = request.header(key, value);
+        }
+        let response = request
+            .body(payload)
+            .send()
+            .map_err(|e| send_error("Failed to get response from Bedrock", e))?;
+
+        let status = response.status();
+        let text = response.text().unwrap_or_default();
+        if status.is_success() {
+            let value: Value = serde_json::from_str(&text).map_err(|e| {
+                AgentError::Generation(format!("Failed to parse Bedrock response: {}", e))
+            })?;
+            return Ok(Box::new(BedrockResponse::from_body(self.family, &value)?));
+        }
+        let text = truncate_error_body(&text, max_error_body_length());
+        if status == reqwest::StatusCode::FORBIDDEN || status == reqwest::StatusCode::UNAUTHORIZED {
+            Err(AgentError::Generation(format!(
+                "Bedrock rejected the AWS credentials for region {} (HTTP {}): {}. Check your AWS credentials and that model access for '{}' is enabled in this region.",
+                self.region, status, text, self.model_id
+            )))
+        } else {
+            let message = format!(
+                "Failed to get response from Bedrock (HTTP {}): {}",
+                status, text
+            );
+            if status == reqwest::StatusCode::TOO_MANY_REQUESTS || status.is_server_error() {
+                Err(AgentError::Unavailable(message))
+            } else {
+                Err(AgentError::Generation(message))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sigv4_get_vanilla() {
+        // "get-vanilla" case from the AWS SigV4 test suite
+        let credentials = AwsCredentials {
+            access_key_id: "AKIDEXAMPLE".to_string(),
+            secret_access_key: "wJalrXUtnFEMI/K7MDENG+bPxRfiCYEXAMPLEKEY".to_string(),
+            session_token: None,
+        };
+        let headers = vec![
+            ("Host".to_string(), "example.amazonaws.com".to_string()),
+            ("X-Amz-Date".to_string(), "20150830T123600Z".to_string()),
+        ];
+        let authorization = sigv4_authorization(
+            &credentials,
+            "GET",
+            "/",
+            &headers,
+            b"",
+            "us-east-1",
+            "service",
+            "20150830T123600Z",
+        );
+        assert_eq!(
+            authorization,
+            "AWS4-HMAC-SHA256 Credential=AKIDEXAMPLE/20150830/us-east-1/service/aws4_request, SignedHeaders=host;x-amz-date, Signature=5fa00fa31553b73ebf1942676e86291e8372ff2a2260956d9b8aae1d763fbf31"
+        );
+    }
+
+    #[test]
+    fn test_anthropic_messages_merge_roles() {
+        let messages = vec![
+            Message {
+                role: MessageRole::System,
+                content: "system".to_string(),
+            },
+            Message {
+                role: MessageRole::User,
+                content: "task".to_string(),
+            },
+            Message {
+                role: MessageRole::User,
+                content: "more".to_string(),
+            },
+        ];
+        let (system, turns) = anthropic_messages(&messages);
+        assert_eq!(system, "system");
+        assert_eq!(turns, vec![json!({"role": "user", "content": "task\n\nmore"})]);
+    }
+
+    #[test]
+    fn test_parse_anthropic_tool_use() {
+        let body = json!({
+            "content": [
+                {"type": "text", "text": "Searching"},
+                {"type": "tool_use", "id": "tu_1", "name": "duckduckgo_search", "input": {"query": "rust"}}
+            ]
+        });
+        let response = BedrockResponse::from_body(BedrockModelFamily::Anthropic, &body).unwrap();
+        assert_eq!(response.get_response().unwrap(), "Searching");
+        let tools = response.get_tools_used().unwrap();
+        assert_eq!(tools[0].function.name, "duckduckgo_search");
+        assert_eq!(tools[0].function.arguments, json!({"query": "rust"}));
+    }
+}
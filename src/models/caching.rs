@@ -0,0 +1,241 @@
+//! A model wrapper that caches responses on disk, to replay agent runs offline and make tests
+//! reproducible.
+
+use std::collections::{BTreeMap, HashMap};
+use std::path::{Path, PathBuf};
+
+use anyhow::Result;
+use log::warn;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+use crate::{
+    errors::AgentError,
+    models::{
+        model_traits::{Model, ModelResponse},
+        openai::ToolCall,
+        types::Message,
+    },
+    tools::ToolInfo,
+};
+
+/// Wraps a model and caches its responses as JSON files in `cache_dir`, keyed by a hash of the
+/// messages, tools, max tokens and extra arguments of the request. A request that was already made is
+/// answered from the cache without calling the inner model.
+///
+/// The key does not identify the inner model, so use a separate cache directory per model.
+#[derive(Debug, Clone)]
+pub struct CachingModel<M: Model> {
+    pub model: M,
+    pub cache_dir: PathBuf,
+    /// When disabled, every request goes to the inner model and nothing is cached.
+    pub enabled: bool,
+}
+
+/// A response as stored in the cache.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct CachedResponse {
+    pub content: String,
+    pub tool_calls: Vec<ToolCall>,
+    /// See [`ModelResponse::get_model_info`]. Missing in the responses cached by older versions.
+    #[serde(default)]
+    pub model_info: Option<String>,
+}
+
+impl ModelResponse for CachedResponse {
+    fn get_response(&self) -> Result<String, AgentError> {
+        Ok(self.content.clone())
+    }
+
+    fn get_tools_used(&self) -> Result<Vec<ToolCall>, AgentError> {
+        Ok(self.tool_calls.clone())
+    }
+
+    fn get_model_info(&self) -> Option<String> {
+        self.model_info.clone()
+    }
+}
+
+impl<M: Model> CachingModel<M> {
+    pub fn new(model: M, cache_dir: impl Into<PathBuf>) -> Self {
+        Self {
+            model,
+            cache_dir: cache_dir.into(),
+            enabled: true,
+        }
+    }
+
+    pub fn with_enabled(mut self, enabled: bool) -> Self {
+        self.enabled = enabled;
+        self
+    }
+
+    /// The cache file of a request.
+    fn cache_path(
+        &self,
+        messages: &[Message],
+        tools: &[ToolInfo],
+        max_tokens: Option<usize>,
+        args: Option<&HashMap<String, Vec<String>>>,
+    ) -> Result<PathBuf, AgentError> {
+        // Sort the arguments so the same request always serializes to the same bytes
+        let args = args.map(|args| args.iter().collect::<BTreeMap<_, _>>());
+        let request = serde_json::to_vec(&json!({
+            "messages": messages,
+            "tools": tools,
+            "max_tokens": max_tokens,
+            "args": args,
+        }))
+        .map_err(|e| AgentError::Generation(format!("Failed to serialize request: {}", e)))?;
+        Ok(self
+            .cache_dir
+            .join(format!("{:016x}.json", fnv1a_hash(&request))))
+    }
+
+    fn store(&self, path: &Path, response: &dyn ModelResponse) -> Result<(), AgentError> {
+        let cached = CachedResponse {
+            content: response.get_response()?,
+            tool_calls: response.get_tools_used()?,
+            model_info: response.get_model_info(),
+        };
+        // A failed write only costs a cache miss next time, so it does not fail the request
+        let written = std::fs::create_dir_all(&self.cache_dir)
+            .and_then(|_| std::fs::write(path, serde_json::to_vec_pretty(&cached)?));
+        if let Err(e) = written {
+            warn!("Failed to cache response in {}: {}", path.display(), e);
+        }
+        Ok(())
+    }
+}
+
+/// Read a cached response. Missing or unreadable files are cache misses.
+fn load(path: &Path) -> Option<CachedResponse> {
+    let content = std::fs::read(path).ok()?;
+    serde_json::from_slice(&content).ok()
+}
+
+/// 64-bit FNV-1a. Unlike `DefaultHasher`, the hash is the same across Rust versions, which keeps the
+/// cache valid.
+fn fnv1a_hash(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf29ce484222325, |hash, byte| {
+        (hash ^ *byte as u64).wrapping_mul(0x100000001b3)
+    })
+}
+
+impl<M: Model> Model for CachingModel<M> {
+    fn model_id(&self) -> Option<&str> {
+        self.model.model_id()
+    }
+
+    fn supports_tool_calling(&self) -> bool {
+        self.model.supports_tool_calling()
+    }
+
+    fn run(
+        &self,
+        messages: Vec<Message>,
+        tools: Vec<ToolInfo>,
+        max_tokens: Option<usize>,
+        args: Option<HashMap<String, Vec<String>>>,
+    ) -> Result<Box<dyn ModelResponse>, AgentError> {
+        if !self.enabled {
+            return self.model.run(messages, tools, max_tokens, args);
+        }
+        let path = self.cache_path(&messages, &tools, max_tokens, args.as_ref())?;
+        if let Some(cached) = load(&path) {
+            return Ok(Box::new(cached));
+        }
+        let response = self.model.run(messages, tools, max_tokens, args)?;
+        self.store(&path, response.as_ref())?;
+        Ok(response)
+    }
+
+    fn run_stream(
+        &self,
+        messages: Vec<Message>,
+        tools: Vec<ToolInfo>,
+        max_tokens: Option<usize>,
+        args: Option<HashMap<String, Vec<String>>>,
+        callback: &mut dyn FnMut(&str),
+    ) -> Result<Box<dyn ModelResponse>, AgentError> {
+        if !self.enabled {
+            return self
+                .model
+                .run_stream(messages, tools, max_tokens, args, callback);
+        }
+        let path = self.cache_path(&messages, &tools, max_tokens, args.as_ref())?;
+        if let Some(cached) = load(&path) {
+            callback(&cached.content);
+            return Ok(Box::new(cached));
+        }
+        let response = self
+            .model
+            .run_stream(messages, tools, max_tokens, args, callback)?;
+        self.store(&path, response.as_ref())?;
+        Ok(response)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::types::MessageRole;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    #[derive(Debug, Clone)]
+    struct CountingModel {
+        calls: Arc<AtomicUsize>,
+    }
+
+    impl Model for CountingModel {
+        fn run(
+            &self,
+            _messages: Vec<Message>,
+            _tools: Vec<ToolInfo>,
+            _max_tokens: Option<usize>,
+            _args: Option<HashMap<String, Vec<String>>>,
+        ) -> Result<Box<dyn ModelResponse>, AgentError> {
+            let calls = self.calls.fetch_add(1, Ordering::SeqCst) + 1;
+            Ok(Box::new(CachedResponse {
+                content: format!("response {}", calls),
+                tool_calls: vec![],
+                model_info: None,
+            }))
+        }
+    }
+
+    #[test]
+    fn test_second_identical_run_is_cached() {
+        let dir = tempfile::tempdir().unwrap();
+        let calls = Arc::new(AtomicUsize::new(0));
+        let model = CachingModel::new(
+            CountingModel {
+                calls: calls.clone(),
+            },
+            dir.path(),
+        );
+        let messages = || {
+            vec![Message {
+                role: MessageRole::User,
+                content: "Hello".to_string(),
+            }]
+        };
+
+        let first = model.run(messages(), vec![], None, None).unwrap();
+        let second = model.run(messages(), vec![], None, None).unwrap();
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+        assert_eq!(
+            second.get_response().unwrap(),
+            first.get_response().unwrap()
+        );
+
+        // A different request misses the cache
+        model.run(messages(), vec![], Some(10), None).unwrap();
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+
+        let model = model.with_enabled(false);
+        model.run(messages(), vec![], None, None).unwrap();
+        assert_eq!(calls.load(Ordering::SeqCst), 3);
+    }
+}
@@ -1,12 +1,23 @@
 use std::collections::HashMap;
+use std::path::{Path, PathBuf};
 
 use anyhow::Result;
-use candle_core::{DType, Device, Tensor};
+use candle_core::{quantized::gguf_file, DType, Device, Tensor};
 use candle_nn::VarBuilder;
-use candle_transformers::{generation::LogitsProcessor, models::llama::{Cache, Config, Llama, LlamaConfig, LlamaEosToks}};
+use candle_transformers::{
+    generation::LogitsProcessor,
+    models::llama::{Cache, Config, Llama, LlamaConfig, LlamaEosToks},
+    models::quantized_llama::ModelWeights,
+};
 use tokenizers::Tokenizer;
 
-use crate::{errors::AgentError, models::model_traits::{Model, ModelResponse}, models::openai::ToolCall, models::types::{Message, MessageRole}, tools::ToolInfo};
+use crate::{
+    errors::AgentError,
+    models::model_traits::{Model, ModelResponse},
+    models::openai::ToolCall,
+    models::types::{Message, MessageRole},
+    tools::ToolInfo,
+};
 
 pub struct CandleResponse {
     text: String,
@@ -22,146 +33,312 @@ impl ModelResponse for CandleResponse {
     }
 }
 
+/// The device the model runs on. CUDA and Metal need candle to be built with its `cuda` or `metal`
+/// feature.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum CandleDevice {
+    #[default]
+    Cpu,
+    /// The CUDA GPU with the given ordinal.
+    Cuda(usize),
+    /// The Metal GPU with the given ordinal.
+    Metal(usize),
+}
+
+impl CandleDevice {
+    fn device(self) -> Result<Device> {
+        Ok(match self {
+            CandleDevice::Cpu => Device::Cpu,
+            CandleDevice::Cuda(ordinal) => Device::new_cuda(ordinal)?,
+            CandleDevice::Metal(ordinal) => Device::new_metal(ordinal)?,
+        })
+    }
+}
+
+/// The files a model is loaded from.
+#[derive(Clone, Debug, PartialEq)]
+struct ModelFiles {
+    /// The f16 `model.safetensors` weights, or the quantized GGUF weights.
+    weights: PathBuf,
+    /// The `config.json` of the model, which the quantized weights do not need.
+    config: Option<PathBuf>,
+    tokenizer: PathBuf,
+}
+
+impl ModelFiles {
+    /// The files of the model at `model_path`, a directory or a `.gguf` file whose tokenizer is in the same
+    /// directory. A `.gguf` file is quantized unless `quantized` says otherwise, and the quantized weights
+    /// of a directory are in `model.gguf`.
+    fn locate(model_path: &str, quantized: Option<bool>) -> Self {
+        let path = Path::new(model_path);
+        let is_gguf = path
+            .extension()
+            .is_some_and(|extension| extension == "gguf");
+        let dir = if is_gguf {
+            path.parent().unwrap_or(Path::new("."))
+        } else {
+            path
+        };
+        let weights = match (quantized.unwrap_or(is_gguf), is_gguf) {
+            (true, true) => path.to_path_buf(),
+            (true, false) => dir.join("model.gguf"),
+            (false, _) => dir.join("model.safetensors"),
+        };
+        Self {
+            config: (!quantized.unwrap_or(is_gguf)).then(|| dir.join("config.json")),
+            weights,
+            tokenizer: dir.join("tokenizer.json"),
+        }
+    }
+}
+
+/// The weights of a Llama model, in f16 or quantized.
+#[derive(Clone, Debug)]
+enum Weights {
+    Full { model: Llama, config: Config },
+    Quantized(ModelWeights),
+}
+
+/// The weights and the key-value cache of one generation.
+enum Session<'a> {
+    Full {
+        model: &'a Llama,
+        cache: Cache,
+    },
+    /// The quantized weights keep their own cache, so every generation uses a copy of them. The tensors
+    /// are shared by the copies.
+    Quantized(ModelWeights),
+}
+
+impl Session<'_> {
+    fn forward(&mut self, input: &Tensor, index_pos: usize) -> candle_core::Result<Tensor> {
+        match self {
+            Session::Full { model, cache } => model.forward(input, index_pos, cache),
+            Session::Quantized(model) => model.forward(input, index_pos),
+        }
+    }
+}
+
 #[derive(Clone, Debug)]
 pub struct CandleModel {
-    model: Llama,
+    weights: Weights,
     tokenizer: Tokenizer,
-    config: Config,
+    eos_token_id: Option<u32>,
     device: Device,
     temperature: f32,
 }
 
 impl CandleModel {
-    pub fn new(model_dir: &str, temperature: Option<f32>) -> Result<Self> {
-        let device = Device::Cpu;
-        let config_path = format!("{}/config.json", model_dir);
-        let llama_cfg: LlamaConfig = serde_json::from_slice(&std::fs::read(config_path)?)?;
-        let config = llama_cfg.into_config(false);
-
-        let weights = vec![format!("{}/model.safetensors", model_dir)];
-        let vb = unsafe { VarBuilder::from_mmaped_safetensors(&weights, DType::F16, &device)? };
-        let model = Llama::load(vb, &config)?;
-        let tokenizer_path = format!("{}/tokenizer.json", model_dir);
-        let tokenizer = Tokenizer::from_file(tokenizer_path).map_err(|e| anyhow::anyhow!(e))?;
+    /// Load a Llama model from `model_path`, either a directory with the f16 `model.safetensors` weights,
+    /// their `config.json` and the `tokenizer.json`, or quantized GGUF weights. The weights are quantized
+    /// when the path is a `.gguf` file unless `quantized` says otherwise; a quantized directory holds a
+    /// `model.gguf` file. The tokenizer of a `.gguf` file is the `tokenizer.json` next to it.
+    pub fn new(
+        model_path: &str,
+        temperature: Option<f32>,
+        quantized: Option<bool>,
+        device: CandleDevice,
+    ) -> Result<Self> {
+        let device = device.device()?;
+        let files = ModelFiles::locate(model_path, quantized);
+        let (weights, eos_token_id) = match &files.config {
+            Some(config_path) => {
+                let llama_cfg: LlamaConfig = serde_json::from_slice(&std::fs::read(config_path)?)?;
+                let config = llama_cfg.into_config(false);
+                let vb = unsafe {
+                    VarBuilder::from_mmaped_safetensors(&[&files.weights], DType::F16, &device)?
+                };
+                let model = Llama::load(vb, &config)?;
+                let eos_token_id = match config.eos_token_id {
+                    Some(LlamaEosToks::Single(id)) => Some(id),
+                    Some(LlamaEosToks::Multiple(ref ids)) => ids.first().cloned(),
+                    None => None,
+                };
+                (Weights::Full { model, config }, eos_token_id)
+            }
+            None => {
+                let mut file = std::fs::File::open(&files.weights)?;
+                let content = gguf_file::Content::read(&mut file)?;
+                let eos_token_id = content
+                    .metadata
+                    .get("tokenizer.ggml.eos_token_id")
+                    .and_then(|id| id.to_u32().ok());
+                let model = ModelWeights::from_gguf(content, &mut file, &device)?;
+                (Weights::Quantized(model), eos_token_id)
+            }
+        };
+        let tokenizer = Tokenizer::from_file(&files.tokenizer).map_err(|e| anyhow::anyhow!(e))?;
         Ok(Self {
-            model,
+            weights,
             tokenizer,
-            config,
+            eos_token_id,
             device,
             temperature: temperature.unwrap_or(0.7),
         })
     }
 
-    fn generate(&self, prompt: &str, max_new_tokens: usize) -> Result<String> {
-        let mut cache = Cache::new(true, DType::F16, &self.config, &self.device)?;
-        let mut tokens = self
-            .tokenizer
-            .encode(prompt, true)
-            .map_err(anyhow::Error::msg)?
-            .get_ids()
-            .to_vec();
-        let mut logits_processor = LogitsProcessor::new(299792458, Some(self.temperature as f64), None);
-        let eos_id = match self.config.eos_token_id {
-            Some(LlamaEosToks::Single(id)) => Some(id),
-            Some(LlamaEosToks::Multiple(ref ids)) => ids.first().cloned(),
-            None => None,
-        };
-
-        for index in 0..max_new_tokens {
-            let (context_size, context_index) = if cache.use_kv_cache && index > 0 {
-                (1, tokens.len() - 1)
-            } else {
-                (tokens.len(), 0)
-            };
-            let ctxt = &tokens[tokens.len() - context_size..];
-            let input = Tensor::new(ctxt, &self.device)?.unsqueeze(0)?;
-            let logits = self.model.forward(&input, context_index, &mut cache)?;
-            let logits = logits.squeeze(0)?;
-            let next_token = logits_processor.sample(&logits)?;
-            tokens.push(next_token);
-            if let Some(eos) = eos_id {
-                if next_token == eos {
-                    break;
-                }
-            }
-        }
+    /// Whether the model runs quantized weights.
+    pub fn is_quantized(&self) -> bool {
+        matches!(self.weights, Weights::Quantized(_))
+    }
 
-        let text = self
-            .tokenizer
-            .decode(&tokens, true)
-            .map_err(anyhow::Error::msg)?;
-        Ok(text)
+    fn session(&self) -> Result<Session<'_>> {
+        Ok(match &self.weights {
+            Weights::Full { model, config } => Session::Full {
+                model,
+                cache: Cache::new(true, DType::F16, config, &self.device)?,
+            },
+            Weights::Quantized(model) => Session::Quantized(model.clone()),
+        })
     }
 
-    fn generate_stream(
+    /// Generate at most `max_new_tokens` after `prompt`, streaming the text to `callback`. The generation
+    /// stops at the end-of-sequence token or at the first of `stop_sequences` generated, which is trimmed
+    /// from the text returned and never streamed.
+    fn generate(
         &self,
         prompt: &str,
         max_new_tokens: usize,
+        stop_sequences: &[String],
         callback: &mut dyn FnMut(&str),
     ) -> Result<String> {
-        let mut cache = Cache::new(true, DType::F16, &self.config, &self.device)?;
+        let mut session = self.session()?;
         let mut tokens = self
             .tokenizer
             .encode(prompt, true)
             .map_err(anyhow::Error::msg)?
             .get_ids()
             .to_vec();
+        let prompt_length = tokens.len();
         let mut logits_processor =
             LogitsProcessor::new(299792458, Some(self.temperature as f64), None);
-        let eos_id = match self.config.eos_token_id {
-            Some(LlamaEosToks::Single(id)) => Some(id),
-            Some(LlamaEosToks::Multiple(ref ids)) => ids.first().cloned(),
-            None => None,
-        };
 
-        let mut output_tokens = Vec::new();
+        let mut filter = StopFilter::new(stop_sequences);
         for index in 0..max_new_tokens {
-            let (context_size, context_index) = if cache.use_kv_cache && index > 0 {
+            let (context_size, context_index) = if index > 0 {
                 (1, tokens.len() - 1)
             } else {
                 (tokens.len(), 0)
             };
             let ctxt = &tokens[tokens.len() - context_size..];
             let input = Tensor::new(ctxt, &self.device)?.unsqueeze(0)?;
-            let logits = self.model.forward(&input, context_index, &mut cache)?;
+            let logits = session.forward(&input, context_index)?;
             let logits = logits.squeeze(0)?;
             let next_token = logits_processor.sample(&logits)?;
+            if self.eos_token_id == Some(next_token) {
+                break;
+            }
             tokens.push(next_token);
-            output_tokens.push(next_token);
-            let token_text = self
+
+            // The whole output is decoded again as a stop sequence can span several tokens
+            let text = self
                 .tokenizer
-                .decode(&[next_token], false)
+                .decode(&tokens[prompt_length..], true)
                 .map_err(anyhow::Error::msg)?;
-            callback(&token_text);
-            if let Some(eos) = eos_id {
-                if next_token == eos {
-                    break;
-                }
+            let (streamed, stopped) = filter.update(text);
+            if !streamed.is_empty() {
+                callback(streamed);
+            }
+            if stopped {
+                break;
             }
         }
-
-        let mut all_tokens = self
-            .tokenizer
-            .encode(prompt, true)
-            .map_err(anyhow::Error::msg)?
-            .get_ids()
-            .to_vec();
-        all_tokens.extend(output_tokens);
-        let text = self
-            .tokenizer
-            .decode(&all_tokens, true)
-            .map_err(anyhow::Error::msg)?;
+        let (held_back, text) = filter.finish();
+        if !held_back.is_empty() {
+            callback(&held_back);
+        }
         Ok(text)
     }
 }
 
+/// Cuts the text of a generation at the first of the stop sequences. The end of the text that could be
+/// the start of a stop sequence is held back from the stream until the next tokens tell, so no part of a
+/// stop sequence is streamed.
+struct StopFilter<'a> {
+    stop_sequences: &'a [String],
+    text: String,
+    /// The length of the text streamed so far.
+    streamed: usize,
+}
+
+impl<'a> StopFilter<'a> {
+    fn new(stop_sequences: &'a [String]) -> Self {
+        Self {
+            stop_sequences,
+            text: String::new(),
+            streamed: 0,
+        }
+    }
+
+    /// Update the filter with the whole text generated so far. Returns the new text to stream, and
+    /// whether the text reached a stop sequence, which ends the generation.
+    fn update(&mut self, text: String) -> (&str, bool) {
+        self.text = text;
+        let stopped = match stop_index(&self.text, self.stop_sequences) {
+            Some(end) => {
+                self.text.truncate(end);
+                true
+            }
+            None => false,
+        };
+        let held_back = if stopped {
+            0
+        } else {
+            partial_stop_length(&self.text, self.stop_sequences)
+        };
+        let start = self.streamed.min(self.text.len());
+        let end = (self.text.len() - held_back).max(start);
+        self.streamed = end;
+        (self.text.get(start..end).unwrap_or_default(), stopped)
+    }
+
+    /// Finish the generation. Returns the text still held back, to stream it, and the whole text.
+    fn finish(self) -> (String, String) {
+        let held_back = self.text.get(self.streamed..).unwrap_or_default();
+        (held_back.to_string(), self.text)
+    }
+}
+
+/// The stop sequences given by the agent in the extra arguments of a request.
+fn stop_sequences(args: &Option<HashMap<String, Vec<String>>>) -> &[String] {
+    args.as_ref()
+        .and_then(|args| args.get("stop"))
+        .map_or(&[], Vec::as_slice)
+}
+
+/// The length of the longest end of `text` that is the start of one of `stop_sequences`.
+fn partial_stop_length(text: &str, stop_sequences: &[String]) -> usize {
+    stop_sequences
+        .iter()
+        .flat_map(|stop| {
+            (1..stop.len())
+                .filter(|&length| stop.is_char_boundary(length) && text.ends_with(&stop[..length]))
+        })
+        .max()
+        .unwrap_or(0)
+}
+
+/// Where the first of `stop_sequences` found in `text` starts.
+fn stop_index(text: &str, stop_sequences: &[String]) -> Option<usize> {
+    stop_sequences
+        .iter()
+        .filter(|stop| !stop.is_empty())
+        .filter_map(|stop| text.find(stop.as_str()))
+        .min()
+}
+
 impl Model for CandleModel {
+    /// The tools are not given to the model, it writes its tool calls as text.
+    fn supports_tool_calling(&self) -> bool {
+        false
+    }
+
     fn run(
         &self,
         messages: Vec<Message>,
         _tools: Vec<ToolInfo>,
         max_tokens: Option<usize>,
-        _args: Option<HashMap<String, Vec<String>>>,
+        args: Option<HashMap<String, Vec<String>>>,
     ) -> Result<Box<dyn ModelResponse>, AgentError> {
         let conversation = messages
             .iter()
@@ -176,7 +353,12 @@ impl Model for CandleModel {
             .join("\n");
 
         let text = self
-            .generate(&conversation, max_tokens.unwrap_or(256))
+            .generate(
+                &conversation,
+                max_tokens.unwrap_or(256),
+                stop_sequences(&args),
+                &mut |_| {},
+            )
             .map_err(|e| AgentError::Generation(e.to_string()))?;
         Ok(Box::new(CandleResponse { text }))
     }
@@ -186,7 +368,7 @@ impl Model for CandleModel {
         messages: Vec<Message>,
         _tools: Vec<ToolInfo>,
         max_tokens: Option<usize>,
-        _args: Option<HashMap<String, Vec<String>>>,
+        args: Option<HashMap<String, Vec<String>>>,
         callback: &mut dyn FnMut(&str),
     ) -> Result<Box<dyn ModelResponse>, AgentError> {
         let conversation = messages
@@ -202,9 +384,79 @@ impl Model for CandleModel {
             .join("\n");
 
         let text = self
-            .generate_stream(&conversation, max_tokens.unwrap_or(256), callback)
+            .generate(
+                &conversation,
+                max_tokens.unwrap_or(256),
+                stop_sequences(&args),
+                callback,
+            )
             .map_err(|e| AgentError::Generation(e.to_string()))?;
         Ok(Box::new(CandleResponse { text }))
     }
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_locate_model_files() {
+        let files = ModelFiles::locate("models/llama-q4_k_m.gguf", None);
+        assert_eq!(files.weights, Path::new("models/llama-q4_k_m.gguf"));
+        assert_eq!(files.config, None);
+        assert_eq!(files.tokenizer, Path::new("models/tokenizer.json"));
+
+        let files = ModelFiles::locate("models/llama", None);
+        assert_eq!(files.weights, Path::new("models/llama/model.safetensors"));
+        assert_eq!(
+            files.config.as_deref(),
+            Some(Path::new("models/llama/config.json"))
+        );
+
+        let files = ModelFiles::locate("models/llama", Some(true));
+        assert_eq!(files.weights, Path::new("models/llama/model.gguf"));
+        assert_eq!(files.config, None);
+    }
+    #[test]
+    fn test_stop_sequences() {
+        let args = Some(HashMap::from([(
+            "stop".to_string(),
+            vec!["<end_code>".to_string(), "Observation:".to_string()],
+        )]));
+        let stop = stop_sequences(&args);
+
+        // The output decoded after every token, the stop sequence spanning two of them
+        let generate = |tokens: &[&str]| {
+            let mut filter = StopFilter::new(stop);
+            let mut text = String::new();
+            let mut streamed = Vec::new();
+            for token in tokens {
+                text.push_str(token);
+                let (chunk, stopped) = filter.update(text.clone());
+                streamed.push(chunk.to_string());
+                if stopped {
+                    break;
+                }
+            }
+            let (held_back, text) = filter.finish();
+            streamed.push(held_back);
+            (streamed, text)
+        };
+        let (streamed, text) = generate(&["Thought: I", " search", "\nObserv", "ation:", " 42"]);
+        assert_eq!(text, "Thought: I search\n");
+        // The start of the stop sequence is held back, and never streamed
+        assert_eq!(streamed, ["Thought: I", " search", "\n", "", ""]);
+
+        // Text held back is streamed once it turns out not to be a stop sequence
+        let (streamed, text) = generate(&["See", " <end", "ing>", " and <e"]);
+        assert_eq!(text, "See <ending> and <e");
+        assert_eq!(streamed, ["See", " ", "<ending>", " and ", "<e"]);
+
+        assert_eq!(
+            stop_index("print(1)<end_code>\nObservation:", stop),
+            Some(8)
+        );
+        assert_eq!(stop_index("Observation", stop), None);
+        assert!(stop_sequences(&None).is_empty());
+    }
+}
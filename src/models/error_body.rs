@@ -0,0 +1,86 @@
+//! The bodies of failed model requests in error messages. An endpoint can answer with a whole HTML error
+//! page, so the bodies are truncated, to [`DEFAULT_MAX_ERROR_BODY_LENGTH`] characters unless configured
+//! otherwise with [`set_max_error_body_length`].
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use reqwest::blocking::Response;
+use reqwest::StatusCode;
+
+use crate::errors::AgentError;
+
+/// The default maximum number of characters of an error body in an error message.
+pub const DEFAULT_MAX_ERROR_BODY_LENGTH: usize = 2000;
+
+static MAX_ERROR_BODY_LENGTH: AtomicUsize = AtomicUsize::new(DEFAULT_MAX_ERROR_BODY_LENGTH);
+
+/// Set the maximum number of characters of an error body kept in the error messages of all models.
+pub fn set_max_error_body_length(max_length: usize) {
+    MAX_ERROR_BODY_LENGTH.store(max_length, Ordering::Relaxed);
+}
+
+/// The maximum number of characters of an error body kept in the error messages of all models.
+pub fn max_error_body_length() -> usize {
+    MAX_ERROR_BODY_LENGTH.load(Ordering::Relaxed)
+}
+
+/// The status and body of a failed response for an error message, e.g. `HTTP 502 Bad Gateway: <body>`.
+/// The body is truncated to [`max_error_body_length`] characters, and described if it cannot be read.
+pub fn error_body(response: Response) -> String {
+    let status = response.status();
+    let body = match response.text() {
+        Ok(body) => truncate_error_body(&body, max_error_body_length()),
+        Err(e) => format!("<unreadable response body: {}>", e),
+    };
+    format!("HTTP {}: {}", status, body)
+}
+
+/// The error of a model request that could not be sent, e.g. `Failed to get response from OpenAI: <error>`.
+/// Unless the request could not be built, e.g. because of an invalid url, sending it again may succeed, so
+/// the error is [`AgentError::Unavailable`].
+pub fn send_error(context: &str, error: reqwest::Error) -> AgentError {
+    let message = format!("{}: {}", context, error);
+    if error.is_builder() {
+        AgentError::Generation(message)
+    } else {
+        AgentError::Unavailable(message)
+    }
+}
+
+/// The error of a failed response, with its status and body. See [`error_body`]. Rate limits (HTTP 429) and
+/// server errors (HTTP 5xx) are [`AgentError::Unavailable`].
+pub fn response_error(context: &str, response: Response) -> AgentError {
+    let status = response.status();
+    let message = format!("{}: {}", context, error_body(response));
+    if status == StatusCode::TOO_MANY_REQUESTS || status.is_server_error() {
+        AgentError::Unavailable(message)
+    } else {
+        AgentError::Generation(message)
+    }
+}
+
+/// Truncate `body` to `max_length` characters, saying how many were left out.
+pub fn truncate_error_body(body: &str, max_length: usize) -> String {
+    let body = body.trim();
+    match body.char_indices().nth(max_length) {
+        Some((end, _)) => format!(
+            "{}... [{} more characters]",
+            &body[..end],
+            body[end..].chars().count()
+        ),
+        None => body.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_truncate_error_body() {
+        let body = format!("<html>{}</html>", "é".repeat(3000));
+        let truncated = truncate_error_body(&body, 10);
+        assert_eq!(truncated, "<html>éééé... [3003 more characters]");
+        assert_eq!(truncate_error_body(" not found\n", 10), "not found");
+    }
+}
@@ -0,0 +1,230 @@
+//! A model for any provider that speaks the OpenAI chat-completions protocol (Groq, Together, Fireworks,
+//! OpenRouter, vLLM, ...). The API key is read from a configurable environment variable and extra headers
+//! can be sent with every request.
+
+use std::collections::HashMap;
+use std::time::Duration;
+
+use anyhow::Result;
+use reqwest::blocking::Client;
+use serde_json::json;
+
+use crate::{
+    errors::AgentError,
+    models::{
+        error_body::{response_error, send_error},
+        model_traits::{Model, ModelResponse},
+        openai::OpenAIResponse,
+        pool::PoolSettings,
+        types::Message,
+    },
+    tools::ToolInfo,
+};
+
+#[derive(Debug, Clone)]
+pub struct GenericOpenAIModel {
+    pub base_url: String,
+    pub model_id: String,
+    pub client: Client,
+    pub temperature: f32,
+    pub api_key: Option<String>,
+    pub headers: HashMap<String, String>,
+}
+
+pub struct GenericOpenAIModelBuilder {
+    base_url: String,
+    model_id: Option<String>,
+    temperature: Option<f32>,
+    api_key: Option<String>,
+    api_key_env: Option<String>,
+    headers: HashMap<String, String>,
+    client: Option<Client>,
+    pool: PoolSettings,
+}
+
+impl GenericOpenAIModelBuilder {
+    /// Start building a model for the chat-completions endpoint at `base_url`,
+    /// e.g. `https://api.groq.com/openai/v1/chat/completions`.
+    pub fn new(base_url: &str) -> Self {
+        Self {
+            base_url: base_url.to_string(),
+            model_id: None,
+            temperature: None,
+            api_key: None,
+            api_key_env: None,
+            headers: HashMap::new(),
+            client: None,
+            pool: PoolSettings::default(),
+        }
+    }
+
+    pub fn model_id(mut self, model_id: &str) -> Self {
+        self.model_id = Some(model_id.to_string());
+        self
+    }
+
+    pub fn temperature(mut self, temperature: Option<f32>) -> Self {
+        self.temperature = temperature;
+        self
+    }
+
+    /// Use the given API key. Takes precedence over [`Self::api_key_env`].
+    pub fn api_key(mut self, api_key: &str) -> Self {
+        self.api_key = Some(api_key.to_string());
+        self
+    }
+
+    /// Read the API key from the given environment variable, e.g. `GROQ_API_KEY`.
+    pub fn api_key_env(mut self, api_key_env: &str) -> Self {
+        self.api_key_env = Some(api_key_env.to_string());
+        self
+    }
+
+    /// Send an extra header with every request, e.g. `HTTP-Referer` for OpenRouter.
+    pub fn header(mut self, key: &str, value: &str) -> Self {
+        self.headers.insert(key.to_string(), value.to_string());
+        self
+    }
+
+    pub fn headers(mut self, headers: HashMap<String, String>) -> Self {
+        self.headers.extend(headers);
+        self
+    }
+
+    /// Use the given client. Its pool settings take precedence over [`Self::pool_max_idle_per_host`] and
+    /// [`Self::pool_idle_timeout`].
+    pub fn client(mut self, client: Client) -> Self {
+        self.client = Some(client);
+        self
+    }
+
+    /// Keep at most `max_idle_per_host` idle connections open. See [`crate::models::pool`] for sane values.
+    pub fn pool_max_idle_per_host(mut self, max_idle_per_host: usize) -> Self {
+        self.pool.max_idle_per_host = max_idle_per_host;
+        self
+    }
+
+    /// Close idle connections after `idle_timeout`, never if `None`.
+    pub fn pool_idle_timeout(mut self, idle_timeout: Option<Duration>) -> Self {
+        self.pool.idle_timeout = idle_timeout;
+        self
+    }
+
+    /// Build the model. Fails if an API key environment variable was given but is not set.
+    /// Without a key the requests are sent without an `Authorization` header, as local servers expect.
+    pub fn build(self) -> Result<GenericOpenAIModel, AgentError> {
+        let api_key = match (self.api_key, self.api_key_env) {
+            (Some(api_key), _) => Some(api_key),
+            (None, Some(env)) => Some(std::env::var(&env).map_err(|_| {
+                AgentError::Generation(format!("Environment variable {} must be set", env))
+            })?),
+            (None, None) => None,
+        };
+        Ok(GenericOpenAIModel {
+            base_url: self.base_url,
+            model_id: self.model_id.unwrap_or_else(|| "gpt-4o-mini".to_string()),
+            client: self.client.unwrap_or_else(|| self.pool.client()),
+            temperature: self.temperature.unwrap_or(0.5),
+            api_key,
+            headers: self.headers,
+        })
+    }
+}
+
+impl Model for GenericOpenAIModel {
+    fn model_id(&self) -> Option<&str> {
+        Some(&self.model_id)
+    }
+
+    fn run(
+        &self,
+        messages: Vec<Message>,
+        tools_to_call_from: Vec<ToolInfo>,
+        max_tokens: Option<usize>,
+        args: Option<HashMap<String, Vec<String>>>,
+    ) -> Result<Box<dyn ModelResponse>, AgentError> {
+        let max_tokens = max_tokens.unwrap_or(1500);
+        let messages = messages
+            .iter()
+            .map(|message| {
+                json!({
+                    "role": message.role,
+                    "content": message.content
+                })
+            })
+            .collect::<Vec<_>>();
+        let mut body = json!({
+            "model": self.model_id,
+            "messages": messages,
+            "temperature": self.temperature,
+            "max_tokens": max_tokens,
+        });
+        if !tools_to_call_from.is_empty() {
+            body["tools"] = json!(tools_to_call_from);
+            body["tool_choice"] = json!("required");
+        }
+        if let Some(args) = args {
+            let body_map = body.as_object_mut().unwrap();
+            for (key, value) in args {
+                body_map.insert(key, json!(value));
+            }
+        }
+
+        let mut request = self.client.post(&self.base_url).json(&body);
+        if let Some(api_key) = &self.api_key {
+            request = request.header("Authorization", format!("Bearer {}", api_key));
+        }
+        for (key, value) in &self.headers {
+            request = request.header(key, value);
+        }
+        let context = format!("Failed to get response from {}", self.base_url);
+        let response = request.send().map_err(|e| send_error(&context, e))?;
+
+        if response.status().is_success() {
+            let response = response
+                .json::<OpenAIResponse>()
+                .map_err(|e| AgentError::Generation(format!("Failed to parse response: {}", e)))?;
+            Ok(Box::new(response))
+        } else {
+            Err(response_error(&context, response))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_build_with_custom_key_env() {
+        std::env::set_var("SMOLAGENTS_TEST_PROVIDER_KEY", "secret");
+        let model = GenericOpenAIModelBuilder::new("http://localhost:8000/v1/chat/completions")
+            .model_id("llama-3.1-8b")
+            .api_key_env("SMOLAGENTS_TEST_PROVIDER_KEY")
+            .header("HTTP-Referer", "https://example.com")
+            .build()
+            .unwrap();
+        assert_eq!(model.base_url, "http://localhost:8000/v1/chat/completions");
+        assert_eq!(model.model_id, "llama-3.1-8b");
+        assert_eq!(model.api_key.as_deref(), Some("secret"));
+        assert_eq!(
+            model.headers.get("HTTP-Referer").map(String::as_str),
+            Some("https://example.com")
+        );
+
+        let missing = GenericOpenAIModelBuilder::new("http://localhost:8000/v1/chat/completions")
+            .api_key_env("SMOLAGENTS_TEST_MISSING_KEY")
+            .build();
+        assert!(missing.is_err());
+    }
+
+    #[test]
+    fn test_build_with_pool_settings() {
+        let model = GenericOpenAIModelBuilder::new("http://localhost:8000/v1/chat/completions")
+            .pool_max_idle_per_host(16)
+            .pool_idle_timeout(Some(Duration::from_secs(30)))
+            .build()
+            .unwrap();
+        assert_eq!(model.model_id, "gpt-4o-mini");
+    }
+}
@@ -1,15 +1,16 @@
+use serde_json::{json, Value};
 use std::collections::HashMap;
-use serde_json::json;
 
 use crate::{
     errors::AgentError,
+    models::error_body::{response_error, send_error},
     models::model_traits::{Model, ModelResponse},
-    models::openai::ToolCall,
+    models::openai::{OpenAIResponse, ToolCall},
+    models::sse::{read_chat_completion_stream, read_text_generation_stream},
     models::types::{Message, MessageRole},
     tools::ToolInfo,
 };
 
-
 #[derive(Debug)]
 pub struct HuggingFaceResponse {
     text: String,
@@ -25,6 +26,64 @@ impl ModelResponse for HuggingFaceResponse {
     }
 }
 
+/// A chat template rendering the messages into the prompt of the text-generation task.
+///
+/// `message` is rendered for every message, with `{role}` replaced by `system`, `user` or `assistant` and
+/// `{content}` by the content of the message. `generation_prompt` is appended to start the answer of the
+/// assistant.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ChatTemplate {
+    pub message: String,
+    pub generation_prompt: String,
+}
+
+impl ChatTemplate {
+    pub fn new(message: &str, generation_prompt: &str) -> Self {
+        Self {
+            message: message.to_string(),
+            generation_prompt: generation_prompt.to_string(),
+        }
+    }
+
+    /// The chat template of Zephyr, the default model.
+    pub fn zephyr() -> Self {
+        Self::new("<|{role}|>\n{content}</s>\n", "<|assistant|>\n")
+    }
+
+    /// Render the messages into a prompt. Tool calls are written by the assistant and their results
+    /// given back by the user.
+    pub fn apply(&self, messages: &[Message]) -> String {
+        let mut prompt = messages
+            .iter()
+            .map(|message| {
+                let role = match message.role {
+                    MessageRole::System => "system",
+                    MessageRole::User | MessageRole::ToolResponse => "user",
+                    MessageRole::Assistant | MessageRole::ToolCall => "assistant",
+                };
+                self.message
+                    .replace("{role}", role)
+                    .replace("{content}", &message.content)
+            })
+            .collect::<String>();
+        prompt.push_str(&self.generation_prompt);
+        prompt
+    }
+}
+
+/// How the messages are sent to the model.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub enum PromptFormat {
+    /// A `Role: content` transcript for the text-generation task.
+    #[default]
+    Transcript,
+    /// A prompt rendered with the given chat template for the text-generation task.
+    ChatTemplate(ChatTemplate),
+    /// The messages themselves, sent to the OpenAI compatible chat-completions endpoint of the model. The
+    /// server applies the chat template of the model, and the model can call tools.
+    ChatCompletions,
+}
+
 #[derive(Debug, Clone)]
 pub struct HuggingFaceModel {
     pub base_url: String,
@@ -32,6 +91,7 @@ pub struct HuggingFaceModel {
     pub client: reqwest::blocking::Client,
     pub api_key: String,
     pub temperature: f32,
+    pub prompt_format: PromptFormat,
 }
 
 impl HuggingFaceModel {
@@ -41,10 +101,11 @@ impl HuggingFaceModel {
         temperature: Option<f32>,
         api_key: Option<String>,
     ) -> Self {
-        let api_key = api_key.unwrap_or_else(|| {
-            std::env::var("HF_API_KEY").expect("HF_API_KEY must be set")
-        });
-        let model_id = model_id.unwrap_or("HuggingFaceH4/zephyr-7b-beta").to_string();
+        let api_key =
+            api_key.unwrap_or_else(|| std::env::var("HF_API_KEY").expect("HF_API_KEY must be set"));
+        let model_id = model_id
+            .unwrap_or("HuggingFaceH4/zephyr-7b-beta")
+            .to_string();
         let base_url = base_url
             .unwrap_or("https://api-inference.huggingface.co/models")
             .to_string();
@@ -55,72 +116,160 @@ impl HuggingFaceModel {
             client,
             api_key,
             temperature: temperature.unwrap_or(0.5),
+            prompt_format: PromptFormat::default(),
         }
     }
-}
 
-impl Model for HuggingFaceModel {
-    fn run(
+    /// Send the messages to the chat-completions endpoint of the model, for models that support it. The
+    /// model's own chat template is applied and tools are available.
+    pub fn with_chat_completions(mut self) -> Self {
+        self.prompt_format = PromptFormat::ChatCompletions;
+        self
+    }
+
+    /// Render the messages with `chat_template` instead of joining them into a transcript.
+    pub fn with_chat_template(mut self, chat_template: ChatTemplate) -> Self {
+        self.prompt_format = PromptFormat::ChatTemplate(chat_template);
+        self
+    }
+
+    fn url(&self) -> String {
+        match self.prompt_format {
+            PromptFormat::ChatCompletions => {
+                format!("{}/{}/v1/chat/completions", self.base_url, self.model_id)
+            }
+            _ => format!("{}/{}", self.base_url, self.model_id),
+        }
+    }
+
+    /// The body of a request, for the chat-completions endpoint or the text-generation task depending on
+    /// the prompt format. The extra arguments override the other fields of a chat completion.
+    fn request_body(
         &self,
-        messages: Vec<Message>,
-        _tools_to_call_from: Vec<ToolInfo>,
+        messages: &[Message],
+        tools_to_call_from: Vec<ToolInfo>,
         max_tokens: Option<usize>,
-        _args: Option<HashMap<String, Vec<String>>>,
-    ) -> Result<Box<dyn ModelResponse>, AgentError> {
-        let conversation = messages
-            .iter()
-            .map(|m| format!("{}: {}", match m.role {
-                MessageRole::User => "User",
-                MessageRole::Assistant => "Assistant",
-                MessageRole::System => "System",
-                MessageRole::ToolCall => "Tool",
-                MessageRole::ToolResponse => "ToolResponse",
-            }, m.content))
-            .collect::<Vec<_>>()
-            .join("\n");
-
-        let body = json!({
-            "inputs": conversation,
+        args: Option<HashMap<String, Vec<String>>>,
+    ) -> Value {
+        let max_tokens = max_tokens.unwrap_or(1500);
+        let inputs = match &self.prompt_format {
+            PromptFormat::ChatCompletions => {
+                let messages = messages
+                    .iter()
+                    .map(|message| {
+                        json!({
+                            "role": message.role,
+                            "content": message.content
+                        })
+                    })
+                    .collect::<Vec<_>>();
+                let mut body = json!({
+                    "model": self.model_id,
+                    "messages": messages,
+                    "temperature": self.temperature,
+                    "max_tokens": max_tokens,
+                });
+                if !tools_to_call_from.is_empty() {
+                    body["tools"] = json!(tools_to_call_from);
+                    body["tool_choice"] = json!("required");
+                }
+                let body_map = body.as_object_mut().unwrap();
+                for (key, value) in args.unwrap_or_default() {
+                    body_map.insert(key, json!(value));
+                }
+                return body;
+            }
+            PromptFormat::ChatTemplate(chat_template) => chat_template.apply(messages),
+            PromptFormat::Transcript => messages
+                .iter()
+                .map(|m| {
+                    format!(
+                        "{}: {}",
+                        match m.role {
+                            MessageRole::User => "User",
+                            MessageRole::Assistant => "Assistant",
+                            MessageRole::System => "System",
+                            MessageRole::ToolCall => "Tool",
+                            MessageRole::ToolResponse => "ToolResponse",
+                        },
+                        m.content
+                    )
+                })
+                .collect::<Vec<_>>()
+                .join("\n"),
+        };
+        json!({
+            "inputs": inputs,
             "parameters": {
-                "max_new_tokens": max_tokens.unwrap_or(1500),
+                "max_new_tokens": max_tokens,
                 "temperature": self.temperature
             }
-        });
+        })
+    }
 
-        let url = format!("{}/{}", self.base_url, self.model_id);
+    fn send(&self, body: &Value) -> Result<reqwest::blocking::Response, AgentError> {
         let response = self
             .client
-            .post(&url)
+            .post(self.url())
             .header("Authorization", format!("Bearer {}", self.api_key))
-            .json(&body)
+            .json(body)
             .send()
-            .map_err(|e| AgentError::Generation(format!("Failed to get response from Hugging Face: {}", e)))?;
-
+            .map_err(|e| send_error("Failed to get response from Hugging Face", e))?;
         if response.status().is_success() {
-            let value: serde_json::Value = response
-                .json()
-                .map_err(|e| AgentError::Generation(e.to_string()))?;
-            let text = if let Some(arr) = value.as_array() {
-                arr.first()
-                    .and_then(|v| v.get("generated_text"))
-                    .and_then(|v| v.as_str())
-                    .unwrap_or_default()
-                    .to_string()
-            } else {
-                value
-                    .get("generated_text")
-                    .and_then(|v| v.as_str())
-                    .unwrap_or_default()
-                    .to_string()
-            };
-            Ok(Box::new(HuggingFaceResponse { text }))
+            Ok(response)
         } else {
-            Err(AgentError::Generation(format!(
-                "Failed to get response from Hugging Face: {}",
-                response.text().unwrap_or_default()
-            )))
+            Err(response_error(
+                "Failed to get response from Hugging Face",
+                response,
+            ))
         }
     }
+}
+
+impl Model for HuggingFaceModel {
+    fn model_id(&self) -> Option<&str> {
+        Some(&self.model_id)
+    }
+
+    /// Only the chat-completions endpoint takes tools, the text-generation task ignores them.
+    fn supports_tool_calling(&self) -> bool {
+        self.prompt_format == PromptFormat::ChatCompletions
+    }
+
+    fn run(
+        &self,
+        messages: Vec<Message>,
+        tools_to_call_from: Vec<ToolInfo>,
+        max_tokens: Option<usize>,
+        args: Option<HashMap<String, Vec<String>>>,
+    ) -> Result<Box<dyn ModelResponse>, AgentError> {
+        let body = self.request_body(&messages, tools_to_call_from, max_tokens, args);
+        let response = self.send(&body)?;
+
+        if self.prompt_format == PromptFormat::ChatCompletions {
+            let response = response
+                .json::<OpenAIResponse>()
+                .map_err(|e| AgentError::Generation(format!("Failed to parse response: {}", e)))?;
+            return Ok(Box::new(response));
+        }
+        let value: Value = response
+            .json()
+            .map_err(|e| AgentError::Generation(e.to_string()))?;
+        let text = if let Some(arr) = value.as_array() {
+            arr.first()
+                .and_then(|v| v.get("generated_text"))
+                .and_then(|v| v.as_str())
+                .unwrap_or_default()
+                .to_string()
+        } else {
+            value
+                .get("generated_text")
+                .and_then(|v| v.as_str())
+                .unwrap_or_default()
+                .to_string()
+        };
+        Ok(Box::new(HuggingFaceResponse { text }))
+    }
 
     fn run_stream(
         &self,
@@ -130,13 +279,72 @@ impl Model for HuggingFaceModel {
         args: Option<HashMap<String, Vec<String>>>,
         callback: &mut dyn FnMut(&str),
     ) -> Result<Box<dyn ModelResponse>, AgentError> {
-        let response = self.run(messages, tools, max_tokens, args)?;
-        let text = response.get_response()?;
-        for token in text.split_whitespace() {
-            callback(token);
-            callback(" ");
+        let mut body = self.request_body(&messages, tools, max_tokens, args);
+        body["stream"] = json!(true);
+        let response = std::io::BufReader::new(self.send(&body)?);
+
+        if self.prompt_format == PromptFormat::ChatCompletions {
+            return Ok(Box::new(read_chat_completion_stream(response, callback)?));
         }
-        Ok(response)
+        let text = read_text_generation_stream(response, callback)?;
+        Ok(Box::new(HuggingFaceResponse { text }))
     }
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn messages() -> Vec<Message> {
+        vec![
+            Message {
+                role: MessageRole::System,
+                content: "You are a helpful assistant".to_string(),
+            },
+            Message {
+                role: MessageRole::User,
+                content: "Hello".to_string(),
+            },
+        ]
+    }
+
+    #[test]
+    fn test_chat_completions_request_body() {
+        let model = HuggingFaceModel::new(None, None, Some(0.2), Some("key".to_string()))
+            .with_chat_completions();
+        assert!(model.supports_tool_calling());
+        assert_eq!(
+            model.url(),
+            "https://api-inference.huggingface.co/models/HuggingFaceH4/zephyr-7b-beta/v1/chat/completions"
+        );
+
+        let args = HashMap::from([("stop".to_string(), vec!["Observation:".to_string()])]);
+        let body = model.request_body(&messages(), vec![], Some(100), Some(args));
+        assert_eq!(
+            body,
+            json!({
+                "model": "HuggingFaceH4/zephyr-7b-beta",
+                "messages": [
+                    {"role": "system", "content": "You are a helpful assistant"},
+                    {"role": "user", "content": "Hello"},
+                ],
+                "temperature": 0.2f32,
+                "max_tokens": 100,
+                "stop": ["Observation:"],
+            })
+        );
+    }
+
+    #[test]
+    fn test_chat_template() {
+        let model = HuggingFaceModel::new(None, None, None, Some("key".to_string()))
+            .with_chat_template(ChatTemplate::zephyr());
+        assert!(!model.supports_tool_calling());
+        let body = model.request_body(&messages(), vec![], None, None);
+        assert_eq!(
+            body["inputs"],
+            "<|system|>\nYou are a helpful assistant</s>\n<|user|>\nHello</s>\n<|assistant|>\n"
+        );
+        assert_eq!(body["parameters"]["max_new_tokens"], 1500);
+    }
+}
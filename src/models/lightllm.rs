@@ -7,9 +7,11 @@ use serde_json::json;
 use crate::{
     errors::AgentError,
     models::{
+        error_body::{response_error, send_error},
         model_traits::{Model, ModelResponse},
-        openai::{AssistantMessage, Choice, OpenAIResponse},
-        types::{Message, MessageRole},
+        openai::OpenAIResponse,
+        sse::read_chat_completion_stream,
+        types::Message,
     },
     tools::ToolInfo,
 };
@@ -42,6 +44,10 @@ impl LightLLMModel {
 }
 
 impl Model for LightLLMModel {
+    fn model_id(&self) -> Option<&str> {
+        Some(&self.model_id)
+    }
+
     fn run(
         &self,
         messages: Vec<Message>,
@@ -79,19 +85,19 @@ impl Model for LightLLMModel {
         if let Some(key) = &self.api_key {
             request = request.header("Authorization", format!("Bearer {}", key));
         }
-        let response = request.send().map_err(|e| {
-            AgentError::Generation(format!("Failed to get response from LightLLM: {}", e))
-        })?;
+        let response = request
+            .send()
+            .map_err(|e| send_error("Failed to get response from LightLLM", e))?;
         if response.status().is_success() {
             let resp: OpenAIResponse = response
                 .json()
                 .map_err(|e| AgentError::Generation(e.to_string()))?;
             Ok(Box::new(resp))
         } else {
-            Err(AgentError::Generation(format!(
-                "Failed to get response from LightLLM: {}",
-                response.text().unwrap_or_default()
-            )))
+            Err(response_error(
+                "Failed to get response from LightLLM",
+                response,
+            ))
         }
     }
 
@@ -129,40 +135,11 @@ impl Model for LightLLMModel {
         if let Some(key) = &self.api_key {
             request = request.header("Authorization", format!("Bearer {}", key));
         }
-        let response = request.send().map_err(|e| {
-            AgentError::Generation(format!("Failed to get response from LightLLM: {}", e))
-        })?;
-
-        use std::io::{BufRead, BufReader};
-        let mut reader = BufReader::new(response);
-        let mut content = String::new();
-        let mut line = String::new();
-        while reader.read_line(&mut line).map_err(|e| AgentError::Generation(e.to_string()))? > 0 {
-            if line.starts_with("data: ") {
-                let data = line.trim_start_matches("data: ").trim();
-                if data == "[DONE]" {
-                    break;
-                }
-                if let Ok(val) = serde_json::from_str::<serde_json::Value>(data) {
-                    if let Some(token) = val["choices"][0]["delta"]["content"].as_str() {
-                        callback(token);
-                        content.push_str(token);
-                    }
-                }
-            }
-            line.clear();
-        }
+        let response = request
+            .send()
+            .map_err(|e| send_error("Failed to get response from LightLLM", e))?;
 
-        let response = OpenAIResponse {
-            choices: vec![Choice {
-                message: AssistantMessage {
-                    role: MessageRole::Assistant,
-                    content: Some(content),
-                    tool_calls: None,
-                    refusal: None,
-                },
-            }],
-        };
+        let response = read_chat_completion_stream(std::io::BufReader::new(response), callback)?;
         Ok(Box::new(response))
     }
 }
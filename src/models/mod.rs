@@ -1,8 +1,16 @@
 pub mod azure;
+#[cfg(feature = "bedrock")]
+pub mod bedrock;
+pub mod caching;
+pub mod error_body;
 pub mod candle;
+pub mod generic_openai;
 pub mod huggingface;
 pub mod lightllm;
 pub mod model_traits;
 pub mod ollama;
 pub mod openai;
+pub mod openai_responses;
+pub mod pool;
+pub mod sse;
 pub mod types;
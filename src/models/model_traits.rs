@@ -1,4 +1,5 @@
 use std::collections::HashMap;
+use std::fmt::Debug;
 
 use crate::{
     errors::AgentError,
@@ -6,12 +7,45 @@ use crate::{
     tools::tool_traits::ToolInfo,
 };
 use anyhow::Result;
+use serde::Deserialize;
+
+/// The number of tokens of a request and of its response, as reported by the backend.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+pub struct TokenUsage {
+    #[serde(alias = "prompt_tokens")]
+    pub input_tokens: usize,
+    #[serde(alias = "completion_tokens")]
+    pub output_tokens: usize,
+}
+
 pub trait ModelResponse {
     fn get_response(&self) -> Result<String, AgentError>;
     fn get_tools_used(&self) -> Result<Vec<ToolCall>, AgentError>;
+
+    /// The model that served the response as reported by the backend, e.g. `gpt-4o-2024-08-06
+    /// (fp_6b68a8204b)` with the system fingerprint, for auditing. `None` if the backend does not report it.
+    fn get_model_info(&self) -> Option<String> {
+        None
+    }
+
+    /// The number of tokens the request and the response used. `None` if the backend does not report it.
+    fn get_token_usage(&self) -> Option<TokenUsage> {
+        None
+    }
 }
 
 pub trait Model {
+    /// The id of the model serving the requests, e.g. `gpt-4o-mini`, if the backend has one.
+    fn model_id(&self) -> Option<&str> {
+        None
+    }
+
+    /// Whether the model supports native tool calling. The agents make the models that do not write
+    /// their tool calls as text, see [`crate::agents::ToolCallingStyle::TextReAct`].
+    fn supports_tool_calling(&self) -> bool {
+        true
+    }
+
     fn run(
         &self,
         input_messages: Vec<Message>,
@@ -34,3 +68,9 @@ pub trait Model {
         Ok(response)
     }
 }
+
+/// A model turning texts into embedding vectors, e.g. to search documents by meaning.
+pub trait Embedder: Debug + Send + Sync {
+    /// The embeddings of `texts`, in the same order.
+    fn embed(&self, texts: &[String]) -> Result<Vec<Vec<f32>>, AgentError>;
+}
@@ -1,20 +1,29 @@
 use std::collections::HashMap;
+use std::sync::OnceLock;
+use std::time::Duration;
 
 use serde::Deserialize;
 use serde_json::json;
 
 use crate::{errors::AgentError, tools::ToolInfo};
 use anyhow::Result;
+use log::warn;
 
 use super::{
-    model_traits::{Model, ModelResponse},
+    error_body::{response_error, send_error},
+    model_traits::{Embedder, Model, ModelResponse, TokenUsage},
     openai::ToolCall,
+    pool::PoolSettings,
     types::{Message, MessageRole},
 };
 
 #[derive(Debug, Deserialize)]
 pub struct OllamaResponse {
     pub message: AssistantMessage,
+    /// The number of tokens of the prompt.
+    pub prompt_eval_count: Option<usize>,
+    /// The number of tokens of the response.
+    pub eval_count: Option<usize>,
 }
 
 #[derive(Debug, Deserialize)]
@@ -32,6 +41,33 @@ impl ModelResponse for OllamaResponse {
     fn get_tools_used(&self) -> Result<Vec<ToolCall>, AgentError> {
         Ok(self.message.tool_calls.clone().unwrap_or_default())
     }
+
+    fn get_token_usage(&self) -> Option<TokenUsage> {
+        Some(TokenUsage {
+            input_tokens: self.prompt_eval_count?,
+            output_tokens: self.eval_count?,
+        })
+    }
+}
+
+/// The details of a model given by `/api/show`.
+#[derive(Debug, Deserialize)]
+struct OllamaShowResponse {
+    /// What the model can do, e.g. `completion` and `tools`. Older servers do not report it.
+    capabilities: Option<Vec<String>>,
+    #[serde(default)]
+    template: String,
+}
+
+impl OllamaShowResponse {
+    /// Whether the model supports tool calling, from its capabilities or else from whether its chat
+    /// template renders the tools.
+    fn supports_tools(&self) -> bool {
+        match &self.capabilities {
+            Some(capabilities) => capabilities.iter().any(|capability| capability == "tools"),
+            None => self.template.contains(".Tools"),
+        }
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -41,6 +77,8 @@ pub struct OllamaModel {
     url: String,
     client: reqwest::blocking::Client,
     ctx_length: usize,
+    /// Whether the model supports tool calling, probed once with `/api/show`.
+    tool_support: OnceLock<bool>,
 }
 
 #[derive(Default)]
@@ -50,17 +88,18 @@ pub struct OllamaModelBuilder {
     client: Option<reqwest::blocking::Client>,
     url: Option<String>,
     ctx_length: Option<usize>,
+    pool: PoolSettings,
 }
 
 impl OllamaModelBuilder {
     pub fn new() -> Self {
-        let client = reqwest::blocking::Client::new();
         Self {
             model_id: "llama3.2".to_string(),
             temperature: Some(0.5),
-            client: Some(client),
+            client: None,
             url: Some("http://localhost:11434".to_string()),
             ctx_length: Some(2048),
+            pool: PoolSettings::default(),
         }
     }
 
@@ -84,18 +123,72 @@ impl OllamaModelBuilder {
         self
     }
 
+    /// Keep at most `max_idle_per_host` idle connections open. See [`crate::models::pool`] for sane values.
+    pub fn pool_max_idle_per_host(mut self, max_idle_per_host: usize) -> Self {
+        self.pool.max_idle_per_host = max_idle_per_host;
+        self
+    }
+
+    /// Close idle connections after `idle_timeout`, never if `None`.
+    pub fn pool_idle_timeout(mut self, idle_timeout: Option<Duration>) -> Self {
+        self.pool.idle_timeout = idle_timeout;
+        self
+    }
+
     pub fn build(self) -> OllamaModel {
         OllamaModel {
             model_id: self.model_id,
             temperature: self.temperature.unwrap_or(0.5),
             url: self.url.unwrap_or("http://localhost:11434".to_string()),
-            client: self.client.unwrap_or_default(),
+            client: self.client.unwrap_or_else(|| self.pool.client()),
             ctx_length: self.ctx_length.unwrap_or(2048),
+            tool_support: OnceLock::new(),
         }
     }
 }
 
+impl OllamaModel {
+    /// Ask the server whether the model supports tool calling.
+    fn probe_tool_support(&self) -> Result<bool, AgentError> {
+        let response = self
+            .client
+            .post(format!("{}/api/show", self.url))
+            .json(&json!({ "model": self.model_id }))
+            .send()
+            .map_err(|e| send_error("Failed to get model details from Ollama", e))?;
+        if !response.status().is_success() {
+            return Err(response_error(
+                "Failed to get model details from Ollama",
+                response,
+            ));
+        }
+        let details = response.json::<OllamaShowResponse>().map_err(|e| {
+            AgentError::Generation(format!("Invalid model details from Ollama: {}", e))
+        })?;
+        Ok(details.supports_tools())
+    }
+}
+
 impl Model for OllamaModel {
+    fn model_id(&self) -> Option<&str> {
+        Some(&self.model_id)
+    }
+
+    /// Many models served by Ollama do not support tool calling, and the server rejects the requests
+    /// giving them tools. The server is asked once; if it cannot tell, the model is assumed to support
+    /// tool calling.
+    fn supports_tool_calling(&self) -> bool {
+        *self
+            .tool_support
+            .get_or_init(|| match self.probe_tool_support() {
+                Ok(supported) => supported,
+                Err(e) => {
+                    warn!("Failed to detect tool support of {}: {}", self.model_id, e);
+                    true
+                }
+            })
+    }
+
     fn run(
         &self,
         messages: Vec<Message>,
@@ -113,8 +206,6 @@ impl Model for OllamaModel {
             })
             .collect::<Vec<_>>();
 
-        let tools = json!(tools_to_call_from);
-
         let mut body = json!({
             "model": self.model_id,
             "messages": messages,
@@ -123,9 +214,11 @@ impl Model for OllamaModel {
             "options": json!({
                 "num_ctx": self.ctx_length,
             }),
-            "tools": tools,
             "max_tokens": max_tokens.unwrap_or(1500),
         });
+        if !tools_to_call_from.is_empty() && self.supports_tool_calling() {
+            body["tools"] = json!(tools_to_call_from);
+        }
         if let Some(args) = args {
             for (key, value) in args {
                 body["options"][key] = json!(value);
@@ -137,10 +230,190 @@ impl Model for OllamaModel {
             .post(format!("{}/api/chat", self.url))
             .json(&body)
             .send()
-            .map_err(|e| {
-                AgentError::Generation(format!("Failed to get response from Ollama: {}", e))
-            })?;
-        let output = response.json::<OllamaResponse>().unwrap();
+            .map_err(|e| send_error("Failed to get response from Ollama", e))?;
+        if !response.status().is_success() {
+            return Err(response_error(
+                "Failed to get response from Ollama",
+                response,
+            ));
+        }
+        let output = response
+            .json::<OllamaResponse>()
+            .map_err(|e| AgentError::Generation(format!("Failed to parse response: {}", e)))?;
         Ok(Box::new(output))
     }
 }
+
+/// Embeddings from a local Ollama server, e.g. for [`RagTool::new_with_embeddings`](crate::tools::RagTool::new_with_embeddings).
+#[derive(Debug, Clone)]
+pub struct OllamaEmbeddingModel {
+    pub model_id: String,
+    pub url: String,
+    pub client: reqwest::blocking::Client,
+}
+
+#[derive(Debug, Deserialize)]
+struct OllamaEmbeddingResponse {
+    embedding: Vec<f32>,
+}
+
+impl OllamaEmbeddingModel {
+    /// Use the embedding model `model_id`, `nomic-embed-text` by default, of the server at `url`,
+    /// `http://localhost:11434` by default.
+    pub fn new(model_id: Option<&str>, url: Option<&str>) -> Self {
+        Self {
+            model_id: model_id.unwrap_or("nomic-embed-text").to_string(),
+            url: url.unwrap_or("http://localhost:11434").to_string(),
+            client: reqwest::blocking::Client::new(),
+        }
+    }
+}
+
+impl Embedder for OllamaEmbeddingModel {
+    fn embed(&self, texts: &[String]) -> Result<Vec<Vec<f32>>, AgentError> {
+        // The endpoint embeds one text per request
+        texts
+            .iter()
+            .map(|text| {
+                let response = self
+                    .client
+                    .post(format!("{}/api/embeddings", self.url))
+                    .json(&json!({
+                        "model": self.model_id,
+                        "prompt": text,
+                    }))
+                    .send()
+                    .map_err(|e| send_error("Failed to get embeddings from Ollama", e))?;
+                if !response.status().is_success() {
+                    return Err(response_error(
+                        "Failed to get embeddings from Ollama",
+                        response,
+                    ));
+                }
+                let response = response.json::<OllamaEmbeddingResponse>().map_err(|e| {
+                    AgentError::Generation(format!("Invalid embeddings from Ollama: {}", e))
+                })?;
+                Ok(response.embedding)
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_server::{serve, Request, Response};
+    use crate::tools::{AnyTool, FinalAnswerTool};
+    use serde_json::Value;
+
+    /// Answer the next requests with `responses`, one each.
+    fn serve_json(responses: Vec<Value>) -> (String, std::thread::JoinHandle<Vec<Request>>) {
+        serve(responses.len(), move |i, _| {
+            Response::json(200, responses[i].clone())
+        })
+    }
+
+    fn model(url: String, model_id: &str) -> OllamaModel {
+        OllamaModelBuilder::new()
+            .model_id(model_id)
+            .url(url)
+            .build()
+    }
+
+    #[test]
+    fn test_tool_support_probe() {
+        let (url, server) = serve_json(vec![
+            json!({"capabilities": ["completion", "tools"], "template": ""}),
+            json!({"capabilities": ["completion"], "template": ""}),
+            // Servers without capabilities render the tools in the template of the models supporting them
+            json!({"template": "{{- if .Tools }}Tools: {{ .Tools }}{{ end }}"}),
+        ]);
+        assert!(model(url.clone(), "qwen2.5").supports_tool_calling());
+        assert!(!model(url.clone(), "gemma2").supports_tool_calling());
+        assert!(model(url, "llama3.1").supports_tool_calling());
+
+        let requests = server.join().unwrap();
+        assert!(requests.iter().all(|request| request.path == "/api/show"));
+        let models = requests
+            .iter()
+            .map(|request| request.json()["model"].clone())
+            .collect::<Vec<_>>();
+        assert_eq!(models, vec!["qwen2.5", "gemma2", "llama3.1"]);
+    }
+
+    #[test]
+    fn test_failed_probe_is_cached() {
+        let (url, server) = serve(3, |i, _| match i {
+            0 => Response::json(404, json!({"error": "404 page not found"})),
+            _ => Response::json(
+                200,
+                json!({"message": {"role": "assistant", "content": "Hi"}}),
+            ),
+        });
+        let model = model(url, "llama3.1");
+        for _ in 0..2 {
+            assert!(model.supports_tool_calling());
+            let tools = vec![FinalAnswerTool::new().tool_info()];
+            model.run(vec![], tools, None, None).unwrap();
+        }
+
+        let requests = server.join().unwrap();
+        let paths = requests
+            .iter()
+            .map(|request| request.path.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(paths, vec!["/api/show", "/api/chat", "/api/chat"]);
+        assert!(requests[1].json()["tools"].is_array());
+    }
+
+    #[test]
+    fn test_tools_left_out_without_tool_support() {
+        let chat = json!({"message": {"role": "assistant", "content": "Action: final_answer"}});
+        let (url, server) = serve_json(vec![
+            json!({"capabilities": ["completion"]}),
+            chat.clone(),
+            chat,
+        ]);
+        let model = model(url, "gemma2");
+        let messages = vec![Message {
+            role: MessageRole::User,
+            content: "Hello".to_string(),
+        }];
+        for _ in 0..2 {
+            let tools = vec![FinalAnswerTool::new().tool_info()];
+            let response = model.run(messages.clone(), tools, None, None).unwrap();
+            assert_eq!(response.get_response().unwrap(), "Action: final_answer");
+        }
+
+        // The capabilities are asked once
+        let requests = server.join().unwrap();
+        let paths = requests
+            .iter()
+            .map(|request| request.path.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(paths, vec!["/api/show", "/api/chat", "/api/chat"]);
+        assert!(requests[1].json().get("tools").is_none());
+    }
+
+    #[test]
+    fn test_chat_error_is_returned() {
+        let (url, server) = serve(2, |i, _| match i {
+            0 => Response::json(200, json!({"capabilities": ["completion", "tools"]})),
+            _ => Response::json(
+                404,
+                json!({"error": "model \"qwen9\" not found, try pulling it first"}),
+            ),
+        });
+        let model = model(url, "qwen9");
+        let error = model
+            .run(vec![], vec![FinalAnswerTool::new().tool_info()], None, None)
+            .err()
+            .unwrap();
+        server.join().unwrap();
+        assert_eq!(
+            error.message(),
+            r#"Failed to get response from Ollama: HTTP 404 Not Found: {"error":"model \"qwen9\" not found, try pulling it first"}"#
+        );
+        assert!(!error.is_retryable());
+    }
+}
@@ -1,10 +1,15 @@
 use std::collections::HashMap;
+use std::time::Duration;
 
 use crate::errors::AgentError;
-use crate::models::model_traits::{Model, ModelResponse};
+use crate::models::error_body::{response_error, send_error};
+use crate::models::model_traits::{Embedder, Model, ModelResponse, TokenUsage};
+use crate::models::pool::PoolSettings;
+use crate::models::sse::read_chat_completion_stream;
 use crate::models::types::{Message, MessageRole};
 use crate::tools::ToolInfo;
 use anyhow::Result;
+use log::warn;
 use reqwest::blocking::Client;
 use serde::{Deserialize, Serialize};
 use serde_json::{json, Value};
@@ -12,6 +17,11 @@ use serde_json::{json, Value};
 #[derive(Debug, Deserialize)]
 pub struct OpenAIResponse {
     pub choices: Vec<Choice>,
+    /// The model that served the request, which can be more specific than the requested one.
+    pub model: Option<String>,
+    /// Identifies the backend configuration serving the model.
+    pub system_fingerprint: Option<String>,
+    pub usage: Option<TokenUsage>,
 }
 
 #[derive(Debug, Deserialize)]
@@ -48,18 +58,31 @@ where
     D: serde::Deserializer<'de>,
 {
     let value = Value::deserialize(deserializer)?;
+    Ok(parse_arguments(value))
+}
 
-    // If it's a string, try to parse it as JSON
-    if let Value::String(s) = &value {
-        if let Ok(parsed) = serde_json::from_str(s) {
-            return Ok(parsed);
+/// Parse arguments given as a string holding a JSON object or array, even if the string was encoded as
+/// JSON several times. Other arguments are returned unchanged.
+pub(crate) fn parse_arguments(arguments: Value) -> Value {
+    let mut parsed = arguments.clone();
+    while let Value::String(s) = &parsed {
+        match serde_json::from_str::<Value>(s) {
+            Ok(value @ (Value::String(_) | Value::Object(_) | Value::Array(_))) => parsed = value,
+            _ => break,
         }
     }
-
-    Ok(value)
+    match parsed {
+        Value::Object(_) | Value::Array(_) => parsed,
+        _ => arguments,
+    }
 }
 
 impl FunctionCall {
+    /// The arguments as a JSON object, also when the backend gave them as a string holding JSON.
+    pub fn normalized_arguments(&self) -> Value {
+        parse_arguments(self.arguments.clone())
+    }
+
     pub fn get_arguments(&self) -> Result<HashMap<String, String>> {
         // First try to parse as a HashMap directly
         if let Ok(map) = serde_json::from_value(self.arguments.clone()) {
@@ -82,16 +105,33 @@ impl FunctionCall {
 
 impl ModelResponse for OpenAIResponse {
     fn get_response(&self) -> Result<String, AgentError> {
-        Ok(self
+        let message = &self
             .choices
             .first()
             .ok_or(AgentError::Generation(
                 "No message returned from OpenAI".to_string(),
             ))?
-            .message
-            .content
-            .clone()
-            .unwrap_or_default())
+            .message;
+        match (&message.content, &message.refusal) {
+            (Some(content), _) if !content.is_empty() => Ok(content.clone()),
+            // The model declined the request, report why instead of an empty answer
+            (_, Some(refusal)) => Err(AgentError::Generation(format!(
+                "The model refused the request: {}",
+                refusal
+            ))),
+            (content, None) => Ok(content.clone().unwrap_or_default()),
+        }
+    }
+
+    fn get_model_info(&self) -> Option<String> {
+        match (&self.model, &self.system_fingerprint) {
+            (Some(model), Some(fingerprint)) => Some(format!("{} ({})", model, fingerprint)),
+            (model, fingerprint) => model.clone().or_else(|| fingerprint.clone()),
+        }
+    }
+
+    fn get_token_usage(&self) -> Option<TokenUsage> {
+        self.usage
     }
 
     fn get_tools_used(&self) -> Result<Vec<ToolCall>, AgentError> {
@@ -115,6 +155,14 @@ pub struct OpenAIServerModel {
     pub client: Client,
     pub temperature: f32,
     pub api_key: String,
+    /// Penalizes tokens by how often they already appear, between -2.0 and 2.0. Left to the server if not set.
+    pub frequency_penalty: Option<f32>,
+    /// Penalizes tokens that already appear at all, between -2.0 and 2.0. Left to the server if not set.
+    pub presence_penalty: Option<f32>,
+    /// Biases of token ids, between -100 (ban the token) and 100 (force it).
+    pub logit_bias: Option<HashMap<u32, i32>>,
+    /// The connection pool settings `client` was built with.
+    pub pool: PoolSettings,
 }
 
 impl OpenAIServerModel {
@@ -137,18 +185,51 @@ impl OpenAIServerModel {
             client,
             temperature: temperature.unwrap_or(0.5),
             api_key,
+            frequency_penalty: None,
+            presence_penalty: None,
+            logit_bias: None,
+            pool: PoolSettings::default(),
         }
     }
-}
 
-impl Model for OpenAIServerModel {
-    fn run(
+    pub fn with_frequency_penalty(mut self, frequency_penalty: f32) -> Self {
+        self.frequency_penalty = Some(frequency_penalty);
+        self
+    }
+
+    pub fn with_presence_penalty(mut self, presence_penalty: f32) -> Self {
+        self.presence_penalty = Some(presence_penalty);
+        self
+    }
+
+    pub fn with_logit_bias(mut self, logit_bias: HashMap<u32, i32>) -> Self {
+        self.logit_bias = Some(logit_bias);
+        self
+    }
+
+    /// Keep at most `max_idle_per_host` idle connections open. Replaces the client.
+    /// See [`crate::models::pool`] for sane values.
+    pub fn with_pool_max_idle_per_host(mut self, max_idle_per_host: usize) -> Self {
+        self.pool.max_idle_per_host = max_idle_per_host;
+        self.client = self.pool.client();
+        self
+    }
+
+    /// Close idle connections after `idle_timeout`, never if `None`. Replaces the client.
+    pub fn with_pool_idle_timeout(mut self, idle_timeout: Option<Duration>) -> Self {
+        self.pool.idle_timeout = idle_timeout;
+        self.client = self.pool.client();
+        self
+    }
+
+    /// The body of a chat completion request. The extra arguments override the other fields.
+    fn request_body(
         &self,
         messages: Vec<Message>,
         tools_to_call_from: Vec<ToolInfo>,
         max_tokens: Option<usize>,
         args: Option<HashMap<String, Vec<String>>>,
-    ) -> Result<Box<dyn ModelResponse>, AgentError> {
+    ) -> Value {
         let max_tokens = max_tokens.unwrap_or(1500);
 
         let messages = messages
@@ -171,6 +252,15 @@ impl Model for OpenAIServerModel {
             body["tools"] = json!(tools_to_call_from);
             body["tool_choice"] = json!("required");
         }
+        if let Some(frequency_penalty) = self.frequency_penalty {
+            body["frequency_penalty"] = json!(frequency_penalty);
+        }
+        if let Some(presence_penalty) = self.presence_penalty {
+            body["presence_penalty"] = json!(presence_penalty);
+        }
+        if let Some(logit_bias) = &self.logit_bias {
+            body["logit_bias"] = json!(logit_bias);
+        }
 
         if let Some(args) = args {
             let body_map = body.as_object_mut().unwrap();
@@ -178,6 +268,23 @@ impl Model for OpenAIServerModel {
                 body_map.insert(key, json!(value));
             }
         }
+        body
+    }
+}
+
+impl Model for OpenAIServerModel {
+    fn model_id(&self) -> Option<&str> {
+        Some(&self.model_id)
+    }
+
+    fn run(
+        &self,
+        messages: Vec<Message>,
+        tools_to_call_from: Vec<ToolInfo>,
+        max_tokens: Option<usize>,
+        args: Option<HashMap<String, Vec<String>>>,
+    ) -> Result<Box<dyn ModelResponse>, AgentError> {
+        let body = self.request_body(messages, tools_to_call_from, max_tokens, args);
 
         let response = self
             .client
@@ -185,19 +292,19 @@ impl Model for OpenAIServerModel {
             .header("Authorization", format!("Bearer {}", self.api_key))
             .json(&body)
             .send()
-            .map_err(|e| {
-                AgentError::Generation(format!("Failed to get response from OpenAI: {}", e))
-            })?;
+            .map_err(|e| send_error("Failed to get response from OpenAI", e))?;
 
         match response.status() {
             reqwest::StatusCode::OK => {
-                let response = response.json::<OpenAIResponse>().unwrap();
+                let response = response.json::<OpenAIResponse>().map_err(|e| {
+                    AgentError::Generation(format!("Failed to parse response: {}", e))
+                })?;
                 Ok(Box::new(response))
             }
-            _ => Err(AgentError::Generation(format!(
-                "Failed to get response from OpenAI: {}",
-                response.text().unwrap()
-            ))),
+            _ => Err(response_error(
+                "Failed to get response from OpenAI",
+                response,
+            )),
         }
     }
 
@@ -209,78 +316,318 @@ impl Model for OpenAIServerModel {
         args: Option<HashMap<String, Vec<String>>>,
         callback: &mut dyn FnMut(&str),
     ) -> Result<Box<dyn ModelResponse>, AgentError> {
-        let max_tokens = max_tokens.unwrap_or(1500);
+        let mut body = self.request_body(messages, tools_to_call_from, max_tokens, args);
+        body["stream"] = json!(true);
 
-        let messages = messages
-            .iter()
-            .map(|message| {
-                json!({
-                    "role": message.role,
-                    "content": message.content
-                })
-            })
-            .collect::<Vec<_>>();
-        let mut body = json!({
-            "model": self.model_id,
-            "messages": messages,
-            "temperature": self.temperature,
-            "max_tokens": max_tokens,
-            "stream": true
-        });
+        let response = self
+            .client
+            .post(&self.base_url)
+            .header("Authorization", format!("Bearer {}", self.api_key))
+            .json(&body)
+            .send()
+            .map_err(|e| send_error("Failed to get response from OpenAI", e))?;
 
-        if !tools_to_call_from.is_empty() {
-            body["tools"] = json!(tools_to_call_from);
-            body["tool_choice"] = json!("required");
+        let response =
+            read_chat_completion_stream(std::io::BufReader::new(response), callback)?;
+        Ok(Box::new(response))
+    }
+}
+
+/// The default number of texts embedded per request.
+const DEFAULT_EMBEDDING_BATCH_SIZE: usize = 96;
+
+/// Embeddings from the OpenAI embeddings API, or a compatible server.
+///
+/// The texts are sent in batches of `batch_size`. A batch that fails with a rate limit, server or
+/// connection error is retried after a delay that doubles with every attempt, the other batches are
+/// not sent again.
+#[derive(Debug, Clone)]
+pub struct OpenAIEmbeddingModel {
+    pub base_url: String,
+    pub model_id: String,
+    pub client: Client,
+    pub api_key: String,
+    /// The maximum number of texts embedded per request.
+    pub batch_size: usize,
+    /// The number of times a failed batch is retried.
+    pub max_retries: usize,
+    /// The delay before the first retry of a batch.
+    pub retry_delay: Duration,
+}
+
+#[derive(Debug, Deserialize)]
+struct EmbeddingResponse {
+    data: Vec<EmbeddingData>,
+}
+
+#[derive(Debug, Deserialize)]
+struct EmbeddingData {
+    index: usize,
+    embedding: Vec<f32>,
+}
+
+impl OpenAIEmbeddingModel {
+    /// Use the embedding model `model_id`, `text-embedding-3-small` by default. The API key defaults to
+    /// `OPENAI_API_KEY`.
+    pub fn new(base_url: Option<&str>, model_id: Option<&str>, api_key: Option<String>) -> Self {
+        let api_key = api_key
+            .or_else(|| std::env::var("OPENAI_API_KEY").ok())
+            .unwrap_or_default();
+        OpenAIEmbeddingModel {
+            base_url: base_url
+                .unwrap_or("https://api.openai.com/v1/embeddings")
+                .to_string(),
+            model_id: model_id.unwrap_or("text-embedding-3-small").to_string(),
+            client: Client::new(),
+            api_key,
+            batch_size: DEFAULT_EMBEDDING_BATCH_SIZE,
+            max_retries: 3,
+            retry_delay: Duration::from_secs(1),
         }
+    }
 
-        if let Some(args) = args {
-            let body_map = body.as_object_mut().unwrap();
-            for (key, value) in args {
-                body_map.insert(key, json!(value));
+    pub fn with_batch_size(mut self, batch_size: usize) -> Self {
+        self.batch_size = batch_size.max(1);
+        self
+    }
+
+    pub fn with_max_retries(mut self, max_retries: usize) -> Self {
+        self.max_retries = max_retries;
+        self
+    }
+
+    pub fn with_retry_delay(mut self, retry_delay: Duration) -> Self {
+        self.retry_delay = retry_delay;
+        self
+    }
+
+    /// Embed one batch, retrying it while the failure is temporary.
+    fn embed_batch(&self, texts: &[String]) -> Result<Vec<Vec<f32>>, AgentError> {
+        let mut delay = self.retry_delay;
+        let mut attempt = 0;
+        loop {
+            match self.request_batch(texts) {
+                Ok(embeddings) => return Ok(embeddings),
+                Err(error) if error.is_retryable() && attempt < self.max_retries => {
+                    warn!("{}. Retrying in {:?}", error, delay);
+                    std::thread::sleep(delay);
+                    delay *= 2;
+                    attempt += 1;
+                }
+                Err(error) => return Err(error),
             }
         }
+    }
 
+    /// Send one embeddings request. See [`AgentError::is_retryable`] for the errors worth retrying.
+    fn request_batch(&self, texts: &[String]) -> Result<Vec<Vec<f32>>, AgentError> {
         let response = self
             .client
             .post(&self.base_url)
             .header("Authorization", format!("Bearer {}", self.api_key))
-            .json(&body)
+            .json(&json!({
+                "model": self.model_id,
+                "input": texts,
+            }))
             .send()
-            .map_err(|e| {
-                AgentError::Generation(format!("Failed to get response from OpenAI: {}", e))
-            })?;
-
-        use std::io::{BufRead, BufReader};
-
-        let mut reader = BufReader::new(response);
-        let mut content = String::new();
-        let mut line = String::new();
-        while reader.read_line(&mut line).map_err(|e| AgentError::Generation(e.to_string()))? > 0 {
-            if line.starts_with("data: ") {
-                let data = line.trim_start_matches("data: ").trim();
-                if data == "[DONE]" {
-                    break;
-                }
-                if let Ok(val) = serde_json::from_str::<serde_json::Value>(data) {
-                    if let Some(token) = val["choices"][0]["delta"]["content"].as_str() {
-                        callback(token);
-                        content.push_str(token);
-                    }
-                }
-            }
-            line.clear();
+            .map_err(|e| send_error("Failed to get embeddings from OpenAI", e))?;
+        if !response.status().is_success() {
+            return Err(response_error(
+                "Failed to get embeddings from OpenAI",
+                response,
+            ));
         }
+        let mut response = response.json::<EmbeddingResponse>().map_err(|e| {
+            AgentError::Generation(format!("Invalid embeddings from OpenAI: {}", e))
+        })?;
+        if response.data.len() != texts.len() {
+            return Err(AgentError::Generation(format!(
+                "Invalid embeddings from OpenAI: {} embeddings for {} texts",
+                response.data.len(),
+                texts.len()
+            )));
+        }
+        // The embeddings are not guaranteed to be in the order of the inputs
+        response.data.sort_by_key(|data| data.index);
+        Ok(response
+            .data
+            .into_iter()
+            .map(|data| data.embedding)
+            .collect())
+    }
+}
 
-        let response = OpenAIResponse {
-            choices: vec![Choice {
-                message: AssistantMessage {
-                    role: MessageRole::Assistant,
-                    content: Some(content),
-                    tool_calls: None,
-                    refusal: None,
-                },
-            }],
-        };
-        Ok(Box::new(response))
+impl Embedder for OpenAIEmbeddingModel {
+    fn embed(&self, texts: &[String]) -> Result<Vec<Vec<f32>>, AgentError> {
+        let mut embeddings = Vec::with_capacity(texts.len());
+        // `batch_size` is public, a batch size of 0 set without `with_batch_size` sends one text per request
+        for batch in texts.chunks(self.batch_size.max(1)) {
+            embeddings.extend(self.embed_batch(batch)?);
+        }
+        Ok(embeddings)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_server::{serve, Response};
+
+    #[test]
+    fn test_refusal_is_surfaced() {
+        let response: OpenAIResponse = serde_json::from_str(
+            r#"{
+                "id": "chatcmpl-123",
+                "object": "chat.completion",
+                "choices": [{
+                    "index": 0,
+                    "message": {
+                        "role": "assistant",
+                        "content": null,
+                        "refusal": "I'm sorry, I can't help with that request."
+                    },
+                    "finish_reason": "stop"
+                }]
+            }"#,
+        )
+        .unwrap();
+        let error = response.get_response().unwrap_err();
+        assert_eq!(
+            error.message(),
+            "The model refused the request: I'm sorry, I can't help with that request."
+        );
+        assert!(response.get_tools_used().unwrap().is_empty());
+        assert_eq!(response.get_model_info(), None);
+    }
+
+    #[test]
+    fn test_model_info() {
+        let response: OpenAIResponse = serde_json::from_str(
+            r#"{
+                "id": "chatcmpl-B9MHDbslfkBeAs8l4bebGdFOJ6PeG",
+                "object": "chat.completion",
+                "created": 1741570283,
+                "model": "gpt-4o-2024-08-06",
+                "choices": [{
+                    "index": 0,
+                    "message": {"role": "assistant", "content": "Hello!", "refusal": null},
+                    "finish_reason": "stop"
+                }],
+                "usage": {"prompt_tokens": 9, "completion_tokens": 3, "total_tokens": 12},
+                "system_fingerprint": "fp_fc9f1d7035"
+            }"#,
+        )
+        .unwrap();
+        assert_eq!(
+            response.get_model_info().as_deref(),
+            Some("gpt-4o-2024-08-06 (fp_fc9f1d7035)")
+        );
+    }
+
+    #[test]
+    fn test_penalties_only_sent_when_set() {
+        let model = OpenAIServerModel::new(None, None, None, Some("key".to_string()));
+        let body = model.request_body(vec![], vec![], None, None);
+        assert!(body.get("frequency_penalty").is_none());
+        assert!(body.get("presence_penalty").is_none());
+        assert!(body.get("logit_bias").is_none());
+
+        let model = model
+            .with_frequency_penalty(0.5)
+            .with_presence_penalty(-1.0)
+            .with_logit_bias(HashMap::from([(50256, -100)]));
+        let body = model.request_body(vec![], vec![], None, None);
+        assert_eq!(body["frequency_penalty"], json!(0.5));
+        assert_eq!(body["presence_penalty"], json!(-1.0));
+        assert_eq!(body["logit_bias"], json!({ "50256": -100 }));
+    }
+
+    #[test]
+    fn test_error_body_is_truncated() {
+        let page = format!("<html>{}</html>", "<p>Bad gateway</p>".repeat(1000));
+        let (url, server) = serve(1, move |_, _| Response::json(502, json!(page)));
+        let url = format!("{}/v1/chat/completions", url);
+        let model = OpenAIServerModel::new(Some(&url), None, None, Some("key".to_string()));
+        let error = model
+            .run(
+                vec![Message {
+                    role: MessageRole::User,
+                    content: "Hello".to_string(),
+                }],
+                vec![],
+                None,
+                None,
+            )
+            .err()
+            .unwrap();
+        server.join().unwrap();
+
+        let message = error.message();
+        assert!(message.starts_with("Failed to get response from OpenAI: HTTP 502 Bad Gateway: \"<html><p>Bad gateway"));
+        assert!(message.ends_with("more characters]"), "{}", message);
+        assert!(message.len() < 2100, "{}", message.len());
+        assert!(error.is_retryable());
+    }
+
+    #[test]
+    fn test_embeddings_are_batched_in_order() {
+        // The second request is rate limited, the embeddings come back in reverse order
+        let (url, server) = serve(4, |i, request| {
+            if i == 1 {
+                return Response::json(429, json!({"error": {"message": "Rate limit reached"}}));
+            }
+            let data = request.json()["input"]
+                .as_array()
+                .unwrap()
+                .iter()
+                .enumerate()
+                .rev()
+                .map(|(index, text)| {
+                    let n: f32 = text.as_str().unwrap()[1..].parse().unwrap();
+                    json!({"index": index, "embedding": [n]})
+                })
+                .collect::<Vec<_>>();
+            Response::json(200, json!({ "data": data }))
+        });
+        let url = format!("{}/v1/embeddings", url);
+        let model = OpenAIEmbeddingModel::new(Some(&url), None, Some("key".to_string()))
+            .with_batch_size(2)
+            .with_retry_delay(Duration::ZERO);
+        let texts = (0..5).map(|n| format!("t{}", n)).collect::<Vec<_>>();
+
+        let embeddings = model.embed(&texts).unwrap();
+        assert_eq!(
+            embeddings,
+            vec![vec![0.0], vec![1.0], vec![2.0], vec![3.0], vec![4.0]]
+        );
+        let inputs = server
+            .join()
+            .unwrap()
+            .iter()
+            .map(|request| request.json()["input"].clone())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            inputs,
+            vec![
+                json!(["t0", "t1"]),
+                json!(["t2", "t3"]),
+                json!(["t2", "t3"]),
+                json!(["t4"])
+            ]
+        );
+    }
+
+    #[test]
+    fn test_zero_batch_size() {
+        let (url, server) = serve(2, |_, _| {
+            Response::json(200, json!({"data": [{"index": 0, "embedding": [1.0]}]}))
+        });
+        let url = format!("{}/v1/embeddings", url);
+        let mut model = OpenAIEmbeddingModel::new(Some(&url), None, Some("key".to_string()));
+        model.batch_size = 0;
+        let texts = vec!["t0".to_string(), "t1".to_string()];
+
+        assert_eq!(model.embed(&texts).unwrap(), vec![vec![1.0], vec![1.0]]);
+        assert_eq!(server.join().unwrap().len(), 2);
     }
 }
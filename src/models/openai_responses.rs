@@ -0,0 +1,330 @@
+//! A model served by the OpenAI Responses API (`/v1/responses`), recommended by OpenAI for its newer
+//! models. See [`crate::models::openai::OpenAIServerModel`] for the chat completions API.
+//!
+//! The Responses API has no stop sequences, so the `stop` argument the agents give is left out of the
+//! requests.
+
+use std::collections::HashMap;
+
+use crate::errors::AgentError;
+use crate::models::error_body::{response_error, send_error};
+use crate::models::model_traits::{Model, ModelResponse, TokenUsage};
+use crate::models::openai::{parse_arguments, FunctionCall, ToolCall};
+use crate::models::types::{Message, MessageRole};
+use crate::tools::ToolInfo;
+use anyhow::Result;
+use reqwest::blocking::Client;
+use serde::Deserialize;
+use serde_json::{json, Value};
+
+#[derive(Debug, Deserialize)]
+pub struct OpenAIResponsesResponse {
+    pub output: Vec<OutputItem>,
+    /// The model that served the request, which can be more specific than the requested one.
+    pub model: Option<String>,
+    pub usage: Option<TokenUsage>,
+}
+
+/// An item of the output of a response.
+#[derive(Debug, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum OutputItem {
+    Message {
+        content: Vec<OutputContent>,
+    },
+    FunctionCall {
+        call_id: Option<String>,
+        name: String,
+        arguments: Value,
+    },
+    /// Items the agents do not use, like reasoning summaries.
+    #[serde(other)]
+    Other,
+}
+
+/// A part of the content of an output message.
+#[derive(Debug, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum OutputContent {
+    OutputText {
+        text: String,
+    },
+    Refusal {
+        refusal: String,
+    },
+    #[serde(other)]
+    Other,
+}
+
+impl ModelResponse for OpenAIResponsesResponse {
+    fn get_response(&self) -> Result<String, AgentError> {
+        let mut text = String::new();
+        let mut refusal = None;
+        for content in self.output.iter().flat_map(|item| match item {
+            OutputItem::Message { content } => content.as_slice(),
+            _ => &[],
+        }) {
+            match content {
+                OutputContent::OutputText { text: part } => text.push_str(part),
+                OutputContent::Refusal { refusal: reason } => refusal = Some(reason),
+                OutputContent::Other => {}
+            }
+        }
+        match refusal {
+            // The model declined the request, report why instead of an empty answer
+            Some(refusal) if text.is_empty() => Err(AgentError::Generation(format!(
+                "The model refused the request: {}",
+                refusal
+            ))),
+            _ => Ok(text),
+        }
+    }
+
+    fn get_tools_used(&self) -> Result<Vec<ToolCall>, AgentError> {
+        Ok(self
+            .output
+            .iter()
+            .filter_map(|item| match item {
+                OutputItem::FunctionCall {
+                    call_id,
+                    name,
+                    arguments,
+                } => Some(ToolCall {
+                    id: call_id.clone(),
+                    call_type: Some("function".to_string()),
+                    function: FunctionCall {
+                        name: name.clone(),
+                        arguments: parse_arguments(arguments.clone()),
+                    },
+                }),
+                _ => None,
+            })
+            .collect())
+    }
+
+    fn get_model_info(&self) -> Option<String> {
+        self.model.clone()
+    }
+
+    fn get_token_usage(&self) -> Option<TokenUsage> {
+        self.usage
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct OpenAIResponsesModel {
+    pub base_url: String,
+    pub model_id: String,
+    pub client: Client,
+    pub temperature: f32,
+    pub api_key: String,
+}
+
+impl OpenAIResponsesModel {
+    pub fn new(
+        base_url: Option<&str>,
+        model_id: Option<&str>,
+        temperature: Option<f32>,
+        api_key: Option<String>,
+    ) -> Self {
+        let api_key = api_key.unwrap_or_else(|| {
+            std::env::var("OPENAI_API_KEY").expect("OPENAI_API_KEY must be set")
+        });
+        let model_id = model_id.unwrap_or("gpt-4o-mini").to_string();
+        let base_url = base_url.unwrap_or("https://api.openai.com/v1/responses");
+
+        OpenAIResponsesModel {
+            base_url: base_url.to_string(),
+            model_id,
+            client: Client::new(),
+            temperature: temperature.unwrap_or(0.5),
+            api_key,
+        }
+    }
+
+    /// The body of a request. The messages are the `input` items: the tool calls recorded in the memory
+    /// are written by the assistant and their results given back by the user. The extra arguments
+    /// override the other fields.
+    fn request_body(
+        &self,
+        messages: Vec<Message>,
+        tools_to_call_from: Vec<ToolInfo>,
+        max_tokens: Option<usize>,
+        args: Option<HashMap<String, Vec<String>>>,
+    ) -> Value {
+        let input = messages
+            .iter()
+            .map(|message| {
+                let role = match message.role {
+                    MessageRole::System => "system",
+                    MessageRole::User | MessageRole::ToolResponse => "user",
+                    MessageRole::Assistant | MessageRole::ToolCall => "assistant",
+                };
+                json!({
+                    "role": role,
+                    "content": message.content
+                })
+            })
+            .collect::<Vec<_>>();
+        let mut body = json!({
+            "model": self.model_id,
+            "input": input,
+            "temperature": self.temperature,
+            "max_output_tokens": max_tokens.unwrap_or(1500),
+        });
+
+        // The function tools are not nested in a `function` object like in chat completions
+        if !tools_to_call_from.is_empty() {
+            let tools = tools_to_call_from
+                .iter()
+                .map(|tool| {
+                    json!({
+                        "type": "function",
+                        "name": tool.function.name,
+                        "description": tool.function.description,
+                        "parameters": tool.function.parameters,
+                    })
+                })
+                .collect::<Vec<_>>();
+            body["tools"] = json!(tools);
+            body["tool_choice"] = json!("required");
+        }
+
+        if let Some(args) = args {
+            let body_map = body.as_object_mut().unwrap();
+            for (key, value) in args {
+                if key != "stop" {
+                    body_map.insert(key, json!(value));
+                }
+            }
+        }
+        body
+    }
+}
+
+impl Model for OpenAIResponsesModel {
+    fn model_id(&self) -> Option<&str> {
+        Some(&self.model_id)
+    }
+
+    fn run(
+        &self,
+        messages: Vec<Message>,
+        tools_to_call_from: Vec<ToolInfo>,
+        max_tokens: Option<usize>,
+        args: Option<HashMap<String, Vec<String>>>,
+    ) -> Result<Box<dyn ModelResponse>, AgentError> {
+        let body = self.request_body(messages, tools_to_call_from, max_tokens, args);
+
+        let response = self
+            .client
+            .post(&self.base_url)
+            .header("Authorization", format!("Bearer {}", self.api_key))
+            .json(&body)
+            .send()
+            .map_err(|e| send_error("Failed to get response from OpenAI", e))?;
+
+        match response.status() {
+            reqwest::StatusCode::OK => {
+                let response = response.json::<OpenAIResponsesResponse>().map_err(|e| {
+                    AgentError::Generation(format!("Failed to parse response: {}", e))
+                })?;
+                Ok(Box::new(response))
+            }
+            _ => Err(response_error(
+                "Failed to get response from OpenAI",
+                response,
+            )),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tools::{AnyTool, FinalAnswerTool};
+
+    #[test]
+    fn test_request_body() {
+        let model = OpenAIResponsesModel::new(None, Some("gpt-4.1"), Some(0.2), Some("key".into()));
+        let messages = vec![
+            Message {
+                role: MessageRole::System,
+                content: "You are a helpful assistant".to_string(),
+            },
+            Message {
+                role: MessageRole::ToolResponse,
+                content: "Observation: 42".to_string(),
+            },
+        ];
+        let args = HashMap::from([
+            ("stop".to_string(), vec!["Observation:".to_string()]),
+            ("user".to_string(), vec!["ada".to_string()]),
+        ]);
+        let tools = vec![FinalAnswerTool::new().tool_info()];
+        let body = model.request_body(messages, tools, Some(100), Some(args));
+
+        assert_eq!(
+            body["input"],
+            json!([
+                {"role": "system", "content": "You are a helpful assistant"},
+                {"role": "user", "content": "Observation: 42"},
+            ])
+        );
+        assert_eq!(body["model"], "gpt-4.1");
+        assert_eq!(body["max_output_tokens"], 100);
+        assert_eq!(body["tools"][0]["type"], "function");
+        assert_eq!(body["tools"][0]["name"], "final_answer");
+        assert!(body["tools"][0]["parameters"]["properties"]["answer"].is_object());
+        assert_eq!(body["tool_choice"], "required");
+        assert_eq!(body["user"], json!(["ada"]));
+        assert!(body.get("stop").is_none());
+    }
+
+    #[test]
+    fn test_parse_output() {
+        let response: OpenAIResponsesResponse = serde_json::from_str(
+            r#"{
+                "id": "resp_123",
+                "object": "response",
+                "model": "gpt-4.1-2025-04-14",
+                "output": [
+                    {"type": "reasoning", "id": "rs_1", "summary": []},
+                    {
+                        "type": "message",
+                        "role": "assistant",
+                        "content": [{"type": "output_text", "text": "Let me check.", "annotations": []}]
+                    },
+                    {
+                        "type": "function_call",
+                        "id": "fc_1",
+                        "call_id": "call_1",
+                        "name": "get_weather",
+                        "arguments": "{\"city\": \"Paris\"}"
+                    }
+                ]
+            }"#,
+        )
+        .unwrap();
+
+        assert_eq!(response.get_response().unwrap(), "Let me check.");
+        let tool_calls = response.get_tools_used().unwrap();
+        assert_eq!(tool_calls.len(), 1);
+        assert_eq!(tool_calls[0].id.as_deref(), Some("call_1"));
+        assert_eq!(tool_calls[0].function.name, "get_weather");
+        assert_eq!(tool_calls[0].function.arguments, json!({"city": "Paris"}));
+        assert_eq!(
+            response.get_model_info().as_deref(),
+            Some("gpt-4.1-2025-04-14")
+        );
+
+        let refusal: OpenAIResponsesResponse = serde_json::from_str(
+            r#"{"output": [{"type": "message", "content": [{"type": "refusal", "refusal": "No."}]}]}"#,
+        )
+        .unwrap();
+        assert_eq!(
+            refusal.get_response().unwrap_err().message(),
+            "The model refused the request: No."
+        );
+    }
+}
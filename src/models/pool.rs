@@ -0,0 +1,46 @@
+//! Connection pool settings of the HTTP clients used by the models.
+//!
+//! The defaults are those of reqwest: every idle connection is kept for 90 seconds. That is fine for a
+//! few agents, but when hundreds of agents run in parallel (see [`crate::parallel`]) the idle connections
+//! can exhaust the file descriptors of the process. Keeping 8 to 32 idle connections per host and closing
+//! them after 30 seconds or so is a sane starting point for heavy workloads.
+
+use std::time::Duration;
+
+use log::warn;
+use reqwest::blocking::Client;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PoolSettings {
+    /// The maximum number of idle connections kept open per host. Unlimited by default.
+    pub max_idle_per_host: usize,
+    /// How long an idle connection is kept open, 90 seconds by default. `None` keeps them forever.
+    pub idle_timeout: Option<Duration>,
+}
+
+impl Default for PoolSettings {
+    fn default() -> Self {
+        Self {
+            max_idle_per_host: usize::MAX,
+            idle_timeout: Some(Duration::from_secs(90)),
+        }
+    }
+}
+
+impl PoolSettings {
+    /// Build an HTTP client with these pool settings. If the client cannot be built, a warning is logged
+    /// and a client with the default settings is returned.
+    pub fn client(&self) -> Client {
+        Client::builder()
+            .pool_max_idle_per_host(self.max_idle_per_host)
+            .pool_idle_timeout(self.idle_timeout)
+            .build()
+            .unwrap_or_else(|e| {
+                warn!(
+                    "Failed to build an HTTP client with {:?}, using the default pool settings: {}",
+                    self, e
+                );
+                Client::new()
+            })
+    }
+}
@@ -0,0 +1,214 @@
+//! Assembly of streamed chat completions. Providers that speak the OpenAI protocol stream a response as
+//! server-sent events, with the content and the tool-call arguments split over many `delta` chunks.
+//! The text-generation task of Hugging Face streams one `token` per event instead.
+
+use std::collections::BTreeMap;
+use std::io::BufRead;
+
+use serde::Deserialize;
+use serde_json::Value;
+
+use crate::errors::AgentError;
+use crate::models::model_traits::TokenUsage;
+use crate::models::openai::{AssistantMessage, Choice, FunctionCall, OpenAIResponse, ToolCall};
+use crate::models::types::MessageRole;
+
+/// A tool call whose fields are still being streamed.
+#[derive(Default)]
+struct PartialToolCall {
+    id: Option<String>,
+    call_type: Option<String>,
+    name: String,
+    arguments: String,
+}
+
+/// Read a chat-completions event stream, pass every content token to `callback` as it arrives and
+/// return the complete response, including the tool calls reassembled from their deltas.
+pub fn read_chat_completion_stream(
+    reader: impl BufRead,
+    callback: &mut dyn FnMut(&str),
+) -> Result<OpenAIResponse, AgentError> {
+    let mut content = String::new();
+    // Tool calls are identified by their index, only the first delta of a call has its id and name
+    let mut tool_calls = BTreeMap::<u64, PartialToolCall>::new();
+    let (mut model, mut system_fingerprint, mut usage) = (None, None, None);
+    for line in reader.lines() {
+        let line = line.map_err(|e| AgentError::Generation(e.to_string()))?;
+        let Some(data) = line.strip_prefix("data:") else {
+            continue;
+        };
+        let data = data.trim();
+        if data == "[DONE]" {
+            break;
+        }
+        let Ok(chunk) = serde_json::from_str::<Value>(data) else {
+            continue;
+        };
+        if let Some(chunk_model) = chunk["model"].as_str() {
+            model = Some(chunk_model.to_string());
+        }
+        if let Some(fingerprint) = chunk["system_fingerprint"].as_str() {
+            system_fingerprint = Some(fingerprint.to_string());
+        }
+        // Sent in the last chunk when the request asks for it with `stream_options`
+        if let Ok(chunk_usage) = TokenUsage::deserialize(&chunk["usage"]) {
+            usage = Some(chunk_usage);
+        }
+        // Azure sends chunks without choices, e.g. the content filter results of the prompt
+        let delta = &chunk["choices"][0]["delta"];
+        if let Some(token) = delta["content"].as_str() {
+            callback(token);
+            content.push_str(token);
+        }
+        for tool_call in delta["tool_calls"].as_array().into_iter().flatten() {
+            let index = tool_call["index"].as_u64().unwrap_or(0);
+            let partial = tool_calls.entry(index).or_default();
+            if let Some(id) = tool_call["id"].as_str() {
+                partial.id = Some(id.to_string());
+            }
+            if let Some(call_type) = tool_call["type"].as_str() {
+                partial.call_type = Some(call_type.to_string());
+            }
+            if let Some(name) = tool_call["function"]["name"].as_str() {
+                partial.name.push_str(name);
+            }
+            if let Some(arguments) = tool_call["function"]["arguments"].as_str() {
+                partial.arguments.push_str(arguments);
+            }
+        }
+    }
+
+    let tool_calls = tool_calls
+        .into_values()
+        .map(|partial| ToolCall {
+            id: partial.id,
+            call_type: partial.call_type,
+            function: FunctionCall {
+                name: partial.name,
+                arguments: parse_arguments(&partial.arguments),
+            },
+        })
+        .collect::<Vec<_>>();
+
+    Ok(OpenAIResponse {
+        choices: vec![Choice {
+            message: AssistantMessage {
+                role: MessageRole::Assistant,
+                content: Some(content),
+                tool_calls: (!tool_calls.is_empty()).then_some(tool_calls),
+                refusal: None,
+            },
+        }],
+        model,
+        system_fingerprint,
+        usage,
+    })
+}
+
+/// Read a Hugging Face text-generation event stream, pass every generated token to `callback` as it
+/// arrives and return the generated text. Special tokens, e.g. the end of sequence, are skipped.
+pub fn read_text_generation_stream(
+    reader: impl BufRead,
+    callback: &mut dyn FnMut(&str),
+) -> Result<String, AgentError> {
+    let mut text = String::new();
+    for line in reader.lines() {
+        let line = line.map_err(|e| AgentError::Generation(e.to_string()))?;
+        let Some(data) = line.strip_prefix("data:") else {
+            continue;
+        };
+        let Ok(event) = serde_json::from_str::<Value>(data.trim()) else {
+            continue;
+        };
+        if let Some(error) = event["error"].as_str() {
+            return Err(AgentError::Generation(error.to_string()));
+        }
+        let token = &event["token"];
+        if let (Some(token), false) = (token["text"].as_str(), token["special"] == true) {
+            callback(token);
+            text.push_str(token);
+        }
+    }
+    Ok(text)
+}
+
+/// Parse the concatenated argument fragments of a tool call, keeping them as a string if they are not
+/// valid JSON so the error can be reported to the model.
+fn parse_arguments(arguments: &str) -> Value {
+    if arguments.trim().is_empty() {
+        return Value::Object(Default::default());
+    }
+    serde_json::from_str(arguments).unwrap_or_else(|_| Value::String(arguments.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::model_traits::ModelResponse;
+    use serde_json::json;
+
+    #[test]
+    fn test_azure_tool_call_stream() {
+        let stream = [
+            r#"data: {"choices":[],"prompt_filter_results":[{"prompt_index":0,"content_filter_results":{}}]}"#,
+            r#"data: {"choices":[{"index":0,"delta":{"role":"assistant","tool_calls":[{"index":0,"id":"call_1","type":"function","function":{"name":"duckduckgo_search","arguments":""}}]}}]}"#,
+            r#"data: {"choices":[{"index":0,"delta":{"tool_calls":[{"index":0,"function":{"arguments":"{\"query\": "}}]}}]}"#,
+            r#"data: {"choices":[{"index":0,"delta":{"tool_calls":[{"index":1,"id":"call_2","type":"function","function":{"name":"final_answer","arguments":"{}"}}]}}]}"#,
+            r#"data: {"choices":[{"index":0,"delta":{"tool_calls":[{"index":0,"function":{"arguments":"\"rust\"}"}}]}}]}"#,
+            r#"data: {"choices":[{"index":0,"delta":{},"finish_reason":"tool_calls"}]}"#,
+            "data: [DONE]",
+        ]
+        .join("\n\n");
+
+        let mut tokens = Vec::new();
+        let response = read_chat_completion_stream(stream.as_bytes(), &mut |token| {
+            tokens.push(token.to_string())
+        })
+        .unwrap();
+        assert!(tokens.is_empty());
+
+        let tool_calls = response.get_tools_used().unwrap();
+        assert_eq!(tool_calls.len(), 2);
+        assert_eq!(tool_calls[0].id.as_deref(), Some("call_1"));
+        assert_eq!(tool_calls[0].function.name, "duckduckgo_search");
+        assert_eq!(tool_calls[0].function.arguments, json!({"query": "rust"}));
+        assert_eq!(tool_calls[1].function.name, "final_answer");
+        assert_eq!(tool_calls[1].function.arguments, json!({}));
+    }
+
+    #[test]
+    fn test_text_generation_stream() {
+        let stream = [
+            r#"data:{"index":1,"token":{"id":1,"text":"Hello","logprob":-0.1,"special":false},"generated_text":null,"details":null}"#,
+            r#"data:{"index":2,"token":{"id":2,"text":" world","logprob":-0.2,"special":false},"generated_text":null,"details":null}"#,
+            r#"data:{"index":3,"token":{"id":3,"text":"</s>","logprob":-0.3,"special":true},"generated_text":"Hello world","details":null}"#,
+        ]
+        .join("\n\n");
+
+        let mut tokens = Vec::new();
+        let text = read_text_generation_stream(stream.as_bytes(), &mut |token| {
+            tokens.push(token.to_string())
+        })
+        .unwrap();
+        assert_eq!(tokens, vec!["Hello", " world"]);
+        assert_eq!(text, "Hello world");
+
+        let error = r#"data:{"error":"Input validation error","error_type":"validation"}"#;
+        assert!(read_text_generation_stream(error.as_bytes(), &mut |_| {}).is_err());
+    }
+
+    #[test]
+    fn test_content_stream() {
+        let stream = "data: {\"choices\":[{\"delta\":{\"content\":\"Hello\"}}]}\n\n\
+                      data: {\"choices\":[{\"delta\":{\"content\":\" world\"}}]}\n\n\
+                      data: [DONE]\n\n";
+        let mut tokens = Vec::new();
+        let response = read_chat_completion_stream(stream.as_bytes(), &mut |token| {
+            tokens.push(token.to_string())
+        })
+        .unwrap();
+        assert_eq!(tokens, vec!["Hello", " world"]);
+        assert_eq!(response.get_response().unwrap(), "Hello world");
+        assert!(response.get_tools_used().unwrap().is_empty());
+    }
+}
@@ -25,7 +25,7 @@ impl std::fmt::Display for MessageRole {
     }
 }
 
-#[derive(Debug, Serialize, Clone)]
+#[derive(Debug, Deserialize, Serialize, Clone)]
 pub struct Message {
     pub role: MessageRole,
     pub content: String,
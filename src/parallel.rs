@@ -1,4 +1,5 @@
-use std::sync::Arc;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::{mpsc, Arc, Mutex};
 use std::thread;
 
 use anyhow::Result;
@@ -9,7 +10,9 @@ use crate::agents::Agent;
 ///
 /// # Arguments
 ///
-/// * `builder` - An `Arc` containing a closure that can create a new agent.
+/// * `builder` - An `Arc` containing a closure that can create a new agent, e.g.
+///   [`FunctionCallingAgent::fork_builder`](crate::agents::FunctionCallingAgent::fork_builder) to fork a
+///   configured agent.
 /// * `tasks` - Slice of task strings to be executed.
 ///
 /// # Returns
@@ -34,6 +37,205 @@ where
 
     handles
         .into_iter()
-        .map(|h| h.join().unwrap_or_else(|_| Err(anyhow::anyhow!("Thread panicked"))))
+        .map(|h| {
+            h.join()
+                .unwrap_or_else(|_| Err(anyhow::anyhow!("Thread panicked")))
+        })
         .collect()
 }
+
+/// Run multiple tasks in parallel and stream their results as they complete.
+///
+/// Every task runs at the same time on a fresh agent instance. See
+/// [`run_tasks_streaming_with_concurrency`] to limit the number of concurrent tasks.
+///
+/// # Returns
+///
+/// A receiver yielding `(task_index, result)` for each task in completion order, not in the order of
+/// `tasks`. The channel is closed once every task has finished.
+pub fn run_tasks_streaming<A>(
+    builder: Arc<dyn Fn() -> A + Send + Sync>,
+    tasks: &[String],
+) -> mpsc::Receiver<(usize, Result<String>)>
+where
+    A: Agent + 'static,
+{
+    run_tasks_streaming_with_concurrency(builder, tasks, tasks.len())
+}
+
+/// Run multiple tasks with at most `max_concurrency` agents at a time and stream their results as they
+/// complete, e.g. to stay under the rate limit of a model provider.
+///
+/// Tasks are started in the order of `tasks`. Each result is sent as `(task_index, result)` as soon as its
+/// task finishes, so results can arrive out of order. A panicking agent is reported as an error for its
+/// task and does not stop the others.
+pub fn run_tasks_streaming_with_concurrency<A>(
+    builder: Arc<dyn Fn() -> A + Send + Sync>,
+    tasks: &[String],
+    max_concurrency: usize,
+) -> mpsc::Receiver<(usize, Result<String>)>
+where
+    A: Agent + 'static,
+{
+    let (sender, receiver) = mpsc::channel();
+    let queue = Arc::new(Mutex::new(
+        tasks
+            .iter()
+            .cloned()
+            .enumerate()
+            .collect::<Vec<_>>()
+            .into_iter(),
+    ));
+
+    for _ in 0..max_concurrency.clamp(1, tasks.len().max(1)) {
+        let builder = builder.clone();
+        let queue = queue.clone();
+        let sender = sender.clone();
+        thread::spawn(move || loop {
+            let next = queue.lock().ok().and_then(|mut queue| queue.next());
+            let Some((index, task)) = next else {
+                break;
+            };
+            let result = panic::catch_unwind(AssertUnwindSafe(|| {
+                let mut agent = builder();
+                agent.run(&task, false, true)
+            }))
+            .unwrap_or_else(|_| Err(anyhow::anyhow!("Thread panicked")));
+            if sender.send((index, result)).is_err() {
+                // The receiver was dropped, nobody is waiting for the remaining tasks
+                break;
+            }
+        });
+    }
+
+    receiver
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::agents::FunctionCallingAgent;
+    use crate::errors::AgentError;
+    use crate::models::model_traits::{Model, ModelResponse};
+    use crate::models::openai::OpenAIResponse;
+    use crate::models::types::Message;
+    use crate::tools::ToolInfo;
+    use serde_json::json;
+    use std::collections::HashMap;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::Duration;
+
+    /// A model answering each task with its name, e.g. `task-3`, and counting the requests running at
+    /// once. It panics on the task `task-panic`.
+    #[derive(Debug, Clone, Default)]
+    struct TaskModel {
+        running: Arc<AtomicUsize>,
+        most_running: Arc<AtomicUsize>,
+    }
+
+    impl Model for TaskModel {
+        fn run(
+            &self,
+            messages: Vec<Message>,
+            _tools: Vec<ToolInfo>,
+            _max_tokens: Option<usize>,
+            _args: Option<HashMap<String, Vec<String>>>,
+        ) -> Result<Box<dyn ModelResponse>, AgentError> {
+            let task = messages
+                .iter()
+                .rev()
+                .find_map(|message| {
+                    message
+                        .content
+                        .split_whitespace()
+                        .find(|word| word.starts_with("task-"))
+                })
+                .unwrap()
+                .to_string();
+            let running = self.running.fetch_add(1, Ordering::SeqCst) + 1;
+            self.most_running.fetch_max(running, Ordering::SeqCst);
+            thread::sleep(Duration::from_millis(20));
+            self.running.fetch_sub(1, Ordering::SeqCst);
+            if task == "task-panic" {
+                panic!("the model crashed");
+            }
+            let response: OpenAIResponse = serde_json::from_value(json!({
+                "choices": [{"message": {
+                    "role": "assistant",
+                    "content": null,
+                    "tool_calls": [{
+                        "id": "call_1",
+                        "type": "function",
+                        "function": {
+                            "name": "final_answer",
+                            "arguments": json!({"answer": task}).to_string()
+                        }
+                    }]
+                }}]
+            }))
+            .unwrap();
+            Ok(Box::new(response))
+        }
+    }
+
+    fn builder(model: TaskModel) -> Arc<dyn Fn() -> FunctionCallingAgent<TaskModel> + Send + Sync> {
+        Arc::new(move || {
+            FunctionCallingAgent::new(model.clone(), vec![], None, None, None, Some(2)).unwrap()
+        })
+    }
+
+    fn tasks(names: &[&str]) -> Vec<String> {
+        names.iter().map(|name| name.to_string()).collect()
+    }
+
+    #[test]
+    fn test_streaming_delivers_every_task() {
+        let tasks = tasks(&["task-0", "task-1", "task-2", "task-3"]);
+        let mut results = run_tasks_streaming(builder(TaskModel::default()), &tasks)
+            .iter()
+            .map(|(index, result)| (index, result.unwrap()))
+            .collect::<Vec<_>>();
+        results.sort();
+        assert_eq!(
+            results,
+            vec![
+                (0, "task-0".to_string()),
+                (1, "task-1".to_string()),
+                (2, "task-2".to_string()),
+                (3, "task-3".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_streaming_concurrency_is_bounded() {
+        let model = TaskModel::default();
+        let tasks = tasks(&["task-0", "task-1", "task-2", "task-3", "task-4", "task-5"]);
+        let results = run_tasks_streaming_with_concurrency(builder(model.clone()), &tasks, 2)
+            .iter()
+            .collect::<Vec<_>>();
+        assert_eq!(results.len(), 6);
+        assert!(results.iter().all(|(_, result)| result.is_ok()));
+        assert!(model.most_running.load(Ordering::SeqCst) <= 2);
+    }
+
+    #[test]
+    fn test_streaming_reports_a_panicking_task() {
+        let tasks = tasks(&["task-0", "task-panic", "task-2"]);
+        // A single worker also runs the tasks after the one that panicked
+        let mut results =
+            run_tasks_streaming_with_concurrency(builder(TaskModel::default()), &tasks, 1)
+                .iter()
+                .map(|(index, result)| (index, result.map_err(|e| e.to_string())))
+                .collect::<Vec<_>>();
+        results.sort();
+        assert_eq!(
+            results,
+            vec![
+                (0, Ok("task-0".to_string())),
+                (1, Err("Thread panicked".to_string())),
+                (2, Ok("task-2".to_string())),
+            ]
+        );
+    }
+}
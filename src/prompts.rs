@@ -344,3 +344,11 @@ pub const FUNCTION_CALLING_SYSTEM_PROMPT: &str = r#"You are an expert assistant
 
 Now Begin! If you solve the task correctly, you will receive a reward of $1,000,000.
 "#;
+
+/// Sent to the code agent when its output could not be parsed, to ask for a corrected reply within the same step.
+pub const CODE_PARSE_CORRECTION_PROMPT: &str = r#"Your previous output had no valid code block, so it could not be executed.
+Reply with your thoughts followed by exactly one code block in this format:
+Code:
+```py
+# Your python code here
+```<end_code>"#;
@@ -1,31 +1,107 @@
-use std::path::Path;
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
 use tempfile::{tempdir, Builder, TempDir};
 
 /// Sandbox provides an isolated temporary directory for agent execution.
+///
+/// The directory is deleted when the sandbox is dropped, unless it is kept with [`Sandbox::persist`].
+/// If the sandbox was made the current working directory, the previous one is restored first.
 pub struct Sandbox {
+    // Declared before `dir` so the working directory is restored before the directory is deleted.
+    cwd: CwdGuard,
     dir: TempDir,
 }
 
+/// Remembers the working directory from before `set_as_cwd` and restores it on drop.
+#[derive(Default)]
+struct CwdGuard {
+    previous: OnceLock<PathBuf>,
+}
+
+impl Drop for CwdGuard {
+    fn drop(&mut self) {
+        if let Some(previous) = self.previous.get() {
+            let _ = std::env::set_current_dir(previous);
+        }
+    }
+}
+
 impl Sandbox {
     /// Create a new sandbox directory. If the `SANDBOX_DIR` environment variable
     /// is set, the sandbox will be created inside that directory.
     pub fn new() -> std::io::Result<Self> {
         if let Ok(path) = std::env::var("SANDBOX_DIR") {
-            let dir = Builder::new().prefix("smolagents-").tempdir_in(path)?;
-            Ok(Self { dir })
+            Self::new_in(path)
         } else {
             let dir = tempdir()?;
-            Ok(Self { dir })
+            Ok(Self {
+                cwd: CwdGuard::default(),
+                dir,
+            })
         }
     }
 
+    /// Create a new sandbox directory inside `path`.
+    pub fn new_in(path: impl AsRef<Path>) -> std::io::Result<Self> {
+        let dir = Builder::new().prefix("smolagents-").tempdir_in(path)?;
+        Ok(Self {
+            cwd: CwdGuard::default(),
+            dir,
+        })
+    }
+
     /// Path to the sandbox directory.
     pub fn path(&self) -> &Path {
         self.dir.path()
     }
 
-    /// Set the sandbox directory as the current working directory.
+    /// Set the sandbox directory as the current working directory. The previous working directory
+    /// is restored by [`Sandbox::restore_cwd`] or when the sandbox is dropped or persisted.
     pub fn set_as_cwd(&self) -> std::io::Result<()> {
-        std::env::set_current_dir(self.path())
+        let current = std::env::current_dir()?;
+        std::env::set_current_dir(self.path())?;
+        // Keep the directory from before the first call if the sandbox is entered several times
+        self.cwd.previous.get_or_init(|| current);
+        Ok(())
+    }
+
+    /// Restore the working directory from before [`Sandbox::set_as_cwd`]. Does nothing if the
+    /// sandbox was never made the working directory.
+    pub fn restore_cwd(&self) -> std::io::Result<()> {
+        match self.cwd.previous.get() {
+            Some(previous) => std::env::set_current_dir(previous),
+            None => Ok(()),
+        }
+    }
+
+    /// Keep the sandbox directory on disk instead of deleting it, and return its path.
+    pub fn persist(self) -> PathBuf {
+        let Sandbox { cwd, dir } = self;
+        drop(cwd);
+        dir.keep()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_persist_keeps_directory() {
+        let parent = tempdir().unwrap();
+        let sandbox = Sandbox::new_in(parent.path()).unwrap();
+        assert!(sandbox.path().starts_with(parent.path()));
+        std::fs::write(sandbox.path().join("output.txt"), "result").unwrap();
+
+        let path = sandbox.persist();
+        assert_eq!(
+            std::fs::read_to_string(path.join("output.txt")).unwrap(),
+            "result"
+        );
+
+        let dropped = Sandbox::new_in(parent.path()).unwrap();
+        let dropped_path = dropped.path().to_path_buf();
+        drop(dropped);
+        assert!(!dropped_path.exists());
     }
 }
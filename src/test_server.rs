@@ -0,0 +1,103 @@
+//! A minimal HTTP server for the tests of the models and tools, answering a fixed number of requests on
+//! a local port.
+
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::TcpListener;
+use std::thread::JoinHandle;
+
+use serde_json::Value;
+
+/// A request received by the server.
+#[derive(Debug, Clone)]
+pub struct Request {
+    pub path: String,
+    pub body: Vec<u8>,
+}
+
+impl Request {
+    /// The body of the request, parsed as JSON.
+    pub fn json(&self) -> Value {
+        serde_json::from_slice(&self.body).unwrap()
+    }
+}
+
+/// A response of the server.
+#[derive(Debug, Clone)]
+pub struct Response {
+    pub status: u16,
+    pub content_type: &'static str,
+    pub body: String,
+}
+
+impl Response {
+    pub fn json(status: u16, body: Value) -> Self {
+        Self {
+            status,
+            content_type: "application/json",
+            body: body.to_string(),
+        }
+    }
+
+    pub fn html(status: u16, body: String) -> Self {
+        Self {
+            status,
+            content_type: "text/html",
+            body,
+        }
+    }
+}
+
+/// Answer `count` requests with `respond`, which gets the number of the request and the request. Returns
+/// the url of the server, e.g. `http://127.0.0.1:4321`, and the server thread, which returns the requests
+/// once it answered them all.
+pub fn serve(
+    count: usize,
+    mut respond: impl FnMut(usize, &Request) -> Response + Send + 'static,
+) -> (String, JoinHandle<Vec<Request>>) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let url = format!("http://{}", listener.local_addr().unwrap());
+    let handle = std::thread::spawn(move || {
+        let mut requests = Vec::new();
+        for (i, stream) in listener.incoming().take(count).enumerate() {
+            let mut stream = stream.unwrap();
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            let mut request_line = String::new();
+            reader.read_line(&mut request_line).unwrap();
+            let path = request_line
+                .split_whitespace()
+                .nth(1)
+                .unwrap_or_default()
+                .to_string();
+            let mut content_length = 0;
+            loop {
+                let mut line = String::new();
+                reader.read_line(&mut line).unwrap();
+                if line.trim().is_empty() {
+                    break;
+                }
+                if let Some((name, value)) = line.split_once(':') {
+                    if name.eq_ignore_ascii_case("content-length") {
+                        content_length = value.trim().parse().unwrap();
+                    }
+                }
+            }
+            let mut body = vec![0; content_length];
+            reader.read_exact(&mut body).unwrap();
+            let request = Request { path, body };
+
+            let response = respond(i, &request);
+            // The client can stop reading before the end, e.g. at a limit of the response size
+            let _ = write!(
+                stream,
+                "HTTP/1.1 {} Status\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                response.status,
+                response.content_type,
+                response.body.len(),
+                response.body
+            );
+            requests.push(request);
+        }
+        requests
+    });
+    (url, handle)
+}
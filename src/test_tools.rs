@@ -0,0 +1,62 @@
+//! Tools shared by the tests of the agents and the interpreter.
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use anyhow::Result;
+use schemars::JsonSchema;
+use serde::Deserialize;
+use serde_json::Value;
+
+use crate::tools::Tool;
+
+/// The parameters of a tool without arguments.
+#[derive(Debug, Deserialize, JsonSchema)]
+pub struct NoParams {}
+
+/// A tool counting its calls. It returns `call 1`, `call 2`, ...
+#[derive(Debug, Clone, Default)]
+pub struct CounterTool {
+    pub calls: Arc<AtomicUsize>,
+}
+
+impl Tool for CounterTool {
+    type Params = NoParams;
+    fn name(&self) -> &'static str {
+        "counter"
+    }
+    fn description(&self) -> &'static str {
+        "Counts its calls"
+    }
+    fn forward(&self, _arguments: NoParams) -> Result<String> {
+        let calls = self.calls.fetch_add(1, Ordering::SeqCst) + 1;
+        Ok(format!("call {}", calls))
+    }
+}
+
+/// A tool named `lookup` returning a fixed JSON document. The document is structured output, or a string
+/// like the output of most tools when `as_string` is set.
+#[derive(Debug, Clone)]
+pub struct JsonTool {
+    pub output: Value,
+    pub as_string: bool,
+}
+
+impl Tool for JsonTool {
+    type Params = NoParams;
+    fn name(&self) -> &'static str {
+        "lookup"
+    }
+    fn description(&self) -> &'static str {
+        "Looks up a JSON document"
+    }
+    fn forward(&self, _arguments: NoParams) -> Result<String> {
+        Ok(self.output.to_string())
+    }
+    fn forward_value(&self, arguments: NoParams) -> Result<Value> {
+        if self.as_string {
+            return self.forward(arguments).map(Value::String);
+        }
+        Ok(self.output.clone())
+    }
+}
@@ -1,8 +1,15 @@
+use std::io::Read;
+use std::sync::OnceLock;
+
+use reqwest::blocking::{Client, Response};
+use reqwest::Url;
 use schemars::JsonSchema;
 use serde::{de::DeserializeOwned, Deserialize, Serialize};
 
-use super::tool_traits::{Parameters, Tool};
-use anyhow::Result;
+use super::ddg_search::DuckDuckGoSearchTool;
+use super::tool_traits::{AnyTool, Parameters, Tool};
+use super::visit_website::VisitWebsiteTool;
+use anyhow::{anyhow, Context, Result};
 
 #[derive(Deserialize, JsonSchema)]
 #[schemars(title = "BaseParams")]
@@ -32,3 +39,108 @@ impl Tool for BaseTool {
         Ok("Not implemented".to_string())
     }
 }
+
+/// The user agent sent by the tools that access the web.
+pub const DEFAULT_USER_AGENT: &str = "Mozilla/5.0 (Windows NT 10.0; Win64; x64) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/91.0.4472.124 Safari/537.36";
+
+/// The HTTP client shared by the web tools, using [`DEFAULT_USER_AGENT`].
+///
+/// The client is built once per process. Clients are reference counted, so every tool holding a clone
+/// reuses the same connection pool.
+pub fn default_http_client() -> Client {
+    static CLIENT: OnceLock<Client> = OnceLock::new();
+    CLIENT
+        .get_or_init(|| {
+            Client::builder()
+                .user_agent(DEFAULT_USER_AGENT)
+                .build()
+                .unwrap_or_else(|_| Client::new())
+        })
+        .clone()
+}
+
+/// Whether the host of a search result url is one of the `domains` or a subdomain of one. Excluding
+/// `example.com` excludes `www.example.com` as well, but excluding `www.example.com` leaves `example.com`
+/// and its other subdomains in.
+pub(crate) fn is_excluded(url: &str, domains: &[String]) -> bool {
+    let host = dedup_key(url);
+    let host = host.split('/').next().unwrap_or_default();
+    domains.iter().any(|domain| {
+        host == domain
+            || host
+                .strip_suffix(domain.as_str())
+                .is_some_and(|subdomain| subdomain.ends_with('.'))
+    })
+}
+
+/// The host and path of a result url, so the same page is only returned once whatever its scheme, query
+/// or fragment. DuckDuckGo shows the urls without a scheme.
+pub(crate) fn dedup_key(url: &str) -> String {
+    let parsed = if url.contains("://") {
+        Url::parse(url)
+    } else {
+        Url::parse(&format!("https://{}", url))
+    };
+    match parsed {
+        Ok(parsed) => format!(
+            "{}{}",
+            parsed.host_str().unwrap_or_default(),
+            parsed.path().trim_end_matches('/')
+        ),
+        Err(_) => url.to_string(),
+    }
+}
+
+/// The default maximum number of bytes read from a response body by the tools, so a huge download cannot
+/// exhaust the memory.
+pub const DEFAULT_MAX_BODY_BYTES: usize = 10 * 1024 * 1024;
+
+/// Read the body of `response` up to `max_bytes` bytes, without downloading the rest. Returns the text,
+/// with invalid UTF-8 replaced, and whether the body was truncated.
+pub fn read_body(response: Response, max_bytes: usize) -> Result<(String, bool)> {
+    let mut bytes = Vec::new();
+    response
+        .take(max_bytes as u64 + 1)
+        .read_to_end(&mut bytes)
+        .context("Failed to read the response")?;
+    let truncated = bytes.len() > max_bytes;
+    bytes.truncate(max_bytes);
+    Ok((String::from_utf8_lossy(&bytes).into_owned(), truncated))
+}
+
+/// Read a JSON body of at most `max_bytes` bytes. A larger body is an error.
+pub fn read_json_body<T: DeserializeOwned>(response: Response, max_bytes: usize) -> Result<T> {
+    let (body, truncated) = read_body(response, max_bytes)?;
+    if truncated {
+        return Err(anyhow!("The response is larger than {} bytes", max_bytes));
+    }
+    serde_json::from_str(&body).context("Failed to parse JSON")
+}
+
+/// The notice appended to a response truncated after `max_bytes` bytes.
+pub fn truncation_notice(max_bytes: usize) -> String {
+    format!("[Response truncated after {} bytes]", max_bytes)
+}
+
+/// The curated tools added by `with_default_tools` of the agents: web search and website visits, which
+/// need no configuration.
+pub fn default_tools() -> Vec<Box<dyn AnyTool>> {
+    vec![
+        Box::new(DuckDuckGoSearchTool::new()),
+        Box::new(VisitWebsiteTool::new()),
+    ]
+}
+
+/// Append `defaults` to `tools`, skipping every default whose name is already taken. A tool in `tools`
+/// therefore takes precedence over a default of the same name, and earlier defaults over later ones.
+pub fn merge_tools(
+    mut tools: Vec<Box<dyn AnyTool>>,
+    defaults: Vec<Box<dyn AnyTool>>,
+) -> Vec<Box<dyn AnyTool>> {
+    for default in defaults {
+        if !tools.iter().any(|tool| tool.name() == default.name()) {
+            tools.push(default);
+        }
+    }
+    tools
+}
@@ -0,0 +1,197 @@
+//! This module contains the citation tool. The search tools number their results so the model can tell
+//! which result backs which claim, and the citation tool turns the numbers into the list of sources of the
+//! final answer.
+
+use std::sync::{Arc, Mutex};
+
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+use super::base::{dedup_key, BaseTool};
+use super::ddg_search::SearchResult;
+use super::tool_traits::Tool;
+use anyhow::{anyhow, Result};
+
+#[derive(Debug, Deserialize, JsonSchema)]
+#[schemars(title = "CitationToolParams")]
+pub struct CitationToolParams {
+    #[schemars(description = "The ids of the search results backing the answer")]
+    ids: Vec<usize>,
+}
+
+/// The search results found during a run, numbered from 1 in the order they are first found. A page keeps
+/// its id for every query that finds it again, so the ids stay valid for the whole run. The clones share
+/// the results: give one to the search tools with `with_citations` and one to the [`CitationTool`].
+///
+/// The ids are not reset between runs, so the ids in the memory of an agent run without `reset` still cite
+/// the same pages. The forks of an agent clone its tools and so share the ids as well. Call
+/// [`Citations::clear`] to number the results of a new task from 1 again.
+#[derive(Debug, Clone, Default)]
+pub struct Citations {
+    sources: Arc<Mutex<Vec<SearchResult>>>,
+}
+
+impl Citations {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Set the id of every result, the id of the same page found before or a new one.
+    pub fn number(&self, results: &mut [SearchResult]) {
+        let mut sources = self.sources.lock().unwrap();
+        for result in results {
+            let key = dedup_key(&result.url);
+            let id = match sources
+                .iter()
+                .position(|source| dedup_key(&source.url) == key)
+            {
+                Some(position) => position + 1,
+                None => {
+                    let id = sources.len() + 1;
+                    sources.push(SearchResult {
+                        id: Some(id),
+                        ..result.clone()
+                    });
+                    id
+                }
+            };
+            result.id = Some(id);
+        }
+    }
+
+    /// Forget the results found so far, for every clone.
+    pub fn clear(&self) {
+        self.sources.lock().unwrap().clear();
+    }
+
+    /// The result with the given id.
+    pub fn get(&self, id: usize) -> Option<SearchResult> {
+        let sources = self.sources.lock().unwrap();
+        id.checked_sub(1)
+            .and_then(|index| sources.get(index))
+            .cloned()
+    }
+
+    /// The markdown list of the sources with the given ids, one `[id] [title](url)` line each. Unknown ids
+    /// are an error, so the model does not cite a source it did not find.
+    pub fn sources(&self, ids: &[usize]) -> Result<String> {
+        let mut ids = ids.to_vec();
+        ids.sort_unstable();
+        ids.dedup();
+        let lines = ids
+            .into_iter()
+            .map(|id| {
+                let source = self
+                    .get(id)
+                    .ok_or_else(|| anyhow!("There is no search result with id {}", id))?;
+                Ok(format!("[{}] [{}]({})", id, source.title, source.url))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        Ok(format!("Sources:\n{}", lines.join("\n")))
+    }
+}
+
+/// Lists the sources of the final answer from the ids of the search results backing it.
+#[derive(Debug, Serialize, Default, Clone)]
+pub struct CitationTool {
+    pub tool: BaseTool,
+    #[serde(skip)]
+    pub citations: Citations,
+}
+
+impl CitationTool {
+    pub fn new(citations: Citations) -> Self {
+        CitationTool {
+            tool: BaseTool {
+                name: "cite_sources",
+                description: "Returns the list of sources for the given ids of search results. Append it to your final answer to cite the results backing it.",
+            },
+            citations,
+        }
+    }
+}
+
+impl Tool for CitationTool {
+    type Params = CitationToolParams;
+    fn name(&self) -> &'static str {
+        self.tool.name
+    }
+    fn description(&self) -> &'static str {
+        self.tool.description
+    }
+
+    fn output_description(&self) -> &'static str {
+        "The sources as markdown, one `[id] [title](url)` line each"
+    }
+
+    fn forward(&self, arguments: CitationToolParams) -> Result<String> {
+        self.citations.sources(&arguments.ids)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn result(title: &str, url: &str) -> SearchResult {
+        SearchResult {
+            title: title.to_string(),
+            url: url.to_string(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_citation_ids() {
+        let citations = Citations::new();
+        let mut first = vec![
+            result("Paris", "https://en.wikipedia.org/wiki/Paris"),
+            result("France", "https://en.wikipedia.org/wiki/France"),
+        ];
+        citations.number(&mut first);
+        // Another query finds the first page again, without the scheme
+        let mut second = vec![
+            result("Lyon", "https://en.wikipedia.org/wiki/Lyon"),
+            result("Paris - Wikipedia", "en.wikipedia.org/wiki/Paris/"),
+        ];
+        citations.number(&mut second);
+
+        let ids = |results: &[SearchResult]| results.iter().map(|r| r.id).collect::<Vec<_>>();
+        assert_eq!(ids(&first), vec![Some(1), Some(2)]);
+        assert_eq!(ids(&second), vec![Some(3), Some(1)]);
+        assert_eq!(citations.get(1).unwrap().title, "Paris");
+
+        let tool = CitationTool::new(citations.clone());
+        assert_eq!(
+            tool.forward(CitationToolParams { ids: vec![3, 1, 3] })
+                .unwrap(),
+            "Sources:\n[1] [Paris](https://en.wikipedia.org/wiki/Paris)\n\
+             [3] [Lyon](https://en.wikipedia.org/wiki/Lyon)"
+        );
+        assert!(tool.forward(CitationToolParams { ids: vec![0] }).is_err());
+    }
+
+    #[test]
+    fn test_citation_ids_across_runs() {
+        let citations = Citations::new();
+        let mut first_run = vec![result("Paris", "https://en.wikipedia.org/wiki/Paris")];
+        citations.number(&mut first_run);
+
+        // A later run, or a fork sharing the tools, keeps numbering from the last id
+        let fork = citations.clone();
+        let mut second_run = vec![
+            result("Lyon", "https://en.wikipedia.org/wiki/Lyon"),
+            result("Paris", "https://en.wikipedia.org/wiki/Paris"),
+        ];
+        fork.number(&mut second_run);
+        let ids = |results: &[SearchResult]| results.iter().map(|r| r.id).collect::<Vec<_>>();
+        assert_eq!(ids(&second_run), vec![Some(2), Some(1)]);
+        assert_eq!(citations.get(2).unwrap().title, "Lyon");
+
+        fork.clear();
+        assert!(citations.get(1).is_none());
+        let mut new_task = vec![result("Lyon", "https://en.wikipedia.org/wiki/Lyon")];
+        citations.number(&mut new_task);
+        assert_eq!(ids(&new_task), vec![Some(1)]);
+    }
+}
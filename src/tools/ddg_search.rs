@@ -1,30 +1,107 @@
 //! This module contains the DuckDuckGo search tool.
 
+use std::collections::HashSet;
+
+use reqwest::Url;
 use schemars::JsonSchema;
 use scraper::Selector;
 use serde::{Deserialize, Serialize};
 
-use super::base::BaseTool;
+use super::base::{
+    dedup_key, default_http_client, is_excluded, read_body, BaseTool, DEFAULT_MAX_BODY_BYTES,
+};
+use super::citation::Citations;
 use super::tool_traits::Tool;
-use anyhow::Result;
+use anyhow::{Context, Result};
+
+/// The default maximum number of search results returned to the model.
+const DEFAULT_MAX_RESULTS: usize = 10;
 
 #[derive(Deserialize, JsonSchema)]
 #[schemars(title = "DuckDuckGoSearchToolParams")]
 pub struct DuckDuckGoSearchToolParams {
     #[schemars(description = "The query to search for")]
     query: String,
+    #[schemars(description = "The maximum number of results to return. Defaults to 10")]
+    max_results: Option<usize>,
 }
 
-#[derive(Debug, Serialize, Default)]
+#[derive(Debug, Serialize, Default, Clone, PartialEq)]
 pub struct SearchResult {
+    /// The id to cite the result by, set by the search tools that have [`Citations`].
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub id: Option<usize>,
     pub title: String,
     pub snippet: String,
     pub url: String,
 }
 
-#[derive(Debug, Serialize, Default, Clone)]
+/// The shape of the search results returned to the model.
+#[derive(Debug, Serialize, Default, Clone, Copy, PartialEq, Eq)]
+pub enum SearchFormat {
+    /// `[title](url)` followed by the snippet, one block per result.
+    #[default]
+    Markdown,
+    /// A pretty-printed JSON array of `{id, title, snippet, url}` objects, without the ids when the
+    /// results are not numbered.
+    Json,
+    /// The title, url and snippet on separate lines, one block per result.
+    Plain,
+    /// A markdown table with one row per result. See [`to_markdown_table`](super::to_markdown_table).
+    Table,
+}
+
+impl SearchFormat {
+    /// What the results look like in this format, for the description of the search tools.
+    pub fn output_description(&self) -> &'static str {
+        match self {
+            SearchFormat::Markdown => {
+                "The results as markdown, a `[title](url)` link and the snippet for each"
+            }
+            SearchFormat::Json => "The results as a JSON array of `{title, snippet, url}` objects",
+            SearchFormat::Plain => {
+                "The results as text, the title, url and snippet of each on separate lines"
+            }
+            SearchFormat::Table => "The results as a markdown table with a row per result",
+        }
+    }
+
+    /// Format the results for the model. The blocks of numbered results start with their `[id]`.
+    pub fn format(&self, results: &[SearchResult]) -> Result<String> {
+        let blocks = |block: fn(&SearchResult) -> String| {
+            results
+                .iter()
+                .map(|r| match r.id {
+                    Some(id) => format!("[{}] {}", id, block(r)),
+                    None => block(r),
+                })
+                .collect::<Vec<_>>()
+                .join("\n\n")
+        };
+        match self {
+            SearchFormat::Markdown => Ok(blocks(|r| {
+                format!("[{}]({}) \n{}", r.title, r.url, r.snippet)
+            })),
+            SearchFormat::Json => Ok(serde_json::to_string_pretty(results)?),
+            SearchFormat::Plain => Ok(blocks(|r| format!("{}\n{}\n{}", r.title, r.url, r.snippet))),
+            SearchFormat::Table => Ok(super::to_markdown_table(&serde_json::to_value(results)?)),
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Clone)]
 pub struct DuckDuckGoSearchTool {
     pub tool: BaseTool,
+    pub format: SearchFormat,
+    /// The maximum number of results returned when the model does not ask for a number.
+    pub max_results: usize,
+    /// Results from these domains, or their subdomains, are left out.
+    pub exclude_domains: Vec<String>,
+    /// Numbers the results so the model can cite them. See [`Citations`].
+    #[serde(skip)]
+    pub citations: Option<Citations>,
+    #[serde(skip)]
+    pub client: reqwest::blocking::Client,
 }
 
 impl DuckDuckGoSearchTool {
@@ -34,56 +111,128 @@ impl DuckDuckGoSearchTool {
                 name: "duckduckgo_search",
                 description: "Performs a duckduckgo web search for your query then returns a string of the top search results.",
             },
+            format: SearchFormat::Markdown,
+            max_results: DEFAULT_MAX_RESULTS,
+            exclude_domains: Vec::new(),
+            citations: None,
+            client: default_http_client(),
         }
     }
 
-    pub fn forward(&self, query: &str) -> Result<Vec<SearchResult>> {
-        let client = reqwest::blocking::Client::builder()
-            .user_agent("Mozilla/5.0 (compatible; MyRustTool/1.0)")
-            .build()?;
-        let response = client
+    pub fn with_format(mut self, format: SearchFormat) -> Self {
+        self.format = format;
+        self
+    }
+
+    /// Return at most `max_results` results, at least one.
+    pub fn with_max_results(mut self, max_results: usize) -> Self {
+        self.max_results = max_results.max(1);
+        self
+    }
+
+    /// Leave out the results from these domains, e.g. `pinterest.com`, and their subdomains.
+    pub fn with_exclude_domains(mut self, domains: &[&str]) -> Self {
+        self.exclude_domains = domains.iter().map(|domain| domain.to_string()).collect();
+        self
+    }
+
+    /// Number the results with `citations`, so the model can cite them with the [`CitationTool`] given
+    /// the same citations.
+    ///
+    /// [`CitationTool`]: super::CitationTool
+    pub fn with_citations(mut self, citations: Citations) -> Self {
+        self.citations = Some(citations);
+        self
+    }
+
+    pub fn forward(&self, query: &str, max_results: Option<usize>) -> Result<Vec<SearchResult>> {
+        let response = self
+            .client
             .get(format!("https://html.duckduckgo.com/html/?q={}", query))
             .send()?;
-        let html = response.text().unwrap();
-        let document = scraper::Html::parse_document(&html);
-        let result_selector = Selector::parse(".result")
-            .map_err(|e| anyhow::anyhow!("Failed to parse result selector: {}", e))?;
-        let title_selector = Selector::parse(".result__title a")
-            .map_err(|e| anyhow::anyhow!("Failed to parse title selector: {}", e))?;
-        let snippet_selector = Selector::parse(".result__snippet")
-            .map_err(|e| anyhow::anyhow!("Failed to parse snippet selector: {}", e))?;
-        let url_selector = Selector::parse(".result__url")
-            .map_err(|e| anyhow::anyhow!("Failed to parse url selector: {}", e))?;
-        let mut results = Vec::new();
-
-        for result in document.select(&result_selector) {
-            let title_element = result.select(&title_selector).next();
-            let snippet_element = result.select(&snippet_selector).next();
-            if let (Some(title), Some(snippet)) = (title_element, snippet_element) {
-                let title_text = title.text().collect::<String>().trim().to_string();
-                let snippet_text = snippet.text().collect::<String>().trim().to_string();
-                let url = result
-                    .select(&url_selector)
-                    .next()
-                    .unwrap()
-                    .text()
-                    .collect::<Vec<_>>()
-                    .join("")
-                    .trim()
-                    .to_string();
-                if !title_text.is_empty() && !url.is_empty() {
-                    results.push(SearchResult {
-                        title: title_text,
-                        snippet: snippet_text,
-                        url,
-                    });
-                }
-            }
+        let (html, _) = read_body(response, DEFAULT_MAX_BODY_BYTES)
+            .context("Failed to read the search results")?;
+        let mut results = parse_results(
+            &html,
+            max_results.unwrap_or(self.max_results).max(1),
+            &self.exclude_domains,
+        )?;
+        if let Some(citations) = &self.citations {
+            citations.number(&mut results);
         }
         Ok(results)
     }
 }
 
+impl Default for DuckDuckGoSearchTool {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Parse the results of a DuckDuckGo HTML search page, keeping the first `max_results` results and only
+/// the first result of each page. Results from the excluded domains are skipped.
+fn parse_results(
+    html: &str,
+    max_results: usize,
+    exclude_domains: &[String],
+) -> Result<Vec<SearchResult>> {
+    let document = scraper::Html::parse_document(html);
+    let result_selector = Selector::parse(".result")
+        .map_err(|e| anyhow::anyhow!("Failed to parse result selector: {}", e))?;
+    let title_selector = Selector::parse(".result__title a")
+        .map_err(|e| anyhow::anyhow!("Failed to parse title selector: {}", e))?;
+    let snippet_selector = Selector::parse(".result__snippet")
+        .map_err(|e| anyhow::anyhow!("Failed to parse snippet selector: {}", e))?;
+    let url_selector = Selector::parse(".result__url")
+        .map_err(|e| anyhow::anyhow!("Failed to parse url selector: {}", e))?;
+    let mut results = Vec::new();
+    let mut seen = HashSet::new();
+
+    for result in document.select(&result_selector) {
+        if results.len() >= max_results {
+            break;
+        }
+        let title_element = result.select(&title_selector).next();
+        let snippet_element = result.select(&snippet_selector).next();
+        if let (Some(title), Some(snippet)) = (title_element, snippet_element) {
+            let title_text = title.text().collect::<String>().trim().to_string();
+            let snippet_text = snippet.text().collect::<String>().trim().to_string();
+            // DuckDuckGo changes its markup from time to time, the title links to the result as well
+            let url = result
+                .select(&url_selector)
+                .next()
+                .map(|url| url.text().collect::<String>().trim().to_string())
+                .filter(|url| !url.is_empty())
+                .or_else(|| title.value().attr("href").and_then(url_from_href))
+                .unwrap_or_default();
+            if !title_text.is_empty()
+                && !url.is_empty()
+                && !is_excluded(&url, exclude_domains)
+                && seen.insert(dedup_key(&url))
+            {
+                results.push(SearchResult {
+                    id: None,
+                    title: title_text,
+                    snippet: snippet_text,
+                    url,
+                });
+            }
+        }
+    }
+    Ok(results)
+}
+
+/// The target of a result link. DuckDuckGo links to a redirect page with the target in the `uddg`
+/// parameter.
+fn url_from_href(href: &str) -> Option<String> {
+    let url = Url::parse("https://duckduckgo.com").ok()?.join(href).ok()?;
+    match url.query_pairs().find(|(key, _)| key == "uddg") {
+        Some((_, target)) => Some(target.into_owned()),
+        None => Some(url.to_string()),
+    }
+}
+
 impl Tool for DuckDuckGoSearchTool {
     type Params = DuckDuckGoSearchToolParams;
     fn name(&self) -> &'static str {
@@ -92,15 +241,17 @@ impl Tool for DuckDuckGoSearchTool {
     fn description(&self) -> &'static str {
         self.tool.description
     }
+    fn output_description(&self) -> &'static str {
+        self.format.output_description()
+    }
     fn forward(&self, arguments: DuckDuckGoSearchToolParams) -> Result<String> {
-        let query = arguments.query;
-        let results = self.forward(&query)?;
-        let results_string = results
-            .iter()
-            .map(|r| format!("[{}]({}) \n{}", r.title, r.url, r.snippet))
-            .collect::<Vec<_>>()
-            .join("\n\n");
-        Ok(results_string)
+        let results = self.forward(&arguments.query, arguments.max_results)?;
+        self.format.format(&results)
+    }
+
+    fn forward_value(&self, arguments: DuckDuckGoSearchToolParams) -> Result<serde_json::Value> {
+        let results = self.forward(&arguments.query, arguments.max_results)?;
+        Ok(serde_json::to_value(results)?)
     }
 }
 
@@ -108,12 +259,209 @@ impl Tool for DuckDuckGoSearchTool {
 mod tests {
     use super::*;
 
+    fn results() -> Vec<SearchResult> {
+        vec![
+            SearchResult {
+                id: None,
+                title: "Paris".to_string(),
+                snippet: "The capital of France.".to_string(),
+                url: "https://en.wikipedia.org/wiki/Paris".to_string(),
+            },
+            SearchResult {
+                id: None,
+                title: "France".to_string(),
+                snippet: "A country in Europe.".to_string(),
+                url: "https://en.wikipedia.org/wiki/France".to_string(),
+            },
+        ]
+    }
+
+    #[test]
+    fn test_markdown_format() {
+        assert_eq!(
+            SearchFormat::Markdown.format(&results()).unwrap(),
+            "[Paris](https://en.wikipedia.org/wiki/Paris) \nThe capital of France.\n\n\
+             [France](https://en.wikipedia.org/wiki/France) \nA country in Europe."
+        );
+    }
+
+    #[test]
+    fn test_json_format() {
+        let json = SearchFormat::Json.format(&results()).unwrap();
+        let value: serde_json::Value = serde_json::from_str(&json).unwrap();
+        assert_eq!(value[0]["title"], "Paris");
+        assert_eq!(value[1]["url"], "https://en.wikipedia.org/wiki/France");
+        assert_eq!(value.as_array().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn test_plain_format() {
+        assert_eq!(
+            SearchFormat::Plain.format(&results()).unwrap(),
+            "Paris\nhttps://en.wikipedia.org/wiki/Paris\nThe capital of France.\n\n\
+             France\nhttps://en.wikipedia.org/wiki/France\nA country in Europe."
+        );
+    }
+
+    #[test]
+    fn test_table_format() {
+        assert_eq!(
+            SearchFormat::Table.format(&results()).unwrap(),
+            "| snippet | title | url |\n| --- | --- | --- |\n\
+             | The capital of France. | Paris | https://en.wikipedia.org/wiki/Paris |\n\
+             | A country in Europe. | France | https://en.wikipedia.org/wiki/France |"
+        );
+    }
+
+    fn result_block(title: &str, url: &str, snippet: &str) -> String {
+        format!(
+            r#"<div class="result results_links results_links_deep web-result ">
+  <div class="links_main links_deep result__body">
+    <h2 class="result__title">
+      <a rel="nofollow" class="result__a" href="//duckduckgo.com/l/?uddg=https%3A%2F%2F{url}">{title}</a>
+    </h2>
+    <div class="result__extras">
+      <div class="result__extras__url">
+        <a class="result__url" href="//duckduckgo.com/l/?uddg=https%3A%2F%2F{url}">
+                  {url}
+                  </a>
+      </div>
+    </div>
+    <a class="result__snippet" href="//duckduckgo.com/l/?uddg=https%3A%2F%2F{url}">{snippet}</a>
+    <div class="clear"></div>
+  </div>
+</div>"#
+        )
+    }
+
+    /// A DuckDuckGo HTML results page, trimmed to the markup the parser reads.
+    fn results_page() -> String {
+        let blocks = [
+            result_block(
+                "Paris - Wikipedia",
+                "en.wikipedia.org/wiki/Paris",
+                "<b>Paris</b> is the capital and largest city of France.",
+            ),
+            result_block(
+                "Paris | History, Map, Population",
+                "www.britannica.com/place/Paris",
+                "<b>Paris</b>, city and capital of France.",
+            ),
+            // The same page again, with a query string and a trailing slash
+            result_block(
+                "Paris - Wikipedia",
+                "en.wikipedia.org/wiki/Paris/?lang=en",
+                "<b>Paris</b> is the capital and largest city of France.",
+            ),
+            result_block(
+                "France - Wikipedia",
+                "en.wikipedia.org/wiki/France",
+                "France is a country in Western Europe.",
+            ),
+            result_block(
+                "Paris travel guide",
+                "www.lonelyplanet.com/france/paris",
+                "Everything you need to know about <b>Paris</b>.",
+            ),
+        ];
+        format!(
+            r#"<html><body><div class="serp__results"><div id="links" class="results">{}</div></div></body></html>"#,
+            blocks.join("\n")
+        )
+    }
+
+    #[test]
+    fn test_parse_results() {
+        let html = results_page();
+        let results = parse_results(&html, 10, &[]).unwrap();
+        let urls = results.iter().map(|r| r.url.as_str()).collect::<Vec<_>>();
+        assert_eq!(
+            urls,
+            vec![
+                "en.wikipedia.org/wiki/Paris",
+                "www.britannica.com/place/Paris",
+                "en.wikipedia.org/wiki/France",
+                "www.lonelyplanet.com/france/paris",
+            ]
+        );
+        assert_eq!(results[0].title, "Paris - Wikipedia");
+        assert_eq!(
+            results[0].snippet,
+            "Paris is the capital and largest city of France."
+        );
+
+        let results = parse_results(&html, 2, &[]).unwrap();
+        assert_eq!(results.len(), 2);
+        assert_eq!(results[1].url, "www.britannica.com/place/Paris");
+    }
+
+    #[test]
+    fn test_default_max_results() {
+        let tool = DuckDuckGoSearchTool::default();
+        let results = parse_results(&results_page(), tool.max_results, &[]).unwrap();
+        assert_eq!(results.len(), 4);
+
+        let tool = DuckDuckGoSearchTool::new().with_max_results(0);
+        let results = parse_results(&results_page(), tool.max_results, &[]).unwrap();
+        assert_eq!(results.len(), 1);
+    }
+
+    #[test]
+    fn test_exclude_domains() {
+        let html = results_page();
+        let exclude_domains = [
+            "wikipedia.org".to_string(),
+            "www.britannica.com".to_string(),
+        ];
+        let results = parse_results(&html, 2, &exclude_domains).unwrap();
+        let urls = results.iter().map(|r| r.url.as_str()).collect::<Vec<_>>();
+        assert_eq!(urls, vec!["www.lonelyplanet.com/france/paris"]);
+
+        let exclude_domains = ["planet.com".to_string()];
+        assert!(!is_excluded(
+            "https://www.lonelyplanet.com/",
+            &exclude_domains
+        ));
+
+        let exclude_domains = ["www.example.com".to_string()];
+        assert!(is_excluded(
+            "https://www.example.com/page",
+            &exclude_domains
+        ));
+        assert!(!is_excluded("https://example.com/page", &exclude_domains));
+        assert!(!is_excluded("https://blog.example.com/", &exclude_domains));
+
+        let exclude_domains = ["example.com".to_string()];
+        assert!(is_excluded(
+            "https://www.example.com/page",
+            &exclude_domains
+        ));
+        assert!(is_excluded("https://blog.example.com/", &exclude_domains));
+    }
+
+    #[test]
+    fn test_parse_results_without_result_url() {
+        let html = results_page().replace("result__url", "result__link");
+        let results = parse_results(&html, 10, &[]).unwrap();
+        let urls = results.iter().map(|r| r.url.as_str()).collect::<Vec<_>>();
+        assert_eq!(
+            urls,
+            vec![
+                "https://en.wikipedia.org/wiki/Paris",
+                "https://www.britannica.com/place/Paris",
+                "https://en.wikipedia.org/wiki/France",
+                "https://www.lonelyplanet.com/france/paris",
+            ]
+        );
+        assert_eq!(results[0].title, "Paris - Wikipedia");
+    }
+
     #[test]
     #[ignore]
     fn test_duckduckgo_search_tool() {
         let tool = DuckDuckGoSearchTool::new();
         let query = "What is the capital of France?";
-        let result = tool.forward(query).unwrap();
+        let result = tool.forward(query, None).unwrap();
         assert!(result.iter().any(|r| r.snippet.contains("Paris")));
     }
 }
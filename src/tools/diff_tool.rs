@@ -0,0 +1,93 @@
+//! This module contains the diff tool. The model uses this tool to show the changes between two versions of a text.
+
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use similar::TextDiff;
+
+use super::base::BaseTool;
+use super::tool_traits::Tool;
+use anyhow::Result;
+
+const DEFAULT_CONTEXT_LINES: usize = 3;
+
+#[derive(Deserialize, JsonSchema)]
+#[schemars(title = "DiffToolParams")]
+pub struct DiffToolParams {
+    #[schemars(description = "The original text")]
+    before: String,
+    #[schemars(description = "The changed text")]
+    after: String,
+    #[schemars(
+        description = "The number of unchanged lines shown around each change. Defaults to 3"
+    )]
+    context_lines: Option<usize>,
+}
+
+#[derive(Debug, Serialize, Default, Clone)]
+pub struct DiffTool {
+    pub tool: BaseTool,
+}
+
+impl DiffTool {
+    pub fn new() -> Self {
+        DiffTool {
+            tool: BaseTool {
+                name: "diff",
+                description: "Compares two versions of a file or text and returns the changes as a unified diff.",
+            },
+        }
+    }
+
+    pub fn forward(&self, before: &str, after: &str, context_lines: Option<usize>) -> String {
+        if before == after {
+            return "no changes".to_string();
+        }
+        TextDiff::from_lines(before, after)
+            .unified_diff()
+            .context_radius(context_lines.unwrap_or(DEFAULT_CONTEXT_LINES))
+            .missing_newline_hint(true)
+            .header("before", "after")
+            .to_string()
+    }
+}
+
+impl Tool for DiffTool {
+    type Params = DiffToolParams;
+
+    fn name(&self) -> &'static str {
+        self.tool.name
+    }
+
+    fn description(&self) -> &'static str {
+        self.tool.description
+    }
+
+    fn output_description(&self) -> &'static str {
+        "The changes as a unified diff"
+    }
+
+    fn forward(&self, arguments: DiffToolParams) -> Result<String> {
+        Ok(self.forward(&arguments.before, &arguments.after, arguments.context_lines))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_diff_tool() {
+        let tool = DiffTool::new();
+        assert_eq!(tool.forward("a\nb\n", "a\nb\n", None), "no changes");
+
+        let diff = tool.forward("a\nb\nc\n", "a\nB\nc\n", Some(0));
+        assert_eq!(diff, "--- before\n+++ after\n@@ -2 +2 @@\n-b\n+B\n");
+
+        // Only the missing trailing newline differs
+        let diff = tool.forward("a\nb", "a\nb\n", None);
+        assert_eq!(
+            diff,
+            "--- before\n+++ after\n@@ -1,2 +1,2 @@\n a\n-b\n\\ No newline at end of file\n+b\n"
+        );
+    }
+}
@@ -0,0 +1,87 @@
+//! Helpers to render the structured results of tools as text for the model and the final answer.
+
+use serde_json::Value;
+
+/// Render a JSON array of objects as a GitHub flavored markdown table, with one column per key sorted by
+/// name, as `serde_json` does not keep the order of the keys. Missing keys and `null`s are empty cells,
+/// nested values are written as JSON. Any other value is returned as JSON.
+pub fn to_markdown_table(value: &Value) -> String {
+    let Some(rows) = value
+        .as_array()
+        .filter(|rows| !rows.is_empty())
+        .and_then(|rows| {
+            rows.iter()
+                .map(Value::as_object)
+                .collect::<Option<Vec<_>>>()
+        })
+    else {
+        return value.to_string();
+    };
+
+    let mut columns: Vec<&str> = rows
+        .iter()
+        .flat_map(|row| row.keys().map(String::as_str))
+        .collect();
+    columns.sort_unstable();
+    columns.dedup();
+
+    let line = |cells: Vec<String>| format!("| {} |", cells.join(" | "));
+    let mut lines = vec![
+        line(columns.iter().map(|column| escape_cell(column)).collect()),
+        line(columns.iter().map(|_| "---".to_string()).collect()),
+    ];
+    for row in rows {
+        lines.push(line(
+            columns
+                .iter()
+                .map(|column| match row.get(*column) {
+                    None | Some(Value::Null) => String::new(),
+                    Some(Value::String(text)) => escape_cell(text),
+                    Some(value) => escape_cell(&value.to_string()),
+                })
+                .collect(),
+        ));
+    }
+    lines.join("\n")
+}
+
+/// Keep a cell on one line and escape the pipes that would end it.
+fn escape_cell(text: &str) -> String {
+    text.split_whitespace()
+        .collect::<Vec<_>>()
+        .join(" ")
+        .replace('|', "\\|")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_to_markdown_table() {
+        let value = json!([
+            {"city": "Paris", "population": 2102650},
+            {"city": "Lyon | Rhône", "population": 522250},
+            {"city": "Nice\nCôte d'Azur"},
+        ]);
+        assert_eq!(
+            to_markdown_table(&value),
+            "| city | population |\n\
+             | --- | --- |\n\
+             | Paris | 2102650 |\n\
+             | Lyon \\| Rhône | 522250 |\n\
+             | Nice Côte d'Azur |  |"
+        );
+
+        // The keys of the later rows are sorted with the others
+        let value = json!([{"name": "Ada"}, {"born": 1815, "name": "Lovelace"}]);
+        assert_eq!(
+            to_markdown_table(&value),
+            "| born | name |\n| --- | --- |\n|  | Ada |\n| 1815 | Lovelace |"
+        );
+
+        assert_eq!(to_markdown_table(&json!(["a", "b"])), r#"["a","b"]"#);
+        assert_eq!(to_markdown_table(&json!([])), "[]");
+    }
+}
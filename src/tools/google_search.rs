@@ -1,13 +1,31 @@
-//! This module contains the Google search tool.
+//! This module contains the Google search tool. It searches through SerpApi, which can also query other engines
+//! like Bing or DuckDuckGo with the same API key.
 
 use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
 use serde_json::json;
 
-use super::base::BaseTool;
+use super::base::{
+    default_http_client, is_excluded, read_body, read_json_body, BaseTool, DEFAULT_MAX_BODY_BYTES,
+};
+use super::citation::Citations;
+use super::ddg_search::SearchResult;
 use super::tool_traits::Tool;
 use anyhow::{anyhow, Context, Result};
 
+/// The SerpApi engine used by default.
+pub const DEFAULT_ENGINE: &str = "google";
+
+/// The key of the organic results in the SerpApi response, for the given engine.
+fn default_results_key(engine: &str) -> &'static str {
+    match engine {
+        // Google News and Jobs have their own layouts
+        "google_news" => "news_results",
+        "google_jobs" => "jobs_results",
+        _ => "organic_results",
+    }
+}
+
 #[derive(Deserialize, JsonSchema)]
 #[schemars(title = "GoogleSearchToolParams")]
 pub struct GoogleSearchToolParams {
@@ -17,10 +35,21 @@ pub struct GoogleSearchToolParams {
     filter_year: Option<String>,
 }
 
-#[derive(Debug, Serialize, Default, Clone)]
+#[derive(Debug, Serialize, Clone)]
 pub struct GoogleSearchTool {
     pub tool: BaseTool,
     pub api_key: String,
+    /// The SerpApi engine, e.g. `google` or `bing`.
+    pub engine: String,
+    /// The key of the results in the response of the engine.
+    pub results_key: String,
+    /// Results from these domains, or their subdomains, are left out.
+    pub exclude_domains: Vec<String>,
+    /// Numbers the results so the model can cite them. See [`Citations`].
+    #[serde(skip)]
+    pub citations: Option<Citations>,
+    #[serde(skip)]
+    pub client: reqwest::blocking::Client,
 }
 
 impl GoogleSearchTool {
@@ -35,54 +64,121 @@ impl GoogleSearchTool {
                 description: "Performs a google web search for your query then returns a string of the top search results.",
             },
             api_key,
+            engine: DEFAULT_ENGINE.to_string(),
+            results_key: default_results_key(DEFAULT_ENGINE).to_string(),
+            exclude_domains: Vec::new(),
+            citations: None,
+            client: default_http_client(),
         }
     }
 
-    fn forward(&self, query: &str, filter_year: Option<&str>) -> Result<String> {
-        if self.api_key.is_empty() {
-            return Err(anyhow!("SERPAPI_API_KEY missing"));
+    /// Search with another SerpApi engine, e.g. `bing` or `duckduckgo`. The results are read from the key
+    /// the engine uses, which can be changed with [`GoogleSearchTool::with_results_key`].
+    pub fn with_engine(mut self, engine: &str) -> Self {
+        if engine != DEFAULT_ENGINE {
+            self.tool.description = "Performs a web search for your query then returns a string of the top search results.";
         }
-        let params = {
-            let mut params = json!({
-                "engine": "google",
-                "q": query,
-                "api_key": self.api_key,
-                "google_domain": "google.com",
-            });
+        self.engine = engine.to_string();
+        self.results_key = default_results_key(engine).to_string();
+        self
+    }
+
+    pub fn with_results_key(mut self, results_key: &str) -> Self {
+        self.results_key = results_key.to_string();
+        self
+    }
+
+    /// Leave out the results from these domains, e.g. `pinterest.com`, and their subdomains. The domains
+    /// are excluded in the query with `-site:` as well, so they do not take the place of other results.
+    pub fn with_exclude_domains(mut self, domains: &[&str]) -> Self {
+        self.exclude_domains = domains.iter().map(|domain| domain.to_string()).collect();
+        self
+    }
+
+    /// Number the results with `citations`, so the model can cite them with the [`CitationTool`] given
+    /// the same citations. The results are listed by their ids instead of their ranks, and the code agent
+    /// gets them as a list of `{id, title, snippet, url}` objects.
+    ///
+    /// [`CitationTool`]: super::CitationTool
+    pub fn with_citations(mut self, citations: Citations) -> Self {
+        self.citations = Some(citations);
+        self
+    }
 
-            if let Some(year) = filter_year {
-                params["tbs"] = json!(format!("cdr:1,cd_min:01/01/{},cd_max:12/31/{}", year, year));
+    /// The query parameters of the SerpApi request.
+    fn params(&self, query: &str, filter_year: Option<&str>) -> Result<serde_json::Value> {
+        let query = self
+            .exclude_domains
+            .iter()
+            .fold(query.to_string(), |query, domain| format!("{} -site:{}", query, domain));
+        let mut params = json!({
+            "engine": self.engine,
+            "q": query,
+            "api_key": self.api_key,
+        });
+        if self.engine == DEFAULT_ENGINE {
+            params["google_domain"] = json!("google.com");
+        }
+
+        if let Some(year) = filter_year {
+            if self.engine != DEFAULT_ENGINE {
+                return Err(anyhow!("Filtering on year is not supported by the {} engine. Do not filter on year.", self.engine));
             }
+            params["tbs"] = json!(format!("cdr:1,cd_min:01/01/{},cd_max:12/31/{}", year, year));
+        }
 
-            params
-        };
+        Ok(params)
+    }
 
-        let client = reqwest::blocking::Client::new();
-        let resp = client
+    fn forward(&self, query: &str, filter_year: Option<&str>) -> Result<String> {
+        let results = self.search(query, filter_year)?;
+        self.format_results(&results, query, filter_year)
+    }
+
+    /// The SerpApi response for the query.
+    fn search(&self, query: &str, filter_year: Option<&str>) -> Result<serde_json::Value> {
+        if self.api_key.is_empty() {
+            return Err(anyhow!("SERPAPI_API_KEY missing"));
+        }
+        let params = self.params(query, filter_year)?;
+
+        let resp = self
+            .client
             .get("https://serpapi.com/search.json")
             .query(&params)
             .send()
             .context("Failed to send request")?;
         if !resp.status().is_success() {
             let status = resp.status();
-            let text = resp.text().unwrap_or_default();
+            let (text, _) = read_body(resp, DEFAULT_MAX_BODY_BYTES).unwrap_or_default();
             return Err(anyhow!("Failed to fetch search results: HTTP {}: {}", status, text));
         }
 
-        let results: serde_json::Value = resp.json().context("Failed to parse JSON")?;
+        read_json_body(resp, DEFAULT_MAX_BODY_BYTES)
+    }
 
+    /// The results of a SerpApi response, without the excluded domains.
+    fn pages<'a>(&self, results: &'a serde_json::Value, query: &str, filter_year: Option<&str>) -> Result<Vec<&'a serde_json::Value>> {
+        let results_key = &self.results_key;
         let organic_results = results
-            .get("organic_results")
+            .get(results_key)
             .and_then(|v| v.as_array())
             .ok_or_else(|| {
                 if let Some(year) = filter_year {
-                    anyhow!("'organic_results' key not found for query: '{}' with filtering on year={}. Use a less restrictive query or do not filter on year.", query, year)
+                    anyhow!("'{}' key not found for query: '{}' with filtering on year={}. Use a less restrictive query or do not filter on year.", results_key, query, year)
                 } else {
-                    anyhow!("'organic_results' key not found for query: '{}'. Use a less restrictive query.", query)
+                    anyhow!("'{}' key not found for query: '{}'. Use a less restrictive query.", results_key, query)
                 }
             })?;
 
-        if organic_results.is_empty() {
+        let pages = organic_results
+            .iter()
+            .filter(|page| {
+                let link = page.get("link").and_then(|v| v.as_str()).unwrap_or("");
+                !is_excluded(link, &self.exclude_domains)
+            })
+            .collect::<Vec<_>>();
+        if pages.is_empty() {
             let suffix = if let Some(year) = filter_year {
                 format!(" with filter year={}", year)
             } else {
@@ -90,9 +186,45 @@ impl GoogleSearchTool {
             };
             return Err(anyhow!("No results found for '{}'. Try with a more general query{}.", query, suffix));
         }
+        Ok(pages)
+    }
+
+    /// The results of a SerpApi response, numbered when the tool has citations.
+    fn search_results(&self, results: &serde_json::Value, query: &str, filter_year: Option<&str>) -> Result<Vec<SearchResult>> {
+        let text = |page: &serde_json::Value, key: &str| {
+            page.get(key).and_then(|v| v.as_str()).unwrap_or("").to_string()
+        };
+        let mut results = self
+            .pages(results, query, filter_year)?
+            .into_iter()
+            .map(|page| SearchResult {
+                id: None,
+                title: text(page, "title"),
+                snippet: text(page, "snippet"),
+                url: text(page, "link"),
+            })
+            .collect::<Vec<_>>();
+        if let Some(citations) = &self.citations {
+            citations.number(&mut results);
+        }
+        Ok(results)
+    }
+
+    /// Format the results of a SerpApi response for the model.
+    fn format_results(&self, results: &serde_json::Value, query: &str, filter_year: Option<&str>) -> Result<String> {
+        let pages = self.pages(results, query, filter_year)?;
+        // Cited results are listed by their ids
+        let ids = match self.citations {
+            Some(_) => self
+                .search_results(results, query, filter_year)?
+                .iter()
+                .map(|result| result.id.unwrap_or_default())
+                .collect(),
+            None => (0..pages.len()).collect::<Vec<_>>(),
+        };
 
         let mut web_snippets = Vec::new();
-        for (idx, page) in organic_results.iter().enumerate() {
+        for (page, idx) in pages.into_iter().zip(ids) {
             let date_published = page.get("date").map_or(String::new(), |d| {
                 format!("\nDate published: {}", d.as_str().unwrap_or(""))
             });
@@ -120,6 +252,12 @@ impl GoogleSearchTool {
     }
 }
 
+impl Default for GoogleSearchTool {
+    fn default() -> Self {
+        Self::new(None)
+    }
+}
+
 impl Tool for GoogleSearchTool {
     type Params = GoogleSearchToolParams;
     fn name(&self) -> &'static str {
@@ -129,17 +267,128 @@ impl Tool for GoogleSearchTool {
         self.tool.description
     }
 
+    fn output_description(&self) -> &'static str {
+        "The results as markdown, a numbered `[title](url)` link and the snippet for each"
+    }
+
     fn forward(&self, arguments: GoogleSearchToolParams) -> Result<String> {
         let query = arguments.query;
         let filter_year = arguments.filter_year;
         self.forward(&query, filter_year.as_deref())
     }
+
+    fn forward_value(&self, arguments: GoogleSearchToolParams) -> Result<serde_json::Value> {
+        if self.citations.is_none() {
+            return Tool::forward(self, arguments).map(serde_json::Value::String);
+        }
+        let filter_year = arguments.filter_year.as_deref();
+        let response = self.search(&arguments.query, filter_year)?;
+        let results = self.search_results(&response, &arguments.query, filter_year)?;
+        Ok(serde_json::to_value(results)?)
+    }
+
+    fn validate(&self) -> Result<()> {
+        if self.api_key.is_empty() {
+            return Err(anyhow!(
+                "SERPAPI_API_KEY missing: set it or pass an API key to GoogleSearchTool::new"
+            ));
+        }
+        Ok(())
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    #[test]
+    fn test_bing_engine() {
+        let tool = GoogleSearchTool::new(Some("key".to_string())).with_engine("bing");
+        let params = tool.params("rust", None).unwrap();
+        assert_eq!(params["engine"], "bing");
+        assert_eq!(params["q"], "rust");
+        assert!(params.get("google_domain").is_none());
+        assert!(tool.params("rust", Some("2020")).is_err());
+
+        let response = json!({
+            "search_metadata": {"status": "Success"},
+            "search_parameters": {"engine": "bing", "q": "rust"},
+            "organic_results": [
+                {
+                    "position": 1,
+                    "title": "Rust Programming Language",
+                    "link": "https://www.rust-lang.org/",
+                    "displayed_link": "https://www.rust-lang.org",
+                    "snippet": "A language empowering everyone to build reliable and efficient software."
+                },
+                {
+                    "position": 2,
+                    "title": "Rust (programming language) - Wikipedia",
+                    "link": "https://en.wikipedia.org/wiki/Rust_(programming_language)",
+                    "displayed_link": "https://en.wikipedia.org/wiki/Rust_(programming_language)",
+                    "snippet": "Rust is a general-purpose programming language."
+                }
+            ]
+        });
+        let results = tool.format_results(&response, "rust", None).unwrap();
+        assert_eq!(
+            results,
+            "## Search Results\n0. [Rust Programming Language](https://www.rust-lang.org/)\n\nA language empowering everyone to build reliable and efficient software.\n\n\
+             1. [Rust (programming language) - Wikipedia](https://en.wikipedia.org/wiki/Rust_(programming_language))\n\nRust is a general-purpose programming language."
+        );
+    }
+
+    #[test]
+    fn test_exclude_domains() {
+        let tool =
+            GoogleSearchTool::new(Some("key".to_string())).with_exclude_domains(&["wikipedia.org"]);
+        let params = tool.params("rust", None).unwrap();
+        assert_eq!(params["q"], "rust -site:wikipedia.org");
+
+        let response = json!({
+            "organic_results": [
+                {"title": "Rust - Wikipedia", "link": "https://en.wikipedia.org/wiki/Rust"},
+                {"title": "Rust Programming Language", "link": "https://www.rust-lang.org/"}
+            ]
+        });
+        let results = tool.format_results(&response, "rust", None).unwrap();
+        assert_eq!(
+            results,
+            "## Search Results\n0. [Rust Programming Language](https://www.rust-lang.org/)\n"
+        );
+
+        let tool = tool.with_exclude_domains(&["wikipedia.org", "rust-lang.org"]);
+        assert!(tool.format_results(&response, "rust", None).is_err());
+    }
+
+    #[test]
+    fn test_citations() {
+        let citations = Citations::new();
+        let tool = GoogleSearchTool::new(Some("key".to_string())).with_citations(citations.clone());
+        let response = json!({
+            "organic_results": [
+                {"title": "Rust Programming Language", "link": "https://www.rust-lang.org/", "snippet": "Rust."},
+                {"title": "Rust - Wikipedia", "link": "https://en.wikipedia.org/wiki/Rust"}
+            ]
+        });
+        let results = tool.search_results(&response, "rust", None).unwrap();
+        assert_eq!(results.iter().map(|r| r.id).collect::<Vec<_>>(), vec![Some(1), Some(2)]);
+        assert_eq!(results[0].snippet, "Rust.");
+
+        // The same pages keep their ids in the next searches
+        let response = json!({
+            "organic_results": [
+                {"title": "Rust - Wikipedia", "link": "https://en.wikipedia.org/wiki/Rust"},
+                {"title": "Crates", "link": "https://crates.io/"}
+            ]
+        });
+        let results = tool.format_results(&response, "rust crates", None).unwrap();
+        assert_eq!(
+            results,
+            "## Search Results\n2. [Rust - Wikipedia](https://en.wikipedia.org/wiki/Rust)\n\n\n3. [Crates](https://crates.io/)\n"
+        );
+    }
+
     #[test]
     #[ignore]
     fn test_google_search_tool() {
@@ -0,0 +1,213 @@
+//! This module contains the HTML select tool. The model uses this tool to extract specific elements of a webpage with CSS selectors.
+
+use reqwest::Url;
+use schemars::JsonSchema;
+use scraper::{Html, Selector};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+use super::base::{
+    default_http_client, read_body, truncation_notice, BaseTool, DEFAULT_MAX_BODY_BYTES,
+};
+use super::tool_traits::Tool;
+use anyhow::{anyhow, Context, Result};
+
+/// The default maximum number of matching elements returned to the model.
+const DEFAULT_MAX_MATCHES: usize = 100;
+
+#[derive(Deserialize, JsonSchema)]
+#[schemars(title = "HtmlSelectToolParams")]
+pub struct HtmlSelectToolParams {
+    #[schemars(description = "The HTML to search. Either html or url must be given")]
+    html: Option<String>,
+    #[schemars(description = "The url of the webpage to search. Either html or url must be given")]
+    url: Option<String>,
+    #[schemars(
+        description = "The CSS selector of the elements, e.g. 'table.prices td' or 'a[href]'"
+    )]
+    selector: String,
+    #[schemars(
+        description = "The attribute to extract from each element, e.g. 'href'. Returns the text of the elements if not given"
+    )]
+    attr: Option<String>,
+}
+
+#[derive(Debug, Serialize, Clone)]
+pub struct HtmlSelectTool {
+    pub tool: BaseTool,
+    /// The maximum number of matching elements returned to the model.
+    pub max_matches: usize,
+    /// The maximum number of bytes of a page read, [`DEFAULT_MAX_BODY_BYTES`] by default. Only the elements
+    /// in the first `max_response_bytes` of a larger page are selected.
+    pub max_response_bytes: usize,
+    #[serde(skip)]
+    pub client: reqwest::blocking::Client,
+}
+
+impl HtmlSelectTool {
+    pub fn new() -> Self {
+        HtmlSelectTool {
+            tool: BaseTool {
+                name: "html_select",
+                description: "Selects the elements of an HTML page matching a CSS selector and returns their text, or one of their attributes, as a JSON list.",
+            },
+            max_matches: DEFAULT_MAX_MATCHES,
+            max_response_bytes: DEFAULT_MAX_BODY_BYTES,
+            client: default_http_client(),
+        }
+    }
+
+    pub fn with_max_matches(mut self, max_matches: usize) -> Self {
+        self.max_matches = max_matches;
+        self
+    }
+
+    pub fn with_max_response_bytes(mut self, max_response_bytes: usize) -> Self {
+        self.max_response_bytes = max_response_bytes;
+        self
+    }
+
+    pub fn forward(
+        &self,
+        html: Option<&str>,
+        url: Option<&str>,
+        selector: &str,
+        attr: Option<&str>,
+    ) -> Result<String> {
+        let selector = Selector::parse(selector)
+            .map_err(|e| anyhow!("Invalid CSS selector '{}': {}", selector, e))?;
+        let (html, base_url, truncated) = match (html, url) {
+            (Some(html), _) => (html.to_string(), None, false),
+            (None, Some(url)) => {
+                let url = Url::parse(url).with_context(|| format!("Invalid url: {}", url))?;
+                let (html, truncated) = self.fetch(&url)?;
+                (html, Some(url), truncated)
+            }
+            (None, None) => return Err(anyhow!("Either html or url must be given")),
+        };
+        let selected = select(&html, &selector, attr, base_url.as_ref(), self.max_matches);
+        match truncated {
+            true => Ok(format!(
+                "{}\n\n{}",
+                selected,
+                truncation_notice(self.max_response_bytes)
+            )),
+            false => Ok(selected.to_string()),
+        }
+    }
+
+    /// Fetch the page at `url`, returning its HTML and whether it was truncated.
+    fn fetch(&self, url: &Url) -> Result<(String, bool)> {
+        let response = self
+            .client
+            .get(url.clone())
+            .send()
+            .with_context(|| format!("Failed to make the request to {}", url))?;
+        if !response.status().is_success() {
+            return Err(anyhow!(
+                "Failed to fetch the webpage {}: HTTP {}",
+                url,
+                response.status()
+            ));
+        }
+        read_body(response, self.max_response_bytes)
+    }
+}
+
+/// The text, or the `attr` attribute, of the first `max_matches` elements matching `selector`. Links are
+/// resolved against `base_url` when the page was fetched.
+fn select(
+    html: &str,
+    selector: &Selector,
+    attr: Option<&str>,
+    base_url: Option<&Url>,
+    max_matches: usize,
+) -> serde_json::Value {
+    let document = Html::parse_document(html);
+    let matches = document.select(selector);
+    let values = match attr {
+        Some(attr) => matches
+            .filter_map(|element| element.value().attr(attr))
+            .map(|value| match (base_url, attr) {
+                (Some(base_url), "href" | "src") => base_url
+                    .join(value)
+                    .map(|url| url.to_string())
+                    .unwrap_or_else(|_| value.to_string()),
+                _ => value.to_string(),
+            })
+            .take(max_matches)
+            .collect::<Vec<_>>(),
+        None => matches
+            .map(|element| {
+                element
+                    .text()
+                    .collect::<String>()
+                    .split_whitespace()
+                    .collect::<Vec<_>>()
+                    .join(" ")
+            })
+            .take(max_matches)
+            .collect::<Vec<_>>(),
+    };
+    json!(values)
+}
+
+impl Default for HtmlSelectTool {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Tool for HtmlSelectTool {
+    type Params = HtmlSelectToolParams;
+
+    fn name(&self) -> &'static str {
+        self.tool.name
+    }
+
+    fn description(&self) -> &'static str {
+        self.tool.description
+    }
+
+    fn output_description(&self) -> &'static str {
+        "A JSON list with the text, or the attribute, of each matching element"
+    }
+
+    fn forward(&self, arguments: HtmlSelectToolParams) -> Result<String> {
+        self.forward(
+            arguments.html.as_deref(),
+            arguments.url.as_deref(),
+            &arguments.selector,
+            arguments.attr.as_deref(),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HTML: &str = r#"<html><body>
+        <a href="/docs">Docs</a>
+        <p>Read the <a href="https://example.com/guide">guide</a></p>
+        <a name="top">Top</a>
+    </body></html>"#;
+
+    #[test]
+    fn test_html_select_tool() {
+        let tool = HtmlSelectTool::new();
+        let hrefs = tool.forward(Some(HTML), None, "a", Some("href")).unwrap();
+        assert_eq!(hrefs, r#"["/docs","https://example.com/guide"]"#);
+
+        let texts = tool.forward(Some(HTML), None, "p", None).unwrap();
+        assert_eq!(texts, r#"["Read the guide"]"#);
+
+        let base_url = Url::parse("https://example.com/blog/").unwrap();
+        let selector = Selector::parse("a[href]").unwrap();
+        let links = select(HTML, &selector, Some("href"), Some(&base_url), 1);
+        assert_eq!(links, json!(["https://example.com/docs"]));
+
+        assert!(tool.forward(Some(HTML), None, "a[", None).is_err());
+        assert!(tool.forward(None, None, "a", None).is_err());
+    }
+}
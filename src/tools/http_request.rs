@@ -0,0 +1,217 @@
+//! This module contains the HTTP request tool. The model uses this tool to call web APIs and read the raw response.
+
+use std::collections::HashMap;
+
+use reqwest::blocking::Client;
+use reqwest::redirect::Policy;
+use reqwest::{Method, Url};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+use super::base::{read_body, truncation_notice, BaseTool, DEFAULT_USER_AGENT};
+use super::tool_traits::Tool;
+use anyhow::{anyhow, Context, Result};
+
+/// The default maximum number of bytes of the response body returned to the model.
+const DEFAULT_MAX_RESPONSE_BYTES: usize = 100_000;
+
+const MAX_REDIRECTS: usize = 10;
+
+#[derive(Deserialize, JsonSchema)]
+#[schemars(title = "HttpRequestToolParams")]
+pub struct HttpRequestToolParams {
+    #[schemars(description = "The HTTP method, e.g. 'GET', 'POST', 'PUT', 'PATCH' or 'DELETE'")]
+    method: String,
+    #[schemars(description = "The url to send the request to")]
+    url: String,
+    #[schemars(description = "The headers to send with the request")]
+    headers: Option<HashMap<String, String>>,
+    #[schemars(description = "The body of the request, e.g. a JSON string")]
+    body: Option<String>,
+}
+
+/// Sends raw HTTP requests to an allowlist of hosts and returns the status and response text.
+///
+/// The allowlist is required so the tool cannot be pointed at internal services. Redirects to hosts
+/// outside of the allowlist are refused as well.
+#[derive(Debug, Serialize, Clone)]
+pub struct HttpRequestTool {
+    pub tool: BaseTool,
+    /// The hosts the tool may send requests to. An entry `*.example.com` allows every subdomain of
+    /// `example.com`.
+    pub allowed_hosts: Vec<String>,
+    /// The maximum number of bytes of the response body returned to the model.
+    pub max_response_bytes: usize,
+    #[serde(skip)]
+    pub client: Client,
+}
+
+impl HttpRequestTool {
+    /// Create the tool for the given hosts, e.g. `["api.github.com", "*.example.com"]`. Requests to any
+    /// other host are refused, so an empty list allows nothing.
+    ///
+    /// Panics if the HTTP client cannot be built, e.g. when the TLS backend fails to initialize.
+    pub fn new(allowed_hosts: &[&str]) -> Self {
+        let allowed_hosts = allowed_hosts
+            .iter()
+            .map(|host| host.trim().to_lowercase())
+            .collect::<Vec<_>>();
+        let redirect_hosts = allowed_hosts.clone();
+        let client = Client::builder()
+            .user_agent(DEFAULT_USER_AGENT)
+            .redirect(Policy::custom(move |attempt| {
+                if attempt.previous().len() >= MAX_REDIRECTS {
+                    attempt.error("too many redirects")
+                } else if is_allowed(&redirect_hosts, attempt.url()) {
+                    attempt.follow()
+                } else {
+                    let error = format!("redirect to {} is not allowed", attempt.url());
+                    attempt.error(error)
+                }
+            }))
+            .build()
+            // A default client would follow redirects to any host
+            .expect("Failed to build the HTTP client with the redirect policy");
+
+        HttpRequestTool {
+            tool: BaseTool {
+                name: "http_request",
+                description: "Sends an HTTP request and returns the status code and the raw response text. Use this to call web APIs.",
+            },
+            allowed_hosts,
+            max_response_bytes: DEFAULT_MAX_RESPONSE_BYTES,
+            client,
+        }
+    }
+
+    pub fn with_max_response_bytes(mut self, max_response_bytes: usize) -> Self {
+        self.max_response_bytes = max_response_bytes;
+        self
+    }
+
+    pub fn forward(
+        &self,
+        method: &str,
+        url: &str,
+        headers: Option<&HashMap<String, String>>,
+        body: Option<&str>,
+    ) -> Result<String> {
+        let method = Method::from_bytes(method.trim().to_uppercase().as_bytes())
+            .map_err(|_| anyhow!("Invalid HTTP method: {}", method))?;
+        let url = Url::parse(url).with_context(|| format!("Invalid url: {}", url))?;
+        if !is_allowed(&self.allowed_hosts, &url) {
+            return Err(anyhow!(
+                "Requests to {} are not allowed. Allowed hosts: {}",
+                url.host_str().unwrap_or_default(),
+                self.allowed_hosts.join(", ")
+            ));
+        }
+
+        let mut request = self.client.request(method, url.clone());
+        for (key, value) in headers.into_iter().flatten() {
+            request = request.header(key, value);
+        }
+        if let Some(body) = body {
+            request = request.body(body.to_string());
+        }
+        let response = request
+            .send()
+            .with_context(|| format!("Failed to make the request to {}", url))?;
+        let status = response.status();
+
+        let (body, truncated) = read_body(response, self.max_response_bytes)?;
+
+        let mut output = format!("HTTP {}\n\n{}", status, body);
+        if truncated {
+            output.push_str("\n\n");
+            output.push_str(&truncation_notice(self.max_response_bytes));
+        }
+        Ok(output)
+    }
+}
+
+/// Whether `url` is an http(s) url whose host is in `allowed_hosts`.
+fn is_allowed(allowed_hosts: &[String], url: &Url) -> bool {
+    if !matches!(url.scheme(), "http" | "https") {
+        return false;
+    }
+    let Some(host) = url.host_str() else {
+        return false;
+    };
+    let host = host.to_lowercase();
+    allowed_hosts
+        .iter()
+        .any(|allowed| match allowed.strip_prefix("*.") {
+            Some(domain) => host
+                .strip_suffix(domain)
+                .is_some_and(|subdomain| subdomain.ends_with('.')),
+            None => host == *allowed,
+        })
+}
+
+impl Tool for HttpRequestTool {
+    type Params = HttpRequestToolParams;
+
+    fn name(&self) -> &'static str {
+        self.tool.name
+    }
+
+    fn description(&self) -> &'static str {
+        self.tool.description
+    }
+
+    fn output_description(&self) -> &'static str {
+        "The status code and the raw response text"
+    }
+
+    fn forward(&self, arguments: HttpRequestToolParams) -> Result<String> {
+        self.forward(
+            &arguments.method,
+            &arguments.url,
+            arguments.headers.as_ref(),
+            arguments.body.as_deref(),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_allowed_hosts() {
+        let allowed = vec!["api.github.com".to_string(), "*.example.com".to_string()];
+        let allows = |url: &str| is_allowed(&allowed, &Url::parse(url).unwrap());
+        assert!(allows("https://api.github.com/repos"));
+        assert!(allows("http://API.GitHub.com"));
+        assert!(allows("https://data.example.com/v1"));
+        assert!(!allows("https://example.com"));
+        assert!(!allows("https://badexample.com"));
+        assert!(!allows("https://github.com"));
+        assert!(!allows("http://127.0.0.1:8080"));
+        assert!(!allows("file:///etc/passwd"));
+    }
+
+    #[test]
+    fn test_disallowed_host_is_refused() {
+        let tool = HttpRequestTool::new(&["api.github.com"]);
+        let error = tool
+            .forward("GET", "http://169.254.169.254/latest/meta-data", None, None)
+            .unwrap_err();
+        assert!(error.to_string().contains("not allowed"));
+        assert!(tool
+            .forward("FETCH ME", "https://api.github.com", None, None)
+            .is_err());
+    }
+
+    #[test]
+    #[ignore]
+    fn test_http_request_tool() {
+        let tool = HttpRequestTool::new(&["httpbin.org"]).with_max_response_bytes(20);
+        let out = tool
+            .forward("POST", "https://httpbin.org/post", None, Some("{}"))
+            .unwrap();
+        assert!(out.starts_with("HTTP 200 OK"));
+        assert!(out.ends_with("[Response truncated after 20 bytes]"));
+    }
+}
@@ -0,0 +1,261 @@
+//! This module contains the image generation tool. It generates images with the OpenAI images API.
+
+use std::path::PathBuf;
+
+use base64::Engine;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+use super::base::{
+    default_http_client, read_body, read_json_body, BaseTool, DEFAULT_MAX_BODY_BYTES,
+};
+use super::tool_traits::Tool;
+use crate::models::error_body::{max_error_body_length, truncate_error_body};
+use anyhow::{anyhow, Context, Result};
+
+const OPENAI_IMAGES_URL: &str = "https://api.openai.com/v1/images/generations";
+
+#[derive(Deserialize, JsonSchema)]
+#[schemars(title = "ImageGenerationToolParams")]
+pub struct ImageGenerationToolParams {
+    #[schemars(description = "A detailed description of the image to generate")]
+    prompt: String,
+    #[schemars(
+        description = "The size of the image, e.g. '1024x1024', '1792x1024' or '1024x1792'"
+    )]
+    size: Option<String>,
+}
+
+/// How the generated image is returned to the model.
+#[derive(Debug, Serialize, Default, Clone, Copy, PartialEq, Eq)]
+pub enum ImageResponseFormat {
+    /// Return the URL of the image hosted by OpenAI. The URL expires after an hour.
+    #[default]
+    Url,
+    /// Download the image as base64, save it in the output directory and return its path.
+    B64Json,
+}
+
+#[derive(Debug, Serialize, Clone)]
+pub struct ImageGenerationTool {
+    pub tool: BaseTool,
+    pub model: String,
+    pub response_format: ImageResponseFormat,
+    /// Where images are saved with [`ImageResponseFormat::B64Json`]. Relative paths are resolved against
+    /// the working directory, which is the sandbox when the agent runs in one.
+    pub output_dir: PathBuf,
+    /// The images endpoint, the one of OpenAI by default.
+    pub url: String,
+    #[serde(skip)]
+    pub api_key: String,
+    #[serde(skip)]
+    pub client: reqwest::blocking::Client,
+}
+
+impl ImageGenerationTool {
+    pub fn new(api_key: Option<String>) -> Self {
+        let api_key = api_key
+            .or_else(|| std::env::var("OPENAI_API_KEY").ok())
+            .unwrap_or_default();
+
+        ImageGenerationTool {
+            tool: BaseTool {
+                name: "image_generation",
+                description: "Generates an image from a text description and returns where to find the image.",
+            },
+            model: "dall-e-3".to_string(),
+            response_format: ImageResponseFormat::Url,
+            output_dir: PathBuf::from("."),
+            url: OPENAI_IMAGES_URL.to_string(),
+            api_key,
+            client: default_http_client(),
+        }
+    }
+
+    pub fn with_model(mut self, model: &str) -> Self {
+        self.model = model.to_string();
+        self
+    }
+
+    pub fn with_response_format(mut self, response_format: ImageResponseFormat) -> Self {
+        self.response_format = response_format;
+        self
+    }
+
+    pub fn with_output_dir(mut self, output_dir: impl Into<PathBuf>) -> Self {
+        self.output_dir = output_dir.into();
+        self
+    }
+
+    /// Send the requests to another endpoint compatible with the OpenAI images API.
+    pub fn with_url(mut self, url: &str) -> Self {
+        self.url = url.to_string();
+        self
+    }
+
+    pub fn forward(&self, prompt: &str, size: Option<&str>) -> Result<String> {
+        if self.api_key.is_empty() {
+            return Err(anyhow!("OPENAI_API_KEY missing"));
+        }
+        let response_format = match self.response_format {
+            ImageResponseFormat::Url => "url",
+            ImageResponseFormat::B64Json => "b64_json",
+        };
+        let body = json!({
+            "model": self.model,
+            "prompt": prompt,
+            "n": 1,
+            "size": size.unwrap_or("1024x1024"),
+            "response_format": response_format,
+        });
+
+        let resp = self
+            .client
+            .post(&self.url)
+            .bearer_auth(&self.api_key)
+            .json(&body)
+            .send()
+            .context("Failed to send request")?;
+        let status = resp.status();
+        if !status.is_success() {
+            // A gateway or proxy can answer with an HTML page instead of a JSON error
+            let (text, _) = read_body(resp, DEFAULT_MAX_BODY_BYTES).unwrap_or_default();
+            let error = serde_json::from_str::<Value>(&text).unwrap_or_default()["error"].take();
+            let message = match error["message"].as_str() {
+                Some(message) => message.to_string(),
+                None => truncate_error_body(&text, max_error_body_length()),
+            };
+            // A rejected prompt is something the model can fix, so it gets the reason as an observation
+            if error["code"] == "content_policy_violation" {
+                return Ok(format!("The image could not be generated: {}", message));
+            }
+            return Err(anyhow!(
+                "Failed to generate image: HTTP {}: {}",
+                status,
+                message
+            ));
+        }
+        let result: Value = read_json_body(resp, DEFAULT_MAX_BODY_BYTES)?;
+
+        let image = &result["data"][0];
+        match self.response_format {
+            ImageResponseFormat::Url => image["url"]
+                .as_str()
+                .map(|url| format!("Image generated: {}", url))
+                .ok_or_else(|| anyhow!("No image URL in the response")),
+            ImageResponseFormat::B64Json => {
+                let data = image["b64_json"]
+                    .as_str()
+                    .ok_or_else(|| anyhow!("No image data in the response"))?;
+                let path = self.save_image(data)?;
+                Ok(format!("Image saved to {}", path.display()))
+            }
+        }
+    }
+
+    fn save_image(&self, b64_data: &str) -> Result<PathBuf> {
+        let bytes = base64::engine::general_purpose::STANDARD
+            .decode(b64_data)
+            .context("Invalid image data")?;
+        std::fs::create_dir_all(&self.output_dir)?;
+        let path = self.output_dir.join(format!(
+            "image_{}.png",
+            chrono::Local::now().format("%Y%m%d_%H%M%S_%3f")
+        ));
+        std::fs::write(&path, bytes)?;
+        Ok(path)
+    }
+}
+
+impl Default for ImageGenerationTool {
+    fn default() -> Self {
+        Self::new(None)
+    }
+}
+
+impl Tool for ImageGenerationTool {
+    type Params = ImageGenerationToolParams;
+
+    fn name(&self) -> &'static str {
+        self.tool.name
+    }
+
+    fn description(&self) -> &'static str {
+        self.tool.description
+    }
+
+    fn output_description(&self) -> &'static str {
+        "Where the image was saved"
+    }
+
+    fn forward(&self, arguments: ImageGenerationToolParams) -> Result<String> {
+        self.forward(&arguments.prompt, arguments.size.as_deref())
+    }
+
+    fn validate(&self) -> Result<()> {
+        if self.api_key.is_empty() {
+            return Err(anyhow!(
+                "OPENAI_API_KEY missing: set it or pass an API key to ImageGenerationTool::new"
+            ));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_server::{serve, Response};
+
+    #[test]
+    fn test_save_image() {
+        let dir = tempfile::tempdir().unwrap();
+        let tool = ImageGenerationTool::new(Some("key".to_string()))
+            .with_response_format(ImageResponseFormat::B64Json)
+            .with_output_dir(dir.path().join("images"));
+        let data = base64::engine::general_purpose::STANDARD.encode(b"png bytes");
+        let path = tool.save_image(&data).unwrap();
+        assert!(path.starts_with(dir.path().join("images")));
+        assert_eq!(std::fs::read(path).unwrap(), b"png bytes");
+    }
+
+    #[test]
+    fn test_errors_are_read_before_the_image() {
+        let (url, server) = serve(3, |i, _| match i {
+            0 => Response::html(502, "<html>Bad gateway</html>".to_string()),
+            1 => Response::json(
+                400,
+                json!({"error": {"code": "content_policy_violation", "message": "Not allowed"}}),
+            ),
+            _ => Response::json(
+                200,
+                json!({"data": [{"url": "https://images.test/crab.png"}]}),
+            ),
+        });
+        let tool = ImageGenerationTool::new(Some("key".to_string())).with_url(&url);
+
+        let error = tool.forward("A crab", None).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "Failed to generate image: HTTP 502 Bad Gateway: <html>Bad gateway</html>"
+        );
+        assert_eq!(
+            tool.forward("A crab", None).unwrap(),
+            "The image could not be generated: Not allowed"
+        );
+        assert_eq!(
+            tool.forward("A crab", None).unwrap(),
+            "Image generated: https://images.test/crab.png"
+        );
+        server.join().unwrap();
+    }
+
+    #[test]
+    #[ignore]
+    fn test_image_generation_tool() {
+        let tool = ImageGenerationTool::new(None);
+        let out = tool.forward("A watercolor crab", None).unwrap();
+        assert!(out.starts_with("Image generated: https://"));
+    }
+}
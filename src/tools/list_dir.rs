@@ -0,0 +1,258 @@
+//! This module contains the list directory tool. The model uses this tool to discover the files in the working directory.
+
+use std::path::{Component, Path, PathBuf};
+
+use regex::Regex;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+use super::base::BaseTool;
+use super::tool_traits::Tool;
+use anyhow::{anyhow, Context, Result};
+
+/// The default maximum number of paths returned to the model.
+const DEFAULT_MAX_ENTRIES: usize = 500;
+
+#[derive(Deserialize, JsonSchema)]
+#[schemars(title = "ListDirToolParams")]
+pub struct ListDirToolParams {
+    #[schemars(
+        description = "A glob pattern the paths must match, e.g. '*.txt' or 'src/**/*.rs'. A pattern without '/' is matched against the file names. Lists everything if not given"
+    )]
+    pattern: Option<String>,
+    #[schemars(description = "Whether to list the subdirectories as well. Defaults to false")]
+    recursive: Option<bool>,
+}
+
+/// Lists the files under a root directory, the current working directory by default, so the model can
+/// find what to read. Paths are relative to the root and the tool never leaves it: absolute patterns and
+/// patterns with `..` are refused, and symlinked directories are not followed.
+#[derive(Debug, Serialize, Clone)]
+pub struct ListDirTool {
+    pub tool: BaseTool,
+    /// The directory that is listed. The current working directory, e.g. the sandbox, if not set.
+    pub root: Option<PathBuf>,
+    /// The maximum number of paths returned to the model.
+    pub max_entries: usize,
+}
+
+impl ListDirTool {
+    pub fn new() -> Self {
+        ListDirTool {
+            tool: BaseTool {
+                name: "list_dir",
+                description: "Lists the files and directories in the working directory with their sizes. Use this to find the files to work with.",
+            },
+            root: None,
+            max_entries: DEFAULT_MAX_ENTRIES,
+        }
+    }
+
+    pub fn with_root(mut self, root: impl Into<PathBuf>) -> Self {
+        self.root = Some(root.into());
+        self
+    }
+
+    /// Return at most `max_entries` paths, at least one.
+    pub fn with_max_entries(mut self, max_entries: usize) -> Self {
+        self.max_entries = max_entries.max(1);
+        self
+    }
+
+    pub fn forward(&self, pattern: Option<&str>, recursive: bool) -> Result<String> {
+        let root = match &self.root {
+            Some(root) => root.clone(),
+            None => std::env::current_dir().context("Failed to read the working directory")?,
+        };
+        let pattern = pattern.map(glob_to_regex).transpose()?;
+
+        let mut entries = Vec::new();
+        list(&root, Path::new(""), recursive, &mut entries)?;
+        entries.sort();
+
+        let matches = entries
+            .iter()
+            .filter(|(path, _)| match &pattern {
+                Some((regex, true)) => regex.is_match(path.trim_end_matches('/')),
+                Some((regex, false)) => {
+                    let name = path.trim_end_matches('/').rsplit('/').next();
+                    name.is_some_and(|name| regex.is_match(name))
+                }
+                None => true,
+            })
+            .collect::<Vec<_>>();
+        if matches.is_empty() {
+            return Ok("No matching files".to_string());
+        }
+
+        let max_entries = self.max_entries.max(1);
+        let mut output = matches
+            .iter()
+            .take(max_entries)
+            .map(|(path, size)| match size {
+                Some(size) => format!("{} ({} bytes)", path, size),
+                None => path.clone(),
+            })
+            .collect::<Vec<_>>()
+            .join("\n");
+        if matches.len() > max_entries {
+            output.push_str(&format!(
+                "\n[{} more paths not shown]",
+                matches.len() - max_entries
+            ));
+        }
+        Ok(output)
+    }
+}
+
+impl Default for ListDirTool {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Collect the entries of `root/relative` as `/` separated paths relative to `root`, with the size of the
+/// files. Directories end with a `/`.
+fn list(
+    root: &Path,
+    relative: &Path,
+    recursive: bool,
+    entries: &mut Vec<(String, Option<u64>)>,
+) -> Result<()> {
+    let dir = root.join(relative);
+    let read_dir =
+        std::fs::read_dir(&dir).with_context(|| format!("Failed to list {}", dir.display()))?;
+    for entry in read_dir.flatten() {
+        let path = relative.join(entry.file_name());
+        let display = path
+            .components()
+            .map(|component| component.as_os_str().to_string_lossy())
+            .collect::<Vec<_>>()
+            .join("/");
+        // Symlinks are listed but not followed, so a link cannot lead outside of the root
+        let Ok(metadata) = entry.path().symlink_metadata() else {
+            continue;
+        };
+        if metadata.is_dir() {
+            entries.push((format!("{}/", display), None));
+            if recursive {
+                list(root, &path, recursive, entries)?;
+            }
+        } else {
+            entries.push((display, Some(metadata.len())));
+        }
+    }
+    Ok(())
+}
+
+/// Translate a glob pattern to a regex. `*` and `?` do not match `/`, `**` does. Also returns whether the
+/// pattern is matched against the whole path rather than the file name.
+fn glob_to_regex(pattern: &str) -> Result<(Regex, bool)> {
+    let pattern = pattern.trim();
+    let escapes_root = Path::new(pattern).components().any(|component| {
+        matches!(
+            component,
+            Component::ParentDir | Component::RootDir | Component::Prefix(_)
+        )
+    });
+    if escapes_root {
+        return Err(anyhow!(
+            "The pattern '{}' points outside of the working directory. Use a relative pattern without '..'",
+            pattern
+        ));
+    }
+    let pattern = pattern.trim_start_matches("./");
+
+    let mut regex = String::from("^");
+    let mut chars = pattern.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '*' if chars.peek() == Some(&'*') => {
+                chars.next();
+                // `**/` also matches no directory at all
+                if chars.peek() == Some(&'/') {
+                    chars.next();
+                    regex.push_str("(?:.*/)?");
+                } else {
+                    regex.push_str(".*");
+                }
+            }
+            '*' => regex.push_str("[^/]*"),
+            '?' => regex.push_str("[^/]"),
+            c => regex.push_str(&regex::escape(&c.to_string())),
+        }
+    }
+    regex.push('$');
+    let regex = Regex::new(&regex).map_err(|e| anyhow!("Invalid pattern '{}': {}", pattern, e))?;
+    Ok((regex, pattern.contains('/')))
+}
+
+impl Tool for ListDirTool {
+    type Params = ListDirToolParams;
+
+    fn name(&self) -> &'static str {
+        self.tool.name
+    }
+
+    fn description(&self) -> &'static str {
+        self.tool.description
+    }
+
+    fn output_description(&self) -> &'static str {
+        "One line per file, with its size, or directory"
+    }
+
+    fn forward(&self, arguments: ListDirToolParams) -> Result<String> {
+        self.forward(
+            arguments.pattern.as_deref(),
+            arguments.recursive.unwrap_or(false),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_list_dir_tool() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("notes.txt"), "hello").unwrap();
+        std::fs::write(dir.path().join("data.csv"), "a,b").unwrap();
+        std::fs::create_dir(dir.path().join("docs")).unwrap();
+        std::fs::write(dir.path().join("docs/readme.txt"), "read me").unwrap();
+        let tool = ListDirTool::new().with_root(dir.path());
+
+        assert_eq!(
+            tool.forward(Some("*.txt"), false).unwrap(),
+            "notes.txt (5 bytes)"
+        );
+        assert_eq!(
+            tool.forward(Some("*.txt"), true).unwrap(),
+            "docs/readme.txt (7 bytes)\nnotes.txt (5 bytes)"
+        );
+        assert_eq!(
+            tool.forward(Some("docs/*"), true).unwrap(),
+            "docs/readme.txt (7 bytes)"
+        );
+        assert_eq!(
+            tool.forward(None, false).unwrap(),
+            "data.csv (3 bytes)\ndocs/\nnotes.txt (5 bytes)"
+        );
+
+        assert!(tool.forward(Some("../*"), false).is_err());
+        assert!(tool.forward(Some("docs/../../*"), true).is_err());
+        assert!(tool.forward(Some("/etc/*"), false).is_err());
+
+        let tool = ListDirTool::default().with_root(dir.path());
+        assert_eq!(
+            tool.forward(None, false).unwrap(),
+            "data.csv (3 bytes)\ndocs/\nnotes.txt (5 bytes)"
+        );
+        let tool = tool.with_max_entries(0);
+        assert_eq!(
+            tool.forward(None, false).unwrap(),
+            "data.csv (3 bytes)\n[2 more paths not shown]"
+        );
+    }
+}
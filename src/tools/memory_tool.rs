@@ -0,0 +1,162 @@
+//! This module contains the memory tool. The model uses this tool to store notes that persist across steps.
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+use super::base::BaseTool;
+use super::tool_traits::Tool;
+use anyhow::{anyhow, Result};
+
+#[derive(Debug, Deserialize, JsonSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum MemoryOperation {
+    Set,
+    Get,
+    List,
+    Delete,
+}
+
+#[derive(Debug, Deserialize, JsonSchema)]
+#[schemars(title = "MemoryToolParams")]
+pub struct MemoryToolParams {
+    #[schemars(
+        description = "The operation to perform: 'set' stores a value under a key, 'get' reads a key, 'list' shows all keys and 'delete' removes a key"
+    )]
+    operation: MemoryOperation,
+    #[schemars(description = "The key to set, get or delete")]
+    key: Option<String>,
+    #[schemars(description = "The value to store for 'set'")]
+    value: Option<String>,
+}
+
+/// A key-value scratchpad for the model. Each `MemoryTool::new` creates its own store, which is shared
+/// by the clones of the tool, so one agent keeps its notes for its whole lifetime without seeing the
+/// notes of other agents.
+#[derive(Debug, Serialize, Default, Clone)]
+pub struct MemoryTool {
+    pub tool: BaseTool,
+    #[serde(skip)]
+    store: Arc<Mutex<HashMap<String, String>>>,
+}
+
+impl MemoryTool {
+    pub fn new() -> Self {
+        MemoryTool {
+            tool: BaseTool {
+                name: "memory",
+                description: "Stores and retrieves notes that persist across steps. Use it to save intermediate findings under a key and read them back later.",
+            },
+            store: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    pub fn forward(
+        &self,
+        operation: MemoryOperation,
+        key: Option<&str>,
+        value: Option<&str>,
+    ) -> Result<String> {
+        let mut store = self
+            .store
+            .lock()
+            .map_err(|_| anyhow!("Memory store is poisoned"))?;
+        let key = || key.ok_or_else(|| anyhow!("A key is required for this operation"));
+        match operation {
+            MemoryOperation::Set => {
+                let key = key()?;
+                let value = value.ok_or_else(|| anyhow!("A value is required for 'set'"))?;
+                store.insert(key.to_string(), value.to_string());
+                Ok(format!("Stored '{}'", key))
+            }
+            MemoryOperation::Get => {
+                let key = key()?;
+                Ok(store
+                    .get(key)
+                    .cloned()
+                    .unwrap_or_else(|| format!("No value stored for '{}'", key)))
+            }
+            MemoryOperation::List => {
+                if store.is_empty() {
+                    return Ok("The memory is empty".to_string());
+                }
+                let mut keys = store.keys().cloned().collect::<Vec<_>>();
+                keys.sort();
+                Ok(format!("Stored keys: {}", keys.join(", ")))
+            }
+            MemoryOperation::Delete => {
+                let key = key()?;
+                match store.remove(key) {
+                    Some(_) => Ok(format!("Deleted '{}'", key)),
+                    None => Ok(format!("No value stored for '{}'", key)),
+                }
+            }
+        }
+    }
+}
+
+impl Tool for MemoryTool {
+    type Params = MemoryToolParams;
+    fn name(&self) -> &'static str {
+        self.tool.name
+    }
+    fn description(&self) -> &'static str {
+        self.tool.description
+    }
+
+    fn output_description(&self) -> &'static str {
+        "A confirmation, the stored value or the list of keys"
+    }
+
+    fn forward(&self, arguments: MemoryToolParams) -> Result<String> {
+        self.forward(
+            arguments.operation,
+            arguments.key.as_deref(),
+            arguments.value.as_deref(),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_memory_tool() {
+        let tool = MemoryTool::new();
+        tool.forward(MemoryOperation::Set, Some("city"), Some("Paris"))
+            .unwrap();
+        assert_eq!(
+            tool.forward(MemoryOperation::Get, Some("city"), None)
+                .unwrap(),
+            "Paris"
+        );
+        assert_eq!(
+            tool.forward(MemoryOperation::List, None, None).unwrap(),
+            "Stored keys: city"
+        );
+
+        // Clones share the store, other instances do not
+        let clone = tool.clone();
+        assert_eq!(
+            clone
+                .forward(MemoryOperation::Get, Some("city"), None)
+                .unwrap(),
+            "Paris"
+        );
+        let other = MemoryTool::new();
+        assert_eq!(
+            other.forward(MemoryOperation::List, None, None).unwrap(),
+            "The memory is empty"
+        );
+
+        tool.forward(MemoryOperation::Delete, Some("city"), None)
+            .unwrap();
+        assert_eq!(
+            clone.forward(MemoryOperation::List, None, None).unwrap(),
+            "The memory is empty"
+        );
+    }
+}
@@ -0,0 +1,301 @@
+//! This module contains the meta search tool. It queries several search tools at once and merges their
+//! results, so a single failing or empty search engine does not leave the model without results.
+
+use std::collections::HashMap;
+
+use log::warn;
+use regex::Regex;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+use super::base::{dedup_key, BaseTool};
+use super::citation::Citations;
+use super::ddg_search::{SearchFormat, SearchResult};
+use super::tool_traits::{AnyTool, Tool};
+use anyhow::{anyhow, Result};
+
+/// The default maximum number of merged results returned to the model.
+const DEFAULT_MAX_RESULTS: usize = 10;
+
+/// Dampens the weight of the top ranks in the reciprocal rank fusion of the results.
+const RANK_OFFSET: f64 = 60.0;
+
+#[derive(Deserialize, JsonSchema)]
+#[schemars(title = "MetaSearchToolParams")]
+pub struct MetaSearchToolParams {
+    #[schemars(description = "The query to search for")]
+    query: String,
+    #[schemars(description = "The maximum number of results to return. Defaults to 10")]
+    max_results: Option<usize>,
+}
+
+/// Sends the query to every backend search tool concurrently and merges the results. Results found by
+/// several backends are returned once and ranked higher, backends that fail are skipped.
+///
+/// The backends are called with a `query` argument. They may return a JSON list of `{title, url,
+/// snippet}` objects, like [`DuckDuckGoSearchTool`](super::DuckDuckGoSearchTool), or markdown with one
+/// `[title](url)` link per result, like [`GoogleSearchTool`](super::GoogleSearchTool).
+#[derive(Debug, Serialize)]
+pub struct MetaSearchTool {
+    pub tool: BaseTool,
+    #[serde(skip)]
+    pub backends: Vec<Box<dyn AnyTool>>,
+    pub format: SearchFormat,
+    /// The maximum number of results returned when the model does not ask for a number.
+    pub max_results: usize,
+    /// Numbers the merged results so the model can cite them. See [`Citations`].
+    #[serde(skip)]
+    pub citations: Option<Citations>,
+}
+
+impl Clone for MetaSearchTool {
+    fn clone(&self) -> Self {
+        MetaSearchTool {
+            tool: self.tool.clone(),
+            backends: self.backends.iter().map(|tool| tool.clone_box()).collect(),
+            format: self.format,
+            max_results: self.max_results,
+            citations: self.citations.clone(),
+        }
+    }
+}
+
+impl MetaSearchTool {
+    pub fn new(backends: Vec<Box<dyn AnyTool>>) -> Self {
+        MetaSearchTool {
+            tool: BaseTool {
+                name: "web_search",
+                description: "Searches the web with several search engines at once and returns a combined list of the top results.",
+            },
+            backends,
+            format: SearchFormat::Markdown,
+            max_results: DEFAULT_MAX_RESULTS,
+            citations: None,
+        }
+    }
+
+    pub fn with_format(mut self, format: SearchFormat) -> Self {
+        self.format = format;
+        self
+    }
+
+    /// Return at most `max_results` merged results, at least one.
+    pub fn with_max_results(mut self, max_results: usize) -> Self {
+        self.max_results = max_results.max(1);
+        self
+    }
+
+    /// Number the merged results with `citations`. The ids the backends give their own results are
+    /// dropped.
+    pub fn with_citations(mut self, citations: Citations) -> Self {
+        self.citations = Some(citations);
+        self
+    }
+
+    pub fn forward(&self, query: &str, max_results: Option<usize>) -> Result<Vec<SearchResult>> {
+        let responses = std::thread::scope(|scope| {
+            let handles = self
+                .backends
+                .iter()
+                .map(|backend| scope.spawn(|| backend.forward_value(json!({ "query": query }))))
+                .collect::<Vec<_>>();
+            handles
+                .into_iter()
+                .map(|handle| handle.join())
+                .collect::<Vec<_>>()
+        });
+
+        let mut rankings = Vec::new();
+        let mut errors = Vec::new();
+        for (backend, response) in self.backends.iter().zip(responses) {
+            match response {
+                Ok(Ok(value)) => rankings.push(parse_results(value)),
+                Ok(Err(e)) => errors.push(format!("{}: {}", backend.name(), e)),
+                Err(_) => errors.push(format!("{}: the search panicked", backend.name())),
+            }
+        }
+        for error in &errors {
+            warn!("Search backend failed, skipping it. {}", error);
+        }
+        if rankings.is_empty() {
+            return Err(anyhow!("All searches failed. {}", errors.join(". ")));
+        }
+        let mut results = merge_results(rankings, max_results.unwrap_or(self.max_results).max(1));
+        if let Some(citations) = &self.citations {
+            citations.number(&mut results);
+        }
+        Ok(results)
+    }
+}
+
+impl Default for MetaSearchTool {
+    fn default() -> Self {
+        Self::new(vec![])
+    }
+}
+
+/// The results in the output of a backend, either a JSON list of results or markdown links.
+fn parse_results(value: Value) -> Vec<SearchResult> {
+    match value {
+        Value::Array(items) => items
+            .iter()
+            .filter_map(|item| {
+                let text = |key: &str| item.get(key).and_then(Value::as_str).map(str::to_string);
+                Some(SearchResult {
+                    id: None,
+                    title: text("title").unwrap_or_default(),
+                    snippet: text("snippet").unwrap_or_default(),
+                    url: text("url").or_else(|| text("link"))?,
+                })
+            })
+            .collect(),
+        Value::String(text) => parse_markdown_results(&text),
+        _ => vec![],
+    }
+}
+
+/// One result per block of markdown with a `[title](url)` link, the text after the link being the snippet.
+fn parse_markdown_results(text: &str) -> Vec<SearchResult> {
+    let link = Regex::new(r"\[([^\]]*)\]\((\S+?)\)").unwrap();
+    text.split("\n\n")
+        .filter_map(|block| {
+            let captures = link.captures(block)?;
+            let end = captures.get(0)?.end();
+            Some(SearchResult {
+                id: None,
+                title: captures[1].trim().to_string(),
+                snippet: block[end..].trim().to_string(),
+                url: captures[2].to_string(),
+            })
+        })
+        .collect()
+}
+
+/// Merge the rankings of the backends by reciprocal rank fusion: every result scores `1 / (60 + rank)`
+/// for each backend that found it. Duplicates keep the title and snippet of their first occurrence.
+fn merge_results(rankings: Vec<Vec<SearchResult>>, max_results: usize) -> Vec<SearchResult> {
+    let mut merged: Vec<(SearchResult, f64)> = Vec::new();
+    let mut positions = HashMap::<String, usize>::new();
+    for ranking in rankings {
+        for (rank, result) in ranking.into_iter().enumerate() {
+            let score = 1.0 / (RANK_OFFSET + rank as f64 + 1.0);
+            match positions.get(&dedup_key(&result.url)) {
+                Some(&position) => merged[position].1 += score,
+                None => {
+                    positions.insert(dedup_key(&result.url), merged.len());
+                    merged.push((result, score));
+                }
+            }
+        }
+    }
+    // The sort is stable, so ties keep the order of the backends
+    merged.sort_by(|a, b| b.1.total_cmp(&a.1));
+    merged
+        .into_iter()
+        .take(max_results)
+        .map(|(result, _)| result)
+        .collect()
+}
+
+impl Tool for MetaSearchTool {
+    type Params = MetaSearchToolParams;
+
+    fn name(&self) -> &'static str {
+        self.tool.name
+    }
+
+    fn description(&self) -> &'static str {
+        self.tool.description
+    }
+
+    fn output_description(&self) -> &'static str {
+        self.format.output_description()
+    }
+
+    fn forward(&self, arguments: MetaSearchToolParams) -> Result<String> {
+        let results = self.forward(&arguments.query, arguments.max_results)?;
+        self.format.format(&results)
+    }
+
+    fn forward_value(&self, arguments: MetaSearchToolParams) -> Result<Value> {
+        let results = self.forward(&arguments.query, arguments.max_results)?;
+        Ok(serde_json::to_value(results)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Deserialize, JsonSchema)]
+    struct MockSearchParams {
+        #[allow(dead_code)]
+        query: String,
+    }
+
+    /// A search tool returning a fixed output, or failing when there is none.
+    #[derive(Debug, Clone)]
+    struct MockSearch {
+        name: &'static str,
+        output: Option<&'static str>,
+    }
+
+    impl Tool for MockSearch {
+        type Params = MockSearchParams;
+        fn name(&self) -> &'static str {
+            self.name
+        }
+        fn description(&self) -> &'static str {
+            "Searches the web"
+        }
+        fn forward(&self, _arguments: MockSearchParams) -> Result<String> {
+            self.output
+                .map(str::to_string)
+                .ok_or_else(|| anyhow!("rate limited"))
+        }
+    }
+
+    #[test]
+    fn test_meta_search_dedups_results() {
+        let first = MockSearch {
+            name: "first",
+            output: Some(
+                "[Rust](https://www.rust-lang.org/) \nA language.\n\n[Rust book](https://doc.rust-lang.org/book) \nThe book.",
+            ),
+        };
+        let second = MockSearch {
+            name: "second",
+            output: Some(
+                "## Search Results\n0. [Crates](https://crates.io)\nThe registry.\n\n1. [The Rust Programming Language](http://www.rust-lang.org)\nRust home.",
+            ),
+        };
+        let failing = MockSearch {
+            name: "failing",
+            output: None,
+        };
+        let tool = MetaSearchTool::new(vec![
+            Box::new(first),
+            Box::new(failing.clone()),
+            Box::new(second),
+        ]);
+
+        let results = tool.forward("rust", None).unwrap();
+        let urls = results.iter().map(|r| r.url.as_str()).collect::<Vec<_>>();
+        assert_eq!(
+            urls,
+            vec![
+                "https://www.rust-lang.org/",
+                "https://crates.io",
+                "https://doc.rust-lang.org/book"
+            ]
+        );
+        assert_eq!(results[0].snippet, "A language.");
+        assert_eq!(results[1].title, "Crates");
+        assert_eq!(tool.forward("rust", Some(1)).unwrap().len(), 1);
+        assert_eq!(tool.forward("rust", Some(0)).unwrap().len(), 1);
+
+        let tool = MetaSearchTool::new(vec![Box::new(failing)]);
+        assert!(tool.forward("rust", None).is_err());
+    }
+}
@@ -2,27 +2,53 @@
 //! You can also implement your own tools by implementing the `Tool` trait.
 
 pub mod base;
+pub mod citation;
 pub mod ddg_search;
+pub mod diff_tool;
 pub mod final_answer;
+pub mod format;
 pub mod google_search;
+pub mod html_select;
+pub mod list_dir;
+pub mod memory_tool;
+pub mod meta_search;
 pub mod wikipedia_search;
 pub mod rag_tool;
+pub mod registry;
 pub mod tree_sitter_tool;
 pub mod tool_traits;
 pub mod visit_website;
+pub mod wikidata;
 
+#[cfg(feature = "http-request")]
+pub mod http_request;
+#[cfg(feature = "image-generation")]
+pub mod image_generation;
 #[cfg(feature = "code-agent")]
 pub mod python_interpreter;
 
 pub use base::*;
+pub use citation::*;
 pub use ddg_search::*;
+pub use diff_tool::*;
 pub use final_answer::*;
+pub use format::*;
 pub use google_search::*;
+pub use html_select::*;
+pub use list_dir::*;
+pub use memory_tool::*;
+pub use meta_search::*;
 pub use wikipedia_search::*;
 pub use rag_tool::*;
+pub use registry::*;
 pub use tree_sitter_tool::*;
 pub use tool_traits::*;
 pub use visit_website::*;
+pub use wikidata::*;
 
+#[cfg(feature = "http-request")]
+pub use http_request::*;
+#[cfg(feature = "image-generation")]
+pub use image_generation::*;
 #[cfg(feature = "code-agent")]
 pub use python_interpreter::*;
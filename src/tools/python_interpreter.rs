@@ -44,6 +44,9 @@ impl Tool for PythonInterpreterTool {
     fn description(&self) -> &'static str {
         self.tool.description
     }
+    fn output_description(&self) -> &'static str {
+        "The value of the code, after `Evaluation Result:`"
+    }
     fn forward(&self, arguments: PythonInterpreterToolParams) -> Result<String> {
         let result = evaluate_python_code(&arguments.code, vec![], &mut HashMap::new());
         match result {
@@ -1,11 +1,14 @@
-//! A simple retrieval augmented generation tool that searches a local corpus of documents using TF-IDF.
-//! It returns the top matching documents concatenated together.
+//! A simple retrieval augmented generation tool that searches a local corpus of documents using TF-IDF,
+//! or embeddings when it is given an [`Embedder`]. It returns the top matching documents concatenated together.
+
+use std::sync::Arc;
 
 use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
 use tfidf::tfidf::{TfIdf, Term};
 
 use super::{base::BaseTool, tool_traits::Tool};
+use crate::models::model_traits::Embedder;
 use anyhow::Result;
 
 /// Parameters for the RAG tool.
@@ -16,12 +19,21 @@ pub struct RagToolParams {
     query: String,
 }
 
-/// A simple TF-IDF based retrieval tool.
+/// A simple TF-IDF or embeddings based retrieval tool.
 #[derive(Debug, Serialize, Clone)]
 pub struct RagTool {
     pub tool: BaseTool,
     docs: Vec<String>,
     top_k: usize,
+    #[serde(skip)]
+    embeddings: Option<EmbeddingIndex>,
+}
+
+/// The embedder of a `RagTool` and the embeddings of its documents, computed once.
+#[derive(Debug, Clone)]
+struct EmbeddingIndex {
+    embedder: Arc<dyn Embedder>,
+    doc_embeddings: Vec<Vec<f32>>,
 }
 
 impl RagTool {
@@ -35,10 +47,61 @@ impl RagTool {
             },
             docs,
             top_k,
+            embeddings: None,
         }
     }
 
-    fn search(&self, query: &str) -> Vec<String> {
+    /// Create a new `RagTool` searching the documents by the similarity of their embeddings with the query,
+    /// e.g. with an [`OllamaEmbeddingModel`](crate::models::ollama::OllamaEmbeddingModel). The documents
+    /// are embedded once, here.
+    pub fn new_with_embeddings(
+        docs: Vec<String>,
+        top_k: usize,
+        embedder: Arc<dyn Embedder>,
+    ) -> Result<Self> {
+        let doc_embeddings = embedder.embed(&docs)?;
+        Ok(RagTool {
+            tool: BaseTool {
+                name: "rag",
+                description:
+                    "Retrieve relevant documents from a local corpus by meaning, using embeddings.",
+            },
+            docs,
+            top_k,
+            embeddings: Some(EmbeddingIndex {
+                embedder,
+                doc_embeddings,
+            }),
+        })
+    }
+
+    fn search(&self, query: &str) -> Result<Vec<String>> {
+        let scores = match &self.embeddings {
+            Some(embeddings) => self.embedding_scores(embeddings, query)?,
+            None => self.tfidf_scores(query),
+        };
+        Ok(self.top_docs(scores))
+    }
+
+    fn embedding_scores(
+        &self,
+        embeddings: &EmbeddingIndex,
+        query: &str,
+    ) -> Result<Vec<(usize, f32)>> {
+        let query = embeddings
+            .embedder
+            .embed(&[query.to_string()])?
+            .pop()
+            .unwrap_or_default();
+        Ok(embeddings
+            .doc_embeddings
+            .iter()
+            .map(|doc| cosine_similarity(doc, &query))
+            .enumerate()
+            .collect())
+    }
+
+    fn tfidf_scores(&self, query: &str) -> Vec<(usize, f32)> {
         let mut tfidf = TfIdf::new();
         for doc in &self.docs {
             tfidf.add(doc);
@@ -51,6 +114,10 @@ impl RagTool {
             }
             scores.push((i, score));
         }
+        scores
+    }
+
+    fn top_docs(&self, mut scores: Vec<(usize, f32)>) -> Vec<String> {
         scores.sort_by(|a, b| b.1.partial_cmp(&a.1).unwrap_or(std::cmp::Ordering::Equal));
         scores.truncate(self.top_k);
         scores
@@ -60,6 +127,18 @@ impl RagTool {
     }
 }
 
+/// The cosine similarity of two vectors, 0 if either of them is zero.
+fn cosine_similarity(a: &[f32], b: &[f32]) -> f32 {
+    let dot = a.iter().zip(b).map(|(x, y)| x * y).sum::<f32>();
+    let norm = |v: &[f32]| v.iter().map(|x| x * x).sum::<f32>().sqrt();
+    let norms = norm(a) * norm(b);
+    if norms == 0.0 {
+        0.0
+    } else {
+        dot / norms
+    }
+}
+
 impl Tool for RagTool {
     type Params = RagToolParams;
 
@@ -71,8 +150,12 @@ impl Tool for RagTool {
         self.tool.description
     }
 
+    fn output_description(&self) -> &'static str {
+        "The best matching documents, concatenated"
+    }
+
     fn forward(&self, params: RagToolParams) -> Result<String> {
-        let results = self.search(&params.query);
+        let results = self.search(&params.query)?;
         Ok(results.join("\n---\n"))
     }
 }
@@ -95,4 +178,48 @@ mod tests {
         let out = tool.forward(params).unwrap();
         assert!(out.contains("Rust"));
     }
+
+    /// Embeds a text as the number of times it mentions each topic.
+    #[derive(Debug)]
+    struct TopicEmbedder;
+
+    impl Embedder for TopicEmbedder {
+        fn embed(&self, texts: &[String]) -> Result<Vec<Vec<f32>>, crate::errors::AgentError> {
+            let topics = [["rust", "cargo"], ["python", "pip"], ["france", "paris"]];
+            Ok(texts
+                .iter()
+                .map(|text| {
+                    let text = text.to_lowercase();
+                    topics
+                        .iter()
+                        .map(|words| words.iter().map(|w| text.matches(w).count() as f32).sum())
+                        .collect()
+                })
+                .collect())
+        }
+    }
+
+    #[test]
+    fn test_rag_tool_with_embeddings() {
+        let docs = vec![
+            "Rust is a systems programming language".to_string(),
+            "Python is popular for machine learning".to_string(),
+            "The capital of France is Paris".to_string(),
+        ];
+        let tool = RagTool::new_with_embeddings(docs, 1, Arc::new(TopicEmbedder)).unwrap();
+        let params = RagToolParams {
+            query: "Which city is the capital, Paris or Lyon?".to_string(),
+        };
+        assert_eq!(
+            tool.forward(params).unwrap(),
+            "The capital of France is Paris"
+        );
+        let params = RagToolParams {
+            query: "How do I publish a crate with cargo?".to_string(),
+        };
+        assert_eq!(
+            tool.forward(params).unwrap(),
+            "Rust is a systems programming language"
+        );
+    }
 }
@@ -0,0 +1,161 @@
+//! A registry of tool constructors, to create tools by name, e.g. from a config file or the command line.
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::sync::Arc;
+
+use crate::errors::AgentError;
+
+use super::*;
+
+/// Creates a new instance of a tool.
+pub type ToolConstructor = Arc<dyn Fn() -> Box<dyn AnyTool> + Send + Sync>;
+
+/// Maps tool names to constructors. [`ToolRegistry::new`] registers the built-in tools that need no
+/// configuration under their tool names, e.g. `duckduckgo_search`.
+///
+/// The constructors are shared, so a registry is cheap to clone and can be used from several threads.
+#[derive(Clone, Default)]
+pub struct ToolRegistry {
+    constructors: BTreeMap<String, ToolConstructor>,
+}
+
+impl fmt::Debug for ToolRegistry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ToolRegistry")
+            .field("tools", &self.names())
+            .finish()
+    }
+}
+
+impl ToolRegistry {
+    /// A registry with the built-in tools.
+    pub fn new() -> Self {
+        let mut registry = Self::empty();
+        registry
+            .register(
+                "duckduckgo_search",
+                || Box::new(DuckDuckGoSearchTool::new()),
+            )
+            .register("visit_website", || Box::new(VisitWebsiteTool::new()))
+            .register("google_search", || Box::new(GoogleSearchTool::new(None)))
+            .register("wikipedia_search", || Box::new(WikipediaSearchTool::new()))
+            .register("web_search", || {
+                Box::new(MetaSearchTool::new(vec![
+                    Box::new(DuckDuckGoSearchTool::new()),
+                    Box::new(GoogleSearchTool::new(None)),
+                ]))
+            })
+            .register("rag", || Box::new(RagTool::new(vec![], 3)))
+            .register("tree_sitter_parse", || Box::new(TreeSitterTool::new()))
+            .register("memory", || Box::new(MemoryTool::new()))
+            .register("wikidata", || Box::new(WikidataTool::new()))
+            .register("diff", || Box::new(DiffTool::new()))
+            .register("html_select", || Box::new(HtmlSelectTool::new()))
+            .register("list_dir", || Box::new(ListDirTool::new()));
+        #[cfg(feature = "image-generation")]
+        registry.register("image_generation", || {
+            Box::new(ImageGenerationTool::new(None))
+        });
+        #[cfg(feature = "code-agent")]
+        registry.register("python_interpreter", || {
+            Box::new(PythonInterpreterTool::new())
+        });
+        registry
+    }
+
+    /// A registry without any tool.
+    pub fn empty() -> Self {
+        Self::default()
+    }
+
+    /// Register a tool constructor under `name`, replacing the constructor registered under that name.
+    pub fn register(
+        &mut self,
+        name: &str,
+        constructor: impl Fn() -> Box<dyn AnyTool> + Send + Sync + 'static,
+    ) -> &mut Self {
+        self.constructors
+            .insert(name.to_string(), Arc::new(constructor));
+        self
+    }
+
+    /// Create the tool registered under `name`.
+    pub fn build(&self, name: &str) -> Result<Box<dyn AnyTool>, AgentError> {
+        match self.constructors.get(name) {
+            Some(constructor) => Ok(constructor()),
+            None => Err(AgentError::Execution(format!(
+                "Unknown tool '{}'. Available tools: {}",
+                name,
+                self.names().join(", ")
+            ))),
+        }
+    }
+
+    /// The names of the registered tools, sorted.
+    pub fn names(&self) -> Vec<&str> {
+        self.constructors.keys().map(String::as_str).collect()
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        self.constructors.contains_key(name)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::Result;
+    use schemars::JsonSchema;
+    use serde::Deserialize;
+
+    #[derive(Deserialize, JsonSchema)]
+    struct EchoToolParams {
+        text: String,
+    }
+
+    #[derive(Debug, Clone)]
+    struct EchoTool {
+        prefix: String,
+    }
+
+    impl Tool for EchoTool {
+        type Params = EchoToolParams;
+        fn name(&self) -> &'static str {
+            "echo"
+        }
+        fn description(&self) -> &'static str {
+            "Returns the text"
+        }
+        fn forward(&self, arguments: EchoToolParams) -> Result<String> {
+            Ok(format!("{}{}", self.prefix, arguments.text))
+        }
+    }
+
+    #[test]
+    fn test_register_and_build() {
+        let mut registry = ToolRegistry::new();
+        // The built-in tools are registered under their own names
+        for name in registry.names() {
+            assert_eq!(registry.build(name).unwrap().name(), name);
+        }
+
+        let prefix = "echo: ".to_string();
+        registry.register("echo", move || {
+            Box::new(EchoTool {
+                prefix: prefix.clone(),
+            })
+        });
+        let tool = registry.build("echo").unwrap();
+        assert_eq!(
+            tool.forward_json(serde_json::json!({"text": "hi"}))
+                .unwrap(),
+            "echo: hi"
+        );
+
+        let error = registry.build("unknown").unwrap_err();
+        assert!(error.message().starts_with("Unknown tool 'unknown'"));
+        assert!(error.message().contains("echo, google_search"));
+        assert!(ToolRegistry::empty().build("echo").is_err());
+    }
+}
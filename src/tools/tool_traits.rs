@@ -8,6 +8,7 @@ use serde::de::DeserializeOwned;
 use serde::Serialize;
 use serde_json::json;
 use std::fmt::Debug;
+use std::sync::{Arc, Mutex};
 
 use crate::errors::{AgentError, AgentExecutionError};
 use crate::models::openai::FunctionCall;
@@ -22,29 +23,82 @@ pub trait Tool: Debug {
     fn name(&self) -> &'static str;
     /// The description of the tool.
     fn description(&self) -> &'static str;
+    /// What the tool returns, e.g. `The results as a markdown list`. It is described to the model after
+    /// the inputs of the tool. Defaults to nothing.
+    fn output_description(&self) -> &'static str {
+        ""
+    }
     /// The function to call when the tool is used.
     fn forward(&self, arguments: Self::Params) -> Result<String>;
+    /// The function the agents call when the tool is used. Tools override it to add notes for the model to
+    /// the observation, e.g. intermediate results or warnings, besides the returned string. Defaults to
+    /// [`Tool::forward`].
+    fn forward_with_context(
+        &self,
+        arguments: Self::Params,
+        context: &ToolContext,
+    ) -> Result<String> {
+        let _ = context;
+        self.forward(arguments)
+    }
+    /// The function to call when the result of the tool is used as a value, e.g. by the code interpreter.
+    /// Tools producing JSON override it to return the value itself instead of its string. Defaults to the
+    /// string returned by [`Tool::forward`].
+    fn forward_value(&self, arguments: Self::Params) -> Result<serde_json::Value> {
+        self.forward(arguments).map(serde_json::Value::String)
+    }
+    /// Check that the tool is usable, e.g. that a required API key is set. The agents check their tools
+    /// when they are built, so a misconfigured tool fails fast instead of wasting a step. Defaults to `Ok`.
+    fn validate(&self) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// A sink for the notes of a tool call, given to [`Tool::forward_with_context`]. The agents add the notes
+/// of a successful call to its observation.
+#[derive(Debug, Clone, Default)]
+pub struct ToolContext {
+    notes: Arc<Mutex<Vec<String>>>,
+}
+
+impl ToolContext {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add a note for the model to the observation of the call.
+    pub fn note(&self, note: &str) {
+        self.notes.lock().unwrap().push(note.to_string());
+    }
+
+    /// The notes added so far, in order.
+    pub fn notes(&self) -> Vec<String> {
+        self.notes.lock().unwrap().clone()
+    }
 }
 
-#[derive(serde::Serialize, serde::Deserialize, Debug)]
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone)]
 pub enum ToolType {
     #[serde(rename = "function")]
     Function,
 }
 
 /// A struct that contains information about a tool. This is used to serialize the tool for the API.
-#[derive(Serialize, Debug)]
+#[derive(Serialize, Debug, Clone)]
 pub struct ToolInfo {
     #[serde(rename = "type")]
     tool_type: ToolType,
     pub function: ToolFunctionInfo,
 }
 /// This struct contains information about the function to call when the tool is used.
-#[derive(Serialize, Debug)]
+#[derive(Serialize, Debug, Clone)]
 pub struct ToolFunctionInfo {
     pub name: &'static str,
     pub description: &'static str,
     pub parameters: RootSchema,
+    /// What the tool returns. It is only described in the system prompt, the APIs do not take it.
+    #[serde(skip)]
+    pub output_description: &'static str,
 }
 
 impl ToolInfo {
@@ -61,6 +115,7 @@ impl ToolInfo {
                 name: tool.name(),
                 description: tool.description(),
                 parameters,
+                output_description: tool.output_description(),
             },
         }
     }
@@ -84,22 +139,76 @@ pub trait ToolGroup: Debug {
 
 impl ToolGroup for Vec<Box<dyn AnyTool>> {
     fn call(&self, arguments: &FunctionCall) -> Result<String, AgentError> {
-        let tool = self.iter().find(|tool| tool.name() == arguments.name);
-        if let Some(tool) = tool {
-            let p = arguments.arguments.clone();
-            return tool.forward_json(p);
-        }
-        Err(AgentError::Execution("Tool not found".to_string()))
+        traced_tool_call(arguments, || {
+            let tool = self.iter().find(|tool| tool.name() == arguments.name);
+            if let Some(tool) = tool {
+                let p = arguments.arguments.clone();
+                return tool.forward_json(p);
+            }
+            Err(AgentError::Execution("Tool not found".to_string()))
+        })
     }
     fn tool_info(&self) -> Vec<ToolInfo> {
         self.iter().map(|tool| tool.tool_info()).collect()
     }
 }
 
+/// Make a tool call. With the `tracing` feature, the call runs in a `tool.call` span recording the tool
+/// name, the sizes of the arguments and of the output, and whether the call failed.
+pub(crate) fn traced_tool_call(
+    function: &FunctionCall,
+    call: impl FnOnce() -> Result<String, AgentError>,
+) -> Result<String, AgentError> {
+    #[cfg(feature = "tracing")]
+    let span = tracing::info_span!(
+        "tool.call",
+        tool = %function.name,
+        input_bytes = function.arguments.to_string().len(),
+        output_bytes = tracing::field::Empty,
+        error = tracing::field::Empty,
+    )
+    .entered();
+    #[cfg(not(feature = "tracing"))]
+    let _ = function;
+
+    let result = call();
+    #[cfg(feature = "tracing")]
+    {
+        span.record(
+            "output_bytes",
+            result.as_ref().map_or(0, |output| output.len()),
+        );
+        span.record("error", result.is_err());
+    }
+    result
+}
+
 pub trait AnyTool: Debug + Send + Sync {
     fn name(&self) -> &'static str;
     fn description(&self) -> &'static str;
     fn forward_json(&self, json_args: serde_json::Value) -> Result<String, AgentError>;
+    /// Call the tool with a context collecting its notes. See [`Tool::forward_with_context`].
+    fn forward_json_with_context(
+        &self,
+        json_args: serde_json::Value,
+        context: &ToolContext,
+    ) -> Result<String, AgentError> {
+        let _ = context;
+        self.forward_json(json_args)
+    }
+    /// Call the tool for a structured result. See [`Tool::forward_value`]. Defaults to the string returned
+    /// by [`AnyTool::forward_json`].
+    fn forward_value(&self, json_args: serde_json::Value) -> Result<serde_json::Value, AgentError> {
+        self.forward_json(json_args).map(serde_json::Value::String)
+    }
+    /// Check that the tool is usable. See [`Tool::validate`].
+    fn validate(&self) -> Result<()> {
+        Ok(())
+    }
+    /// What the tool returns. See [`Tool::output_description`].
+    fn output_description(&self) -> &'static str {
+        ""
+    }
     fn tool_info(&self) -> ToolInfo;
     fn clone_box(&self) -> Box<dyn AnyTool>;
 }
@@ -114,18 +223,33 @@ impl<T: Tool + Clone + Send + Sync + 'static> AnyTool for T {
     }
 
     fn forward_json(&self, json_args: serde_json::Value) -> Result<String, AgentError> {
-        let params = serde_json::from_value::<T::Params>(json_args.clone()).map_err(|e| {
-            AgentError::Parsing(format!(
-                "Error when executing tool with arguments: {:?}: {}. As a reminder, this tool's description is: {} and takes inputs: {}",
-                json_args,
-                e.to_string(),
-                self.description(),
-                json!(&self.tool_info().function.parameters.schema)["properties"].to_string()
-            ))
-        })?;
+        let params = parse_params(self, json_args)?;
         Tool::forward(self, params).map_err(|e| AgentError::Execution(e.to_string()))
     }
 
+    fn forward_json_with_context(
+        &self,
+        json_args: serde_json::Value,
+        context: &ToolContext,
+    ) -> Result<String, AgentError> {
+        let params = parse_params(self, json_args)?;
+        Tool::forward_with_context(self, params, context)
+            .map_err(|e| AgentError::Execution(e.to_string()))
+    }
+
+    fn forward_value(&self, json_args: serde_json::Value) -> Result<serde_json::Value, AgentError> {
+        let params = parse_params(self, json_args)?;
+        Tool::forward_value(self, params).map_err(|e| AgentError::Execution(e.to_string()))
+    }
+
+    fn validate(&self) -> Result<()> {
+        Tool::validate(self)
+    }
+
+    fn output_description(&self) -> &'static str {
+        Tool::output_description(self)
+    }
+
     fn tool_info(&self) -> ToolInfo {
         ToolInfo::new::<T::Params, T>(self)
     }
@@ -134,3 +258,114 @@ impl<T: Tool + Clone + Send + Sync + 'static> AnyTool for T {
         Box::new(self.clone())
     }
 }
+
+/// Deserialize the arguments of a tool call, reminding the model of the parameters of the tool on failure.
+/// The error names the field that failed, e.g. "field `query`: invalid type: integer `1`, expected a string".
+fn parse_params<T: Tool + Clone + Send + Sync + 'static>(
+    tool: &T,
+    json_args: serde_json::Value,
+) -> Result<T::Params, AgentError> {
+    serde_path_to_error::deserialize::<_, T::Params>(json_args.clone()).map_err(|e| {
+        let field = match e.path().to_string().as_str() {
+            "." => String::new(),
+            path => format!("field `{}`: ", path),
+        };
+        AgentError::Parsing(format!(
+            "Error when executing tool with arguments: {:?}: {}{}. As a reminder, this tool's description is: {} and takes inputs: {}",
+            json_args,
+            field,
+            e.inner(),
+            AnyTool::description(tool),
+            json!(&AnyTool::tool_info(tool).function.parameters.schema)["properties"].to_string()
+        ))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde::Deserialize;
+
+    #[derive(Deserialize, JsonSchema)]
+    struct SearchParams {
+        #[allow(dead_code)]
+        query: String,
+        #[allow(dead_code)]
+        max_results: Option<usize>,
+    }
+
+    #[derive(Debug, Clone)]
+    struct SearchTool;
+
+    impl Tool for SearchTool {
+        type Params = SearchParams;
+        fn name(&self) -> &'static str {
+            "search"
+        }
+        fn description(&self) -> &'static str {
+            "Searches the web"
+        }
+        fn forward(&self, _arguments: SearchParams) -> Result<String> {
+            Ok("results".to_string())
+        }
+    }
+
+    #[test]
+    fn test_parse_error_names_field() {
+        let error = SearchTool.forward_json(json!({"query": 42})).unwrap_err();
+        assert!(
+            error
+                .message()
+                .contains("field `query`: invalid type: integer `42`, expected a string"),
+            "{}",
+            error
+        );
+
+        let error = SearchTool
+            .forward_json(json!({"query": "rust", "max_results": "ten"}))
+            .unwrap_err();
+        assert!(
+            error.message().contains("field `max_results`: "),
+            "{}",
+            error
+        );
+
+        let error = SearchTool.forward_json(json!({})).unwrap_err();
+        assert!(
+            error.message().contains(": missing field `query`"),
+            "{}",
+            error
+        );
+        assert!(SearchTool.forward_json(json!({"query": "rust"})).is_ok());
+    }
+
+    /// A tool implementing [`AnyTool`] by hand, with the default methods.
+    #[derive(Debug, Clone)]
+    struct EchoTool;
+
+    impl AnyTool for EchoTool {
+        fn name(&self) -> &'static str {
+            "echo"
+        }
+        fn description(&self) -> &'static str {
+            "Echoes its arguments"
+        }
+        fn forward_json(&self, json_args: serde_json::Value) -> Result<String, AgentError> {
+            Ok(json_args.to_string())
+        }
+        fn tool_info(&self) -> ToolInfo {
+            SearchTool.tool_info()
+        }
+        fn clone_box(&self) -> Box<dyn AnyTool> {
+            Box::new(self.clone())
+        }
+    }
+
+    #[test]
+    fn test_forward_value_defaults_to_forward_json() {
+        assert_eq!(
+            EchoTool.forward_value(json!({"a": 1})).unwrap(),
+            json!("{\"a\":1}")
+        );
+    }
+}
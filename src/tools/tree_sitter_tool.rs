@@ -76,6 +76,10 @@ impl Tool for TreeSitterTool {
         self.tool.description
     }
 
+    fn output_description(&self) -> &'static str {
+        "The AST as an s-expression"
+    }
+
     fn forward(&self, params: TreeSitterToolParams) -> Result<String> {
         self.forward(&params.code, &params.language)
     }
@@ -1,16 +1,42 @@
 //! This module contains the visit website tool. The model uses this tool to visit a webpage and read its content as a markdown string.
 
+use std::collections::HashMap;
+
 use htmd::HtmlToMarkdown;
+use regex::Regex;
 use reqwest::Url;
 use schemars::JsonSchema;
+use scraper::{ElementRef, Html, Selector};
 use serde::{Deserialize, Serialize};
 
-use super::{base::BaseTool, tool_traits::Tool};
+use super::{
+    base::{default_http_client, read_body, truncation_notice, BaseTool, DEFAULT_MAX_BODY_BYTES},
+    tool_traits::Tool,
+};
 use anyhow::Result;
 
-#[derive(Debug, Serialize, Default, Clone)]
+/// Minimum amount of paragraph text a region needs to be considered the main article.
+const MIN_ARTICLE_TEXT_LENGTH: usize = 250;
+
+/// The tags left out of the markdown by default.
+pub const DEFAULT_SKIP_TAGS: [&str; 5] = ["script", "style", "header", "nav", "footer"];
+
+#[derive(Debug, Serialize, Clone)]
 pub struct VisitWebsiteTool {
     pub tool: BaseTool,
+    /// Only return the main article of the page instead of the full page.
+    pub readability: bool,
+    /// The tags whose content is left out of the markdown.
+    pub skip_tags: Vec<&'static str>,
+    /// Keep links as markdown links. Otherwise only their text is kept.
+    pub keep_links: bool,
+    /// Keep images as markdown images.
+    pub keep_images: bool,
+    /// The maximum number of bytes of a page read, [`DEFAULT_MAX_BODY_BYTES`] by default. The rest of a
+    /// larger page is not downloaded.
+    pub max_response_bytes: usize,
+    #[serde(skip)]
+    pub client: reqwest::blocking::Client,
 }
 
 impl VisitWebsiteTool {
@@ -20,31 +46,66 @@ impl VisitWebsiteTool {
                 name: "visit_website",
                 description: "Visits a webpage at the given url and reads its content as a markdown string. Use this to browse webpages",
             },
+            readability: false,
+            skip_tags: DEFAULT_SKIP_TAGS.to_vec(),
+            keep_links: true,
+            keep_images: true,
+            max_response_bytes: DEFAULT_MAX_BODY_BYTES,
+            client: default_http_client(),
         }
     }
 
+    /// Extract the main article of each page before converting it to markdown, dropping navigation,
+    /// ads and cookie banners. Pages without a clear article region are returned in full.
+    pub fn with_readability(mut self, readability: bool) -> Self {
+        self.readability = readability;
+        self
+    }
+
+    /// Replace the tags left out of the markdown, [`DEFAULT_SKIP_TAGS`] by default. Some pages put their
+    /// content in a `<header>` or `<nav>`.
+    pub fn with_skip_tags(mut self, skip_tags: &[&'static str]) -> Self {
+        self.skip_tags = skip_tags.to_vec();
+        self
+    }
+
+    pub fn with_links(mut self, keep_links: bool) -> Self {
+        self.keep_links = keep_links;
+        self
+    }
+
+    pub fn with_images(mut self, keep_images: bool) -> Self {
+        self.keep_images = keep_images;
+        self
+    }
+
+    pub fn with_max_response_bytes(mut self, max_response_bytes: usize) -> Self {
+        self.max_response_bytes = max_response_bytes;
+        self
+    }
+
     pub fn forward(&self, url: &str) -> String {
-        let client = reqwest::blocking::Client::builder()
-            .user_agent("Mozilla/5.0 (Windows NT 10.0; Win64; x64) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/91.0.4472.124 Safari/537.36")
-            .build()
-            .unwrap_or_else(|_| reqwest::blocking::Client::new());
+        self.visit(url, self.readability)
+    }
+
+    fn visit(&self, url: &str, readability: bool) -> String {
         let url = match Url::parse(url) {
             Ok(url) => url,
             Err(_) => Url::parse(&format!("https://{}", url)).unwrap(),
         };
 
-        let response = client.get(url.clone()).send();
+        let response = self.client.get(url.clone()).send();
 
         match response {
             Ok(resp) => {
                 if resp.status().is_success() {
-                    match resp.text() {
-                        Ok(text) => {
-                            let converter = HtmlToMarkdown::builder()
-                                .skip_tags(vec!["script", "style", "header", "nav", "footer"])
-                                .build();
-                            converter.convert(&text).unwrap()
-                        }
+                    match read_body(resp, self.max_response_bytes) {
+                        Ok((text, false)) => self.html_to_markdown(&text, readability),
+                        Ok((text, true)) => format!(
+                            "{}\n\n{}",
+                            self.html_to_markdown(&text, readability),
+                            truncation_notice(self.max_response_bytes)
+                        ),
                         Err(_) => "Failed to read response text".to_string(),
                     }
                 } else if resp.status().as_u16() == 999 {
@@ -61,6 +122,97 @@ impl VisitWebsiteTool {
             Err(e) => format!("Failed to make the request to {}: {}", url, e),
         }
     }
+
+    fn html_to_markdown(&self, html: &str, readability: bool) -> String {
+        let mut skip_tags = self.skip_tags.clone();
+        if !self.keep_images {
+            skip_tags.push("img");
+        }
+        let article = if readability {
+            skip_tags.extend(["aside", "form"]);
+            extract_main_content(html)
+        } else {
+            None
+        };
+        let converter = HtmlToMarkdown::builder().skip_tags(skip_tags).build();
+        match converter.convert(article.as_deref().unwrap_or(html)) {
+            Ok(markdown) if self.keep_links => markdown,
+            Ok(markdown) => strip_links(&markdown),
+            Err(e) => format!("Failed to convert the webpage to markdown: {}", e),
+        }
+    }
+}
+
+/// Replace the markdown links with their text, leaving images alone.
+fn strip_links(markdown: &str) -> String {
+    let links = Regex::new(r"(!?)\[([^\]]*)\]\([^)]*\)").unwrap();
+    links
+        .replace_all(markdown, |caps: &regex::Captures| match &caps[1] {
+            "!" => caps[0].to_string(),
+            _ => caps[2].to_string(),
+        })
+        .into_owned()
+}
+
+fn text_len(element: &ElementRef) -> usize {
+    element.text().map(|t| t.trim().len()).sum()
+}
+
+fn link_density(element: &ElementRef, links: &Selector) -> f64 {
+    let total = text_len(element);
+    if total == 0 {
+        return 1.0;
+    }
+    let link_text: usize = element.select(links).map(|a| text_len(&a)).sum();
+    link_text as f64 / total as f64
+}
+
+/// Find the main article of a page, readability style, and return its html.
+///
+/// Explicit `<article>` and `<main>` regions are used when they hold enough text. Otherwise every paragraph
+/// scores its parent (and half of that for its grandparent) by its text length, and the best container is
+/// picked after penalizing link-heavy regions. Returns `None` when no region stands out.
+pub fn extract_main_content(html: &str) -> Option<String> {
+    let document = Html::parse_document(html);
+    let links = Selector::parse("a").ok()?;
+
+    for selector in ["article", "main", "[role=\"main\"]"] {
+        let selector = Selector::parse(selector).ok()?;
+        if let Some(element) = document.select(&selector).max_by_key(text_len) {
+            if text_len(&element) >= MIN_ARTICLE_TEXT_LENGTH {
+                return Some(element.html());
+            }
+        }
+    }
+
+    let paragraphs = Selector::parse("p, pre").ok()?;
+    let mut scores = HashMap::new();
+    for paragraph in document.select(&paragraphs) {
+        let length = text_len(&paragraph);
+        if length < 25 {
+            continue;
+        }
+        let score = 1.0 + length.min(300) as f64 / 100.0 + length as f64 / 100.0;
+        let parent = paragraph.parent().and_then(ElementRef::wrap);
+        if let Some(parent) = parent {
+            *scores.entry(parent.id()).or_insert(0.0) += score;
+            if let Some(grandparent) = parent.parent().and_then(ElementRef::wrap) {
+                *scores.entry(grandparent.id()).or_insert(0.0) += score / 2.0;
+            }
+        }
+    }
+
+    let (best, _) = scores
+        .into_iter()
+        .filter_map(|(id, score)| {
+            let element = document.tree.get(id).and_then(ElementRef::wrap)?;
+            Some((element, score * (1.0 - link_density(&element, &links))))
+        })
+        .max_by(|a, b| a.1.total_cmp(&b.1))?;
+    if text_len(&best) < MIN_ARTICLE_TEXT_LENGTH {
+        return None;
+    }
+    Some(best.html())
 }
 
 #[derive(Deserialize, JsonSchema)]
@@ -68,6 +220,16 @@ impl VisitWebsiteTool {
 pub struct VisitWebsiteToolParams {
     #[schemars(description = "The url of the website to visit")]
     url: String,
+    #[schemars(
+        description = "Only return the main article of the page, without navigation, ads and banners. Useful for news and blog posts"
+    )]
+    readability: Option<bool>,
+}
+
+impl Default for VisitWebsiteTool {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Tool for VisitWebsiteTool {
@@ -80,15 +242,39 @@ impl Tool for VisitWebsiteTool {
         self.tool.description
     }
 
+    fn output_description(&self) -> &'static str {
+        "The content of the webpage as markdown"
+    }
+
     fn forward(&self, arguments: VisitWebsiteToolParams) -> Result<String> {
-        let url = arguments.url;
-        Ok(self.forward(&url))
+        let readability = arguments.readability.unwrap_or(self.readability);
+        Ok(self.visit(&arguments.url, readability))
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::test_server::{serve, Response};
+
+    /// Answer one request on a local port with a page of `size` bytes. Returns the url.
+    fn serve_page(size: usize) -> String {
+        let page = format!("<p>{}</p>", "a".repeat(size - 7));
+        let (url, _) = serve(1, move |_, _| Response::html(200, page.clone()));
+        format!("{}/", url)
+    }
+
+    #[test]
+    fn test_max_response_bytes() {
+        let tool = VisitWebsiteTool::new().with_max_response_bytes(1000);
+        let result = tool.forward(&serve_page(5_000_000));
+        assert!(
+            result.ends_with("[Response truncated after 1000 bytes]"),
+            "{}",
+            result
+        );
+        assert!(result.len() < 1100);
+    }
 
     #[test]
     fn test_visit_website_tool() {
@@ -97,4 +283,40 @@ mod tests {
         let _result = tool.forward(&url);
         println!("{}", _result);
     }
+
+    #[test]
+    fn test_extract_main_content() {
+        let paragraph = "This is a long paragraph of article text that goes on for a while. ".repeat(3);
+        let html = format!(
+            r#"<html><body>
+            <div id="nav"><a href="/">Home</a> <a href="/news">News</a> <a href="/about">About us and more links</a></div>
+            <div id="banner"><p>We use cookies to improve your experience.</p></div>
+            <div id="content"><p>{0}</p><p>{0}</p><p>{0}</p></div>
+            </body></html>"#,
+            paragraph
+        );
+        let article = extract_main_content(&html).unwrap();
+        assert!(article.starts_with("<div id=\"content\">"));
+        assert!(!article.contains("cookies"));
+
+        // Falls back to the full page when there is no clear article
+        assert!(extract_main_content("<html><body><p>Short page</p></body></html>").is_none());
+    }
+
+    #[test]
+    fn test_markdown_options() {
+        let html = "<html><body><nav>Chapter list</nav><p>Read the <a href=\"/guide\">guide</a></p></body></html>";
+        let tool = VisitWebsiteTool::new();
+        let markdown = tool.html_to_markdown(html, false);
+        assert!(!markdown.contains("Chapter list"));
+        assert!(markdown.contains("guide"));
+
+        let tool = tool.with_skip_tags(&["script", "style"]);
+        assert!(tool.html_to_markdown(html, false).contains("Chapter list"));
+
+        assert_eq!(
+            strip_links("See [the guide](/guide) and ![logo](/logo.png)"),
+            "See the guide and ![logo](/logo.png)"
+        );
+    }
 }
@@ -0,0 +1,306 @@
+//! This module contains a Wikidata tool that returns structured facts about an entity.
+
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Map, Value};
+
+use super::{
+    base::{default_http_client, read_json_body, BaseTool, DEFAULT_MAX_BODY_BYTES},
+    tool_traits::Tool,
+};
+use anyhow::{anyhow, Result};
+
+const WIKIDATA_API_URL: &str = "https://www.wikidata.org/w/api.php";
+
+/// The properties returned for an entity, with the key they are reported under.
+const PROPERTIES: &[(&str, &str)] = &[
+    ("P31", "instance_of"),
+    ("P17", "country"),
+    ("P1082", "population"),
+    ("P625", "coordinates"),
+    ("P571", "inception"),
+    ("P569", "date_of_birth"),
+    ("P570", "date_of_death"),
+    ("P856", "official_website"),
+];
+
+/// Properties that can have several values, e.g. a city that is an instance of several classes.
+const MULTI_VALUED_PROPERTIES: &[&str] = &["P31", "P17"];
+
+#[derive(Deserialize, JsonSchema)]
+#[schemars(title = "WikidataToolParams")]
+pub struct WikidataToolParams {
+    #[schemars(description = "The entity to look up, e.g. 'Paris' or 'Marie Curie'")]
+    query: String,
+}
+
+#[derive(Debug, Serialize, Clone)]
+pub struct WikidataTool {
+    pub tool: BaseTool,
+    #[serde(skip)]
+    pub client: reqwest::blocking::Client,
+}
+
+impl WikidataTool {
+    pub fn new() -> Self {
+        WikidataTool {
+            tool: BaseTool {
+                name: "wikidata",
+                description: "Look up an entity on Wikidata and return structured facts about it as JSON: label, description, instance of, country, population, coordinates and dates. Other entities matching the query are listed as candidates.",
+            },
+            client: default_http_client(),
+        }
+    }
+
+    pub fn forward(&self, query: &str) -> Result<String> {
+        let response = self
+            .client
+            .get(WIKIDATA_API_URL)
+            .query(&[
+                ("action", "wbsearchentities"),
+                ("search", query),
+                ("language", "en"),
+                ("format", "json"),
+                ("limit", "5"),
+            ])
+            .send()?
+            .error_for_status()?;
+        let search: Value = read_json_body(response, DEFAULT_MAX_BODY_BYTES)?;
+        let candidates = search_candidates(&search);
+        let Some(top) = candidates.first() else {
+            return Ok(format!("No Wikidata entity found for '{}'", query));
+        };
+        let qid = top["id"]
+            .as_str()
+            .ok_or_else(|| anyhow!("Invalid Wikidata search result"))?;
+
+        let entity = self.get_entities(&[qid], "labels|descriptions|claims")?;
+        let entity = entity
+            .get(qid)
+            .ok_or_else(|| anyhow!("Wikidata entity {} not found", qid))?;
+        let (mut properties, referenced_ids) = extract_properties(entity);
+
+        // Claims that point to other entities only contain their ids, resolve them to labels
+        if !referenced_ids.is_empty() {
+            let ids = referenced_ids
+                .iter()
+                .map(String::as_str)
+                .collect::<Vec<_>>();
+            let labels = self.get_entities(&ids, "labels")?;
+            resolve_labels(&mut properties, &labels);
+        }
+
+        let result = json!({
+            "id": qid,
+            "label": entity["labels"]["en"]["value"],
+            "description": entity["descriptions"]["en"]["value"],
+            "url": format!("https://www.wikidata.org/wiki/{}", qid),
+            "properties": properties,
+            "candidates": &candidates[1..],
+        });
+        Ok(serde_json::to_string_pretty(&result)?)
+    }
+
+    fn get_entities(&self, ids: &[&str], props: &str) -> Result<Value> {
+        let response = self
+            .client
+            .get(WIKIDATA_API_URL)
+            .query(&[
+                ("action", "wbgetentities"),
+                ("ids", &ids.join("|")),
+                ("props", props),
+                ("languages", "en"),
+                ("format", "json"),
+            ])
+            .send()?
+            .error_for_status()?;
+        let response: Value = read_json_body(response, DEFAULT_MAX_BODY_BYTES)?;
+        Ok(response["entities"].clone())
+    }
+}
+
+impl Default for WikidataTool {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// The search results of `wbsearchentities` as `{id, label, description}` objects, best match first.
+fn search_candidates(search: &Value) -> Vec<Value> {
+    search["search"]
+        .as_array()
+        .map(|results| {
+            results
+                .iter()
+                .map(|result| {
+                    json!({
+                        "id": result["id"],
+                        "label": result["label"],
+                        "description": result["description"],
+                    })
+                })
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+/// Extract the values of [`PROPERTIES`] from the claims of an entity. Values that are references to
+/// other entities are returned as their ids, which are also collected to resolve their labels.
+fn extract_properties(entity: &Value) -> (Map<String, Value>, Vec<String>) {
+    let mut properties = Map::new();
+    let mut referenced_ids = Vec::new();
+    for (property, key) in PROPERTIES {
+        let Some(claims) = entity["claims"][*property].as_array() else {
+            continue;
+        };
+        // Only keep the preferred statements if there are any, e.g. the latest population figure
+        let claims = claims
+            .iter()
+            .filter(|claim| claim["rank"] != "deprecated")
+            .collect::<Vec<_>>();
+        let preferred = claims
+            .iter()
+            .copied()
+            .filter(|claim| claim["rank"] == "preferred")
+            .collect::<Vec<_>>();
+        let claims = if preferred.is_empty() {
+            claims
+        } else {
+            preferred
+        };
+
+        let mut values = claims
+            .iter()
+            .filter_map(|claim| datavalue_to_json(&claim["mainsnak"]["datavalue"]))
+            .collect::<Vec<_>>();
+        if values.is_empty() {
+            continue;
+        }
+        for value in &values {
+            if let Some(id) = value.as_str().filter(|id| is_entity_id(id)) {
+                referenced_ids.push(id.to_string());
+            }
+        }
+        let value = if MULTI_VALUED_PROPERTIES.contains(property) {
+            Value::Array(values)
+        } else {
+            values.pop().unwrap()
+        };
+        properties.insert(key.to_string(), value);
+    }
+    referenced_ids.sort();
+    referenced_ids.dedup();
+    (properties, referenced_ids)
+}
+
+/// Convert a Wikidata `datavalue` into a plain JSON value.
+fn datavalue_to_json(datavalue: &Value) -> Option<Value> {
+    let value = &datavalue["value"];
+    match datavalue["type"].as_str()? {
+        "wikibase-entityid" => Some(value["id"].clone()),
+        "quantity" => {
+            let amount = value["amount"].as_str()?.trim_start_matches('+');
+            Some(
+                amount
+                    .parse::<i64>()
+                    .map(Value::from)
+                    .or_else(|_| amount.parse::<f64>().map(Value::from))
+                    .unwrap_or_else(|_| Value::from(amount)),
+            )
+        }
+        "globecoordinate" => Some(json!({
+            "latitude": value["latitude"],
+            "longitude": value["longitude"],
+        })),
+        "time" => Some(Value::from(value["time"].as_str()?.trim_start_matches('+'))),
+        "monolingualtext" => Some(value["text"].clone()),
+        "string" => Some(value.clone()),
+        _ => None,
+    }
+}
+
+fn is_entity_id(id: &str) -> bool {
+    id.len() > 1 && id.starts_with('Q') && id[1..].chars().all(|c| c.is_ascii_digit())
+}
+
+/// Replace the entity ids in `properties` with their English labels, keeping the id if there is none.
+fn resolve_labels(properties: &mut Map<String, Value>, entities: &Value) {
+    let resolve = |value: &mut Value| {
+        if let Some(label) = value
+            .as_str()
+            .filter(|id| is_entity_id(id))
+            .and_then(|id| entities[id]["labels"]["en"]["value"].as_str())
+        {
+            *value = Value::from(label);
+        }
+    };
+    for value in properties.values_mut() {
+        match value {
+            Value::Array(values) => values.iter_mut().for_each(resolve),
+            value => resolve(value),
+        }
+    }
+}
+
+impl Tool for WikidataTool {
+    type Params = WikidataToolParams;
+
+    fn name(&self) -> &'static str {
+        self.tool.name
+    }
+
+    fn description(&self) -> &'static str {
+        self.tool.description
+    }
+
+    fn output_description(&self) -> &'static str {
+        "The facts about the entity as a JSON object"
+    }
+
+    fn forward(&self, params: WikidataToolParams) -> Result<String> {
+        self.forward(&params.query)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_extract_properties() {
+        let entity = json!({
+            "claims": {
+                "P31": [
+                    {"rank": "normal", "mainsnak": {"datavalue": {"type": "wikibase-entityid", "value": {"id": "Q515"}}}},
+                    {"rank": "normal", "mainsnak": {"datavalue": {"type": "wikibase-entityid", "value": {"id": "Q5119"}}}}
+                ],
+                "P1082": [
+                    {"rank": "normal", "mainsnak": {"datavalue": {"type": "quantity", "value": {"amount": "+2145906"}}}},
+                    {"rank": "preferred", "mainsnak": {"datavalue": {"type": "quantity", "value": {"amount": "+2102650"}}}}
+                ],
+                "P625": [
+                    {"rank": "normal", "mainsnak": {"datavalue": {"type": "globecoordinate", "value": {"latitude": 48.8567, "longitude": 2.3508}}}}
+                ]
+            }
+        });
+        let (mut properties, referenced_ids) = extract_properties(&entity);
+        assert_eq!(properties["population"], json!(2102650));
+        assert_eq!(
+            properties["coordinates"],
+            json!({"latitude": 48.8567, "longitude": 2.3508})
+        );
+        assert_eq!(referenced_ids, vec!["Q5119", "Q515"]);
+
+        let labels = json!({"Q515": {"labels": {"en": {"value": "city"}}}});
+        resolve_labels(&mut properties, &labels);
+        assert_eq!(properties["instance_of"], json!(["city", "Q5119"]));
+    }
+
+    #[test]
+    #[ignore]
+    fn test_wikidata_tool() {
+        let tool = WikidataTool::new();
+        let out = tool.forward("Paris").unwrap();
+        assert!(out.contains("Q90"));
+    }
+}
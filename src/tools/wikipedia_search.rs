@@ -3,7 +3,10 @@
 use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
 
-use super::{base::BaseTool, tool_traits::Tool};
+use super::{
+    base::{default_http_client, read_json_body, BaseTool, DEFAULT_MAX_BODY_BYTES},
+    tool_traits::Tool,
+};
 use anyhow::Result;
 
 #[derive(Deserialize, JsonSchema)]
@@ -13,9 +16,11 @@ pub struct WikipediaSearchToolParams {
     query: String,
 }
 
-#[derive(Debug, Serialize, Default, Clone)]
+#[derive(Debug, Serialize, Clone)]
 pub struct WikipediaSearchTool {
     pub tool: BaseTool,
+    #[serde(skip)]
+    pub client: reqwest::blocking::Client,
 }
 
 impl WikipediaSearchTool {
@@ -25,14 +30,15 @@ impl WikipediaSearchTool {
                 name: "wikipedia_search",
                 description: "Search Wikipedia for a term and return a short summary of the top article.",
             },
+            client: default_http_client(),
         }
     }
 
     fn forward(&self, query: &str) -> Result<String> {
         let url = format!("https://en.wikipedia.org/api/rest_v1/page/summary/{}", query.replace(" ", "%20"));
-        let resp = reqwest::blocking::get(url)?;
+        let resp = self.client.get(url).send()?;
         if resp.status().is_success() {
-            let val: serde_json::Value = resp.json()?;
+            let val: serde_json::Value = read_json_body(resp, DEFAULT_MAX_BODY_BYTES)?;
             if let Some(extract) = val.get("extract").and_then(|v| v.as_str()) {
                 Ok(extract.to_string())
             } else if let Some(detail) = val.get("detail").and_then(|v| v.as_str()) {
@@ -46,6 +52,12 @@ impl WikipediaSearchTool {
     }
 }
 
+impl Default for WikipediaSearchTool {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl Tool for WikipediaSearchTool {
     type Params = WikipediaSearchToolParams;
 
@@ -57,6 +69,10 @@ impl Tool for WikipediaSearchTool {
         self.tool.description
     }
 
+    fn output_description(&self) -> &'static str {
+        "The summary of the article as text"
+    }
+
     fn forward(&self, params: WikipediaSearchToolParams) -> Result<String> {
         self.forward(&params.query)
     }
@@ -0,0 +1,54 @@
+#![cfg(feature = "cli")]
+
+#[path = "../src/test_server.rs"]
+#[allow(dead_code)]
+mod test_server;
+
+use std::io::Write;
+use std::process::{Command, Stdio};
+
+use serde_json::json;
+use test_server::{serve, Response};
+
+#[test]
+fn test_quiet_only_prints_the_answers() {
+    // The thought of the model is streamed to the output unless it is quiet
+    let (url, server) = serve(1, |_, _| {
+        Response::json(
+            200,
+            json!({
+                "choices": [{"message": {
+                    "role": "assistant",
+                    "content": "I know this one.",
+                    "tool_calls": [{
+                        "id": "call_1",
+                        "type": "function",
+                        "function": {"name": "final_answer", "arguments": "{\"answer\": \"Paris\"}"}
+                    }]
+                }}]
+            }),
+        )
+    });
+    let dir = tempfile::tempdir().unwrap();
+    let mut cli = Command::new(env!("CARGO_BIN_EXE_smolagents-rs"))
+        .args(["--quiet", "--stream", "--tools", "duck-duck-go"])
+        .arg("--base-url")
+        .arg(format!("{}/v1/chat/completions", url))
+        .current_dir(dir.path())
+        // The model type is picked from the key
+        .env("OPENAI_API_KEY", "key")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .unwrap();
+    cli.stdin
+        .take()
+        .unwrap()
+        .write_all(b"What is the capital of France?\n")
+        .unwrap();
+
+    let output = cli.wait_with_output().unwrap();
+    server.join().unwrap();
+    assert!(output.status.success());
+    assert_eq!(String::from_utf8(output.stdout).unwrap(), "Paris\n");
+}